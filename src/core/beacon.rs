@@ -0,0 +1,238 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use log::info;
+use serde::{Serialize, Deserialize};
+use crate::core::block::{Block, BlockHeader};
+use crate::core::consensus::ValidatorSet;
+use crate::core::encoding::{self, Canonical, Decoder, Encoder};
+use crate::core::finality::{Vote, VoteType};
+use crate::core::transaction::{Transaction, TransactionType};
+
+// Coordination chain.
+//
+// Shard 0 doubles as the beacon chain the other shards anchor to. Its blocks
+// carry crosslinks: `Crosslink` transactions proving, for another shard, that
+// a block is final. The proof is the block's header with precommits from more
+// than 2/3 of the weight of the shard's committee, checked against the
+// validator set the beacon state recorded for the block's epoch, so it holds
+// regardless of which blocks the checking node has seen. The beacon state
+// keeps every crosslink of every shard in the contract storage of the beacon
+// account, so a single beacon state root commits to the state of every shard.
+// The beacon account also records the validator set of each upcoming epoch
+// (see `consensus`), which every shard's blocks and votes are checked against.
+//
+// Cross-shard receipts and refunds only trust headers they can link to
+// anchored blocks. Each block of another shard names, in its header, a
+// finalized beacon block that anchors them: the blocks that beacon block or
+// an earlier one crosslinked, and the beacon block and its ancestors. Every
+// node that has finalized that beacon block judges the block's claims and
+// refunds the same way, whatever it has seen since. Shard committees are
+// drawn from the beacon chain's RANDAO mix.
+
+/// Shard whose chain is the beacon chain.
+pub const BEACON_SHARD: u16 = 0;
+
+/// System account holding the crosslinks of every shard and the validator
+/// set of every epoch.
+pub const BEACON_ADDRESS: &str = "0x0000000000000000000000000000000000000005";
+
+const CROSSLINK_PREFIX: &str = "crosslink/";
+const CROSSLINKED_PREFIX: &str = "crosslinked/";
+const VALIDATOR_SET_PREFIX: &str = "validators/";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Crosslink {
+    pub shard_id: u16,
+    pub height: u64,
+    pub block_hash: String,
+    pub state_root: String,
+    pub linked_at: u64, // Height of the beacon block that recorded it
+}
+
+impl Crosslink {
+    pub fn storage_key(&self) -> String {
+        crosslink_key(self.shard_id)
+    }
+}
+
+// What a crosslink transaction carries: the header of a finalized block of
+// another shard and the precommits that finalized it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FinalityProof {
+    pub header: BlockHeader,
+    pub precommits: Vec<Vote>,
+}
+
+impl FinalityProof {
+    pub fn new(header: BlockHeader, precommits: Vec<Vote>) -> Self {
+        FinalityProof { header, precommits }
+    }
+
+    pub fn from_transaction(tx: &Transaction) -> Result<Self, String> {
+        if !matches!(tx.tx_type, TransactionType::Crosslink) {
+            return Err(format!("Transaction {} is not a crosslink", tx.hash));
+        }
+
+        encoding::from_bytes(&tx.data)
+    }
+
+    /// Transaction recording the crosslink in a beacon block.
+    pub fn to_transaction(&self) -> Transaction {
+        Transaction::new(
+            TransactionType::Crosslink,
+            Vec::new(),
+            Vec::new(),
+            BEACON_SHARD,
+            encoding::to_bytes(self),
+            0,
+        )
+    }
+
+    /// The crosslink the proof claims, as recorded by the beacon block at
+    /// `linked_at`; `verify` is where the proof is checked.
+    pub fn crosslink(&self, linked_at: u64) -> Crosslink {
+        Crosslink {
+            shard_id: self.header.shard_id,
+            height: self.header.height,
+            block_hash: Block::calculate_hash(&self.header),
+            state_root: self.header.state_root.clone(),
+            linked_at,
+        }
+    }
+
+    /// Checks that the precommits are signed by members of the shard's
+    /// committee in `validator_set`, which must be the set of the header's
+    /// epoch, and carry more than 2/3 of its weight.
+    pub fn verify(&self, validator_set: &ValidatorSet) -> Result<(), String> {
+        let shard_id = self.header.shard_id;
+        let block_hash = Block::calculate_hash(&self.header);
+        let mut signers = HashSet::new();
+        let mut weight: u128 = 0;
+
+        for vote in &self.precommits {
+            if vote.vote_type != VoteType::Precommit || vote.shard_id != shard_id ||
+                vote.height != self.header.height || vote.block_hash != block_hash {
+                return Err(format!("Vote from {} is not a precommit of block {}", vote.validator, block_hash));
+            }
+
+            if !validator_set.is_shard_validator(shard_id, &vote.validator) {
+                return Err(format!("Precommit from {} who is not a validator of shard {} in epoch {}",
+                                   vote.validator, shard_id, validator_set.epoch));
+            }
+
+            if !signers.insert(vote.validator.as_str()) {
+                return Err(format!("Duplicate precommit from {}", vote.validator));
+            }
+
+            let public_key = validator_set
+                .public_key(&vote.validator)
+                .ok_or_else(|| format!("Validator {} not found", vote.validator))?;
+            if !vote.verify_signature(&public_key) {
+                return Err(format!("Invalid signature on precommit from {}", vote.validator));
+            }
+
+            weight += validator_set.weight(&vote.validator) as u128;
+        }
+
+        let total_weight = validator_set.total_shard_weight(shard_id);
+        if weight * 3 <= total_weight * 2 {
+            return Err(format!("Precommits of block {} carry {} of {} committee weight, not more than 2/3",
+                               block_hash, weight, total_weight));
+        }
+
+        Ok(())
+    }
+}
+
+/// Key of a shard's crosslink in the beacon account.
+pub fn crosslink_key(shard_id: u16) -> String {
+    format!("{}{:05}", CROSSLINK_PREFIX, shard_id)
+}
+
+pub fn is_crosslink_key(key: &str) -> bool {
+    key.starts_with(CROSSLINK_PREFIX)
+}
+
+/// Key of the crosslink of a shard's block at `height` in the beacon
+/// account. Every crosslink is kept there, so headers linked to it stay
+/// anchored.
+pub fn crosslinked_key(shard_id: u16, height: u64) -> String {
+    format!("{}{:05}/{:020}", CROSSLINKED_PREFIX, shard_id, height)
+}
+
+/// Key of the validator set of `epoch` in the beacon account.
+pub fn validator_set_key(epoch: u64) -> String {
+    format!("{}{:020}", VALIDATOR_SET_PREFIX, epoch)
+}
+
+impl Canonical for FinalityProof {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.header.encode(encoder);
+        encoder.put_seq(&self.precommits);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(FinalityProof {
+            header: BlockHeader::decode(decoder)?,
+            precommits: decoder.get_seq()?,
+        })
+    }
+}
+
+impl Canonical for Crosslink {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_u16(self.shard_id);
+        encoder.put_u64(self.height);
+        encoder.put_str(&self.block_hash);
+        encoder.put_str(&self.state_root);
+        encoder.put_u64(self.linked_at);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(Crosslink {
+            shard_id: decoder.get_u16()?,
+            height: decoder.get_u64()?,
+            block_hash: decoder.get_string()?,
+            state_root: decoder.get_string()?,
+            linked_at: decoder.get_u64()?,
+        })
+    }
+}
+
+// Finality proof of the latest block this node has seen finalized in each
+// other shard, for its beacon blocks to crosslink
+lazy_static::lazy_static! {
+    static ref FINALITY_PROOFS: RwLock<HashMap<u16, FinalityProof>> = RwLock::new(HashMap::new());
+}
+
+pub fn initialize() -> Result<(), Box<dyn std::error::Error>> {
+    info!("Initializing beacon chain crosslinks...");
+    FINALITY_PROOFS.write().unwrap().clear();
+    Ok(())
+}
+
+pub fn shutdown() -> Result<(), Box<dyn std::error::Error>> {
+    info!("Shutting down beacon chain crosslinks...");
+    FINALITY_PROOFS.write().unwrap().clear();
+    Ok(())
+}
+
+/// Keeps the finality proof of a newly finalized block of another shard,
+/// unless a higher block of that shard is already proven.
+pub fn record_finality_proof(proof: FinalityProof) {
+    let mut proofs = FINALITY_PROOFS.write().unwrap();
+    match proofs.get(&proof.header.shard_id) {
+        Some(latest) if latest.header.height >= proof.header.height => {}
+        _ => {
+            proofs.insert(proof.header.shard_id, proof);
+        }
+    }
+}
+
+/// Finality proofs of the latest finalized blocks of other shards, ordered
+/// by shard.
+pub fn finality_proofs() -> Vec<FinalityProof> {
+    let mut proofs: Vec<FinalityProof> = FINALITY_PROOFS.read().unwrap().values().cloned().collect();
+    proofs.sort_by_key(|proof| proof.header.shard_id);
+    proofs
+}
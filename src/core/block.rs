@@ -1,19 +1,29 @@
 use serde::{Serialize, Deserialize};
-use sha2::{Sha256, Digest};
 use std::time::{SystemTime, UNIX_EPOCH};
+use crate::core::crypto;
+use crate::core::encoding::{self, Canonical, Decoder, Encoder};
+use crate::core::merkle::{self, MerkleProof};
 use crate::core::transaction::Transaction;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BlockHeader {
     pub version: u32,
     pub previous_hash: String,
+    pub height: u64,             // Position in the chain, starting at 1
     pub merkle_root: String,
+    pub state_root: String,      // Root of the shard state after applying this block
     pub timestamp: u64,
     pub shard_id: u16,
-    pub difficulty: u32,
-    pub nonce: u64,
+    pub slot: u64,               // Slot the block was proposed in
+    // Proposers that let their turn pass between the parent and this block
+    pub missed_proposers: Vec<String>,
     pub validator: String,
-    pub contribution_score: u32,
+    pub contribution_score: u32, // Validator's score in the validator set of the block's epoch
+    pub randao_reveal: String,   // Validator's RANDAO reveal for the block's epoch
+    pub randao_mix: String,      // RANDAO mix after folding in this block's reveal
+    // Finalized beacon block whose state anchors the block's claims and
+    // refunds; 0 in the beacon shard, which anchors them in its own state
+    pub beacon_height: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,6 +37,7 @@ pub struct Block {
 impl Block {
     pub fn new(
         previous_hash: String,
+        height: u64,
         transactions: Vec<Transaction>,
         shard_id: u16,
         validator: String,
@@ -42,13 +53,18 @@ impl Block {
         let mut header = BlockHeader {
             version: 1,
             previous_hash,
+            height,
             merkle_root,
+            state_root: String::new(), // Set once the block has been applied
             timestamp,
             shard_id,
-            difficulty: 0, // Will be set based on network conditions
-            nonce: 0,      // Will be set during mining/validation
+            slot: 0,                      // Set by the validator
+            missed_proposers: Vec::new(),
             validator,
             contribution_score,
+            randao_reveal: String::new(), // Set by the validator
+            randao_mix: String::new(),
+            beacon_height: 0,             // Set by the validator
         };
         
         let hash = Self::calculate_hash(&header);
@@ -62,66 +78,114 @@ impl Block {
     }
     
     pub fn calculate_hash(header: &BlockHeader) -> String {
-        let serialized = serde_json::to_string(header).unwrap();
-        let mut hasher = Sha256::new();
-        hasher.update(serialized.as_bytes());
-        let result = hasher.finalize();
-        hex::encode(result)
+        encoding::hash(header)
     }
     
     pub fn calculate_merkle_root(transactions: &[Transaction]) -> String {
-        if transactions.is_empty() {
-            return hex::encode([0u8; 32]);
-        }
-        
-        let mut hashes: Vec<String> = transactions
-            .iter()
-            .map(|tx| tx.hash.clone())
-            .collect();
-        
-        while hashes.len() > 1 {
-            let mut new_hashes = Vec::new();
-            
-            for chunk in hashes.chunks(2) {
-                let mut hasher = Sha256::new();
-                if chunk.len() == 2 {
-                    hasher.update(chunk[0].as_bytes());
-                    hasher.update(chunk[1].as_bytes());
-                } else {
-                    hasher.update(chunk[0].as_bytes());
-                    hasher.update(chunk[0].as_bytes()); // Duplicate the last hash if odd number
-                }
-                let result = hasher.finalize();
-                new_hashes.push(hex::encode(result));
-            }
-            
-            hashes = new_hashes;
-        }
-        
-        hashes[0].clone()
+        let leaves: Vec<&[u8]> = transactions.iter().map(|tx| tx.hash.as_bytes()).collect();
+        merkle::compute_root(&leaves)
     }
     
-    pub fn sign(&mut self, private_key: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        // In a real implementation, this would use ed25519 or similar to sign the block hash
-        // For simplicity, we're just using a placeholder
-        self.signature = format!("signed-{}", self.hash);
-        Ok(())
+    /// Commits the block to the state produced by applying it. The hash
+    /// changes, so any existing signature is discarded.
+    pub fn set_state_root(&mut self, state_root: String) {
+        self.header.state_root = state_root;
+        self.hash = Self::calculate_hash(&self.header);
+        self.signature.clear();
+    }
+    
+    /// Places the block in `slot` at `timestamp`, with the proposers that
+    /// missed their turn before it. The hash changes, so any existing
+    /// signature is discarded.
+    pub fn set_slot(&mut self, slot: u64, timestamp: u64, missed_proposers: Vec<String>) {
+        self.header.slot = slot;
+        self.header.timestamp = timestamp;
+        self.header.missed_proposers = missed_proposers;
+        self.hash = Self::calculate_hash(&self.header);
+        self.signature.clear();
+    }
+    
+    /// Sets the validator's RANDAO reveal and the resulting mix. The hash
+    /// changes, so any existing signature is discarded.
+    pub fn set_randao(&mut self, reveal: String, mix: String) {
+        self.header.randao_reveal = reveal;
+        self.header.randao_mix = mix;
+        self.hash = Self::calculate_hash(&self.header);
+        self.signature.clear();
+    }
+    
+    /// Anchors the block's claims and refunds to the beacon block at
+    /// `beacon_height`. The hash changes, so any existing signature is
+    /// discarded.
+    pub fn set_beacon_height(&mut self, beacon_height: u64) {
+        self.header.beacon_height = beacon_height;
+        self.hash = Self::calculate_hash(&self.header);
+        self.signature.clear();
+    }
+    
+    /// Builds a proof that the transaction with `tx_hash` is included under
+    /// this block's merkle root.
+    pub fn transaction_proof(&self, tx_hash: &str) -> Option<MerkleProof> {
+        let index = self.transactions.iter().position(|tx| tx.hash == tx_hash)?;
+        let leaves: Vec<&[u8]> = self.transactions.iter().map(|tx| tx.hash.as_bytes()).collect();
+        MerkleProof::generate(&leaves, index)
+    }
+    
+    pub fn verify_transaction_proof(merkle_root: &str, tx_hash: &str, proof: &MerkleProof) -> bool {
+        proof.verify(merkle_root, tx_hash.as_bytes())
     }
     
-    pub fn verify_signature(&self) -> bool {
-        // In a real implementation, this would verify the signature using the validator's public key
-        // For simplicity, we're just using a placeholder check
-        self.signature == format!("signed-{}", self.hash)
+    /// Returns the bytes that the validator signs: the raw header hash.
+    pub fn signing_message(&self) -> Result<Vec<u8>, String> {
+        hex::decode(&self.hash).map_err(|e| format!("Invalid block hash {}: {}", self.hash, e))
     }
     
-    pub fn is_valid(&self, previous_block: &Block) -> bool {
-        // Check that the previous hash matches
-        if self.header.previous_hash != previous_block.hash {
+    pub fn sign(&mut self, private_key: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        // Always sign the hash of the current header so a stale hash can't be signed
+        self.hash = Self::calculate_hash(&self.header);
+        
+        let message = self.signing_message()?;
+        self.signature = crypto::sign(private_key, &message)?;
+        Ok(())
+    }
+    
+    pub fn verify_signature(&self, public_key: &[u8]) -> bool {
+        if self.signature.is_empty() {
             return false;
         }
         
-        // Check that the timestamp is greater than the previous block
-        if self.header.timestamp <= previous_block.header.timestamp {
+        // The validator address in the header must belong to the signing key
+        if crypto::public_key_to_address(public_key) != self.header.validator {
+            return false;
+        }
+        
+        match self.signing_message() {
+            Ok(message) => crypto::verify(public_key, &message, &self.signature),
+            Err(_) => false,
+        }
+    }
+    
+    /// Checks the block on top of `previous_block`, which is None for the
+    /// first block of a chain. The first block has height 1.
+    pub fn is_valid(&self, previous_block: Option<&Block>, validator_public_key: &[u8]) -> bool {
+        if let Some(previous_block) = previous_block {
+            // Check that the previous hash matches
+            if self.header.previous_hash != previous_block.hash {
+                return false;
+            }
+            
+            // Check that the block directly follows the previous block
+            if self.header.height != previous_block.header.height + 1 {
+                return false;
+            }
+            
+            // Check that the timestamp is greater than the previous block
+            if self.header.timestamp <= previous_block.header.timestamp {
+                return false;
+            }
+        } else if !self.header.previous_hash.is_empty() || self.header.height == 0 {
+            // The first block of a chain, at height 1 or, for a shard created
+            // by a split, at a height consensus checks
             return false;
         }
         
@@ -138,10 +202,159 @@ impl Block {
         }
         
         // Verify the signature
-        if !self.verify_signature() {
+        if !self.verify_signature(validator_public_key) {
             return false;
         }
         
         true
     }
-}
\ No newline at end of file
+}
+
+impl Canonical for BlockHeader {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_u32(self.version);
+        encoder.put_str(&self.previous_hash);
+        encoder.put_u64(self.height);
+        encoder.put_str(&self.merkle_root);
+        encoder.put_str(&self.state_root);
+        encoder.put_u64(self.timestamp);
+        encoder.put_u16(self.shard_id);
+        encoder.put_u64(self.slot);
+        encoder.put_seq(&self.missed_proposers);
+        encoder.put_str(&self.validator);
+        encoder.put_u32(self.contribution_score);
+        encoder.put_str(&self.randao_reveal);
+        encoder.put_str(&self.randao_mix);
+        encoder.put_u64(self.beacon_height);
+    }
+    
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(BlockHeader {
+            version: decoder.get_u32()?,
+            previous_hash: decoder.get_string()?,
+            height: decoder.get_u64()?,
+            merkle_root: decoder.get_string()?,
+            state_root: decoder.get_string()?,
+            timestamp: decoder.get_u64()?,
+            shard_id: decoder.get_u16()?,
+            slot: decoder.get_u64()?,
+            missed_proposers: decoder.get_seq()?,
+            validator: decoder.get_string()?,
+            contribution_score: decoder.get_u32()?,
+            randao_reveal: decoder.get_string()?,
+            randao_mix: decoder.get_string()?,
+            beacon_height: decoder.get_u64()?,
+        })
+    }
+}
+
+impl Canonical for Block {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.header.encode(encoder);
+        encoder.put_seq(&self.transactions);
+        encoder.put_str(&self.hash);
+        encoder.put_str(&self.signature);
+    }
+    
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(Block {
+            header: BlockHeader::decode(decoder)?,
+            transactions: decoder.get_seq()?,
+            hash: decoder.get_string()?,
+            signature: decoder.get_string()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const VALIDATOR_KEY: [u8; 32] = [7; 32];
+    const OTHER_KEY: [u8; 32] = [8; 32];
+    
+    // Public key and address of a test key
+    fn identity(private_key: &[u8; 32]) -> (Vec<u8>, String) {
+        let public_key = crypto::signing_key_from_bytes(private_key).unwrap().verifying_key().to_bytes().to_vec();
+        let address = crypto::public_key_to_address(&public_key);
+        (public_key, address)
+    }
+    
+    // First block of a chain, signed by the owner of `private_key`
+    fn signed_block(private_key: &[u8; 32]) -> Block {
+        let mut block = Block::new(String::new(), 1, Vec::new(), 0, identity(private_key).1, 0);
+        block.sign(private_key).unwrap();
+        block
+    }
+    
+    #[test]
+    fn signed_block_verifies_against_its_validator_key() {
+        let block = signed_block(&VALIDATOR_KEY);
+        let (public_key, _) = identity(&VALIDATOR_KEY);
+        assert!(block.verify_signature(&public_key));
+        assert!(block.is_valid(None, &public_key));
+        
+        // Signing covers the current header, whatever hash was set before
+        let mut restamped = block.clone();
+        restamped.header.timestamp += 1;
+        restamped.sign(&VALIDATOR_KEY).unwrap();
+        assert_eq!(restamped.hash, Block::calculate_hash(&restamped.header));
+        assert!(restamped.is_valid(None, &public_key));
+    }
+    
+    #[test]
+    fn forged_or_missing_signatures_are_rejected() {
+        let block = signed_block(&VALIDATOR_KEY);
+        let (public_key, _) = identity(&VALIDATOR_KEY);
+        let (other_public_key, other_address) = identity(&OTHER_KEY);
+        
+        // Unsigned
+        let mut unsigned = block.clone();
+        unsigned.signature = String::new();
+        assert!(!unsigned.verify_signature(&public_key));
+        assert!(!unsigned.is_valid(None, &public_key));
+        
+        // Checked against a key that isn't the header's validator
+        assert!(!block.verify_signature(&other_public_key));
+        
+        // Signed by another key in the name of the validator
+        let mut impostor = block.clone();
+        impostor.signature = crypto::sign(&OTHER_KEY, &impostor.signing_message().unwrap()).unwrap();
+        assert!(!impostor.verify_signature(&public_key));
+        
+        // Claimed by another validator, keeping the original signature
+        let mut claimed = block.clone();
+        claimed.header.validator = other_address;
+        claimed.hash = Block::calculate_hash(&claimed.header);
+        assert!(!claimed.verify_signature(&other_public_key));
+        
+        // Header changed after signing, with or without the hash
+        let mut tampered = block.clone();
+        tampered.header.state_root = "00".repeat(32);
+        assert!(!tampered.is_valid(None, &public_key));
+        tampered.hash = Block::calculate_hash(&tampered.header);
+        assert!(!tampered.verify_signature(&public_key));
+        assert!(!tampered.is_valid(None, &public_key));
+    }
+    
+    #[test]
+    fn height_must_follow_the_parent() {
+        let (public_key, address) = identity(&VALIDATOR_KEY);
+        let parent = signed_block(&VALIDATOR_KEY);
+        let child = |height: u64| {
+            let mut block = Block::new(parent.hash.clone(), height, Vec::new(), 0, address.clone(), 0);
+            block.header.timestamp = parent.header.timestamp + 1;
+            block.sign(&VALIDATOR_KEY).unwrap();
+            block
+        };
+        assert!(child(2).is_valid(Some(&parent), &public_key));
+        assert!(!child(1).is_valid(Some(&parent), &public_key));
+        assert!(!child(3).is_valid(Some(&parent), &public_key));
+        
+        // A chain starts with a block of its own, never at height 0
+        let mut genesis_height = parent.clone();
+        genesis_height.header.height = 0;
+        genesis_height.sign(&VALIDATOR_KEY).unwrap();
+        assert!(!genesis_height.is_valid(None, &public_key));
+    }
+}
@@ -0,0 +1,842 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{info, warn, debug};
+use crate::core::beacon::{self, FinalityProof, BEACON_SHARD};
+use crate::core::block::Block;
+use crate::core::consensus::{self, ConsensusEngine};
+use crate::core::cross_shard::{CrossShardAction, ReceiptProof};
+use crate::core::crypto;
+use crate::core::finality::{self, FinalityGadget, Vote, VoteType};
+use crate::core::mempool::{self, Mempool};
+use crate::core::migration::{Handover, MigrationAction};
+use crate::core::params;
+use crate::core::randao;
+use crate::core::rewards;
+use crate::core::shard::{self, CrossShardStatus};
+use crate::core::slashing::{self, EvidencePool, SlashingEvidence};
+use crate::core::state::{self, ImportResult, StateManager};
+use crate::core::transaction::{Transaction, TransactionType};
+
+// Block production, import and finality for a shard, tying together its
+// state, the consensus engine, its finality gadget, its slashing evidence
+// pool and its mempool. Locks are always taken in that order: state manager,
+// consensus engine, finality gadget, evidence pool, mempool. The sharding
+// engine, which follows cross-shard transfers, is locked last.
+//
+// Shard splits and merges are decided on the beacon chain and carried out in
+// shard blocks at an epoch boundary (see `migration`): every shard switches
+// to the new map in its last block of the epoch, and the shards taking over
+// addresses import those switches in their first block of the next. A shard
+// that a split creates starts once the validator set creating it is final.
+
+// Limits on what a produced block takes from the mempool
+pub const MAX_BLOCK_TRANSACTIONS: usize = 1000;
+pub const MAX_BLOCK_BYTES: usize = 1024 * 1024;
+
+/// Validates a block received from the network and adds it to its shard's
+/// block tree, switching branches if it makes a heavier chain.
+pub fn import_block(block: &Block) -> Result<ImportResult, String> {
+    let mut found = Vec::new();
+    let result = add_block(block, &mut found);
+    
+    submit_evidence(found);
+    result
+}
+
+fn add_block(block: &Block, found: &mut Vec<SlashingEvidence>) -> Result<ImportResult, String> {
+    let shard_id = block.header.shard_id;
+    
+    // Claims and refunds are checked against the beacon block the header
+    // names, in the beacon state, which is read before the shard is locked
+    state::check_anchors(shard_id, block.header.beacon_height, &block.transactions)?;
+    
+    let state_manager = state::get_state_manager(shard_id)
+        .ok_or_else(|| format!("Shard {} is not served by this node", shard_id))?;
+    let engine = consensus::get_engine();
+    let gadget = finality::get_gadget(shard_id)
+        .ok_or_else(|| format!("No finality gadget for shard {}", shard_id))?;
+    let evidence = slashing::get_evidence_pool(shard_id)
+        .ok_or_else(|| format!("No evidence pool for shard {}", shard_id))?;
+    let mempool = mempool::get_mempool(shard_id)
+        .ok_or_else(|| format!("No mempool for shard {}", shard_id))?;
+    
+    let mut state_manager = state_manager.lock().unwrap();
+    let mut engine = engine.lock().unwrap();
+    let engine = engine.as_mut().ok_or("Consensus engine not initialized")?;
+    let mut gadget = gadget.lock().unwrap();
+    let mut evidence = evidence.lock().unwrap();
+    let mut mempool = mempool.lock().unwrap();
+    
+    let result = import_locked(&mut state_manager, engine, &mut evidence, &mut mempool, block, found)?;
+    
+    // Votes may have reached a quorum before the block arrived
+    try_finalize(&mut state_manager, engine, &mut gadget, &mut evidence, &mut mempool);
+    
+    Ok(result)
+}
+
+fn import_locked(
+    state_manager: &mut StateManager,
+    engine: &mut ConsensusEngine,
+    evidence: &mut EvidencePool,
+    mempool: &mut Mempool,
+    block: &Block,
+    found: &mut Vec<SlashingEvidence>,
+) -> Result<ImportResult, String> {
+    if state_manager.get_block_metadata(&block.hash).is_some() {
+        return Ok(ImportResult::AlreadyKnown);
+    }
+    
+    let best_block_hash = state_manager.get_best_block_hash();
+    let previous_block = if best_block_hash.is_empty() && block.header.previous_hash.is_empty() {
+        None // First block of the chain
+    } else {
+        let previous_block = state_manager
+            .get_block(&block.header.previous_hash)
+            .ok_or_else(|| format!("Parent {} of block {} is unknown", block.header.previous_hash, block.hash))?;
+        Some(previous_block)
+    };
+    
+    // The header, its signature, proposer and RANDAO reveal are checked
+    // against the final validator set of the block's epoch before the state
+    // is touched. The transactions and the state root are checked as the
+    // block is applied, which for blocks on other branches happens when their
+    // branch becomes the best chain.
+    let validator_set = engine.validator_set_at(block.header.height)?;
+    if !engine.validate_block(block, previous_block.as_ref(), validator_set) {
+        return Err(format!("Block {} failed consensus validation", block.hash));
+    }
+    engine.check_migration(block, previous_block.is_none())?;
+    
+    // The block is validly signed; check its validator didn't sign another
+    // block at the same height
+    if let Some(public_key) = validator_set.public_key(&block.header.validator) {
+        found.extend(evidence.observe_block(block, &public_key));
+    }
+    
+    let weight = validator_set.weight(&block.header.validator);
+    let result = state_manager.import_block(block, weight)?;
+    
+    count_blocks(state_manager, engine, block, &result);
+    if matches!(result, ImportResult::Extended | ImportResult::Reorganized { .. }) && starts_beacon_epoch(engine, block) {
+        update_committees(engine, engine.epoch_at(block.header.height));
+    }
+    update_pools(state_manager, evidence, mempool, block, &result);
+    
+    Ok(result)
+}
+
+// Whether `block` is the first beacon block of an epoch
+fn starts_beacon_epoch(engine: &ConsensusEngine, block: &Block) -> bool {
+    block.header.shard_id == BEACON_SHARD
+        && block.header.height > 0
+        && engine.epoch_at(block.header.height) != engine.epoch_at(block.header.height - 1)
+}
+
+// Hands the shard map and committees of `epoch` and the committees of the
+// next epoch, once their validator sets are final, to the sharding engine
+fn update_committees(engine: &ConsensusEngine, epoch: u64) {
+    let (shard_map, current) = match engine.validator_set(epoch) {
+        Ok(validator_set) => (&validator_set.shard_map, &validator_set.committees),
+        Err(_) => return,
+    };
+    let next = engine
+        .validator_set(epoch + 1)
+        .map(|validator_set| validator_set.committees.as_slice())
+        .unwrap_or(&[]);
+    
+    let sharding = shard::get_engine();
+    let mut sharding = sharding.lock().unwrap();
+    if let Some(sharding) = sharding.as_mut() {
+        sharding.set_shard_map(shard_map.clone());
+        sharding.set_committees(epoch, current, next);
+    }
+}
+
+// Keeps the proposers' stats in line with the best chain: blocks joining it
+// are counted, blocks a reorganization removed from it are taken out
+fn count_blocks(state_manager: &StateManager, engine: &mut ConsensusEngine, block: &Block, result: &ImportResult) {
+    match result {
+        ImportResult::Extended => engine.count_block(block),
+        ImportResult::Reorganized { reverted, applied } => {
+            for hash in reverted {
+                engine.uncount_block(hash);
+            }
+            for hash in applied {
+                if let Some(block) = state_manager.get_block(hash) {
+                    engine.count_block(&block);
+                }
+            }
+        }
+        ImportResult::AlreadyKnown | ImportResult::SideChain => {}
+    }
+}
+
+// Queues evidence found by this node in the beacon mempool, to be included
+// in a beacon block and gossiped to peers. The beacon state holds the stake
+// evidence burns, whatever shard the offence was committed in. Called with no
+// shard locked, since the beacon shard's locks come first.
+fn submit_evidence(found: Vec<SlashingEvidence>) {
+    if found.is_empty() {
+        return;
+    }
+    
+    let (state_manager, mempool) = match (state::get_state_manager(BEACON_SHARD), mempool::get_mempool(BEACON_SHARD)) {
+        (Some(state_manager), Some(mempool)) => (state_manager, mempool),
+        _ => {
+            warn!("Cannot submit slashing evidence: beacon shard {} is not served", BEACON_SHARD);
+            return;
+        }
+    };
+    let state_manager = state_manager.lock().unwrap();
+    let mut mempool = mempool.lock().unwrap();
+    
+    for evidence in found {
+        let tx = evidence.to_transaction();
+        let hash = tx.hash.clone();
+        
+        match mempool.add(tx, &state_manager, now()) {
+            Ok(_) => mempool.announce(&hash),
+            Err(e) => debug!("Not submitting evidence against {}: {}", evidence.offender(), e),
+        }
+    }
+}
+
+// Brings the mempool and evidence pool in line with a change of the best
+// chain, and follows the cross-shard transfers of the applied blocks
+fn update_pools(
+    state_manager: &StateManager,
+    evidence: &mut EvidencePool,
+    mempool: &mut Mempool,
+    block: &Block,
+    result: &ImportResult,
+) {
+    match result {
+        ImportResult::Extended => {
+            mempool.remove_for_block(block);
+            evidence.remove_for_block(block);
+            track_cross_shard(block, false);
+        }
+        ImportResult::Reorganized { reverted, applied } => {
+            for hash in applied {
+                if let Some(block) = state_manager.get_block(hash) {
+                    mempool.remove_for_block(&block);
+                    evidence.remove_for_block(&block);
+                    track_cross_shard(&block, false);
+                }
+            }
+            mempool.revalidate(state_manager);
+            
+            // Transactions of the abandoned blocks go back into the pool
+            let now = now();
+            for hash in reverted {
+                if let Some(block) = state_manager.get_block(hash) {
+                    for tx in block.transactions {
+                        let _ = mempool.add(tx, state_manager, now);
+                    }
+                }
+            }
+        }
+        ImportResult::AlreadyKnown | ImportResult::SideChain => {}
+    }
+}
+
+/// Validates a finality vote from the network and finalizes its block once
+/// a precommit quorum is reached. Returns false for votes already known.
+pub fn import_vote(vote: Vote) -> Result<bool, String> {
+    let mut found = Vec::new();
+    let added = add_vote(vote, &mut found);
+    
+    // A rejected vote may still prove that its validator voted twice
+    submit_evidence(found);
+    added
+}
+
+fn add_vote(vote: Vote, found: &mut Vec<SlashingEvidence>) -> Result<bool, String> {
+    let shard_id = vote.shard_id;
+    
+    let state_manager = state::get_state_manager(shard_id)
+        .ok_or_else(|| format!("Shard {} is not served by this node", shard_id))?;
+    let engine = consensus::get_engine();
+    let gadget = finality::get_gadget(shard_id)
+        .ok_or_else(|| format!("No finality gadget for shard {}", shard_id))?;
+    let evidence = slashing::get_evidence_pool(shard_id)
+        .ok_or_else(|| format!("No evidence pool for shard {}", shard_id))?;
+    let mempool = mempool::get_mempool(shard_id)
+        .ok_or_else(|| format!("No mempool for shard {}", shard_id))?;
+    
+    let mut state_manager = state_manager.lock().unwrap();
+    let mut engine = engine.lock().unwrap();
+    let engine = engine.as_mut().ok_or("Consensus engine not initialized")?;
+    let mut gadget = gadget.lock().unwrap();
+    let mut evidence = evidence.lock().unwrap();
+    let mut mempool = mempool.lock().unwrap();
+    
+    let result = gadget.add_vote(vote, engine, state_manager.get_finalized_height());
+    
+    // A rejected vote may prove that its validator voted twice
+    report_equivocations(engine, &mut gadget, &mut evidence, found);
+    
+    let added = result?;
+    if added {
+        try_finalize(&mut state_manager, engine, &mut gadget, &mut evidence, &mut mempool);
+    }
+    
+    Ok(added)
+}
+
+/// Casts the local validator's votes: a prevote for the best block if it
+/// hasn't voted at that height yet, and precommits for blocks that reached a
+/// prevote quorum. The votes are recorded locally and returned for
+/// broadcasting.
+pub fn cast_votes(shard_id: u16, signing_key: &[u8]) -> Result<Vec<Vote>, String> {
+    let key = crypto::signing_key_from_bytes(signing_key)?;
+    let address = crypto::public_key_to_address(key.verifying_key().as_bytes());
+    
+    let state_manager = state::get_state_manager(shard_id)
+        .ok_or_else(|| format!("Shard {} is not served by this node", shard_id))?;
+    let engine = consensus::get_engine();
+    let gadget = finality::get_gadget(shard_id)
+        .ok_or_else(|| format!("No finality gadget for shard {}", shard_id))?;
+    let evidence = slashing::get_evidence_pool(shard_id)
+        .ok_or_else(|| format!("No evidence pool for shard {}", shard_id))?;
+    let mempool = mempool::get_mempool(shard_id)
+        .ok_or_else(|| format!("No mempool for shard {}", shard_id))?;
+    
+    let mut state_manager = state_manager.lock().unwrap();
+    let mut engine = engine.lock().unwrap();
+    let engine = engine.as_mut().ok_or("Consensus engine not initialized")?;
+    let mut gadget = gadget.lock().unwrap();
+    let mut evidence = evidence.lock().unwrap();
+    let mut mempool = mempool.lock().unwrap();
+    
+    let finalized_height = state_manager.get_finalized_height();
+    let mut votes = Vec::new();
+    
+    // Votes are weighed by the validator set of their height's epoch
+    let is_shard_validator = |height: u64| {
+        engine
+            .validator_set_at(height)
+            .is_ok_and(|validator_set| validator_set.is_shard_validator(shard_id, &address))
+    };
+    
+    let height = state_manager.get_current_height();
+    if height > finalized_height && is_shard_validator(height) && !gadget.has_voted(height, VoteType::Prevote, &address) {
+        votes.push(Vote::new(VoteType::Prevote, shard_id, height, state_manager.get_best_block_hash(), address.clone()));
+    }
+    
+    // Precommit only blocks this node knows, so it can follow them if finalized
+    for height in gadget.pending_heights(finalized_height) {
+        if !is_shard_validator(height) || gadget.has_voted(height, VoteType::Precommit, &address) {
+            continue;
+        }
+        
+        if let Some(block_hash) = gadget.quorum(height, VoteType::Prevote, engine) {
+            if state_manager.get_block_metadata(&block_hash).is_some() {
+                votes.push(Vote::new(VoteType::Precommit, shard_id, height, block_hash, address.clone()));
+            }
+        }
+    }
+    
+    for vote in &mut votes {
+        vote.sign(signing_key)?;
+        gadget.add_vote(vote.clone(), engine, finalized_height)?;
+    }
+    
+    try_finalize(&mut state_manager, engine, &mut gadget, &mut evidence, &mut mempool);
+    
+    Ok(votes)
+}
+
+// Turns conflicting votes seen by the finality gadget into double-vote evidence
+fn report_equivocations(
+    engine: &ConsensusEngine,
+    gadget: &mut FinalityGadget,
+    evidence: &mut EvidencePool,
+    found: &mut Vec<SlashingEvidence>,
+) {
+    for (first, second) in gadget.take_equivocations() {
+        let public_key = match engine
+            .validator_set_at(first.height)
+            .ok()
+            .and_then(|validator_set| validator_set.public_key(&first.validator))
+        {
+            Some(public_key) => public_key,
+            None => continue,
+        };
+        
+        warn!("Validator {} cast conflicting {:?} votes at height {}", first.validator, first.vote_type, first.height);
+        let double_vote = SlashingEvidence::DoubleVote {
+            public_key: hex::encode(public_key),
+            first,
+            second,
+        };
+        
+        if evidence.add(double_vote.clone()) {
+            found.push(double_vote);
+        }
+    }
+}
+
+// Finalizes the highest block with a precommit quorum, if this node knows it
+fn try_finalize(
+    state_manager: &mut StateManager,
+    engine: &mut ConsensusEngine,
+    gadget: &mut FinalityGadget,
+    evidence: &mut EvidencePool,
+    mempool: &mut Mempool,
+) {
+    let (height, block_hash) = match gadget.finalizable(engine, state_manager.get_finalized_height()) {
+        Some(finalizable) => finalizable,
+        None => return,
+    };
+    
+    let block = match state_manager.get_block(&block_hash) {
+        Some(block) => block,
+        None => {
+            debug!("Block {} has a precommit quorum but is not known yet", block_hash);
+            return;
+        }
+    };
+    
+    let previous_finalized_height = state_manager.get_finalized_height();
+    match state_manager.finalize_block(&block_hash) {
+        Ok(reorganization) => {
+            if let Some(result) = reorganization {
+                count_blocks(state_manager, engine, &block, &result);
+                update_pools(state_manager, evidence, mempool, &block, &result);
+            }
+            track_finalized(state_manager, &block_hash, previous_finalized_height);
+            if block.header.shard_id != BEACON_SHARD {
+                let precommits = gadget.votes_for(height, VoteType::Precommit, &block_hash);
+                beacon::record_finality_proof(FinalityProof::new(block.header.clone(), precommits));
+            }
+            engine.prune_counted(state_manager.get_shard_id(), height);
+            gadget.prune(height);
+            evidence.prune(height);
+            
+            // Validator sets recorded by newly finalized beacon blocks are
+            // final too
+            if state_manager.get_shard_id() == BEACON_SHARD {
+                engine.sync_validator_sets(state_manager);
+                if let Err(e) = create_shards(engine) {
+                    warn!("Failed to start new shards: {}", e);
+                }
+                update_committees(engine, engine.epoch_at(state_manager.get_current_height()));
+            }
+        }
+        Err(e) => warn!("Failed to finalize block {}: {}", block_hash, e),
+    }
+}
+
+// Follows the cross-shard transfers of newly finalized blocks, from
+// `block_hash` down to the previously finalized height
+fn track_finalized(state_manager: &StateManager, block_hash: &str, previous_finalized_height: u64) {
+    let mut hash = block_hash.to_string();
+    
+    while let Some(metadata) = state_manager.get_block_metadata(&hash) {
+        if metadata.height <= previous_finalized_height {
+            break;
+        }
+        
+        if let Some(block) = state_manager.get_block(&hash) {
+            track_cross_shard(&block, true);
+        }
+        hash = metadata.parent_hash;
+    }
+}
+
+// Finality proofs for every other shard that finalized blocks past its
+// latest crosslink in the beacon state
+fn new_crosslinks(state_manager: &StateManager) -> Vec<FinalityProof> {
+    beacon::finality_proofs()
+        .into_iter()
+        .filter(|proof| {
+            let linked_height = state_manager.crosslink(proof.header.shard_id).map(|crosslink| crosslink.height);
+            linked_height.is_none_or(|height| height < proof.header.height)
+        })
+        .collect()
+}
+
+// Advances the cross-shard transfers that a block on the best chain sends,
+// claims or refunds
+fn track_cross_shard(block: &Block, finalized: bool) {
+    let sharding = shard::get_engine();
+    let mut sharding = sharding.lock().unwrap();
+    let sharding = match sharding.as_mut() {
+        Some(sharding) => sharding,
+        None => return,
+    };
+    
+    let receipt_timeout = params::get().cross_shard.receipt_timeout;
+    for tx in block.transactions.iter().filter(|tx| matches!(tx.tx_type, TransactionType::ShardCrossing)) {
+        let progress = match CrossShardAction::from_transaction(tx) {
+            Ok(CrossShardAction::Send { target_shard }) => {
+                let status = if finalized { CrossShardStatus::SourceConfirmed } else { CrossShardStatus::Pending };
+                Some((tx.hash.clone(), tx.shard_id, target_shard, status))
+            }
+            Ok(CrossShardAction::Claim { receipt }) => receipt.verify(receipt_timeout).ok().map(|receipt| {
+                let status = if finalized { CrossShardStatus::Completed } else { CrossShardStatus::TargetConfirmed };
+                (receipt.tx_hash, receipt.source_shard, receipt.target_shard, status)
+            }),
+            Ok(CrossShardAction::Refund { receipt, .. }) if finalized => receipt.verify(receipt_timeout).ok().map(|receipt| {
+                (receipt.tx_hash, receipt.source_shard, receipt.target_shard, CrossShardStatus::Failed)
+            }),
+            _ => None,
+        };
+        
+        if let Some((tx_hash, source_shard, target_shard, status)) = progress {
+            if let Err(e) = sharding.advance_cross_shard_transaction(&tx_hash, source_shard, target_shard, status) {
+                debug!("Not tracking cross-shard transfer {}: {}", tx_hash, e);
+            }
+        }
+    }
+}
+
+/// Produces a block for the current slot of the shard if the validator owning
+/// `signing_key` is one of its proposers and its turn has come. The block is
+/// applied locally and returned for broadcasting; None means there is nothing
+/// to propose yet.
+pub fn produce_block(shard_id: u16, signing_key: &[u8]) -> Result<Option<Block>, String> {
+    let key = crypto::signing_key_from_bytes(signing_key)?;
+    let address = crypto::public_key_to_address(key.verifying_key().as_bytes());
+    
+    let state_manager = state::get_state_manager(shard_id)
+        .ok_or_else(|| format!("Shard {} is not served by this node", shard_id))?;
+    let engine = consensus::get_engine();
+    let evidence = slashing::get_evidence_pool(shard_id)
+        .ok_or_else(|| format!("No evidence pool for shard {}", shard_id))?;
+    let mempool = mempool::get_mempool(shard_id)
+        .ok_or_else(|| format!("No mempool for shard {}", shard_id))?;
+    
+    // Blocks of other shards anchor their claims and refunds to the latest
+    // finalized beacon block; its state is locked first and only held while
+    // transactions are picked
+    let beacon_state = match shard_id {
+        BEACON_SHARD => None,
+        _ => Some(state::get_state_manager(BEACON_SHARD).ok_or("Beacon shard not loaded")?),
+    };
+    let beacon_state = beacon_state.as_ref().map(|beacon_state| beacon_state.lock().unwrap());
+    let beacon_height = beacon_state.as_ref().map_or(0, |beacon_state| beacon_state.get_finalized_height());
+    
+    // The first block of an epoch that gives this shard addresses of other
+    // shards imports their switches, read before this shard is locked
+    let (imports_height, imports) = match build_imports(shard_id, beacon_state.as_deref(), beacon_height)? {
+        Some(imports) => imports,
+        None => return Ok(None),
+    };
+    
+    let mut state_manager = state_manager.lock().unwrap();
+    let mut engine = engine.lock().unwrap();
+    let engine = engine.as_mut().ok_or("Consensus engine not initialized")?;
+    let mut evidence = evidence.lock().unwrap();
+    let mut mempool = mempool.lock().unwrap();
+    
+    let best_block_hash = state_manager.get_best_block_hash();
+    let (parent_slot, parent_mix) = if best_block_hash.is_empty() {
+        (None, randao::INITIAL_MIX.to_string())
+    } else {
+        state_manager
+            .get_block(&best_block_hash)
+            .map(|parent| (Some(parent.header.slot), parent.header.randao_mix))
+            .ok_or_else(|| format!("Best block {} not found", best_block_hash))?
+    };
+    
+    // Propose at most once per slot, and only once our turn in it has come
+    let timestamp = now();
+    let slot = engine.slot_at(timestamp);
+    if parent_slot.is_some_and(|parent_slot| parent_slot >= slot) {
+        return Ok(None);
+    }
+    
+    let height = state_manager.get_current_height() + 1;
+    if height != imports_height {
+        return Ok(None); // A block arrived in between
+    }
+    
+    let validator_set = match engine.validator_set_at(height) {
+        Ok(validator_set) => validator_set,
+        Err(e) => {
+            debug!("Not producing a block: {}", e);
+            return Ok(None);
+        }
+    };
+    
+    let rank = match engine.proposer_rank(validator_set, shard_id, slot, &parent_mix, &address) {
+        Some(rank) if timestamp >= engine.proposal_time(slot, rank) => rank,
+        _ => return Ok(None),
+    };
+    let missed_proposers = engine.missed_proposers(validator_set, shard_id, parent_slot, slot, rank, &parent_mix);
+    let switch_map = match engine.switch_map(height) {
+        Ok(shard_map) => shard_map.cloned(),
+        Err(e) => {
+            debug!("Not producing a block: {}", e);
+            return Ok(None);
+        }
+    };
+    
+    let contribution_score = validator_set
+        .get(&address)
+        .map(|validator| validator.contribution_score)
+        .unwrap_or(0);
+    
+    // Imports come first, so claims can follow them; beacon blocks then
+    // crosslink the latest finalized block of every other shard
+    let mut transactions = imports;
+    if shard_id == BEACON_SHARD {
+        transactions.extend(new_crosslinks(&state_manager).iter().map(FinalityProof::to_transaction));
+    }
+    
+    // Take the best paying transactions that still apply on top of the
+    // current state, leaving room for the coinbase and the switch. A
+    // transaction that fails leaves the state untouched.
+    let reserved = 1 + switch_map.is_some() as usize;
+    let limit = (MAX_BLOCK_TRANSACTIONS - reserved).saturating_sub(transactions.len());
+    let candidates = mempool.select(limit, MAX_BLOCK_BYTES);
+    let rejected = state_manager.trial(|state| -> Result<Vec<String>, String> {
+        for tx in &transactions {
+            state.apply_transaction(tx)?;
+        }
+        
+        let mut rejected = Vec::new();
+        for tx in candidates {
+            let anchored = beacon_state
+                .as_ref()
+                .map_or(Ok(()), |beacon_state| beacon_state.check_anchors(&tx, beacon_height));
+            match anchored.and_then(|()| state.apply_transaction(&tx)) {
+                Ok(()) => transactions.push(tx),
+                Err(e) => {
+                    debug!("Dropping transaction {} from the mempool: {}", tx.hash, e);
+                    rejected.push(tx.hash);
+                }
+            }
+        }
+        Ok(rejected)
+    })?;
+    for tx_hash in &rejected {
+        mempool.remove(tx_hash);
+    }
+    drop(beacon_state);
+    
+    // The coinbase comes first and pays out the block reward and the fees
+    let params = params::get();
+    let fees = transactions
+        .iter()
+        .try_fold(0u64, |total, tx| total.checked_add(tx.fee()?))
+        .ok_or_else(|| "block fees overflow".to_string())?;
+    let amount = rewards::block_reward(&params, height)
+        .checked_add(fees)
+        .ok_or_else(|| "block reward overflows".to_string())?;
+    let outputs = rewards::coinbase_outputs(&params, validator_set, &address, amount);
+    transactions.insert(0, rewards::coinbase(shard_id, height, outputs));
+    
+    // The last block of an epoch before the shard map changes ends with the
+    // switch to the new map, handing over what leaves the shard
+    if let Some(shard_map) = switch_map {
+        let handover = state_manager.trial(|state| -> Result<Handover, String> {
+            state.apply_coinbase(&transactions, height)?;
+            for tx in &transactions[1..] {
+                state.apply_transaction(tx)?;
+            }
+            Ok(state.handover(&shard_map))
+        })?;
+        transactions.push(MigrationAction::Switch { shard_map, handover: Box::new(handover) }.to_transaction(shard_id));
+    }
+    
+    let mut block = Block::new(
+        best_block_hash,
+        height,
+        transactions,
+        shard_id,
+        address,
+        contribution_score,
+    );
+    block.set_slot(slot, timestamp, missed_proposers);
+    block.set_beacon_height(beacon_height);
+    
+    let state_root = state_manager.trial(|state| -> Result<String, String> {
+        state.apply_coinbase(&block.transactions, height)?;
+        for tx in &block.transactions[1..] {
+            state.apply_transaction(tx)?;
+        }
+        state.finish_block(&block.header);
+        Ok(state.state_root())
+    })?;
+    block.set_state_root(state_root);
+    
+    let reveal = randao::reveal(signing_key, engine.epoch_at(height))?;
+    let mix = randao::mix(&parent_mix, &reveal)?;
+    block.set_randao(reveal, mix);
+    block.sign(signing_key).map_err(|e| e.to_string())?;
+    
+    // Our own block can only conflict with another of ours
+    import_locked(&mut state_manager, engine, &mut evidence, &mut mempool, &block, &mut Vec::new())?;
+    
+    info!("Produced block {} at height {} with {} transactions",
+          block.hash, height, block.transactions.len());
+    Ok(Some(block))
+}
+
+// Height of the next block of `shard_id` and its imports, if it is the first
+// of an epoch that gives it addresses of other shards: the switch each source
+// made in its block just below, linked to the source's latest crosslink in
+// the beacon state at `beacon_height`. The beacon shard links them to its own
+// latest crosslinks instead, and passes no `beacon_state`. None while a
+// switch isn't anchored yet. Locks the shard itself and then each source in
+// turn, so must be called with at most the beacon state locked.
+fn build_imports(shard_id: u16, beacon_state: Option<&StateManager>, beacon_height: u64) -> Result<Option<(u64, Vec<Transaction>)>, String> {
+    let state_manager = state::get_state_manager(shard_id)
+        .ok_or_else(|| format!("Shard {} is not served by this node", shard_id))?;
+    let height = state_manager.lock().unwrap().get_current_height() + 1;
+    let sources = {
+        let engine = consensus::get_engine();
+        let engine = engine.lock().unwrap();
+        let engine = engine.as_ref().ok_or("Consensus engine not initialized")?;
+        match engine.import_sources(shard_id, height) {
+            Ok(sources) => sources,
+            Err(e) => {
+                debug!("Not producing a block: {}", e);
+                return Ok(None);
+            }
+        }
+    };
+    
+    let mut imports = Vec::new();
+    for source in sources {
+        let crosslink = match beacon_state {
+            Some(beacon_state) => beacon_state.anchor_of(source, beacon_height),
+            None => {
+                let beacon_state = state_manager.lock().unwrap();
+                beacon_state.anchor_of(source, beacon_state.get_current_height())
+            }
+        };
+        let crosslink = match crosslink {
+            Some(crosslink) if crosslink.height + 1 >= height => crosslink,
+            _ => {
+                debug!("Not producing a block: switch of shard {} is not anchored yet", source);
+                return Ok(None);
+            }
+        };
+        
+        let source_state = state::get_state_manager(source)
+            .ok_or_else(|| format!("Shard {} is not served by this node", source))?;
+        let source_state = source_state.lock().unwrap();
+        let switch = source_state.get_best_chain_blocks(height - 1, height - 1).pop().and_then(|block| {
+            let switch = block.transactions
+                .iter()
+                .find(|tx| matches!(MigrationAction::from_transaction(tx), Ok(MigrationAction::Switch { .. })))?;
+            let descendants = source_state.descendant_headers(&block.hash, &crosslink.block_hash)?;
+            ReceiptProof::new(&block, &switch.hash, descendants)
+        });
+        
+        match switch {
+            Some(switch) => imports.push(MigrationAction::Import { switch: Box::new(switch) }.to_transaction(shard_id)),
+            None => {
+                debug!("Not producing a block: switch of shard {} at height {} not found", source, height - 1);
+                return Ok(None);
+            }
+        }
+    }
+    
+    Ok(Some((height, imports)))
+}
+
+// Starts the shards that the final validator sets add to the shard map, each
+// with its state, finality gadget, evidence pool and mempool. Shards started
+// already are left as they are.
+fn create_shards(engine: &ConsensusEngine) -> Result<(), String> {
+    let epoch_length = params::get().epoch_length;
+    for (previous, validator_set) in engine.validator_sets().zip(engine.validator_sets().skip(1)) {
+        let previous_ids = previous.shard_map.shard_ids();
+        for shard_id in validator_set.shard_map.shard_ids().difference(&previous_ids) {
+            let activation_height = validator_set.epoch * epoch_length;
+            state::create_state_manager(*shard_id, &previous.shard_map, &validator_set.shard_map, activation_height)
+                .map_err(|e| e.to_string())?;
+            
+            if finality::get_gadget(*shard_id).is_none() {
+                finality::create_gadget(*shard_id).map_err(|e| e.to_string())?;
+                slashing::create_evidence_pool(*shard_id).map_err(|e| e.to_string())?;
+                mempool::create_mempool(*shard_id).map_err(|e| e.to_string())?;
+            }
+        }
+    }
+    
+    Ok(())
+}
+
+/// Takes over the validator sets the persisted beacon chain finalized, after
+/// `state::open_storage`, and with them the shards created by past
+/// migrations, the shard map and the committees.
+pub fn resume_shards() -> Result<(), Box<dyn std::error::Error>> {
+    let beacon_state = state::get_state_manager(BEACON_SHARD).ok_or("Beacon shard not loaded")?;
+    let beacon_state = beacon_state.lock().unwrap();
+    let engine = consensus::get_engine();
+    let mut engine = engine.lock().unwrap();
+    let engine = engine.as_mut().ok_or("Consensus engine not initialized")?;
+    engine.sync_validator_sets(&beacon_state);
+    create_shards(engine)?;
+    update_committees(engine, engine.epoch_at(beacon_state.get_current_height()));
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::genesis::{self, Genesis, GenesisValidator};
+    
+    const VALIDATOR_KEY: [u8; 32] = [7; 32];
+    
+    // State root and height of a shard served by this node
+    fn tip(shard_id: u16) -> (String, u64) {
+        let state_manager = state::get_state_manager(shard_id).unwrap();
+        let state_manager = state_manager.lock().unwrap();
+        (state_manager.state_root(), state_manager.get_current_height())
+    }
+    
+    #[test]
+    fn produced_block_is_imported_by_a_fresh_node() {
+        // The only genesis validator proposes every slot of the beacon shard
+        let public_key = crypto::signing_key_from_bytes(&VALIDATOR_KEY).unwrap().verifying_key().to_bytes();
+        genesis::set(Genesis {
+            validators: vec![GenesisValidator {
+                public_key: hex::encode(public_key),
+                stake: 5000,
+                computation_power: 1,
+            }],
+            ..Genesis::default()
+        });
+        state::initialize().unwrap();
+        consensus::initialize().unwrap();
+        finality::initialize().unwrap();
+        slashing::initialize().unwrap();
+        mempool::initialize().unwrap();
+        
+        let block = produce_block(BEACON_SHARD, &VALIDATOR_KEY).unwrap().unwrap();
+        assert_eq!(block.header.height, 1);
+        assert_eq!(block.header.validator, crypto::public_key_to_address(&public_key));
+        assert_eq!(import_block(&block).unwrap(), ImportResult::AlreadyKnown);
+        assert!(produce_block(BEACON_SHARD, &[8; 32]).unwrap().is_none());
+        let produced = tip(BEACON_SHARD);
+        assert_eq!(produced, (block.header.state_root.clone(), 1));
+        
+        // A node starting from genesis rejects a block whose state root is
+        // off and reaches the same state with the produced one
+        state::shutdown().unwrap();
+        state::initialize().unwrap();
+        let mut forged = block.clone();
+        forged.header.state_root = "00".repeat(32);
+        forged.sign(&VALIDATOR_KEY).unwrap();
+        let error = import_block(&forged).unwrap_err();
+        assert!(error.contains("state root"), "{}", error);
+        assert_eq!(tip(BEACON_SHARD).1, 0);
+        
+        assert_eq!(import_block(&block).unwrap(), ImportResult::Extended);
+        assert_eq!(tip(BEACON_SHARD), produced);
+    }
+}
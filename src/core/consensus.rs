@@ -1,166 +1,494 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 use log::{info, warn, error, debug};
 use serde::{Serialize, Deserialize};
-use crate::core::block::Block;
+use crate::core::beacon::BEACON_SHARD;
+use crate::core::block::{Block, BlockHeader};
+use crate::core::encoding::{Canonical, Decoder, Encoder};
+use crate::core::genesis;
+use crate::core::migration::MigrationAction;
+use crate::core::params::{self, ChainParams, SlotParams, WeightParams, BPS_SCALE};
+use crate::core::randao;
+use crate::core::rewards;
+use crate::core::shard;
+use crate::core::shard_map::ShardMap;
+use crate::core::staking::Delegation;
+use crate::core::state::{self, StateManager};
 use crate::core::transaction::Transaction;
 
 // Adaptive Proof of Contribution (APoC) consensus algorithm
+//
+// Who may propose and vote in an epoch is decided by its validator set,
+// which the beacon state records on the last beacon block two epochs before
+// (`VALIDATOR_SET_LOOKAHEAD`), from the stake, contribution scores and jailing
+// in that state. Blocks and votes of every shard are checked against the set
+// of their epoch once the beacon chain has finalized the block recording it,
+// so all nodes check them against the same set whatever branch they follow.
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Number of epochs between the beacon block recording a validator set and
+/// the epoch it applies to. The sets of the first epochs come with the
+/// genesis state.
+pub const VALIDATOR_SET_LOOKAHEAD: u64 = 2;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ValidatorInfo {
     pub address: String,
+    pub public_key: String, // Hex-encoded ed25519 public key used to sign blocks
     pub stake_amount: u64,
     pub computation_power: u32,
     pub contribution_score: u32,
+    pub jailed_until_epoch: u64, // Kept out of the active set before this epoch
+    pub delegations: Vec<Delegation>, // On-chain stake, self-bond included
+    pub weight: u64, // Weight in proposer selection, fork choice and finality
+}
+
+/// Validators of one shard in an epoch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Committee {
+    pub shard_id: u16,
+    pub members: Vec<String>, // Sorted addresses
+}
+
+/// Validators of an epoch as recorded in the beacon state: every validator
+/// with a self-bond, the active set, the shard map of the epoch and the
+/// committees of its shards.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValidatorSet {
+    pub epoch: u64,
+    pub validators: Vec<ValidatorInfo>, // Ordered by address
+    pub active: Vec<String>,            // Heaviest first
+    pub shard_map: ShardMap,
+    pub committees: Vec<Committee>,     // Ordered by shard; empty if too few validators to fill them
+}
+
+impl ValidatorSet {
+    /// Builds the set of `epoch` from its candidates: weighs them, picks the
+    /// heaviest that aren't jailed and have the minimum stake as the active
+    /// set, and draws the committees of the shards of `shard_map` from `seed`.
+    pub fn new(params: &ChainParams, epoch: u64, mut validators: Vec<ValidatorInfo>, shard_map: &ShardMap, seed: &str) -> Self {
+        for info in validators.iter_mut() {
+            info.weight = validator_weight(&params.weights, info);
+        }
+        validators.sort_by(|a, b| a.address.cmp(&b.address));
+        
+        let mut eligible: Vec<&ValidatorInfo> = validators
+            .iter()
+            .filter(|info| info.jailed_until_epoch <= epoch && info.stake_amount >= params.min_stake)
+            .collect();
+        
+        // Sort by weight in descending order, then by address so every node
+        // builds the same list
+        eligible.sort_by(|a, b| b.weight.cmp(&a.weight).then(a.address.cmp(&b.address)));
+        let active: Vec<String> = eligible
+            .into_iter()
+            .take(params.max_validators)
+            .map(|info| info.address.clone())
+            .collect();
+        
+        // Without enough validators, every active validator serves every shard
+        let shard_ids: Vec<u16> = shard_map.shard_ids().into_iter().collect();
+        let committees = match shard::draw_committees(&active, &shard_ids, params.min_committee_size, seed, epoch) {
+            Ok(committees) => committees,
+            Err(e) => {
+                warn!("No shard committees for epoch {}, every active validator serves every shard: {}", epoch, e);
+                Vec::new()
+            }
+        };
+        
+        ValidatorSet {
+            epoch,
+            validators,
+            active,
+            shard_map: shard_map.clone(),
+            committees,
+        }
+    }
+    
+    pub fn get(&self, address: &str) -> Option<&ValidatorInfo> {
+        self.validators
+            .binary_search_by(|info| info.address.as_str().cmp(address))
+            .ok()
+            .map(|index| &self.validators[index])
+    }
+    
+    pub fn public_key(&self, address: &str) -> Option<Vec<u8>> {
+        self.get(address).and_then(|info| hex::decode(&info.public_key).ok())
+    }
+    
+    /// Weight a block proposed by `address` adds to its chain in fork choice.
+    pub fn weight(&self, address: &str) -> u64 {
+        self.get(address).map(|info| info.weight).unwrap_or(0)
+    }
+    
+    pub fn is_active(&self, address: &str) -> bool {
+        self.active.iter().any(|active| active == address)
+    }
+    
+    /// Active validators, heaviest first.
+    pub fn active_validators(&self) -> Vec<&ValidatorInfo> {
+        self.active
+            .iter()
+            .filter_map(|address| self.get(address))
+            .collect()
+    }
+    
+    pub fn committee(&self, shard_id: u16) -> Option<&Committee> {
+        self.committees.iter().find(|committee| committee.shard_id == shard_id)
+    }
+    
+    /// Active validators serving `shard_id`: its committee, or every active
+    /// validator if it has none.
+    pub fn shard_validators(&self, shard_id: u16) -> Vec<String> {
+        match self.committee(shard_id) {
+            Some(committee) => self.active
+                .iter()
+                .filter(|address| committee.members.contains(address))
+                .cloned()
+                .collect(),
+            None => self.active.clone(),
+        }
+    }
+    
+    pub fn is_shard_validator(&self, shard_id: u16, address: &str) -> bool {
+        self.is_active(address)
+            && self.committee(shard_id)
+                .is_none_or(|committee| committee.members.iter().any(|member| member == address))
+    }
+    
+    /// Combined weight of the validators serving `shard_id`, against which
+    /// its finality quorums are counted. Summed in `u128`, which no number
+    /// of `u64` weights overflows.
+    pub fn total_shard_weight(&self, shard_id: u16) -> u128 {
+        self.shard_validators(shard_id)
+            .iter()
+            .map(|address| self.weight(address) as u128)
+            .sum()
+    }
+    
+    /// Combined weight of the active set, against which migration approvals
+    /// are counted.
+    pub fn total_weight(&self) -> u128 {
+        self.active
+            .iter()
+            .map(|address| self.weight(address) as u128)
+            .sum()
+    }
+}
+
+/// Weight formula: by default 50% stake + 25% computation + 25% contribution.
+/// Computed in `u128` and capped at `u64::MAX`, so large stakes or genesis
+/// multipliers can't wrap it.
+pub fn validator_weight(weights: &WeightParams, info: &ValidatorInfo) -> u64 {
+    let points_scale = weights.points_scale as u128;
+    let scaled = |value: u128, bps: u64| value * bps as u128 / BPS_SCALE as u128;
+    let weight = scaled(info.stake_amount as u128, weights.stake_bps) +
+        scaled(info.computation_power as u128 * points_scale, weights.computation_bps) +
+        scaled(info.contribution_score as u128 * points_scale, weights.contribution_bps);
+    u64::try_from(weight).unwrap_or(u64::MAX)
+}
+
+/// Blocks a validator proposed on the best chains of the shards this node
+/// follows.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValidatorStats {
     pub last_validation_time: u64,
     pub total_validated_blocks: u64,
 }
 
+// A block counted in its proposer's stats, kept until finalized so a
+// reorganization can take it out again
+#[derive(Debug)]
+struct CountedBlock {
+    shard_id: u16,
+    height: u64,
+    validator: String,
+    timestamp: u64,
+    previous_time: u64, // Proposer's last validation time before the block
+}
+
 #[derive(Debug)]
 pub struct ConsensusEngine {
-    validators: HashMap<String, ValidatorInfo>,
-    active_validators: Vec<String>,
-    current_epoch: u64,
+    validator_sets: BTreeMap<u64, ValidatorSet>, // Sets the beacon chain has finalized, by epoch
+    stats: HashMap<String, ValidatorStats>,
+    counted_blocks: HashMap<String, CountedBlock>, // By block hash
     epoch_length: u64, // Number of blocks per epoch
-    min_stake: u64,
-    target_block_time: u64, // Target time between blocks in seconds
-    difficulty: u32,
+    target_block_time: u64, // Duration of a slot in seconds
+    slots: SlotParams,
+    genesis_time: u64,      // Start of slot 0
 }
 
 impl ConsensusEngine {
-    pub fn new(min_stake: u64, epoch_length: u64, target_block_time: u64) -> Self {
+    /// Creates an engine without any validator set; the proposer timing
+    /// comes from the chain parameters, and slots are counted from the
+    /// genesis timestamp.
+    pub fn new(epoch_length: u64, target_block_time: u64) -> Self {
         ConsensusEngine {
-            validators: HashMap::new(),
-            active_validators: Vec::new(),
-            current_epoch: 0,
+            validator_sets: BTreeMap::new(),
+            stats: HashMap::new(),
+            counted_blocks: HashMap::new(),
             epoch_length,
-            min_stake,
             target_block_time,
-            difficulty: 1000, // Initial difficulty
+            slots: params::get().slots,
+            genesis_time: genesis::get().timestamp,
         }
     }
     
-    pub fn register_validator(&mut self, address: String, stake_amount: u64, computation_power: u32) -> Result<(), String> {
-        if stake_amount < self.min_stake {
-            return Err(format!("Stake amount {} is below minimum {}", stake_amount, self.min_stake));
-        }
-        
-        let validator = ValidatorInfo {
-            address: address.clone(),
-            stake_amount,
-            computation_power,
-            contribution_score: 0, // Initial score
-            last_validation_time: 0,
-            total_validated_blocks: 0,
-        };
-        
-        self.validators.insert(address.clone(), validator);
-        debug!("Registered validator: {}", address);
-        
-        // Update active validators list
-        self.update_active_validators();
-        
-        Ok(())
+    /// Height of the beacon block recording the validator set of `epoch`,
+    /// or None for the sets of the genesis state.
+    pub fn validator_set_height(&self, epoch: u64) -> Option<u64> {
+        epoch
+            .checked_sub(VALIDATOR_SET_LOOKAHEAD)
+            .map(|recorded_in| (recorded_in + 1) * self.epoch_length - 1)
     }
     
-    pub fn update_validator_stake(&mut self, address: &str, new_stake: u64) -> Result<(), String> {
-        if let Some(validator) = self.validators.get_mut(address) {
-            if new_stake < self.min_stake {
-                return Err(format!("New stake amount {} is below minimum {}", new_stake, self.min_stake));
-            }
-            
-            validator.stake_amount = new_stake;
-            debug!("Updated stake for validator {}: {}", address, new_stake);
-            
-            // Update active validators list
-            self.update_active_validators();
+    /// Takes over the validator sets recorded in `beacon` state after the
+    /// last known one, as long as the block recording them is final. Sets
+    /// only ever become final, so a known set is never replaced.
+    pub fn sync_validator_sets(&mut self, beacon: &StateManager) {
+        let finalized_height = beacon.get_finalized_height();
+        let mut epoch = self.validator_sets.keys().next_back().map_or(0, |epoch| epoch + 1);
+        
+        while self.validator_set_height(epoch).is_none_or(|height| height <= finalized_height) {
+            let set = match beacon.validator_set(epoch) {
+                Some(set) => set,
+                None => break,
+            };
             
-            Ok(())
-        } else {
-            Err(format!("Validator {} not found", address))
+            debug!("Validator set of epoch {} is final: {} active validators", epoch, set.active.len());
+            self.validator_sets.insert(epoch, set);
+            epoch += 1;
         }
     }
     
-    pub fn update_contribution_score(&mut self, address: &str, contribution: u32) -> Result<(), String> {
-        if let Some(validator) = self.validators.get_mut(address) {
-            validator.contribution_score += contribution;
-            debug!("Updated contribution score for validator {}: +{} (total: {})", 
-                   address, contribution, validator.contribution_score);
+    /// Returns the final validator set of `epoch`.
+    pub fn validator_set(&self, epoch: u64) -> Result<&ValidatorSet, String> {
+        self.validator_sets
+            .get(&epoch)
+            .ok_or_else(|| format!("Validator set of epoch {} is not final yet", epoch))
+    }
+    
+    /// Returns the final validator set in force at `height`.
+    pub fn validator_set_at(&self, height: u64) -> Result<&ValidatorSet, String> {
+        self.validator_set(self.epoch_at(height))
+    }
+    
+    /// Returns the slot containing `timestamp`.
+    pub fn slot_at(&self, timestamp: u64) -> u64 {
+        timestamp.saturating_sub(self.genesis_time) / self.target_block_time
+    }
+    
+    pub fn slot_start(&self, slot: u64) -> u64 {
+        self.genesis_time + slot * self.target_block_time
+    }
+    
+    /// Earliest time the proposer ranked `rank` may propose in `slot`.
+    pub fn proposal_time(&self, slot: u64, rank: usize) -> u64 {
+        self.slot_start(slot) + rank as u64 * self.slots.proposer_timeout
+    }
+    
+    /// Ranks the proposers of `slot` in `shard_id` from the RANDAO mix of the
+    /// parent block: the elected proposer first, then the fallback
+    /// proposers. Each is drawn from the shard's validators not drawn yet,
+    /// with probability proportional to its weight.
+    pub fn select_proposers(&self, validator_set: &ValidatorSet, shard_id: u16, slot: u64, randao_mix: &str) -> Vec<String> {
+        let mut candidates: Vec<(String, u64)> = validator_set.shard_validators(shard_id)
+            .into_iter()
+            .map(|address| {
+                let weight = validator_set.weight(&address);
+                (address, weight)
+            })
+            .collect();
+        
+        let mut proposers = Vec::new();
+        for rank in 0..=self.slots.fallback_proposers {
+            if candidates.is_empty() {
+                break;
+            }
             
-            // Update active validators list
-            self.update_active_validators();
+            let random = match randao::random_value(randao_mix, slot, rank) {
+                Ok(random) => random,
+                Err(e) => {
+                    warn!("Cannot select proposers for slot {}: {}", slot, e);
+                    return Vec::new();
+                }
+            };
             
-            Ok(())
-        } else {
-            Err(format!("Validator {} not found", address))
+            let total_weight: u128 = candidates.iter().map(|(_, weight)| *weight as u128).sum();
+            let index = if total_weight == 0 {
+                // No weight to go by, fall back to a uniform choice
+                (random % candidates.len() as u64) as usize
+            } else {
+                let mut target = random as u128 % total_weight;
+                let mut index = 0;
+                while target >= candidates[index].1 as u128 {
+                    target -= candidates[index].1 as u128;
+                    index += 1;
+                }
+                index
+            };
+            
+            proposers.push(candidates.remove(index).0);
         }
+        
+        proposers
     }
     
-    fn update_active_validators(&mut self) {
-        // Sort validators by a weighted combination of stake, computation power, and contribution score
-        let mut weighted_validators: Vec<(String, u64)> = self.validators
+    /// Returns the rank of `address` among the proposers of `slot`, if any.
+    pub fn proposer_rank(&self, validator_set: &ValidatorSet, shard_id: u16, slot: u64, randao_mix: &str, address: &str) -> Option<usize> {
+        self.select_proposers(validator_set, shard_id, slot, randao_mix)
             .iter()
-            .map(|(addr, info)| {
-                // Weight formula: 50% stake + 25% computation + 25% contribution
-                let weight = (info.stake_amount / 2) + 
-                             (info.computation_power as u64 * 1000 / 4) + 
-                             (info.contribution_score as u64 * 1000 / 4);
-                (addr.clone(), weight)
-            })
-            .collect();
-        
-        // Sort by weight in descending order
-        weighted_validators.sort_by(|a, b| b.1.cmp(&a.1));
+            .position(|proposer| proposer == address)
+    }
+    
+    /// Lists the proposers that let their turn pass before a block proposed
+    /// at `rank` in `slot`: the elected proposer of every empty slot since the
+    /// parent's, then those ranked before the block's proposer in its own
+    /// slot. At most an epoch's worth of empty slots is counted.
+    pub fn missed_proposers(&self, validator_set: &ValidatorSet, shard_id: u16, parent_slot: Option<u64>, slot: u64, rank: usize, randao_mix: &str) -> Vec<String> {
+        let mut missed = Vec::new();
         
-        // Take top validators (in a real implementation, this would be a configurable parameter)
-        let max_validators = 100;
-        self.active_validators = weighted_validators
-            .into_iter()
-            .take(max_validators)
-            .map(|(addr, _)| addr)
-            .collect();
+        if let Some(parent_slot) = parent_slot {
+            let first_empty = (parent_slot + 1).max(slot.saturating_sub(self.epoch_length));
+            for empty_slot in first_empty..slot {
+                missed.extend(self.select_proposers(validator_set, shard_id, empty_slot, randao_mix).into_iter().take(1));
+            }
+        }
         
-        info!("Updated active validators list: {} validators", self.active_validators.len());
+        missed.extend(self.select_proposers(validator_set, shard_id, slot, randao_mix).into_iter().take(rank));
+        missed
     }
     
-    pub fn select_validator(&self, block_height: u64) -> Option<String> {
-        if self.active_validators.is_empty() {
-            warn!("No active validators available");
-            return None;
+    // Checks that a block proposed at `rank` is timestamped inside its slot,
+    // no earlier than its proposer's turn and not too far ahead of our clock
+    fn check_timing(&self, header: &BlockHeader, rank: usize) -> Result<(), String> {
+        let earliest = self.proposal_time(header.slot, rank);
+        let end = self.slot_start(header.slot + 1);
+        if header.timestamp < earliest || header.timestamp >= end {
+            return Err(format!("timestamp {} is outside the window [{}, {}) of rank {} in slot {}",
+                               header.timestamp, earliest, end, rank, header.slot));
         }
         
-        // Use a deterministic selection based on block height
-        // This ensures all nodes will select the same validator
-        let index = (block_height % self.active_validators.len() as u64) as usize;
-        let selected = self.active_validators[index].clone();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        if header.timestamp > now + self.slots.max_future_drift {
+            return Err(format!("timestamp {} is more than {} seconds in the future",
+                               header.timestamp, self.slots.max_future_drift));
+        }
         
-        debug!("Selected validator for block {}: {}", block_height, selected);
-        Some(selected)
+        Ok(())
     }
     
-    pub fn validate_block(&mut self, block: &Block, previous_block: &Block) -> bool {
-        // Check basic block validity
-        if !block.is_valid(previous_block) {
-            error!("Block validation failed: invalid block structure");
+    /// Validates `block` on top of `previous_block` (None for the first block
+    /// of the chain) against the validator set of its epoch, without touching
+    /// any state: its header, signature, proposer, RANDAO reveal, contribution
+    /// score and coinbase split. The state root is checked when the block is
+    /// applied.
+    pub fn validate_block(&self, block: &Block, previous_block: Option<&Block>, validator_set: &ValidatorSet) -> bool {
+        let epoch = self.epoch_at(block.header.height);
+        if validator_set.epoch != epoch {
+            error!("Block validation failed: validator set of epoch {} given for a block of epoch {}", validator_set.epoch, epoch);
             return false;
         }
         
-        // Check that the validator is authorized
-        if !self.active_validators.contains(&block.header.validator) {
-            error!("Block validation failed: unauthorized validator {}", block.header.validator);
+        // Look up the key the block's validator bonded with
+        let validator = match validator_set.get(&block.header.validator) {
+            Some(validator) => validator,
+            None => {
+                error!("Block validation failed: validator {} not found", block.header.validator);
+                return false;
+            }
+        };
+        let public_key = match hex::decode(&validator.public_key) {
+            Ok(key) => key,
+            Err(_) => {
+                error!("Block validation failed: validator {} has an invalid public key", block.header.validator);
+                return false;
+            }
+        };
+        
+        // Check basic block validity, including the validator's signature
+        if !block.is_valid(previous_block, &public_key) {
+            error!("Block validation failed: invalid block structure or signature");
             return false;
         }
         
-        // Check that the contribution score matches our records
-        if let Some(validator) = self.validators.get(&block.header.validator) {
-            if validator.contribution_score != block.header.contribution_score {
-                error!("Block validation failed: contribution score mismatch for validator {}", 
-                       block.header.validator);
+        // Check the RANDAO reveal and the mix it produces
+        if !randao::verify_reveal(&public_key, epoch, &block.header.randao_reveal) {
+            error!("Block validation failed: invalid RANDAO reveal from validator {}", block.header.validator);
+            return false;
+        }
+        
+        let parent_mix = previous_block
+            .map(|previous_block| previous_block.header.randao_mix.as_str())
+            .unwrap_or(randao::INITIAL_MIX);
+        match randao::mix(parent_mix, &block.header.randao_reveal) {
+            Ok(mix) if mix == block.header.randao_mix => {}
+            _ => {
+                error!("Block validation failed: RANDAO mix mismatch");
                 return false;
             }
+        }
+        
+        // Check that the block is in a later slot than its parent, that its
+        // validator is one of the slot's proposers and that it proposed in
+        // its turn
+        let parent_slot = previous_block.map(|previous_block| previous_block.header.slot);
+        if parent_slot.is_some_and(|parent_slot| block.header.slot <= parent_slot) {
+            error!("Block validation failed: slot {} does not follow the parent's slot", block.header.slot);
+            return false;
+        }
+        
+        let shard_id = block.header.shard_id;
+        let rank = match self.proposer_rank(validator_set, shard_id, block.header.slot, parent_mix, &block.header.validator) {
+            Some(rank) => rank,
+            None => {
+                error!("Block validation failed: validator {} is not a proposer of slot {} in shard {}",
+                       block.header.validator, block.header.slot, shard_id);
+                return false;
+            }
+        };
+        
+        if let Err(e) = self.check_timing(&block.header, rank) {
+            error!("Block validation failed: {}", e);
+            return false;
+        }
+        
+        if block.header.missed_proposers != self.missed_proposers(validator_set, shard_id, parent_slot, block.header.slot, rank, parent_mix) {
+            error!("Block validation failed: missed proposers do not match slot {}", block.header.slot);
+            return false;
+        }
+        
+        // Beacon blocks anchor claims and refunds in their own state; blocks
+        // of other shards never go back to an older beacon block than their
+        // parent's
+        let parent_beacon_height = previous_block.map_or(0, |previous_block| previous_block.header.beacon_height);
+        let beacon_height_valid = if shard_id == BEACON_SHARD {
+            block.header.beacon_height == 0
         } else {
-            error!("Block validation failed: validator {} not found", block.header.validator);
+            block.header.beacon_height >= parent_beacon_height
+        };
+        if !beacon_height_valid {
+            error!("Block validation failed: beacon height {} after the parent's {}", block.header.beacon_height, parent_beacon_height);
+            return false;
+        }
+        
+        // Check that the contribution score matches our records
+        if validator.contribution_score != block.header.contribution_score {
+            error!("Block validation failed: contribution score mismatch for validator {}",
+                   block.header.validator);
+            return false;
+        }
+        
+        // Check that the coinbase shares the block reward out as the rules say
+        let coinbase_check = match block.transactions.first() {
+            Some(coinbase) => rewards::verify_coinbase_split(&params::get(), validator_set, &block.header.validator, coinbase),
+            None => Err("block has no coinbase".to_string()),
+        };
+        if let Err(e) = coinbase_check {
+            error!("Block validation failed: {}", e);
             return false;
         }
         
@@ -172,47 +500,200 @@ impl ConsensusEngine {
             }
         }
         
-        // Update validator statistics
-        if let Some(validator) = self.validators.get_mut(&block.header.validator) {
-            validator.last_validation_time = block.header.timestamp;
-            validator.total_validated_blocks += 1;
+        true
+    }
+    
+    /// Counts a block that joined the best chain of its shard in its
+    /// proposer's stats.
+    pub fn count_block(&mut self, block: &Block) {
+        if self.counted_blocks.contains_key(&block.hash) {
+            return;
+        }
+        
+        let stats = self.stats.entry(block.header.validator.clone()).or_default();
+        self.counted_blocks.insert(block.hash.clone(), CountedBlock {
+            shard_id: block.header.shard_id,
+            height: block.header.height,
+            validator: block.header.validator.clone(),
+            timestamp: block.header.timestamp,
+            previous_time: stats.last_validation_time,
+        });
+        
+        stats.last_validation_time = block.header.timestamp;
+        stats.total_validated_blocks += 1;
+    }
+    
+    /// Takes a block that a reorganization removed from the best chain out
+    /// of its proposer's stats.
+    pub fn uncount_block(&mut self, block_hash: &str) {
+        let counted = match self.counted_blocks.remove(block_hash) {
+            Some(counted) => counted,
+            None => return,
+        };
+        
+        if let Some(stats) = self.stats.get_mut(&counted.validator) {
+            stats.total_validated_blocks = stats.total_validated_blocks.saturating_sub(1);
+            if stats.last_validation_time == counted.timestamp {
+                stats.last_validation_time = counted.previous_time;
+            }
+        }
+    }
+    
+    /// Checks that `block` belongs to a shard of its epoch's map and carries
+    /// exactly the shard migration steps the final validator sets call for:
+    /// the switch of `switch_map` and the imports of `import_sources`. The
+    /// `first` block of a chain is at height 1, or at the first height of the
+    /// epoch that created its shard.
+    pub fn check_migration(&self, block: &Block, first: bool) -> Result<(), String> {
+        let shard_id = block.header.shard_id;
+        let height = block.header.height;
+        let epoch = self.epoch_at(height);
+        if !self.validator_set(epoch)?.shard_map.shard_ids().contains(&shard_id) {
+            return Err(format!("Shard {} is not in the shard map of epoch {}", shard_id, epoch));
+        }
+        
+        let created = epoch > 0 && height == epoch * self.epoch_length
+            && !self.validator_set(epoch - 1)?.shard_map.shard_ids().contains(&shard_id);
+        if first && height != 1 && !created {
+            return Err(format!("Chain of shard {} can't start at height {}", shard_id, height));
         }
         
-        // Check if we need to start a new epoch
-        let block_height = previous_block.header.timestamp / self.target_block_time + 1;
-        if block_height % self.epoch_length == 0 {
-            self.start_new_epoch();
+        let mut switches = Vec::new();
+        let mut sources = BTreeSet::new();
+        for tx in &block.transactions {
+            match MigrationAction::from_transaction(tx) {
+                Ok(MigrationAction::Switch { shard_map, .. }) => switches.push(shard_map),
+                Ok(MigrationAction::Import { switch }) if !sources.insert(switch.header.shard_id) => {
+                    return Err(format!("Block {} imports from shard {} twice", block.hash, switch.header.shard_id));
+                }
+                _ => {}
+            }
         }
         
-        // Adjust difficulty if needed
-        self.adjust_difficulty(block.header.timestamp - previous_block.header.timestamp);
+        match (self.switch_map(height)?, switches.as_slice()) {
+            (None, []) => {}
+            (Some(next), [switch]) if switch == next => {}
+            (Some(next), _) => {
+                return Err(format!("Block {} must switch shard {} to shard map version {} once", block.hash, shard_id, next.version));
+            }
+            (None, _) => return Err(format!("Block {} switches shard {} with no new shard map", block.hash, shard_id)),
+        }
         
-        true
+        let expected = self.import_sources(shard_id, height)?;
+        if sources != expected {
+            return Err(format!("Block {} imports from shards {:?}, not {:?}", block.hash, sources, expected));
+        }
+        Ok(())
     }
     
-    fn start_new_epoch(&mut self) {
-        self.current_epoch += 1;
-        info!("Starting new epoch: {}", self.current_epoch);
+    /// Shard map that blocks at `height` switch to: that of the next epoch
+    /// in the last block of an epoch, if it differs from the current one.
+    pub fn switch_map(&self, height: u64) -> Result<Option<&ShardMap>, String> {
+        if !(height + 1).is_multiple_of(self.epoch_length) {
+            return Ok(None);
+        }
         
-        // Update active validators for the new epoch
-        self.update_active_validators();
+        let epoch = self.epoch_at(height);
+        let current = &self.validator_set(epoch)?.shard_map;
+        let next = &self.validator_set(epoch + 1)?.shard_map;
+        Ok((next != current).then_some(next))
     }
     
-    fn adjust_difficulty(&mut self, block_time: u64) {
-        // Simple difficulty adjustment algorithm
-        // If blocks are coming too quickly, increase difficulty
-        // If blocks are coming too slowly, decrease difficulty
-        if block_time < self.target_block_time {
-            self.difficulty = (self.difficulty * 110) / 100; // Increase by 10%
-        } else if block_time > self.target_block_time * 2 {
-            self.difficulty = (self.difficulty * 90) / 100; // Decrease by 10%
+    /// Shards whose switches the block of `shard_id` at `height` imports:
+    /// in the first block of an epoch, those that the previous epoch's map
+    /// gave addresses this one gives `shard_id`.
+    pub fn import_sources(&self, shard_id: u16, height: u64) -> Result<BTreeSet<u16>, String> {
+        let epoch = self.epoch_at(height);
+        if epoch == 0 || height != epoch * self.epoch_length {
+            return Ok(BTreeSet::new());
         }
         
-        debug!("Adjusted difficulty to: {}", self.difficulty);
+        let previous = &self.validator_set(epoch - 1)?.shard_map;
+        Ok(previous.sources(&self.validator_set(epoch)?.shard_map, shard_id))
+    }
+    
+    /// Final validator sets, by epoch.
+    pub fn validator_sets(&self) -> impl Iterator<Item = &ValidatorSet> {
+        self.validator_sets.values()
+    }
+    
+    /// Forgets how to uncount the blocks of `shard_id` up to a newly
+    /// finalized height, which stay counted for good.
+    pub fn prune_counted(&mut self, shard_id: u16, finalized_height: u64) {
+        self.counted_blocks.retain(|_, counted| counted.shard_id != shard_id || counted.height > finalized_height);
     }
     
-    pub fn get_current_difficulty(&self) -> u32 {
-        self.difficulty
+    /// Returns the epoch containing the block at `block_height`.
+    pub fn epoch_at(&self, block_height: u64) -> u64 {
+        block_height / self.epoch_length
+    }
+    
+    pub fn get_validator_stats(&self, address: &str) -> ValidatorStats {
+        self.stats.get(address).cloned().unwrap_or_default()
+    }
+    
+    pub fn get_target_block_time(&self) -> u64 {
+        self.target_block_time
+    }
+}
+
+impl Canonical for ValidatorInfo {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_str(&self.address);
+        encoder.put_str(&self.public_key);
+        encoder.put_u64(self.stake_amount);
+        encoder.put_u32(self.computation_power);
+        encoder.put_u32(self.contribution_score);
+        encoder.put_u64(self.jailed_until_epoch);
+        encoder.put_seq(&self.delegations);
+        encoder.put_u64(self.weight);
+    }
+    
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(ValidatorInfo {
+            address: decoder.get_string()?,
+            public_key: decoder.get_string()?,
+            stake_amount: decoder.get_u64()?,
+            computation_power: decoder.get_u32()?,
+            contribution_score: decoder.get_u32()?,
+            jailed_until_epoch: decoder.get_u64()?,
+            delegations: decoder.get_seq()?,
+            weight: decoder.get_u64()?,
+        })
+    }
+}
+
+impl Canonical for Committee {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_u16(self.shard_id);
+        encoder.put_seq(&self.members);
+    }
+    
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(Committee {
+            shard_id: decoder.get_u16()?,
+            members: decoder.get_seq()?,
+        })
+    }
+}
+
+impl Canonical for ValidatorSet {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_u64(self.epoch);
+        encoder.put_seq(&self.validators);
+        encoder.put_seq(&self.active);
+        self.shard_map.encode(encoder);
+        encoder.put_seq(&self.committees);
+    }
+    
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(ValidatorSet {
+            epoch: decoder.get_u64()?,
+            validators: decoder.get_seq()?,
+            active: decoder.get_seq()?,
+            shard_map: ShardMap::decode(decoder)?,
+            committees: decoder.get_seq()?,
+        })
     }
 }
 
@@ -224,12 +705,17 @@ lazy_static::lazy_static! {
 pub fn initialize() -> Result<(), Box<dyn std::error::Error>> {
     info!("Initializing consensus engine...");
     
-    let engine = ConsensusEngine::new(
-        1000,     // Minimum stake
-        100,      // Epoch length (blocks)
-        30,       // Target block time (seconds)
+    let params = params::get();
+    let mut engine = ConsensusEngine::new(
+        params.epoch_length,      // Epoch length (blocks)
+        params.target_block_time, // Slot duration (seconds)
     );
     
+    // Validator sets of the genesis state, which records the genesis validators
+    if let Some(beacon) = state::get_state_manager(BEACON_SHARD) {
+        engine.sync_validator_sets(&beacon.lock().unwrap());
+    }
+    
     let mut consensus_engine = CONSENSUS_ENGINE.lock().unwrap();
     *consensus_engine = Some(engine);
     
@@ -249,4 +735,144 @@ pub fn shutdown() -> Result<(), Box<dyn std::error::Error>> {
 
 pub fn get_engine() -> Arc<Mutex<Option<ConsensusEngine>>> {
     CONSENSUS_ENGINE.clone()
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::crypto;
+    use crate::core::genesis::{Genesis, GenesisValidator};
+    
+    // Candidate with the given stake and no computation power or contribution
+    fn candidate(index: u8, stake_amount: u64) -> ValidatorInfo {
+        ValidatorInfo {
+            address: format!("0x{:040x}", index),
+            public_key: String::new(),
+            stake_amount,
+            computation_power: 0,
+            contribution_score: 0,
+            jailed_until_epoch: 0,
+            delegations: Vec::new(),
+            weight: 0,
+        }
+    }
+    
+    #[test]
+    fn weights_of_huge_stakes_saturate() {
+        let params = ChainParams::default();
+        let mut info = candidate(1, u64::MAX);
+        info.computation_power = u32::MAX;
+        info.contribution_score = u32::MAX;
+        let weights = WeightParams { points_scale: u64::MAX, ..params.weights.clone() };
+        assert_eq!(validator_weight(&weights, &info), u64::MAX);
+        
+        // The shard total of several saturated weights doesn't wrap
+        let validators = (1..=4).map(|index| candidate(index, u64::MAX)).collect();
+        let set = ValidatorSet::new(&params, 0, validators, &ShardMap::uniform(1), &"ab".repeat(32));
+        let weight = set.weight(&set.active[0]);
+        assert_eq!(set.total_shard_weight(0), weight as u128 * 4);
+        
+        let engine = ConsensusEngine::new(params.epoch_length, params.target_block_time);
+        assert_eq!(engine.select_proposers(&set, 0, 7, &"ab".repeat(32)).len(), params.slots.fallback_proposers as usize + 1);
+    }
+    
+    #[test]
+    fn committees_rotate_and_need_the_minimum_size() {
+        let params = ChainParams { min_committee_size: 2, ..ChainParams::default() };
+        let validators: Vec<ValidatorInfo> = (1..=8).map(|index| candidate(index, 1_000)).collect();
+        let shard_map = ShardMap::uniform(3);
+        let shard_ids = [0, 1, 2];
+        
+        let set = ValidatorSet::new(&params, 4, validators.clone(), &shard_map, &"ab".repeat(32));
+        assert_eq!(set.committees.len(), 3);
+        let mut members: Vec<&String> = set.committees.iter().flat_map(|committee| &committee.members).collect();
+        assert!(set.committees.iter().all(|committee| committee.members.len() >= 2));
+        members.sort();
+        members.dedup();
+        assert_eq!(members.len(), 8);
+        for committee in &set.committees {
+            for member in &committee.members {
+                assert!(set.is_shard_validator(committee.shard_id, member));
+                assert_eq!(shard_ids.iter().filter(|&&shard_id| set.is_shard_validator(shard_id, member)).count(), 1);
+            }
+        }
+        
+        // The same epoch and seed draw the same committees; other epochs or
+        // seeds reshuffle them
+        assert_eq!(ValidatorSet::new(&params, 4, validators.clone(), &shard_map, &"ab".repeat(32)), set);
+        let next = ValidatorSet::new(&params, 5, validators.clone(), &shard_map, &"ab".repeat(32));
+        let reseeded = ValidatorSet::new(&params, 4, validators.clone(), &shard_map, &"cd".repeat(32));
+        assert!(next.committees != set.committees || reseeded.committees != set.committees);
+        
+        // Too few validators for the minimum: every one serves every shard
+        let strict = ChainParams { min_committee_size: 3, ..params };
+        let set = ValidatorSet::new(&strict, 4, validators, &shard_map, &"ab".repeat(32));
+        assert!(set.committees.is_empty());
+        assert_eq!(set.shard_validators(2), set.active);
+    }
+    
+    #[test]
+    fn fallback_proposers_are_distinct_and_deterministic() {
+        let params = ChainParams::default();
+        let validators = (1..=5).map(|index| candidate(index, 1_000 * index as u64)).collect();
+        let set = ValidatorSet::new(&params, 0, validators, &ShardMap::uniform(1), &"cd".repeat(32));
+        let engine = ConsensusEngine::new(params.epoch_length, params.target_block_time);
+        
+        let mix = "ef".repeat(32);
+        let proposers = engine.select_proposers(&set, 0, 3, &mix);
+        assert_eq!(proposers.len(), params.slots.fallback_proposers as usize + 1);
+        let distinct: std::collections::HashSet<&String> = proposers.iter().collect();
+        assert_eq!(distinct.len(), proposers.len());
+        assert_eq!(engine.select_proposers(&set, 0, 3, &mix), proposers);
+        
+        for (rank, proposer) in proposers.iter().enumerate() {
+            assert_eq!(engine.proposer_rank(&set, 0, 3, &mix, proposer), Some(rank));
+        }
+        let unranked = set.active.iter().find(|address| !proposers.contains(address)).unwrap();
+        assert_eq!(engine.proposer_rank(&set, 0, 3, &mix, unranked), None);
+        
+        // With fewer validators than ranks, every one of them is ranked once
+        let few = ValidatorSet::new(&params, 0, vec![candidate(1, 1_000), candidate(2, 2_000)], &ShardMap::uniform(1), &"cd".repeat(32));
+        assert_eq!(engine.select_proposers(&few, 0, 3, &mix).len(), 2);
+    }
+    
+    #[test]
+    fn validator_sets_are_taken_once_final() {
+        let params = ChainParams::default();
+        let public_key = crypto::signing_key_from_bytes(&[7; 32]).unwrap().verifying_key().to_bytes();
+        let genesis = Genesis {
+            validators: vec![GenesisValidator {
+                public_key: hex::encode(public_key),
+                stake: 5_000,
+                computation_power: 1,
+            }],
+            ..Genesis::default()
+        };
+        let mut beacon = StateManager::new(BEACON_SHARD);
+        beacon.apply_genesis(&genesis).unwrap();
+        
+        // The last block of epoch 0 records the set of epoch 2, which isn't
+        // final while that block isn't
+        let mut last = Block::new(String::new(), params.epoch_length - 1, Vec::new(), BEACON_SHARD, String::new(), 0);
+        last.header.randao_mix = "ab".repeat(32);
+        beacon.finish_block(&last.header);
+        assert!(beacon.validator_set(2).is_some());
+        
+        let mut engine = ConsensusEngine::new(params.epoch_length, params.target_block_time);
+        assert_eq!(engine.validator_set_height(1), None);
+        assert_eq!(engine.validator_set_height(2), Some(params.epoch_length - 1));
+        engine.sync_validator_sets(&beacon);
+        
+        // Epochs start at multiples of the epoch length
+        assert_eq!(engine.epoch_at(params.epoch_length - 1), 0);
+        assert_eq!(engine.epoch_at(params.epoch_length), 1);
+        assert_eq!(engine.validator_set_at(2 * params.epoch_length - 1).unwrap().epoch, 1);
+        let error = engine.validator_set_at(2 * params.epoch_length).unwrap_err();
+        assert!(error.contains("not final yet"), "{}", error);
+        
+        // A block is only checked against the set of its own epoch
+        let address = crypto::public_key_to_address(&public_key);
+        let mut block = Block::new(String::new(), 1, Vec::new(), BEACON_SHARD, address, 0);
+        block.sign(&[7; 32]).unwrap();
+        assert!(!engine.validate_block(&block, None, engine.validator_set(1).unwrap()));
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashSet;
+use serde::{Serialize, Deserialize};
+use crate::core::crypto;
+use crate::core::encoding::{self, Canonical, Decoder, Encoder};
+use crate::core::transaction::{Transaction, TransactionType};
+
+// Contribution reports for Adaptive Proof of Contribution.
+//
+// A contributor claims a number of units of one kind of work it did for the
+// network during an epoch: blocks it relayed, sync requests it served or AI
+// compute jobs it ran. The claim only counts if enough bonded validators that
+// observed the work sign it, and the contributor can't be one of them. Each
+// kind of work is worth a fixed number of points per unit, up to a cap of
+// units per contributor and epoch, and every account's score decays at the
+// end of each epoch, so a score reflects recent work and can't be farmed.
+// Like stake, scores are only kept in the beacon shard, and count through
+// the validator sets it records: blocks carry their proposer's score in the
+// set of their epoch, not its current score.
+
+/// System account whose storage counts the units credited per epoch.
+pub const CONTRIBUTION_ADDRESS: &str = "0x0000000000000000000000000000000000000002";
+
+const CLAIMED_PREFIX: &str = "claimed/";
+
+const ATTESTATION_DOMAIN: &[u8] = b"nexacore-contribution";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ContributionKind {
+    RelayedBlocks,
+    ServedSyncRequests,
+    ComputeJobs,
+}
+
+impl ContributionKind {
+    fn tag(&self) -> u8 {
+        match self {
+            ContributionKind::RelayedBlocks => 0,
+            ContributionKind::ServedSyncRequests => 1,
+            ContributionKind::ComputeJobs => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self, String> {
+        match tag {
+            0 => Ok(ContributionKind::RelayedBlocks),
+            1 => Ok(ContributionKind::ServedSyncRequests),
+            2 => Ok(ContributionKind::ComputeJobs),
+            tag => Err(format!("Unknown contribution kind {}", tag)),
+        }
+    }
+}
+
+// Work a contributor claims to have done in an epoch
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContributionClaim {
+    pub contributor: String,
+    pub kind: ContributionKind,
+    pub epoch: u64,
+    pub units: u32,
+}
+
+impl ContributionClaim {
+    /// Returns the bytes attesters sign: a domain separator followed by the
+    /// canonical encoding of the claim.
+    pub fn signing_message(&self) -> Vec<u8> {
+        let mut message = ATTESTATION_DOMAIN.to_vec();
+        message.extend_from_slice(&encoding::to_bytes(self));
+        message
+    }
+
+    /// Signs the claim as a witness of the work.
+    pub fn attest(&self, private_key: &[u8]) -> Result<Attestation, String> {
+        let key = crypto::signing_key_from_bytes(private_key)?;
+        Ok(Attestation {
+            public_key: hex::encode(key.verifying_key().as_bytes()),
+            signature: crypto::sign(private_key, &self.signing_message())?,
+        })
+    }
+
+    /// Storage key, in the contribution account, of the units already
+    /// credited for this claim's contributor, kind and epoch.
+    pub fn claimed_key(&self) -> String {
+        format!("{}{:020}/{}/{}", CLAIMED_PREFIX, self.epoch, self.contributor, self.kind.tag())
+    }
+}
+
+// A witness's signature over a claim
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attestation {
+    pub public_key: String, // Hex ed25519 public key of the witness
+    pub signature: String,
+}
+
+impl Attestation {
+    pub fn attester(&self) -> Result<String, String> {
+        let public_key = hex::decode(&self.public_key).map_err(|e| format!("Invalid attester key encoding: {}", e))?;
+        Ok(crypto::public_key_to_address(&public_key))
+    }
+}
+
+// Data of a `ContributionReport` transaction
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContributionReport {
+    pub claim: ContributionClaim,
+    pub attestations: Vec<Attestation>,
+}
+
+impl ContributionReport {
+    pub fn from_transaction(tx: &Transaction) -> Result<Self, String> {
+        if !matches!(tx.tx_type, TransactionType::ContributionReport) {
+            return Err(format!("Transaction {} is not a contribution report", tx.hash));
+        }
+
+        encoding::from_bytes(&tx.data)
+    }
+
+    /// Checks every attestation's signature and returns the attesters'
+    /// addresses. Attesters must be distinct and other than the contributor.
+    pub fn verify_attestations(&self) -> Result<Vec<String>, String> {
+        let message = self.claim.signing_message();
+        let mut attesters = Vec::with_capacity(self.attestations.len());
+        let mut seen = HashSet::new();
+
+        for attestation in &self.attestations {
+            let attester = attestation.attester()?;
+            if attester == self.claim.contributor {
+                return Err(format!("Contributor {} attested its own work", attester));
+            }
+
+            if !seen.insert(attester.clone()) {
+                return Err(format!("Attester {} signed the report twice", attester));
+            }
+
+            let public_key = hex::decode(&attestation.public_key).map_err(|e| format!("Invalid attester key encoding: {}", e))?;
+            if !crypto::verify(&public_key, &message, &attestation.signature) {
+                return Err(format!("Invalid attestation signature from {}", attester));
+            }
+
+            attesters.push(attester);
+        }
+
+        Ok(attesters)
+    }
+}
+
+pub fn is_claimed_key(key: &str) -> bool {
+    key.starts_with(CLAIMED_PREFIX)
+}
+
+/// Epoch of a claimed-units storage key.
+pub fn claimed_key_epoch(key: &str) -> Option<u64> {
+    key.strip_prefix(CLAIMED_PREFIX)?
+        .split('/')
+        .next()?
+        .parse()
+        .ok()
+}
+
+impl Canonical for ContributionClaim {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_str(&self.contributor);
+        encoder.put_u8(self.kind.tag());
+        encoder.put_u64(self.epoch);
+        encoder.put_u32(self.units);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(ContributionClaim {
+            contributor: decoder.get_string()?,
+            kind: ContributionKind::from_tag(decoder.get_u8()?)?,
+            epoch: decoder.get_u64()?,
+            units: decoder.get_u32()?,
+        })
+    }
+}
+
+impl Canonical for Attestation {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_str(&self.public_key);
+        encoder.put_str(&self.signature);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(Attestation {
+            public_key: decoder.get_string()?,
+            signature: decoder.get_string()?,
+        })
+    }
+}
+
+impl Canonical for ContributionReport {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.claim.encode(encoder);
+        encoder.put_seq(&self.attestations);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(ContributionReport {
+            claim: ContributionClaim::decode(decoder)?,
+            attestations: decoder.get_seq()?,
+        })
+    }
+}
@@ -0,0 +1,343 @@
+use serde::{Serialize, Deserialize};
+use crate::core::block::{Block, BlockHeader};
+use crate::core::crypto;
+use crate::core::encoding::{self, Canonical, Decoder, Encoder};
+use crate::core::merkle::MerkleProof;
+use crate::core::state_tree::StorageProof;
+use crate::core::transaction::{Transaction, TransactionType};
+
+// Two-phase cross-shard transfers.
+//
+// A `ShardCrossing` transaction moves value between shards in three steps,
+// each carrying a `CrossShardAction` in its `data`:
+//
+// 1. Send, on the source shard: spends inputs like a transfer, but its first
+//    output is paid on the target shard. The amount is locked in the
+//    cross-shard account, under an entry keyed by the transaction hash.
+//    The transaction, committed in a source block, is the transfer's receipt.
+// 2. Claim, on the target shard: carries the receipt with its inclusion
+//    proof against an anchored source header, and creates the output, keyed
+//    by the receipt id. The cross-shard account records the claim under the
+//    receipt id, so a receipt can only be claimed once, and the record stays
+//    after the output is spent. A migration that moves the recipient to
+//    another shard moves the record along (see `migration`).
+// 3. Refund, on the source shard, if the receipt wasn't claimed before its
+//    deadline: carries the receipt again, an anchored target header from
+//    after the deadline and a storage proof that the target state at that
+//    header has no claim of the receipt. The locked amount is paid back to
+//    the sender.
+//
+// A target block can only claim a receipt if its parent is older than the
+// deadline, so a target header past the deadline without the receipt's
+// claim proves the receipt can never be claimed.
+//
+// Claims and refunds have no inputs and no outputs and pay no fee; anyone
+// may relay them. Headers of other shards are only trusted once the beacon
+// block named by the including block anchors them (see `beacon`): a
+// receipt's header is linked to an anchored block by the headers of its
+// descendants, and a refund's target header must be anchored itself.
+
+/// System account holding the value of transfers sent to other shards.
+pub const CROSS_SHARD_ADDRESS: &str = "0x0000000000000000000000000000000000000003";
+
+const OUTGOING_PREFIX: &str = "outgoing/";
+const CLAIMED_PREFIX: &str = "claimed/";
+
+// Output index of the UTXO a receipt creates: delivered on the target shard
+// or refunded on the source shard
+pub const DELIVERY_INDEX: u32 = 0;
+pub const REFUND_INDEX: u32 = 1;
+
+// Data of a `ShardCrossing` transaction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CrossShardAction {
+    Send {
+        target_shard: u16,
+    },
+    Claim {
+        receipt: Box<ReceiptProof>,
+    },
+    Refund {
+        receipt: Box<ReceiptProof>,
+        target_header: Box<BlockHeader>,
+        absence: Box<StorageProof>, // No claim of the receipt under the target header's state root
+    },
+}
+
+impl CrossShardAction {
+    pub fn from_transaction(tx: &Transaction) -> Result<Self, String> {
+        if !matches!(tx.tx_type, TransactionType::ShardCrossing) {
+            return Err(format!("Transaction {} is not a shard crossing", tx.hash));
+        }
+
+        encoding::from_bytes(&tx.data)
+    }
+
+    /// Headers that a claim or refund needs anchored.
+    pub fn anchor_headers(&self) -> Result<Vec<&BlockHeader>, String> {
+        match self {
+            CrossShardAction::Send { .. } => Ok(Vec::new()),
+            CrossShardAction::Claim { receipt } => Ok(vec![receipt.anchor()?]),
+            CrossShardAction::Refund { receipt, target_header, .. } => Ok(vec![receipt.anchor()?, target_header]),
+        }
+    }
+}
+
+// A send transaction proven against the header of the source block that
+// includes it. Migrations prove the switch of their source shard the same way.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceiptProof {
+    pub header: BlockHeader,
+    pub tx: Transaction,
+    pub proof: MerkleProof,
+    pub descendants: Vec<BlockHeader>, // From the child of `header` up to an anchored block
+}
+
+impl ReceiptProof {
+    /// Builds the proof for the transaction `tx_hash` in `block`, linked
+    /// by `descendants` to an anchored block.
+    pub fn new(block: &Block, tx_hash: &str, descendants: Vec<BlockHeader>) -> Option<Self> {
+        let tx = block.transactions.iter().find(|tx| tx.hash == tx_hash)?.clone();
+        Some(ReceiptProof {
+            header: block.header.clone(),
+            proof: block.transaction_proof(tx_hash)?,
+            tx,
+            descendants,
+        })
+    }
+
+    pub fn block_hash(&self) -> String {
+        Block::calculate_hash(&self.header)
+    }
+
+    /// Checks that each descendant is the child of the header before it and
+    /// returns the last one, which must be anchored for the receipt to hold.
+    pub fn anchor(&self) -> Result<&BlockHeader, String> {
+        let mut parent = &self.header;
+        for header in &self.descendants {
+            if header.shard_id != parent.shard_id || header.height != parent.height + 1 ||
+                header.previous_hash != Block::calculate_hash(parent) {
+                return Err(format!("Header at height {} of receipt {} is not the child of the one before",
+                                   header.height, self.tx.hash));
+            }
+            parent = header;
+        }
+
+        Ok(parent)
+    }
+
+    /// Checks that the transaction is a send included under the header and
+    /// returns its receipt. Whether the header is final is up to the caller.
+    pub fn verify(&self, receipt_timeout: u64) -> Result<Receipt, String> {
+        self.verify_inclusion()?;
+
+        let deadline = self.header.timestamp
+            .checked_add(receipt_timeout)
+            .ok_or_else(|| format!("Deadline of receipt {} overflows", self.tx.hash))?;
+        Receipt::from_send(&self.tx, deadline)
+    }
+
+    /// Checks that the transaction, whatever it does, is included under the
+    /// header and belongs to the header's shard.
+    pub fn verify_inclusion(&self) -> Result<(), String> {
+        if self.tx.hash != self.tx.calculate_hash() {
+            return Err(format!("Receipt transaction {} has a wrong hash", self.tx.hash));
+        }
+
+        if !Block::verify_transaction_proof(&self.header.merkle_root, &self.tx.hash, &self.proof) {
+            return Err(format!("Transaction {} is not included in block {}", self.tx.hash, self.block_hash()));
+        }
+
+        if self.tx.shard_id != self.header.shard_id {
+            return Err(format!("Transaction {} belongs to shard {}, not {}", self.tx.hash, self.tx.shard_id, self.header.shard_id));
+        }
+
+        Ok(())
+    }
+}
+
+// What a send transaction promises: an output on the target shard, or the
+// amount back to the sender after the deadline
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub source_shard: u16,
+    pub target_shard: u16,
+    pub tx_hash: String,
+    pub recipient: String,
+    pub amount: u64,
+    pub script_pubkey: String,
+    pub refund_address: String,
+    pub refund_key: String, // Hex public key that signed the send's first input
+    pub deadline: u64,      // Target blocks whose parent is this recent or later can't claim it
+}
+
+impl Receipt {
+    fn from_send(tx: &Transaction, deadline: u64) -> Result<Self, String> {
+        let target_shard = match CrossShardAction::from_transaction(tx)? {
+            CrossShardAction::Send { target_shard } => target_shard,
+            _ => return Err(format!("Transaction {} is not a cross-shard send", tx.hash)),
+        };
+
+        let (input, output) = match (tx.inputs.first(), tx.outputs.first()) {
+            (Some(input), Some(output)) => (input, output),
+            _ => return Err(format!("Cross-shard send {} has no inputs or outputs", tx.hash)),
+        };
+
+        let refund_key = hex::decode(&input.script_sig).map_err(|e| format!("Invalid sender key encoding: {}", e))?;
+
+        Ok(Receipt {
+            source_shard: tx.shard_id,
+            target_shard,
+            tx_hash: tx.hash.clone(),
+            recipient: output.address.clone(),
+            amount: output.amount,
+            script_pubkey: output.script_pubkey.clone(),
+            refund_address: crypto::public_key_to_address(&refund_key),
+            refund_key: input.script_sig.clone(),
+            deadline,
+        })
+    }
+
+    /// Identifies the receipt; the UTXOs it creates are keyed by it.
+    pub fn id(&self) -> String {
+        encoding::hash(self)
+    }
+
+    pub fn delivery_utxo_key(&self) -> String {
+        format!("{}:{}", self.id(), DELIVERY_INDEX)
+    }
+
+    pub fn refund_utxo_key(&self) -> String {
+        format!("{}:{}", self.id(), REFUND_INDEX)
+    }
+
+    /// Checks a proof that the target state under `state_root` has no
+    /// claim of this receipt.
+    pub fn verify_undelivered(&self, state_root: &str, absence: &StorageProof) -> bool {
+        let key = claimed_key(&self.id());
+        absence.account_proof.address == CROSS_SHARD_ADDRESS
+            && absence.slots.iter().any(|slot| slot.key == key && slot.value.is_none())
+            && absence.verify(state_root)
+    }
+}
+
+/// Key, in the cross-shard account, of the amount a send locked.
+pub fn outgoing_key(tx_hash: &str) -> String {
+    format!("{}{}", OUTGOING_PREFIX, tx_hash)
+}
+
+// What the cross-shard account records for a claimed receipt: the recipient,
+// whose shard keeps the record if a migration moves it, and the height of the
+// block that claimed it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClaimRecord {
+    pub recipient: String,
+    pub claimed_at: u64,
+}
+
+/// Key, in the cross-shard account, of the record of a claimed receipt.
+pub fn claimed_key(receipt_id: &str) -> String {
+    format!("{}{}", CLAIMED_PREFIX, receipt_id)
+}
+
+pub fn is_claimed_key(key: &str) -> bool {
+    key.starts_with(CLAIMED_PREFIX)
+}
+
+impl Canonical for CrossShardAction {
+    fn encode(&self, encoder: &mut Encoder) {
+        match self {
+            CrossShardAction::Send { target_shard } => {
+                encoder.put_u8(0);
+                encoder.put_u16(*target_shard);
+            }
+            CrossShardAction::Claim { receipt } => {
+                encoder.put_u8(1);
+                receipt.encode(encoder);
+            }
+            CrossShardAction::Refund { receipt, target_header, absence } => {
+                encoder.put_u8(2);
+                receipt.encode(encoder);
+                target_header.encode(encoder);
+                absence.encode(encoder);
+            }
+        }
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        match decoder.get_u8()? {
+            0 => Ok(CrossShardAction::Send {
+                target_shard: decoder.get_u16()?,
+            }),
+            1 => Ok(CrossShardAction::Claim {
+                receipt: Box::new(ReceiptProof::decode(decoder)?),
+            }),
+            2 => Ok(CrossShardAction::Refund {
+                receipt: Box::new(ReceiptProof::decode(decoder)?),
+                target_header: Box::new(BlockHeader::decode(decoder)?),
+                absence: Box::new(StorageProof::decode(decoder)?),
+            }),
+            tag => Err(format!("Unknown cross-shard action {}", tag)),
+        }
+    }
+}
+
+impl Canonical for ReceiptProof {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.header.encode(encoder);
+        self.tx.encode(encoder);
+        self.proof.encode(encoder);
+        encoder.put_seq(&self.descendants);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(ReceiptProof {
+            header: BlockHeader::decode(decoder)?,
+            tx: Transaction::decode(decoder)?,
+            proof: MerkleProof::decode(decoder)?,
+            descendants: decoder.get_seq()?,
+        })
+    }
+}
+
+impl Canonical for Receipt {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_u16(self.source_shard);
+        encoder.put_u16(self.target_shard);
+        encoder.put_str(&self.tx_hash);
+        encoder.put_str(&self.recipient);
+        encoder.put_u64(self.amount);
+        encoder.put_str(&self.script_pubkey);
+        encoder.put_str(&self.refund_address);
+        encoder.put_str(&self.refund_key);
+        encoder.put_u64(self.deadline);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(Receipt {
+            source_shard: decoder.get_u16()?,
+            target_shard: decoder.get_u16()?,
+            tx_hash: decoder.get_string()?,
+            recipient: decoder.get_string()?,
+            amount: decoder.get_u64()?,
+            script_pubkey: decoder.get_string()?,
+            refund_address: decoder.get_string()?,
+            refund_key: decoder.get_string()?,
+            deadline: decoder.get_u64()?,
+        })
+    }
+}
+
+impl Canonical for ClaimRecord {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_str(&self.recipient);
+        encoder.put_u64(self.claimed_at);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(ClaimRecord {
+            recipient: decoder.get_string()?,
+            claimed_at: decoder.get_u64()?,
+        })
+    }
+}
@@ -0,0 +1,63 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use sha2::{Sha256, Digest};
+
+// Shared ed25519 helpers used by blocks, transactions and consensus
+
+pub const PUBLIC_KEY_LENGTH: usize = ed25519_dalek::PUBLIC_KEY_LENGTH;
+pub const SECRET_KEY_LENGTH: usize = ed25519_dalek::SECRET_KEY_LENGTH;
+
+pub fn signing_key_from_bytes(private_key: &[u8]) -> Result<SigningKey, String> {
+    let bytes: [u8; SECRET_KEY_LENGTH] = private_key
+        .try_into()
+        .map_err(|_| format!("Invalid private key length: expected {}, got {}", SECRET_KEY_LENGTH, private_key.len()))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+pub fn verifying_key_from_bytes(public_key: &[u8]) -> Result<VerifyingKey, String> {
+    let bytes: [u8; PUBLIC_KEY_LENGTH] = public_key
+        .try_into()
+        .map_err(|_| format!("Invalid public key length: expected {}, got {}", PUBLIC_KEY_LENGTH, public_key.len()))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| format!("Invalid public key: {}", e))
+}
+
+pub fn verifying_key_from_hex(public_key: &str) -> Result<VerifyingKey, String> {
+    let bytes = hex::decode(public_key).map_err(|e| format!("Invalid public key encoding: {}", e))?;
+    verifying_key_from_bytes(&bytes)
+}
+
+/// Signs `message` and returns the hex-encoded signature.
+pub fn sign(private_key: &[u8], message: &[u8]) -> Result<String, String> {
+    let signing_key = signing_key_from_bytes(private_key)?;
+    Ok(hex::encode(signing_key.sign(message).to_bytes()))
+}
+
+/// Verifies a hex-encoded signature over `message`. Malformed keys or
+/// signatures are treated as a failed verification.
+pub fn verify(public_key: &[u8], message: &[u8], signature: &str) -> bool {
+    let verifying_key = match verifying_key_from_bytes(public_key) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+
+    let signature_bytes = match hex::decode(signature) {
+        Ok(bytes) => bytes,
+        Err(_) => return false,
+    };
+
+    let signature = match Signature::from_slice(&signature_bytes) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    verifying_key.verify_strict(message, &signature).is_ok()
+}
+
+/// Derives an account address from a public key, matching the wallet format
+/// (`0x` followed by the first 20 bytes of the SHA-256 of the key).
+pub fn public_key_to_address(public_key: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(public_key);
+    let result = hasher.finalize();
+
+    format!("0x{}", hex::encode(&result[0..20]))
+}
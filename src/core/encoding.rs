@@ -0,0 +1,429 @@
+//! Canonical binary encoding used for hashing, signing and wire transfer.
+//!
+//! Every encoded value starts with a one-byte format version
+//! (`ENCODING_VERSION`), followed by the value's fields in declaration order:
+//!
+//! - integers are fixed-width little-endian (`u8`, `u16`, `u32`, `u64`)
+//! - byte strings and UTF-8 strings are a `u32` length followed by the bytes
+//! - sequences are a `u32` element count followed by the elements
+//! - optional values are a `0` byte (absent) or a `1` byte and the value
+//! - enums are a `u8` variant tag followed by the variant's fields
+//!
+//! Hashes are the hex-encoded SHA-256 of the full encoding, version byte
+//! included. The layout is documented in `docs/canonical-encoding.md`
+//! together with golden vectors that other implementations can test against:
+//!
+//! ```
+//! use nexacore::core::block::{Block, BlockHeader};
+//! use nexacore::core::transaction::{Transaction, TransactionInput, TransactionOutput, TransactionType};
+//!
+//! let header = BlockHeader {
+//!     version: 1,
+//!     previous_hash: "00".repeat(32),
+//!     height: 0,
+//!     merkle_root: "00".repeat(32),
+//!     state_root: "00".repeat(32),
+//!     timestamp: 1_700_000_000,
+//!     shard_id: 0,
+//!     slot: 0,
+//!     missed_proposers: Vec::new(),
+//!     validator: "0x0000000000000000000000000000000000000000".to_string(),
+//!     contribution_score: 0,
+//!     randao_reveal: String::new(),
+//!     randao_mix: String::new(),
+//!     beacon_height: 0,
+//! };
+//! assert_eq!(
+//!     Block::calculate_hash(&header),
+//!     "b60fea8056b09b9e79bdf49de4a901818a96f7201c8caf07fe37fd73598d74f2"
+//! );
+//!
+//! let tx = Transaction {
+//!     version: 1,
+//!     tx_type: TransactionType::Transfer,
+//!     inputs: vec![TransactionInput {
+//!         previous_tx: "11".repeat(32),
+//!         index: 0,
+//!         script_sig: "22".repeat(32),
+//!         amount: 1000,
+//!     }],
+//!     outputs: vec![TransactionOutput {
+//!         address: "0x3333333333333333333333333333333333333333".to_string(),
+//!         amount: 900,
+//!         script_pubkey: "44".repeat(32),
+//!     }],
+//!     timestamp: 1_700_000_000,
+//!     lock_time: 0,
+//!     shard_id: 0,
+//!     data: Vec::new(),
+//!     hash: String::new(),
+//!     signatures: Vec::new(),
+//!     privacy_proof: None,
+//! };
+//! assert_eq!(
+//!     tx.calculate_hash(),
+//!     "134d5cc3669ff6456c9e3155bfda8c56b3d53e24b39dbeea330105056d0404ef"
+//! );
+//! ```
+
+use sha2::{Sha256, Digest};
+
+/// Version of the canonical encoding format, written as the first byte.
+pub const ENCODING_VERSION: u8 = 3;
+
+/// Types with a canonical binary representation.
+pub trait Canonical: Sized {
+    fn encode(&self, encoder: &mut Encoder);
+    fn decode(decoder: &mut Decoder) -> Result<Self, String>;
+}
+
+impl Canonical for String {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_str(self);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        decoder.get_string()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Encoder {
+    buffer: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Encoder {
+            buffer: vec![ENCODING_VERSION],
+        }
+    }
+
+    pub fn put_u8(&mut self, value: u8) {
+        self.buffer.push(value);
+    }
+
+    pub fn put_u16(&mut self, value: u16) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_u32(&mut self, value: u32) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_u64(&mut self, value: u64) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_bool(&mut self, value: bool) {
+        self.put_u8(value as u8);
+    }
+
+    pub fn put_bytes(&mut self, value: &[u8]) {
+        self.put_len(value.len());
+        self.buffer.extend_from_slice(value);
+    }
+
+    pub fn put_str(&mut self, value: &str) {
+        self.put_bytes(value.as_bytes());
+    }
+
+    pub fn put_option<T>(&mut self, value: &Option<T>, put: impl FnOnce(&mut Self, &T)) {
+        match value {
+            Some(inner) => {
+                self.put_u8(1);
+                put(self, inner);
+            }
+            None => self.put_u8(0),
+        }
+    }
+
+    pub fn put_seq<T: Canonical>(&mut self, values: &[T]) {
+        self.put_len(values.len());
+        for value in values {
+            value.encode(self);
+        }
+    }
+
+    pub fn put_len(&mut self, len: usize) {
+        // Lengths above u32::MAX can't be represented; nothing in a block comes close
+        self.put_u32(u32::try_from(len).expect("Length exceeds canonical encoding limit"));
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+#[derive(Debug)]
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, String> {
+        match data.first() {
+            Some(&ENCODING_VERSION) => Ok(Decoder { data, position: 1 }),
+            Some(&version) => Err(format!("Unsupported encoding version {}", version)),
+            None => Err("Empty encoding".to_string()),
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.position
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| format!("Unexpected end of data at offset {}", self.position))?;
+
+        let slice = &self.data[self.position..end];
+        self.position = end;
+        Ok(slice)
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn get_u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn get_bool(&mut self) -> Result<bool, String> {
+        match self.get_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(format!("Invalid boolean value {}", other)),
+        }
+    }
+
+    pub fn get_bytes(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.get_u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    pub fn get_string(&mut self) -> Result<String, String> {
+        String::from_utf8(self.get_bytes()?).map_err(|e| format!("Invalid UTF-8 string: {}", e))
+    }
+
+    pub fn get_option<T>(&mut self, get: impl FnOnce(&mut Self) -> Result<T, String>) -> Result<Option<T>, String> {
+        match self.get_u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(get(self)?)),
+            other => Err(format!("Invalid option tag {}", other)),
+        }
+    }
+
+    pub fn get_seq<T: Canonical>(&mut self) -> Result<Vec<T>, String> {
+        let count = self.get_u32()? as usize;
+
+        // Grow as elements decode: the count is untrusted, and an element can
+        // be far larger in memory than its encoding
+        let mut values = Vec::new();
+        for _ in 0..count {
+            values.push(T::decode(self)?);
+        }
+        Ok(values)
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        if self.position != self.data.len() {
+            return Err(format!("{} trailing bytes after value", self.data.len() - self.position));
+        }
+        Ok(())
+    }
+}
+
+pub fn to_bytes<T: Canonical>(value: &T) -> Vec<u8> {
+    let mut encoder = Encoder::new();
+    value.encode(&mut encoder);
+    encoder.finish()
+}
+
+pub fn from_bytes<T: Canonical>(data: &[u8]) -> Result<T, String> {
+    let mut decoder = Decoder::new(data)?;
+    let value = T::decode(&mut decoder)?;
+    decoder.finish()?;
+    Ok(value)
+}
+
+/// Hex-encoded SHA-256 of an already encoded value.
+pub fn hash_bytes(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hex::encode(hasher.finalize())
+}
+
+pub fn hash<T: Canonical>(value: &T) -> String {
+    hash_bytes(&to_bytes(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::block::Block;
+    use crate::core::transaction::{Transaction, TransactionInput, TransactionOutput, TransactionType};
+
+    // Every primitive in one value
+    #[derive(Debug, PartialEq)]
+    struct Sample {
+        byte: u8,
+        short: u16,
+        word: u32,
+        long: u64,
+        flag: bool,
+        bytes: Vec<u8>,
+        text: String,
+        maybe: Option<u64>,
+        items: Vec<String>,
+    }
+
+    impl Canonical for Sample {
+        fn encode(&self, encoder: &mut Encoder) {
+            encoder.put_u8(self.byte);
+            encoder.put_u16(self.short);
+            encoder.put_u32(self.word);
+            encoder.put_u64(self.long);
+            encoder.put_bool(self.flag);
+            encoder.put_bytes(&self.bytes);
+            encoder.put_str(&self.text);
+            encoder.put_option(&self.maybe, |encoder, value| encoder.put_u64(*value));
+            encoder.put_seq(&self.items);
+        }
+
+        fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+            Ok(Sample {
+                byte: decoder.get_u8()?,
+                short: decoder.get_u16()?,
+                word: decoder.get_u32()?,
+                long: decoder.get_u64()?,
+                flag: decoder.get_bool()?,
+                bytes: decoder.get_bytes()?,
+                text: decoder.get_string()?,
+                maybe: decoder.get_option(|decoder| decoder.get_u64())?,
+                items: decoder.get_seq()?,
+            })
+        }
+    }
+
+    fn sample() -> Sample {
+        Sample {
+            byte: 0xab,
+            short: 0x1234,
+            word: 0xdead_beef,
+            long: u64::MAX - 1,
+            flag: true,
+            bytes: vec![0, 1, 2, 255],
+            text: "nexa ✓".to_string(),
+            maybe: Some(42),
+            items: vec!["a".to_string(), String::new(), "bc".to_string()],
+        }
+    }
+
+    fn transfer() -> Transaction {
+        let mut tx = Transaction::new(
+            TransactionType::Transfer,
+            vec![TransactionInput {
+                previous_tx: "11".repeat(32),
+                index: 3,
+                script_sig: "22".repeat(32),
+                amount: 1000,
+            }],
+            vec![TransactionOutput {
+                address: "0x3333333333333333333333333333333333333333".to_string(),
+                amount: 900,
+                script_pubkey: "44".repeat(32),
+            }],
+            1,
+            vec![7, 8, 9],
+            0,
+        );
+        tx.signatures.push("55".repeat(64));
+        tx.privacy_proof = Some("proof".to_string());
+        tx
+    }
+
+    #[test]
+    fn primitives_round_trip() {
+        let bytes = to_bytes(&sample());
+        assert_eq!(bytes[0], ENCODING_VERSION);
+        assert_eq!(&bytes[1..4], &[0xab, 0x34, 0x12]);
+        assert_eq!(from_bytes::<Sample>(&bytes).unwrap(), sample());
+
+        let empty = Sample { maybe: None, items: Vec::new(), bytes: Vec::new(), ..sample() };
+        assert_eq!(from_bytes::<Sample>(&to_bytes(&empty)).unwrap(), empty);
+    }
+
+    #[test]
+    fn transactions_and_blocks_round_trip() {
+        let tx = transfer();
+        let decoded: Transaction = from_bytes(&to_bytes(&tx)).unwrap();
+        assert_eq!(to_bytes(&decoded), to_bytes(&tx));
+        assert_eq!(decoded.calculate_hash(), tx.calculate_hash());
+
+        let mut block = Block::new("00".repeat(32), 1, vec![tx], 1, "0x0000000000000000000000000000000000000001".to_string(), 5);
+        block.header.missed_proposers.push("0x0000000000000000000000000000000000000002".to_string());
+        block.signature = "66".repeat(64);
+        let decoded: Block = from_bytes(&to_bytes(&block)).unwrap();
+        assert_eq!(to_bytes(&decoded), to_bytes(&block));
+        assert_eq!(Block::calculate_hash(&decoded.header), Block::calculate_hash(&block.header));
+    }
+
+    #[test]
+    fn truncated_input_is_rejected() {
+        let bytes = to_bytes(&transfer());
+        for len in 0..bytes.len() {
+            assert!(from_bytes::<Transaction>(&bytes[..len]).is_err(), "prefix of {} bytes decoded", len);
+        }
+
+        // A length prefix running past the end must not be trusted
+        let mut encoder = Encoder::new();
+        encoder.put_u32(u32::MAX);
+        assert!(from_bytes::<String>(&encoder.finish()).is_err());
+
+        // So must a sequence count claiming more elements than follow
+        let mut encoder = Encoder::new();
+        encoder.put_u32(u32::MAX);
+        encoder.put_u8(0);
+        let bytes = encoder.finish();
+        let mut decoder = Decoder::new(&bytes).unwrap();
+        assert!(decoder.get_seq::<Transaction>().is_err());
+    }
+
+    #[test]
+    fn unknown_versions_tags_and_trailing_bytes_are_rejected() {
+        let bytes = to_bytes(&transfer());
+
+        let mut version = bytes.clone();
+        version[0] = ENCODING_VERSION + 1;
+        assert!(from_bytes::<Transaction>(&version).unwrap_err().contains("version"));
+
+        // The transaction type tag follows the u32 version field
+        let mut tag = bytes.clone();
+        tag[5] = 200;
+        assert!(from_bytes::<Transaction>(&tag).unwrap_err().contains("Unknown transaction type"));
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(from_bytes::<Transaction>(&trailing).unwrap_err().contains("trailing"));
+
+        // Version, fixed-width fields, then 4 bytes and an 8-byte string
+        // with their lengths put the option tag at offset 37
+        let mut option = to_bytes(&sample());
+        assert_eq!(option[37], 1);
+        option[37] = 2;
+        assert!(from_bytes::<Sample>(&option).unwrap_err().contains("option tag"));
+
+        let mut flag = to_bytes(&sample());
+        flag[16] = 2;
+        assert!(from_bytes::<Sample>(&flag).unwrap_err().contains("boolean"));
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use log::{info, debug};
+use serde::{Serialize, Deserialize};
+use crate::core::consensus::ConsensusEngine;
+use crate::core::crypto;
+use crate::core::encoding::{Canonical, Decoder, Encoder};
+use crate::core::genesis;
+
+// BFT finality on top of APoC block production.
+//
+// The validators serving a shard vote on the best block at each height in
+// two steps, weighed by the validator set of the height's epoch. A prevote is
+// cast for the validator's best block; once a block gets prevotes from more
+// than 2/3 of the shard's validator weight, validators precommit it.
+// More than 2/3 of the weight in precommits finalizes the block and all of
+// its ancestors, and the shard state refuses to revert past it.
+//
+// There are no voting rounds: a height whose prevotes split simply isn't
+// finalized, and finalizing a later block covers it. Each validator gets one
+// vote of each type per height; a conflicting second vote is rejected and
+// kept, together with the first, as evidence of double-voting.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum VoteType {
+    Prevote,
+    Precommit,
+}
+
+impl VoteType {
+    fn tag(&self) -> u8 {
+        match self {
+            VoteType::Prevote => 0,
+            VoteType::Precommit => 1,
+        }
+    }
+    
+    fn from_tag(tag: u8) -> Result<Self, String> {
+        match tag {
+            0 => Ok(VoteType::Prevote),
+            1 => Ok(VoteType::Precommit),
+            tag => Err(format!("Unknown vote type {}", tag)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Vote {
+    pub vote_type: VoteType,
+    pub shard_id: u16,
+    pub height: u64,
+    pub block_hash: String,
+    pub validator: String,
+    pub signature: String, // Hex ed25519 signature over the encoding of the other fields
+}
+
+impl Vote {
+    pub fn new(vote_type: VoteType, shard_id: u16, height: u64, block_hash: String, validator: String) -> Self {
+        Vote {
+            vote_type,
+            shard_id,
+            height,
+            block_hash,
+            validator,
+            signature: String::new(), // Will be set by the validator
+        }
+    }
+    
+    /// Returns the bytes the validator signs: the canonical encoding of every
+    /// field except the signature.
+    pub fn signing_message(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        self.encode_fields(&mut encoder);
+        encoder.finish()
+    }
+    
+    pub fn sign(&mut self, private_key: &[u8]) -> Result<(), String> {
+        self.signature = crypto::sign(private_key, &self.signing_message())?;
+        Ok(())
+    }
+    
+    pub fn verify_signature(&self, public_key: &[u8]) -> bool {
+        if crypto::public_key_to_address(public_key) != self.validator {
+            return false;
+        }
+        
+        crypto::verify(public_key, &self.signing_message(), &self.signature)
+    }
+    
+    fn encode_fields(&self, encoder: &mut Encoder) {
+        encoder.put_u8(self.vote_type.tag());
+        encoder.put_u16(self.shard_id);
+        encoder.put_u64(self.height);
+        encoder.put_str(&self.block_hash);
+        encoder.put_str(&self.validator);
+    }
+}
+
+impl Canonical for Vote {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.encode_fields(encoder);
+        encoder.put_str(&self.signature);
+    }
+    
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(Vote {
+            vote_type: VoteType::from_tag(decoder.get_u8()?)?,
+            shard_id: decoder.get_u16()?,
+            height: decoder.get_u64()?,
+            block_hash: decoder.get_string()?,
+            validator: decoder.get_string()?,
+            signature: decoder.get_string()?,
+        })
+    }
+}
+
+// Votes collected for one shard above its last finalized block
+#[derive(Debug)]
+pub struct FinalityGadget {
+    shard_id: u16,
+    votes: HashMap<(u64, VoteType), HashMap<String, Vote>>, // (height, type) -> validator -> vote
+    equivocations: Vec<(Vote, Vote)>,                        // Conflicting vote pairs not yet taken
+}
+
+impl FinalityGadget {
+    pub fn new(shard_id: u16) -> Self {
+        FinalityGadget {
+            shard_id,
+            votes: HashMap::new(),
+            equivocations: Vec::new(),
+        }
+    }
+    
+    /// Checks and records a vote. Returns false for a vote that was already
+    /// known or is at or below `finalized_height`.
+    pub fn add_vote(&mut self, vote: Vote, engine: &ConsensusEngine, finalized_height: u64) -> Result<bool, String> {
+        if vote.shard_id != self.shard_id {
+            return Err(format!("Vote for shard {} sent to shard {}", vote.shard_id, self.shard_id));
+        }
+        
+        if vote.height <= finalized_height {
+            return Ok(false);
+        }
+        
+        let validator_set = engine.validator_set_at(vote.height)?;
+        if !validator_set.is_shard_validator(self.shard_id, &vote.validator) {
+            return Err(format!("Vote from {} who is not a validator of shard {}", vote.validator, self.shard_id));
+        }
+        
+        let public_key = validator_set
+            .public_key(&vote.validator)
+            .ok_or_else(|| format!("Validator {} not found", vote.validator))?;
+        if !vote.verify_signature(&public_key) {
+            return Err(format!("Invalid signature on vote from {}", vote.validator));
+        }
+        
+        let votes = self.votes.entry((vote.height, vote.vote_type)).or_default();
+        if let Some(existing) = votes.get(&vote.validator) {
+            if existing.block_hash == vote.block_hash {
+                return Ok(false);
+            }
+            let error = format!("Validator {} already voted for {} at height {}",
+                                vote.validator, existing.block_hash, vote.height);
+            self.equivocations.push((existing.clone(), vote));
+            return Err(error);
+        }
+        
+        debug!("Recorded {:?} from {} for block {} at height {}",
+               vote.vote_type, vote.validator, vote.block_hash, vote.height);
+        votes.insert(vote.validator.clone(), vote);
+        Ok(true)
+    }
+    
+    /// Returns the conflicting vote pairs seen since the last call.
+    pub fn take_equivocations(&mut self) -> Vec<(Vote, Vote)> {
+        std::mem::take(&mut self.equivocations)
+    }
+    
+    pub fn has_voted(&self, height: u64, vote_type: VoteType, validator: &str) -> bool {
+        self.votes
+            .get(&(height, vote_type))
+            .map(|votes| votes.contains_key(validator))
+            .unwrap_or(false)
+    }
+    
+    /// Returns the block that more than 2/3 of the weight of the shard's
+    /// validators voted for at `height`, if any.
+    pub fn quorum(&self, height: u64, vote_type: VoteType, engine: &ConsensusEngine) -> Option<String> {
+        let votes = self.votes.get(&(height, vote_type))?;
+        let validator_set = engine.validator_set_at(height).ok()?;
+        let total_weight = validator_set.total_shard_weight(self.shard_id);
+        
+        let mut weights: HashMap<&str, u128> = HashMap::new();
+        for vote in votes.values() {
+            *weights.entry(vote.block_hash.as_str()).or_default() += validator_set.weight(&vote.validator) as u128;
+        }
+        
+        weights
+            .into_iter()
+            .find(|(_, weight)| *weight * 3 > total_weight * 2)
+            .map(|(hash, _)| hash.to_string())
+    }
+    
+    /// Votes of a type for `block_hash` at `height`, ordered by validator.
+    pub fn votes_for(&self, height: u64, vote_type: VoteType, block_hash: &str) -> Vec<Vote> {
+        let mut votes: Vec<Vote> = self.votes
+            .get(&(height, vote_type))
+            .map(|votes| votes.values().filter(|vote| vote.block_hash == block_hash).cloned().collect())
+            .unwrap_or_default();
+        votes.sort_by(|a, b| a.validator.cmp(&b.validator));
+        votes
+    }
+    
+    /// Heights above `finalized_height` that have votes, lowest first.
+    pub fn pending_heights(&self, finalized_height: u64) -> Vec<u64> {
+        let heights: HashSet<u64> = self.votes
+            .keys()
+            .map(|(height, _)| *height)
+            .filter(|height| *height > finalized_height)
+            .collect();
+        
+        let mut heights: Vec<u64> = heights.into_iter().collect();
+        heights.sort();
+        heights
+    }
+    
+    /// Returns the highest block above `finalized_height` with a precommit
+    /// quorum, as (height, block hash).
+    pub fn finalizable(&self, engine: &ConsensusEngine, finalized_height: u64) -> Option<(u64, String)> {
+        self.pending_heights(finalized_height)
+            .into_iter()
+            .rev()
+            .find_map(|height| self.quorum(height, VoteType::Precommit, engine).map(|hash| (height, hash)))
+    }
+    
+    /// Drops the votes at or below a newly finalized height.
+    pub fn prune(&mut self, finalized_height: u64) {
+        self.votes.retain(|(height, _), _| *height > finalized_height);
+    }
+}
+
+// Global finality gadget instances (one per shard)
+lazy_static::lazy_static! {
+    static ref FINALITY_GADGETS: Arc<RwLock<HashMap<u16, Arc<Mutex<FinalityGadget>>>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+pub fn initialize() -> Result<(), Box<dyn std::error::Error>> {
+    info!("Initializing finality gadget...");
+    
+    // Finality gadget for every shard of the genesis
+    for shard_id in 0..genesis::get().shard_count {
+        create_gadget(shard_id)?;
+    }
+    
+    info!("Finality gadget initialized successfully");
+    Ok(())
+}
+
+pub fn shutdown() -> Result<(), Box<dyn std::error::Error>> {
+    info!("Shutting down finality gadget...");
+    
+    let mut gadgets = FINALITY_GADGETS.write().unwrap();
+    gadgets.clear();
+    
+    info!("Finality gadget shutdown complete");
+    Ok(())
+}
+
+pub fn get_gadget(shard_id: u16) -> Option<Arc<Mutex<FinalityGadget>>> {
+    let gadgets = FINALITY_GADGETS.read().unwrap();
+    gadgets.get(&shard_id).cloned()
+}
+
+pub fn create_gadget(shard_id: u16) -> Result<(), Box<dyn std::error::Error>> {
+    let mut gadgets = FINALITY_GADGETS.write().unwrap();
+    
+    if gadgets.contains_key(&shard_id) {
+        return Err(format!("Finality gadget for shard {} already exists", shard_id).into());
+    }
+    
+    gadgets.insert(shard_id, Arc::new(Mutex::new(FinalityGadget::new(shard_id))));
+    Ok(())
+}
@@ -0,0 +1,239 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::RwLock;
+use log::info;
+use serde::{Serialize, Deserialize};
+use crate::core::crypto;
+use crate::core::encoding;
+use crate::core::params::{self, ChainParams, BPS_SCALE};
+use crate::core::shard_map::ShardMap;
+
+// The genesis file: everything a chain starts from. It names the chain, sets
+// the chain parameters, the number of shards and how accounts map to them,
+// funds the initial accounts and bonds the stake of the initial validators. It is written in TOML or, if
+// the file name ends in `.json`, in JSON.
+//
+// Nodes only talk to peers with the same genesis hash, and a database created
+// from one genesis can't be opened with another.
+
+// Tokens owned by a key at genesis, paid out as a single UTXO in the shard
+// that owns its address
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenesisAccount {
+    pub public_key: String, // Hex ed25519 public key; the account address derives from it
+    pub balance: u64,
+}
+
+impl GenesisAccount {
+    pub fn address(&self) -> Result<String, String> {
+        address_of(&self.public_key)
+    }
+}
+
+// A validator of the first epoch. Its stake is bonded to itself in the
+// genesis shard's state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenesisValidator {
+    pub public_key: String, // Hex ed25519 public key it signs blocks with
+    pub stake: u64,
+    #[serde(default)]
+    pub computation_power: u32,
+}
+
+impl GenesisValidator {
+    pub fn address(&self) -> Result<String, String> {
+        address_of(&self.public_key)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Genesis {
+    pub chain_id: String,
+    #[serde(default)]
+    pub timestamp: u64, // Creation time given to the genesis state
+    #[serde(default = "default_shard_count")]
+    pub shard_count: u16,
+    #[serde(default)]
+    pub shard_map: Option<ShardMap>, // First version of the shard map; equal ranges if absent
+    #[serde(default)]
+    pub params: ChainParams,
+    #[serde(default)]
+    pub accounts: Vec<GenesisAccount>,
+    #[serde(default)]
+    pub validators: Vec<GenesisValidator>,
+}
+
+fn default_shard_count() -> u16 {
+    1
+}
+
+impl Default for Genesis {
+    // An empty single-shard development chain
+    fn default() -> Self {
+        Genesis {
+            chain_id: "nexacore-dev".to_string(),
+            timestamp: 0,
+            shard_count: default_shard_count(),
+            shard_map: None,
+            params: ChainParams::default(),
+            accounts: Vec::new(),
+            validators: Vec::new(),
+        }
+    }
+}
+
+impl Genesis {
+    /// Reads and checks a genesis file.
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read genesis file {}: {}", path.display(), e))?;
+
+        let genesis: Genesis = if path.extension().is_some_and(|extension| extension == "json") {
+            serde_json::from_str(&contents).map_err(|e| format!("Invalid genesis file {}: {}", path.display(), e))?
+        } else {
+            toml::from_str(&contents).map_err(|e| format!("Invalid genesis file {}: {}", path.display(), e))?
+        };
+
+        genesis.validate()?;
+        Ok(genesis)
+    }
+
+    /// Checks that the genesis describes a chain that can start.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.chain_id.is_empty() {
+            return Err("Genesis has no chain id".to_string());
+        }
+
+        if self.shard_count == 0 {
+            return Err("Genesis must have at least one shard".to_string());
+        }
+
+        if self.params.epoch_length == 0 || self.params.target_block_time == 0 || self.params.cross_shard.receipt_timeout == 0 {
+            return Err("Epoch length, target block time and receipt timeout must be positive".to_string());
+        }
+
+        if self.params.max_validators == 0 {
+            return Err("The active validator set must hold at least one validator".to_string());
+        }
+
+        if self.params.min_committee_size == 0 {
+            return Err("Shard committees must hold at least one validator".to_string());
+        }
+
+        // Fractions in basis points can't exceed the whole
+        let params = &self.params;
+        let fractions = [
+            ("weights.stake_bps", params.weights.stake_bps),
+            ("weights.computation_bps", params.weights.computation_bps),
+            ("weights.contribution_bps", params.weights.contribution_bps),
+            ("slashing.double_sign_stake_bps", params.slashing.double_sign_stake_bps),
+            ("slashing.double_sign_contribution_bps", params.slashing.double_sign_contribution_bps),
+            ("slashing.downtime_stake_bps", params.slashing.downtime_stake_bps),
+            ("slashing.downtime_contribution_bps", params.slashing.downtime_contribution_bps),
+            ("rewards.initial_inflation_bps", params.rewards.initial_inflation_bps),
+            ("rewards.inflation_decay_bps", params.rewards.inflation_decay_bps),
+            ("rewards.min_inflation_bps", params.rewards.min_inflation_bps),
+            ("rewards.proposer_bps", params.rewards.proposer_bps),
+            ("rewards.treasury_bps", params.rewards.treasury_bps),
+            ("staking.commission_bps", params.staking.commission_bps),
+            ("contribution.decay_bps", params.contribution.decay_bps),
+        ];
+        if let Some((name, value)) = fractions.iter().find(|(_, value)| *value > BPS_SCALE) {
+            return Err(format!("Parameter {} is {} basis points, above {}", name, value, BPS_SCALE));
+        }
+
+        if params.rewards.proposer_bps + params.rewards.treasury_bps > BPS_SCALE {
+            return Err(format!("Proposer and treasury shares add up to {} basis points, above {}",
+                               params.rewards.proposer_bps + params.rewards.treasury_bps, BPS_SCALE));
+        }
+
+        let slots = &self.params.slots;
+        if slots.fallback_proposers as u64 * slots.proposer_timeout >= self.params.target_block_time {
+            return Err(format!("{} fallback proposers with a {} second timeout don't fit in a {} second slot",
+                               slots.fallback_proposers, slots.proposer_timeout, self.params.target_block_time));
+        }
+
+        let shard_map = self.shard_map();
+        shard_map.validate(self.shard_count)?;
+        if shard_map.version != 1 {
+            return Err(format!("Genesis shard map has version {}, not 1", shard_map.version));
+        }
+
+        let mapped = shard_map.shard_ids();
+        if let Some(shard_id) = (0..self.shard_count).find(|shard_id| !mapped.contains(shard_id)) {
+            return Err(format!("Genesis shard map gives shard {} no addresses", shard_id));
+        }
+
+        let mut addresses = HashSet::new();
+        for account in &self.accounts {
+            let address = account.address()?;
+            if !addresses.insert(address.clone()) {
+                return Err(format!("Genesis account {} is listed twice", address));
+            }
+        }
+
+        let mut validators = HashSet::new();
+        for validator in &self.validators {
+            let address = validator.address()?;
+            if !validators.insert(address.clone()) {
+                return Err(format!("Genesis validator {} is listed twice", address));
+            }
+
+            if validator.stake < self.params.min_stake {
+                return Err(format!("Genesis validator {} stakes {}, below the minimum {}",
+                                   address, validator.stake, self.params.min_stake));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The shard map the chain starts with.
+    pub fn shard_map(&self) -> ShardMap {
+        self.shard_map.clone().unwrap_or_else(|| ShardMap::uniform(self.shard_count))
+    }
+
+    /// SHA-256 of the genesis in compact JSON, hex-encoded. A TOML file and a
+    /// JSON file describing the same genesis have the same hash.
+    pub fn hash(&self) -> String {
+        // Plain structs always serialize
+        let json = serde_json::to_vec(self).expect("Genesis serializes to JSON");
+        encoding::hash_bytes(&json)
+    }
+
+    /// Hash of the pseudo-transaction whose output 0 funds a genesis account.
+    pub fn funding_hash(&self, address: &str) -> String {
+        encoding::hash_bytes(format!("genesis/{}/{}", self.chain_id, address).as_bytes())
+    }
+}
+
+fn address_of(public_key: &str) -> Result<String, String> {
+    let key = crypto::verifying_key_from_hex(public_key)?;
+    Ok(crypto::public_key_to_address(key.as_bytes()))
+}
+
+lazy_static::lazy_static! {
+    static ref GENESIS: RwLock<Genesis> = RwLock::new(Genesis::default());
+}
+
+pub fn get() -> Genesis {
+    GENESIS.read().unwrap().clone()
+}
+
+/// Loads the genesis file and takes over its chain parameters. Must happen
+/// before the core components are initialized.
+pub fn load(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let genesis = Genesis::from_file(path)?;
+
+    info!("Loaded genesis of chain {} ({} shards, {} accounts, {} validators), hash {}",
+          genesis.chain_id, genesis.shard_count, genesis.accounts.len(), genesis.validators.len(), genesis.hash());
+
+    set(genesis);
+    Ok(())
+}
+
+/// Replaces the genesis and the chain parameters with its own.
+pub fn set(genesis: Genesis) {
+    params::set(genesis.params.clone());
+    *GENESIS.write().unwrap() = genesis;
+}
@@ -0,0 +1,594 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{info, debug};
+use crate::core::block::Block;
+use crate::core::encoding;
+use crate::core::genesis;
+use crate::core::migration::MigrationAction;
+use crate::core::state::{self, StateManager};
+use crate::core::transaction::{Transaction, TransactionType};
+
+// Fee rates are fee units per 1000 bytes of canonical encoding
+const FEE_RATE_SCALE: u64 = 1000;
+
+#[derive(Debug, Clone)]
+pub struct MempoolConfig {
+    pub max_count: usize,          // Maximum number of pooled transactions
+    pub max_bytes: usize,          // Maximum total encoded size of pooled transactions
+    pub expiry_secs: u64,          // Transactions older than this are dropped
+    pub min_fee_rate: u64,         // Minimum fee rate for admission
+    pub min_replacement_fee_rate: u64, // Extra fee rate a replacement must pay for its own size
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        MempoolConfig {
+            max_count: 10_000,
+            max_bytes: 32 * 1024 * 1024,
+            expiry_secs: 3 * 60 * 60,
+            min_fee_rate: 1,
+            min_replacement_fee_rate: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MempoolEntry {
+    tx: Transaction,
+    fee: u64,
+    size: usize,
+    fee_rate: u64,
+    added_at: u64,
+}
+
+// Pending transactions of one shard, waiting to be included in a block.
+//
+// Only transactions spending confirmed outputs are accepted; chains of
+// unconfirmed transactions aren't tracked. Two pooled transactions never
+// spend the same output: a conflicting transaction is either rejected or,
+// if it pays enough more, replaces the transactions it conflicts with.
+#[derive(Debug)]
+pub struct Mempool {
+    shard_id: u16,
+    config: MempoolConfig,
+    entries: HashMap<String, MempoolEntry>, // Key: tx hash
+    spends: HashMap<String, String>,        // Spent output (tx_hash:index) -> spending tx hash
+    total_bytes: usize,
+    unannounced: Vec<String>,               // Transactions queued for gossip
+}
+
+impl Mempool {
+    pub fn new(shard_id: u16, config: MempoolConfig) -> Self {
+        Mempool {
+            shard_id,
+            config,
+            entries: HashMap::new(),
+            spends: HashMap::new(),
+            total_bytes: 0,
+            unannounced: Vec::new(),
+        }
+    }
+
+    /// Validates `tx` against `state` and adds it to the pool. Returns the
+    /// hashes of the transactions it replaced.
+    pub fn add(&mut self, tx: Transaction, state: &StateManager, now: u64) -> Result<Vec<String>, String> {
+        if tx.shard_id != self.shard_id {
+            return Err(format!("Transaction {} belongs to shard {}, not {}", tx.hash, tx.shard_id, self.shard_id));
+        }
+
+        if self.entries.contains_key(&tx.hash) {
+            return Err(format!("Transaction {} is already in the mempool", tx.hash));
+        }
+
+        // Beacon block proposers add crosslinks themselves, and every block
+        // proposer the switches and imports of shard migrations
+        if matches!(tx.tx_type, TransactionType::Crosslink) {
+            return Err(format!("Crosslink {} is only added by block proposers", tx.hash));
+        }
+        if matches!(MigrationAction::from_transaction(&tx), Ok(MigrationAction::Switch { .. } | MigrationAction::Import { .. })) {
+            return Err(format!("Shard migration {} is only added by block proposers", tx.hash));
+        }
+
+        state.validate_transaction(&tx)?;
+
+        let fee = tx.fee().ok_or_else(|| format!("Transaction {} amounts overflow", tx.hash))?;
+        let size = encoding::to_bytes(&tx).len();
+        let fee_rate = fee.saturating_mul(FEE_RATE_SCALE) / size as u64;
+
+        // Slashing evidence, cross-shard receipt claims and refunds and
+        // migration approvals need no fee: they are cheap to verify and each
+        // can only be included once
+        let is_exempt = matches!(tx.tx_type, TransactionType::SlashingEvidence | TransactionType::ShardMigration) ||
+            (matches!(tx.tx_type, TransactionType::ShardCrossing) && tx.inputs.is_empty());
+        if fee_rate < self.config.min_fee_rate && !is_exempt {
+            return Err(format!("Transaction {} fee rate {} is below the minimum {}", tx.hash, fee_rate, self.config.min_fee_rate));
+        }
+
+        // Replace-by-fee: the new transaction must pay more than everything it
+        // evicts, plus relay of its own size, at a higher fee rate than each
+        let conflicts: HashSet<String> = tx.inputs
+            .iter()
+            .filter_map(|input| self.spends.get(&outpoint(&input.previous_tx, input.index)).cloned())
+            .collect();
+
+        if !conflicts.is_empty() {
+            let conflict_fee: u64 = conflicts.iter().map(|hash| self.entries[hash].fee).sum();
+            let required_fee = conflict_fee + (self.config.min_replacement_fee_rate * size as u64).div_ceil(FEE_RATE_SCALE);
+
+            if fee <= required_fee {
+                return Err(format!("Transaction {} conflicts with pooled transactions and pays {} (needs more than {})",
+                                  tx.hash, fee, required_fee));
+            }
+
+            if conflicts.iter().any(|hash| self.entries[hash].fee_rate >= fee_rate) {
+                return Err(format!("Transaction {} does not pay a higher fee rate than the transactions it replaces", tx.hash));
+            }
+        }
+
+        let replaced: Vec<MempoolEntry> = conflicts
+            .iter()
+            .filter_map(|hash| self.remove_entry(hash))
+            .collect();
+
+        let hash = tx.hash.clone();
+        self.insert(MempoolEntry {
+            tx,
+            fee,
+            size,
+            fee_rate,
+            added_at: now,
+        });
+
+        self.evict_to_limits();
+        if !self.entries.contains_key(&hash) {
+            // Put back what the rejected transaction would have replaced
+            for entry in replaced {
+                self.insert(entry);
+            }
+            self.evict_to_limits();
+            return Err(format!("Mempool is full and transaction {} pays too low a fee rate", hash));
+        }
+
+        for entry in &replaced {
+            debug!("Transaction {} replaced by {}", entry.tx.hash, hash);
+        }
+
+        debug!("Added transaction {} to the shard {} mempool", hash, self.shard_id);
+        Ok(replaced.into_iter().map(|entry| entry.tx.hash).collect())
+    }
+
+    fn insert(&mut self, entry: MempoolEntry) {
+        for input in &entry.tx.inputs {
+            self.spends.insert(outpoint(&input.previous_tx, input.index), entry.tx.hash.clone());
+        }
+
+        self.total_bytes += entry.size;
+        self.entries.insert(entry.tx.hash.clone(), entry);
+    }
+
+    pub fn remove(&mut self, tx_hash: &str) -> Option<Transaction> {
+        self.remove_entry(tx_hash).map(|entry| entry.tx)
+    }
+
+    fn remove_entry(&mut self, tx_hash: &str) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(tx_hash)?;
+
+        for input in &entry.tx.inputs {
+            self.spends.remove(&outpoint(&input.previous_tx, input.index));
+        }
+
+        self.unannounced.retain(|hash| hash != tx_hash);
+        self.total_bytes -= entry.size;
+        Some(entry)
+    }
+
+    // Drops the lowest fee rate transactions until the pool is within its limits
+    fn evict_to_limits(&mut self) {
+        while self.entries.len() > self.config.max_count || self.total_bytes > self.config.max_bytes {
+            let lowest = self.entries
+                .values()
+                .min_by(|a, b| a.fee_rate.cmp(&b.fee_rate).then(b.added_at.cmp(&a.added_at)))
+                .map(|entry| entry.tx.hash.clone());
+
+            match lowest {
+                Some(hash) => {
+                    debug!("Evicting transaction {} from the full mempool", hash);
+                    self.remove(&hash);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Drops transactions that have been pooled for longer than the expiry time.
+    pub fn expire(&mut self, now: u64) -> usize {
+        let expired: Vec<String> = self.entries
+            .values()
+            .filter(|entry| now.saturating_sub(entry.added_at) > self.config.expiry_secs)
+            .map(|entry| entry.tx.hash.clone())
+            .collect();
+
+        for hash in &expired {
+            self.remove(hash);
+        }
+
+        expired.len()
+    }
+
+    /// Removes the transactions included in `block` and those that spend the
+    /// same outputs.
+    pub fn remove_for_block(&mut self, block: &Block) {
+        for tx in &block.transactions {
+            self.remove(&tx.hash);
+
+            for input in &tx.inputs {
+                if let Some(hash) = self.spends.get(&outpoint(&input.previous_tx, input.index)).cloned() {
+                    self.remove(&hash);
+                }
+            }
+        }
+    }
+
+    /// Drops transactions that are no longer valid against `state`, e.g. after
+    /// a reorganization.
+    pub fn revalidate(&mut self, state: &StateManager) {
+        let invalid: Vec<String> = self.entries
+            .values()
+            .filter(|entry| state.validate_transaction(&entry.tx).is_err())
+            .map(|entry| entry.tx.hash.clone())
+            .collect();
+
+        for hash in &invalid {
+            self.remove(hash);
+        }
+    }
+
+    /// Returns transactions for a new block, highest fee rate first, within
+    /// the given count and size limits.
+    pub fn select(&self, max_count: usize, max_bytes: usize) -> Vec<Transaction> {
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by(|a, b| {
+            b.fee_rate
+                .cmp(&a.fee_rate)
+                .then(a.added_at.cmp(&b.added_at))
+                .then(a.tx.hash.cmp(&b.tx.hash))
+        });
+
+        let mut selected = Vec::new();
+        let mut bytes = 0;
+        for entry in entries {
+            if selected.len() >= max_count {
+                break;
+            }
+            if bytes + entry.size > max_bytes {
+                continue;
+            }
+
+            bytes += entry.size;
+            selected.push(entry.tx.clone());
+        }
+
+        selected
+    }
+
+    /// Queues a pooled transaction to be gossiped to peers.
+    pub fn announce(&mut self, tx_hash: &str) {
+        if self.entries.contains_key(tx_hash) {
+            self.unannounced.push(tx_hash.to_string());
+        }
+    }
+
+    /// Returns queued transactions that haven't been gossiped yet.
+    pub fn take_unannounced(&mut self) -> Vec<Transaction> {
+        std::mem::take(&mut self.unannounced)
+            .into_iter()
+            .filter_map(|hash| self.entries.get(&hash).map(|entry| entry.tx.clone()))
+            .collect()
+    }
+
+    pub fn get_transaction(&self, tx_hash: &str) -> Option<Transaction> {
+        self.entries.get(tx_hash).map(|entry| entry.tx.clone())
+    }
+
+    pub fn contains(&self, tx_hash: &str) -> bool {
+        self.entries.contains_key(tx_hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        self.total_bytes
+    }
+}
+
+fn outpoint(tx_hash: &str, index: u32) -> String {
+    format!("{}:{}", tx_hash, index)
+}
+
+// Global mempool instances (one per shard)
+lazy_static::lazy_static! {
+    static ref MEMPOOLS: Arc<RwLock<HashMap<u16, Arc<Mutex<Mempool>>>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+pub fn initialize() -> Result<(), Box<dyn std::error::Error>> {
+    info!("Initializing mempool...");
+
+    // Mempool for every shard of the genesis
+    for shard_id in 0..genesis::get().shard_count {
+        create_mempool(shard_id)?;
+    }
+
+    info!("Mempool initialized successfully");
+    Ok(())
+}
+
+pub fn shutdown() -> Result<(), Box<dyn std::error::Error>> {
+    info!("Shutting down mempool...");
+
+    let mut mempools = MEMPOOLS.write().unwrap();
+    mempools.clear();
+
+    info!("Mempool shutdown complete");
+    Ok(())
+}
+
+pub fn get_mempool(shard_id: u16) -> Option<Arc<Mutex<Mempool>>> {
+    let mempools = MEMPOOLS.read().unwrap();
+    mempools.get(&shard_id).cloned()
+}
+
+pub fn create_mempool(shard_id: u16) -> Result<(), Box<dyn std::error::Error>> {
+    let mut mempools = MEMPOOLS.write().unwrap();
+
+    if mempools.contains_key(&shard_id) {
+        return Err(format!("Mempool for shard {} already exists", shard_id).into());
+    }
+
+    mempools.insert(shard_id, Arc::new(Mutex::new(Mempool::new(shard_id, MempoolConfig::default()))));
+    Ok(())
+}
+
+/// Admits a transaction into its shard's mempool after validating it against
+/// the shard state. Transactions submitted locally are queued for gossip;
+/// those received from peers have already been flooded to the network.
+pub fn submit_transaction(tx: Transaction, announce: bool) -> Result<String, String> {
+    let state_manager = state::get_state_manager(tx.shard_id)
+        .ok_or_else(|| format!("Shard {} is not served by this node", tx.shard_id))?;
+    let mempool = get_mempool(tx.shard_id)
+        .ok_or_else(|| format!("No mempool for shard {}", tx.shard_id))?;
+
+    let now = unix_time();
+    let hash = tx.hash.clone();
+
+    // Claims and refunds are checked against the latest finalized beacon
+    // block, whose state is locked before this shard's
+    state::check_anchors(tx.shard_id, state::finalized_beacon_height(), std::slice::from_ref(&tx))?;
+
+    // Lock order: state manager before mempool
+    let state_manager = state_manager.lock().unwrap();
+    let mut mempool = mempool.lock().unwrap();
+    mempool.expire(now);
+    mempool.add(tx, &state_manager, now)?;
+
+    if announce {
+        mempool.announce(&hash);
+    }
+
+    Ok(hash)
+}
+
+/// Drops the transactions that have been pooled for too long from a shard's
+/// mempool. Block producers call it before every proposal, so stale
+/// transactions go even when nothing new is submitted.
+pub fn expire_transactions(shard_id: u16) -> usize {
+    match get_mempool(shard_id) {
+        Some(mempool) => mempool.lock().unwrap().expire(unix_time()),
+        None => 0,
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::crypto;
+    use crate::core::genesis::{Genesis, GenesisAccount};
+    use crate::core::params;
+    use crate::core::rewards;
+    use crate::core::transaction::{TransactionInput, TransactionOutput};
+
+    const KEYS: [[u8; 32]; 3] = [[1; 32], [2; 32], [3; 32]];
+    const FUNDS: u64 = 100_000;
+    const VALIDATOR: &str = "0x00000000000000000000000000000000000000aa";
+
+    // Hex public key and address of a test key
+    fn identity(private_key: &[u8; 32]) -> (String, String) {
+        let public_key = crypto::signing_key_from_bytes(private_key).unwrap().verifying_key().to_bytes();
+        (hex::encode(public_key), crypto::public_key_to_address(&public_key))
+    }
+
+    // Genesis funding every test key with FUNDS
+    fn genesis() -> Genesis {
+        Genesis {
+            accounts: KEYS
+                .iter()
+                .map(|key| GenesisAccount {
+                    public_key: identity(key).0,
+                    balance: FUNDS,
+                })
+                .collect(),
+            ..Genesis::default()
+        }
+    }
+
+    fn funded_state() -> StateManager {
+        let mut state = StateManager::new(0);
+        state.apply_genesis(&genesis()).unwrap();
+        state
+    }
+
+    // Transfer of the genesis funds of `key` back to itself, leaving `fee`
+    fn transfer(key: &[u8; 32], fee: u64) -> Transaction {
+        let (public_key, address) = identity(key);
+        let input = TransactionInput {
+            previous_tx: genesis().funding_hash(&address),
+            index: 0,
+            script_sig: String::new(),
+            amount: FUNDS,
+        };
+        let output = TransactionOutput {
+            address,
+            amount: FUNDS - fee,
+            script_pubkey: public_key,
+        };
+
+        let mut tx = Transaction::new(TransactionType::Transfer, vec![input], vec![output], 0, Vec::new(), 0);
+        tx.sign(&[key]).unwrap();
+        tx
+    }
+
+    // Imports a block with `transactions` on top of the best block of `state`
+    fn extend(state: &mut StateManager, transactions: Vec<Transaction>, weight: u64) -> Block {
+        let height = state.get_current_height() + 1;
+        let fees: u64 = transactions.iter().map(|tx| tx.fee().unwrap()).sum();
+        let reward = TransactionOutput {
+            address: VALIDATOR.to_string(),
+            amount: rewards::block_reward(&params::get(), height) + fees,
+            script_pubkey: String::new(),
+        };
+
+        let mut all = vec![rewards::coinbase(0, height, vec![reward])];
+        all.extend(transactions);
+        let mut block = Block::new(state.get_best_block_hash(), height, all, 0, VALIDATOR.to_string(), 0);
+
+        let mut scratch = state.scratch_copy();
+        scratch.apply_coinbase(&block.transactions, height).unwrap();
+        for tx in &block.transactions[1..] {
+            scratch.apply_transaction(tx).unwrap();
+        }
+        scratch.finish_block(&block.header);
+        block.set_state_root(scratch.state_root());
+
+        state.import_block(&block, weight).unwrap();
+        block
+    }
+
+    #[test]
+    fn replacement_must_pay_more() {
+        let state = funded_state();
+        let mut mempool = Mempool::new(0, MempoolConfig::default());
+
+        let original = transfer(&KEYS[0], 100);
+        assert_eq!(mempool.add(original.clone(), &state, 0).unwrap(), Vec::<String>::new());
+
+        // Same fee, and a fee that doesn't also pay for the replacement's size
+        assert!(mempool.add(transfer(&KEYS[0], 100), &state, 0).is_err());
+        assert!(mempool.add(transfer(&KEYS[0], 101), &state, 0).is_err());
+        assert!(mempool.contains(&original.hash));
+
+        let replacement = transfer(&KEYS[0], 1000);
+        assert_eq!(mempool.add(replacement.clone(), &state, 0).unwrap(), vec![original.hash.clone()]);
+        assert!(!mempool.contains(&original.hash));
+        assert!(mempool.contains(&replacement.hash));
+        assert_eq!(mempool.len(), 1);
+
+        // The replaced transaction can't come back
+        assert!(mempool.add(original, &state, 0).is_err());
+    }
+
+    #[test]
+    fn full_pool_evicts_lowest_fee_rate() {
+        let state = funded_state();
+        let config = MempoolConfig {
+            max_count: 2,
+            ..MempoolConfig::default()
+        };
+        let mut mempool = Mempool::new(0, config);
+
+        let low = transfer(&KEYS[0], 100);
+        let high = transfer(&KEYS[1], 1000);
+        mempool.add(low.clone(), &state, 0).unwrap();
+        mempool.add(high.clone(), &state, 0).unwrap();
+
+        // Paying less than everything pooled, it's turned away
+        assert!(mempool.add(transfer(&KEYS[2], 50), &state, 0).is_err());
+        assert!(mempool.contains(&low.hash) && mempool.contains(&high.hash));
+
+        let middle = transfer(&KEYS[2], 500);
+        mempool.add(middle.clone(), &state, 0).unwrap();
+        assert_eq!(mempool.len(), 2);
+        assert!(!mempool.contains(&low.hash));
+        let selected: Vec<String> = mempool.select(10, usize::MAX).into_iter().map(|tx| tx.hash).collect();
+        assert_eq!(selected, vec![high.hash, middle.hash]);
+    }
+
+    #[test]
+    fn old_transactions_expire() {
+        let state = funded_state();
+        let config = MempoolConfig {
+            expiry_secs: 60,
+            ..MempoolConfig::default()
+        };
+        let mut mempool = Mempool::new(0, config);
+
+        let old = transfer(&KEYS[0], 100);
+        let recent = transfer(&KEYS[1], 100);
+        mempool.add(old.clone(), &state, 1000).unwrap();
+        mempool.add(recent.clone(), &state, 1050).unwrap();
+
+        assert_eq!(mempool.expire(1060), 0);
+        assert_eq!(mempool.expire(1061), 1);
+        assert!(!mempool.contains(&old.hash));
+        assert!(mempool.contains(&recent.hash));
+
+        // Its inputs are free to spend again
+        mempool.add(transfer(&KEYS[0], 100), &state, 1061).unwrap();
+        assert_eq!(mempool.expire(2000), 2);
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.size_bytes(), 0);
+    }
+
+    #[test]
+    fn reorganization_drops_invalidated_transactions() {
+        let mut state = funded_state();
+        let first = extend(&mut state, Vec::new(), 1);
+        let mut fork = state.state_at(&first.hash).unwrap();
+        extend(&mut state, Vec::new(), 1);
+
+        let mut mempool = Mempool::new(0, MempoolConfig::default());
+        let pooled = transfer(&KEYS[0], 100);
+        let untouched = transfer(&KEYS[1], 100);
+        mempool.add(pooled.clone(), &state, 0).unwrap();
+        mempool.add(untouched.clone(), &state, 0).unwrap();
+
+        // The heavier branch spends the same output in another transaction
+        let conflicting = transfer(&KEYS[0], 300);
+        let branch = extend(&mut fork, vec![conflicting], 5);
+        assert!(matches!(state.import_block(&branch, 5).unwrap(), state::ImportResult::Reorganized { .. }));
+
+        mempool.revalidate(&state);
+        assert!(!mempool.contains(&pooled.hash));
+        assert!(mempool.contains(&untouched.hash));
+        assert_eq!(mempool.len(), 1);
+
+        // Reverting the branch makes the output spendable again
+        state.revert_block().unwrap();
+        mempool.revalidate(&state);
+        mempool.add(pooled, &state, 0).unwrap();
+        assert_eq!(mempool.len(), 2);
+    }
+}
@@ -0,0 +1,154 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use crate::core::encoding::{Canonical, Decoder, Encoder};
+
+// Binary Merkle tree over transaction hashes.
+//
+// Leaves and inner nodes are hashed with different prefixes so a leaf can
+// never be passed off as an inner node (second-preimage protection):
+//
+//   leaf  = SHA-256(0x00 || tx_hash)
+//   inner = SHA-256(0x01 || left || right)
+//
+// When a level has an odd number of nodes the last one is promoted to the
+// next level unchanged instead of being paired with a copy of itself, so two
+// different transaction lists can't produce the same root. Transaction
+// hashes are hashed as their hex strings, and the root of an empty tree is
+// 32 zero bytes.
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+pub type Hash = [u8; 32];
+
+pub fn hash_leaf(data: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+pub fn hash_node(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+fn next_level(level: &[Hash]) -> Vec<Hash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_node(left, right),
+            [single] => *single, // Odd node is promoted
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Computes the hex-encoded root over the given leaf data.
+pub fn compute_root<T: AsRef<[u8]>>(leaves: &[T]) -> String {
+    if leaves.is_empty() {
+        return hex::encode([0u8; 32]);
+    }
+
+    let mut level: Vec<Hash> = leaves.iter().map(|leaf| hash_leaf(leaf.as_ref())).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+
+    hex::encode(level[0])
+}
+
+// Inclusion proof for a single leaf. Siblings are listed from the leaf level
+// upwards; levels where the node was promoted contribute no sibling.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub leaf_index: u32,
+    pub leaf_count: u32,
+    pub siblings: Vec<String>,
+}
+
+impl MerkleProof {
+    pub fn generate<T: AsRef<[u8]>>(leaves: &[T], leaf_index: usize) -> Option<Self> {
+        if leaf_index >= leaves.len() {
+            return None;
+        }
+
+        let mut siblings = Vec::new();
+        let mut level: Vec<Hash> = leaves.iter().map(|leaf| hash_leaf(leaf.as_ref())).collect();
+        let mut index = leaf_index;
+
+        while level.len() > 1 {
+            let sibling = index ^ 1;
+            if sibling < level.len() {
+                siblings.push(hex::encode(level[sibling]));
+            }
+
+            level = next_level(&level);
+            index /= 2;
+        }
+
+        Some(MerkleProof {
+            leaf_index: leaf_index as u32,
+            leaf_count: leaves.len() as u32,
+            siblings,
+        })
+    }
+
+    /// Checks that `leaf` is included at `leaf_index` under the hex-encoded `root`.
+    pub fn verify(&self, root: &str, leaf: &[u8]) -> bool {
+        if self.leaf_index >= self.leaf_count {
+            return false;
+        }
+
+        let mut hash = hash_leaf(leaf);
+        let mut index = self.leaf_index as u64;
+        let mut size = self.leaf_count as u64;
+        let mut siblings = self.siblings.iter();
+
+        while size > 1 {
+            let is_right = index % 2 == 1;
+            let has_sibling = is_right || index + 1 < size;
+
+            if has_sibling {
+                let sibling = match siblings.next().and_then(|s| decode_hash(s)) {
+                    Some(sibling) => sibling,
+                    None => return false,
+                };
+
+                hash = if is_right {
+                    hash_node(&sibling, &hash)
+                } else {
+                    hash_node(&hash, &sibling)
+                };
+            }
+
+            index /= 2;
+            size = (size + 1) / 2;
+        }
+
+        siblings.next().is_none() && hex::encode(hash) == root
+    }
+}
+
+impl Canonical for MerkleProof {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_u32(self.leaf_index);
+        encoder.put_u32(self.leaf_count);
+        encoder.put_seq(&self.siblings);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(MerkleProof {
+            leaf_index: decoder.get_u32()?,
+            leaf_count: decoder.get_u32()?,
+            siblings: decoder.get_seq()?,
+        })
+    }
+}
+
+fn decode_hash(value: &str) -> Option<Hash> {
+    hex::decode(value).ok()?.try_into().ok()
+}
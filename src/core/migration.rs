@@ -0,0 +1,293 @@
+use serde::{Serialize, Deserialize};
+use crate::core::beacon::BEACON_SHARD;
+use crate::core::block::BlockHeader;
+use crate::core::contribution::Attestation;
+use crate::core::crypto;
+use crate::core::cross_shard::ReceiptProof;
+use crate::core::encoding::{self, Canonical, Decoder, Encoder};
+use crate::core::shard_map::ShardMap;
+use crate::core::state::{Account, UTXO};
+use crate::core::transaction::{Transaction, TransactionType};
+
+// Shard splits and merges.
+//
+// A migration is decided on the beacon chain. Active validators approve it
+// with `Approve` actions in beacon blocks, at most once per epoch each, and
+// the last beacon block of the epoch adopts the proposal approved by more
+// than 2/3 of the weight of the epoch's active set: it applies it to the shard
+// map of the next epoch's set and records the new map with the set of the
+// epoch after, whose committees are drawn over the new shards. That is the
+// activation epoch. No migration is approved while another is under way.
+//
+// Every shard then takes the new map over in its blocks, so the moved state is
+// committed by their state roots:
+//
+// 1. Switch, in the last block before the activation epoch of every shard of
+//    the old map: records the new map and hands over what leaves the shard,
+//    which every validator of the shard recomputes: the accounts and UTXOs of
+//    the addresses it no longer owns, and the cross-shard claim records of
+//    those recipients. A merged shard also hands over the cross-shard account
+//    as a whole, with the amounts it locked, and adds no blocks afterwards.
+// 2. Import, in the first block of the activation epoch of every shard that
+//    takes over addresses, once per source shard: carries the source's
+//    switch, proven against an anchored header like a receipt, and adds the
+//    part of the handover it now owns. A shard created by a split starts
+//    from the genesis state and the new map, at the height before the
+//    activation epoch.
+//
+// Consensus checks that each block carries exactly the switch and imports the
+// validator sets call for. A shard accepts no claims while imports are
+// pending, and a refund needs a target header from between the activation of
+// the refunding shard's map and its own height, so a claim recorded before a
+// migration is always seen wherever its recipient went.
+//
+// The beacon shard keeps the stake, contribution scores and jailing of
+// validators in their accounts, so it never hands addresses over: it can't be
+// split or merged into another shard, only take over a merged shard.
+
+const APPROVAL_PREFIX: &str = "migration/";
+const APPROVAL_DOMAIN: &[u8] = b"nexacore-migration";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MigrationKind {
+    Split { shard_id: u16, new_shard_id: u16 }, // Half the addresses of shard_id move to a new shard
+    Merge { shard_id: u16, into: u16 },         // Every address of shard_id moves, and the shard retires
+}
+
+impl MigrationKind {
+    /// Next version of `shard_map`, with the migration applied. The beacon
+    /// shard keeps its addresses.
+    pub fn apply(&self, shard_map: &ShardMap) -> Result<ShardMap, String> {
+        match *self {
+            MigrationKind::Split { shard_id, .. } | MigrationKind::Merge { shard_id, .. } if shard_id == BEACON_SHARD => {
+                Err(format!("Beacon shard {} keeps its addresses", BEACON_SHARD))
+            }
+            MigrationKind::Split { shard_id, new_shard_id } => shard_map.split(shard_id, new_shard_id),
+            MigrationKind::Merge { shard_id, into } => shard_map.merge(shard_id, into),
+        }
+    }
+}
+
+// A split or merge, approved in beacon blocks of `epoch`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MigrationProposal {
+    pub kind: MigrationKind,
+    pub epoch: u64,
+}
+
+impl MigrationProposal {
+    /// Returns the bytes validators sign to approve the proposal: a domain
+    /// separator followed by its canonical encoding.
+    pub fn signing_message(&self) -> Vec<u8> {
+        let mut message = APPROVAL_DOMAIN.to_vec();
+        message.extend_from_slice(&encoding::to_bytes(self));
+        message
+    }
+
+    /// Signs the proposal as an approving validator.
+    pub fn approve(&self, private_key: &[u8]) -> Result<Attestation, String> {
+        let key = crypto::signing_key_from_bytes(private_key)?;
+        Ok(Attestation {
+            public_key: hex::encode(key.verifying_key().as_bytes()),
+            signature: crypto::sign(private_key, &self.signing_message())?,
+        })
+    }
+
+    /// Checks an approval's signature and returns the approving validator's
+    /// address.
+    pub fn verify_approval(&self, approval: &Attestation) -> Result<String, String> {
+        let validator = approval.attester()?;
+        let public_key = hex::decode(&approval.public_key).map_err(|e| format!("Invalid approver key encoding: {}", e))?;
+        if !crypto::verify(&public_key, &self.signing_message(), &approval.signature) {
+            return Err(format!("Invalid approval signature from {}", validator));
+        }
+        Ok(validator)
+    }
+}
+
+// A contract storage entry
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StorageEntry {
+    pub key: String,
+    pub value: Vec<u8>,
+}
+
+// What a switch hands over to the shards taking over its addresses
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Handover {
+    pub accounts: Vec<Account>,            // Whole, with their storage, ordered by address
+    pub utxos: Vec<UTXO>,                  // Ordered by key
+    pub escrow_balance: u64,               // Of the cross-shard account, which only a merged shard hands over
+    pub escrow_entries: Vec<StorageEntry>, // Of the cross-shard account, ordered by key
+}
+
+// Data of a `ShardMigration` transaction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MigrationAction {
+    Approve {
+        proposal: MigrationProposal,
+        approval: Attestation, // Of an active validator
+    },
+    Switch {
+        shard_map: ShardMap,
+        handover: Box<Handover>,
+    },
+    Import {
+        switch: Box<ReceiptProof>, // The source shard's switch
+    },
+}
+
+impl MigrationAction {
+    pub fn from_transaction(tx: &Transaction) -> Result<Self, String> {
+        if !matches!(tx.tx_type, TransactionType::ShardMigration) {
+            return Err(format!("Transaction {} is not a shard migration", tx.hash));
+        }
+
+        encoding::from_bytes(&tx.data)
+    }
+
+    pub fn to_transaction(&self, shard_id: u16) -> Transaction {
+        Transaction::new(
+            TransactionType::ShardMigration,
+            Vec::new(),
+            Vec::new(),
+            shard_id,
+            encoding::to_bytes(self),
+            0,
+        )
+    }
+
+    /// Headers that an import needs anchored.
+    pub fn anchor_headers(&self) -> Result<Vec<&BlockHeader>, String> {
+        match self {
+            MigrationAction::Import { switch } => Ok(vec![switch.anchor()?]),
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Key, in the beacon account, of the proposal a validator approved in
+/// `epoch`. Zero-padded so the approvals of an epoch sort together.
+pub fn approval_key(epoch: u64, validator: &str) -> String {
+    format!("{}{}", approval_prefix(epoch), validator)
+}
+
+/// Common prefix of the approval keys of `epoch`.
+pub fn approval_prefix(epoch: u64) -> String {
+    format!("{}{:020}/", APPROVAL_PREFIX, epoch)
+}
+
+impl Canonical for MigrationKind {
+    fn encode(&self, encoder: &mut Encoder) {
+        match *self {
+            MigrationKind::Split { shard_id, new_shard_id } => {
+                encoder.put_u8(0);
+                encoder.put_u16(shard_id);
+                encoder.put_u16(new_shard_id);
+            }
+            MigrationKind::Merge { shard_id, into } => {
+                encoder.put_u8(1);
+                encoder.put_u16(shard_id);
+                encoder.put_u16(into);
+            }
+        }
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        match decoder.get_u8()? {
+            0 => Ok(MigrationKind::Split {
+                shard_id: decoder.get_u16()?,
+                new_shard_id: decoder.get_u16()?,
+            }),
+            1 => Ok(MigrationKind::Merge {
+                shard_id: decoder.get_u16()?,
+                into: decoder.get_u16()?,
+            }),
+            tag => Err(format!("Unknown migration kind {}", tag)),
+        }
+    }
+}
+
+impl Canonical for MigrationProposal {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.kind.encode(encoder);
+        encoder.put_u64(self.epoch);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(MigrationProposal {
+            kind: MigrationKind::decode(decoder)?,
+            epoch: decoder.get_u64()?,
+        })
+    }
+}
+
+impl Canonical for StorageEntry {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_str(&self.key);
+        encoder.put_bytes(&self.value);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(StorageEntry {
+            key: decoder.get_string()?,
+            value: decoder.get_bytes()?,
+        })
+    }
+}
+
+impl Canonical for Handover {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_seq(&self.accounts);
+        encoder.put_seq(&self.utxos);
+        encoder.put_u64(self.escrow_balance);
+        encoder.put_seq(&self.escrow_entries);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(Handover {
+            accounts: decoder.get_seq()?,
+            utxos: decoder.get_seq()?,
+            escrow_balance: decoder.get_u64()?,
+            escrow_entries: decoder.get_seq()?,
+        })
+    }
+}
+
+impl Canonical for MigrationAction {
+    fn encode(&self, encoder: &mut Encoder) {
+        match self {
+            MigrationAction::Approve { proposal, approval } => {
+                encoder.put_u8(0);
+                proposal.encode(encoder);
+                approval.encode(encoder);
+            }
+            MigrationAction::Switch { shard_map, handover } => {
+                encoder.put_u8(1);
+                shard_map.encode(encoder);
+                handover.encode(encoder);
+            }
+            MigrationAction::Import { switch } => {
+                encoder.put_u8(2);
+                switch.encode(encoder);
+            }
+        }
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        match decoder.get_u8()? {
+            0 => Ok(MigrationAction::Approve {
+                proposal: MigrationProposal::decode(decoder)?,
+                approval: Attestation::decode(decoder)?,
+            }),
+            1 => Ok(MigrationAction::Switch {
+                shard_map: ShardMap::decode(decoder)?,
+                handover: Box::new(Handover::decode(decoder)?),
+            }),
+            2 => Ok(MigrationAction::Import {
+                switch: Box::new(ReceiptProof::decode(decoder)?),
+            }),
+            tag => Err(format!("Unknown migration action {}", tag)),
+        }
+    }
+}
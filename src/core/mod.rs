@@ -1,8 +1,27 @@
+pub mod beacon;
 pub mod block;
+pub mod chain;
+pub mod contribution;
+pub mod cross_shard;
+pub mod crypto;
+pub mod encoding;
+pub mod finality;
+pub mod genesis;
+pub mod mempool;
+pub mod merkle;
+pub mod migration;
+pub mod params;
+pub mod randao;
+pub mod rewards;
+pub mod slashing;
+pub mod staking;
 pub mod transaction;
 pub mod consensus;
 pub mod shard;
+pub mod shard_map;
 pub mod state;
+pub mod state_tree;
+pub mod storage;
 
 use log::{info, error};
 
@@ -12,12 +31,24 @@ pub fn initialize() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize blockchain state
     state::initialize()?;
     
+    // Initialize the finality proofs of other shards for beacon crosslinks
+    beacon::initialize()?;
+    
     // Initialize sharding system
     shard::initialize()?;
     
     // Initialize consensus engine
     consensus::initialize()?;
     
+    // Initialize finality gadget
+    finality::initialize()?;
+    
+    // Initialize slashing evidence pool
+    slashing::initialize()?;
+    
+    // Initialize transaction pool
+    mempool::initialize()?;
+    
     info!("Core components initialized successfully");
     Ok(())
 }
@@ -26,8 +57,12 @@ pub fn shutdown() -> Result<(), Box<dyn std::error::Error>> {
     info!("Shutting down core components...");
     
     // Shutdown in reverse order
+    mempool::shutdown()?;
+    slashing::shutdown()?;
+    finality::shutdown()?;
     consensus::shutdown()?;
     shard::shutdown()?;
+    beacon::shutdown()?;
     state::shutdown()?;
     
     info!("Core components shutdown complete");
@@ -0,0 +1,239 @@
+use std::sync::RwLock;
+use serde::{Serialize, Deserialize};
+use crate::core::contribution::ContributionKind;
+
+// Chain-wide parameters. Every node must use the same values, since they
+// decide which blocks are valid and what state they produce. They are set
+// from the genesis file; fields it leaves out keep their defaults.
+
+// Fractions are expressed in basis points
+pub const BPS_SCALE: u64 = 10_000;
+
+// Validator weight: a weighted sum of stake, computation power and
+// contribution score. A point of computation power or contribution counts as
+// `points_scale` units of stake.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WeightParams {
+    pub stake_bps: u64,
+    pub computation_bps: u64,
+    pub contribution_bps: u64,
+    pub points_scale: u64,
+}
+
+impl Default for WeightParams {
+    fn default() -> Self {
+        WeightParams {
+            stake_bps: 5_000,
+            computation_bps: 2_500,
+            contribution_bps: 2_500,
+            points_scale: 1_000,
+        }
+    }
+}
+
+// Block timing. Time is divided into slots of `target_block_time` seconds
+// from the genesis timestamp, with at most one block per slot. The elected
+// proposer of a slot may propose from its start; if it doesn't, each fallback
+// proposer in turn may step in `proposer_timeout` seconds later.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SlotParams {
+    pub fallback_proposers: u32, // Proposers ranked after the elected one in every slot
+    pub proposer_timeout: u64,   // Seconds each ranked proposer has before the next may propose
+    pub max_future_drift: u64,   // Seconds a block timestamp may be ahead of the local clock
+}
+
+impl Default for SlotParams {
+    fn default() -> Self {
+        SlotParams {
+            fallback_proposers: 2,
+            proposer_timeout: 10,
+            max_future_drift: 15,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SlashingParams {
+    pub double_sign_stake_bps: u64,        // Stake burned for double-signing or double-voting
+    pub double_sign_contribution_bps: u64, // Contribution score removed for double-signing or double-voting
+    pub double_sign_jail_epochs: u64,      // Epochs an equivocating validator stays out of the active set
+    pub downtime_stake_bps: u64,           // Stake burned for missing too many proposals
+    pub downtime_contribution_bps: u64,    // Contribution score removed for missing too many proposals
+    pub downtime_jail_epochs: u64,         // Epochs a validator stays out of the active set for downtime
+    pub max_missed_proposals: u32,         // Missed slots tolerated per epoch
+}
+
+impl Default for SlashingParams {
+    fn default() -> Self {
+        SlashingParams {
+            double_sign_stake_bps: 500,
+            double_sign_contribution_bps: 10_000,
+            double_sign_jail_epochs: 10,
+            downtime_stake_bps: 10,
+            downtime_contribution_bps: 5_000,
+            downtime_jail_epochs: 1,
+            max_missed_proposals: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RewardParams {
+    pub initial_supply: u64,        // Tokens in existence before the first block
+    pub initial_inflation_bps: u64, // Yearly inflation in the first year
+    pub inflation_decay_bps: u64,   // Decrease of the inflation rate every year
+    pub min_inflation_bps: u64,     // Floor of the inflation rate
+    pub proposer_bps: u64,          // Share of each block's reward and fees paid to its proposer
+    pub treasury_bps: u64,          // Share paid to the treasury; the rest goes to the active validators
+    pub treasury_address: String,
+}
+
+impl Default for RewardParams {
+    fn default() -> Self {
+        RewardParams {
+            initial_supply: 1_000_000_000_000_000,
+            initial_inflation_bps: 400,
+            inflation_decay_bps: 50,
+            min_inflation_bps: 100,
+            proposer_bps: 2_000,
+            treasury_bps: 1_000,
+            treasury_address: "0x0000000000000000000000000000000000000000".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StakingParams {
+    pub unbonding_blocks: u64, // Blocks between an unbond request and the payout
+    pub commission_bps: u64,   // Share of a validator's rewards it keeps before paying its delegators
+}
+
+impl Default for StakingParams {
+    fn default() -> Self {
+        StakingParams {
+            unbonding_blocks: 2_880,
+            commission_bps: 1_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CrossShardParams {
+    pub receipt_timeout: u64, // Seconds after its source block until an unclaimed receipt can be refunded
+}
+
+impl Default for CrossShardParams {
+    fn default() -> Self {
+        CrossShardParams {
+            receipt_timeout: 3_600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContributionKindParams {
+    pub points_per_unit: u32,     // Contribution score earned per unit of work
+    pub max_units_per_epoch: u32, // Units credited per contributor and epoch
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContributionParams {
+    pub min_attestations: usize, // Bonded validators that must sign a contribution report
+    pub decay_bps: u64,          // Fraction of every contribution score lost at the end of each epoch
+    pub relayed_blocks: ContributionKindParams,
+    pub served_sync_requests: ContributionKindParams,
+    pub compute_jobs: ContributionKindParams,
+}
+
+impl Default for ContributionParams {
+    fn default() -> Self {
+        ContributionParams {
+            min_attestations: 2,
+            decay_bps: 1_000,
+            relayed_blocks: ContributionKindParams {
+                points_per_unit: 1,
+                max_units_per_epoch: 1_000,
+            },
+            served_sync_requests: ContributionKindParams {
+                points_per_unit: 2,
+                max_units_per_epoch: 500,
+            },
+            compute_jobs: ContributionKindParams {
+                points_per_unit: 10,
+                max_units_per_epoch: 100,
+            },
+        }
+    }
+}
+
+impl ContributionParams {
+    pub fn for_kind(&self, kind: ContributionKind) -> &ContributionKindParams {
+        match kind {
+            ContributionKind::RelayedBlocks => &self.relayed_blocks,
+            ContributionKind::ServedSyncRequests => &self.served_sync_requests,
+            ContributionKind::ComputeJobs => &self.compute_jobs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChainParams {
+    pub epoch_length: u64,      // Number of blocks per epoch
+    pub target_block_time: u64, // Duration of a slot in seconds
+    pub min_stake: u64,         // Stake a validator needs to be active
+    pub max_validators: usize,  // Size of the active validator set
+    pub min_committee_size: usize, // Validators each shard committee needs; with fewer, every validator serves every shard
+    pub weights: WeightParams,
+    pub slots: SlotParams,
+    pub slashing: SlashingParams,
+    pub rewards: RewardParams,
+    pub staking: StakingParams,
+    pub contribution: ContributionParams,
+    pub cross_shard: CrossShardParams,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams {
+            epoch_length: 100,
+            target_block_time: 30,
+            min_stake: 1_000,
+            max_validators: 100,
+            min_committee_size: 3,
+            weights: WeightParams::default(),
+            slots: SlotParams::default(),
+            slashing: SlashingParams::default(),
+            rewards: RewardParams::default(),
+            staking: StakingParams::default(),
+            contribution: ContributionParams::default(),
+            cross_shard: CrossShardParams::default(),
+        }
+    }
+}
+
+impl ChainParams {
+    pub fn epoch_at(&self, block_height: u64) -> u64 {
+        block_height / self.epoch_length
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref CHAIN_PARAMS: RwLock<ChainParams> = RwLock::new(ChainParams::default());
+}
+
+pub fn get() -> ChainParams {
+    CHAIN_PARAMS.read().unwrap().clone()
+}
+
+/// Replaces the chain parameters. Must happen before any block is processed.
+pub fn set(params: ChainParams) {
+    *CHAIN_PARAMS.write().unwrap() = params;
+}
@@ -0,0 +1,70 @@
+use sha2::{Sha256, Digest};
+use crate::core::crypto;
+
+// RANDAO random beacon used for proposer selection.
+//
+// Every block carries a reveal: its validator's ed25519 signature over the
+// block's epoch. Signatures are deterministic, so a validator has exactly one
+// valid reveal per epoch and can't grind it, yet nobody else can predict it.
+// Each block folds its reveal into the running mix inherited from its parent,
+// and the proposers of a slot are drawn from the parent's mix, so the next
+// proposers are only known once the previous block has been published.
+
+/// Mix in effect before the first block of a chain.
+pub const INITIAL_MIX: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+const REVEAL_DOMAIN: &[u8] = b"nexacore-randao";
+
+fn reveal_message(epoch: u64) -> Vec<u8> {
+    let mut message = REVEAL_DOMAIN.to_vec();
+    message.extend_from_slice(&epoch.to_le_bytes());
+    message
+}
+
+/// Produces the reveal of the validator owning `private_key` for `epoch`.
+pub fn reveal(private_key: &[u8], epoch: u64) -> Result<String, String> {
+    crypto::sign(private_key, &reveal_message(epoch))
+}
+
+pub fn verify_reveal(public_key: &[u8], epoch: u64, reveal: &str) -> bool {
+    crypto::verify(public_key, &reveal_message(epoch), reveal)
+}
+
+/// Folds a reveal into the mix: the new mix is the old one XORed with the
+/// SHA-256 of the reveal.
+pub fn mix(previous_mix: &str, reveal: &str) -> Result<String, String> {
+    let previous_mix = decode_mix(previous_mix)?;
+    let reveal = hex::decode(reveal).map_err(|e| format!("Invalid RANDAO reveal: {}", e))?;
+
+    let digest = Sha256::digest(&reveal);
+    let mixed: Vec<u8> = previous_mix
+        .iter()
+        .zip(digest.iter())
+        .map(|(a, b)| a ^ b)
+        .collect();
+
+    Ok(hex::encode(mixed))
+}
+
+/// Derives the random value used to draw the proposer ranked `rank` in
+/// `slot` from the mix of the parent block.
+pub fn random_value(mix: &str, slot: u64, rank: u32) -> Result<u64, String> {
+    let mix = decode_mix(mix)?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(mix);
+    hasher.update(slot.to_le_bytes());
+    hasher.update(rank.to_le_bytes());
+    let digest = hasher.finalize();
+
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[0..8]);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn decode_mix(mix: &str) -> Result<[u8; 32], String> {
+    let bytes = hex::decode(mix).map_err(|e| format!("Invalid RANDAO mix: {}", e))?;
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| format!("Invalid RANDAO mix length: expected 32, got {}", bytes.len()))
+}
@@ -0,0 +1,180 @@
+use std::collections::BTreeMap;
+use crate::core::consensus::{ValidatorInfo, ValidatorSet};
+use crate::core::params::{ChainParams, BPS_SCALE};
+use crate::core::transaction::{Transaction, TransactionOutput, TransactionType};
+
+// Block rewards, fee collection and inflation.
+//
+// Every block starts with a coinbase transaction. It spends nothing and pays
+// out the block reward, newly minted according to the inflation schedule,
+// plus the fees left over by the block's other transactions. The total is
+// split between the block's proposer, the active validators in proportion to
+// their contribution scores, and the treasury. A validator with delegations
+// keeps a commission on what it earns and shares the rest with its
+// delegators, itself included, in proportion to their stake.
+//
+// The inflation rate starts at 4% a year and drops by 0.5% every year down to
+// a 1% floor. A year is counted in blocks at the target block time, and each
+// year's issuance is spread evenly over its blocks.
+
+const SECONDS_PER_YEAR: u64 = 365 * 24 * 60 * 60;
+
+pub fn blocks_per_year(params: &ChainParams) -> u64 {
+    (SECONDS_PER_YEAR / params.target_block_time).max(1)
+}
+
+/// Yearly inflation rate, in basis points, for the given year of the chain
+/// (0 for the first year).
+pub fn inflation_bps(params: &ChainParams, year: u64) -> u64 {
+    let rewards = &params.rewards;
+    let decay = rewards.inflation_decay_bps.saturating_mul(year);
+    rewards.initial_inflation_bps
+        .saturating_sub(decay)
+        .max(rewards.min_inflation_bps)
+}
+
+/// Total supply at the start of `year`, from the inflation of the years before.
+pub fn supply_at_year(params: &ChainParams, year: u64) -> u64 {
+    let mut supply = params.rewards.initial_supply as u128;
+    for past_year in 0..year {
+        supply += supply * inflation_bps(params, past_year) as u128 / BPS_SCALE as u128;
+    }
+    supply.min(u64::MAX as u128) as u64
+}
+
+/// Tokens minted by the block at `height`.
+pub fn block_reward(params: &ChainParams, height: u64) -> u64 {
+    let blocks_per_year = blocks_per_year(params);
+    let year = height.saturating_sub(1) / blocks_per_year;
+
+    let issuance = supply_at_year(params, year) as u128 * inflation_bps(params, year) as u128 / BPS_SCALE as u128;
+    (issuance / blocks_per_year as u128) as u64
+}
+
+/// Splits `amount` between the proposer, the active validators of
+/// `validator_set` and the treasury. Outputs are ordered by address with the
+/// treasury last, and empty shares are left out, so every node derives the
+/// same outputs.
+pub fn coinbase_outputs(params: &ChainParams, validator_set: &ValidatorSet, proposer: &str, amount: u64) -> Vec<TransactionOutput> {
+    let rewards = &params.rewards;
+    let proposer_share = (amount as u128 * rewards.proposer_bps as u128 / BPS_SCALE as u128) as u64;
+    let treasury_share = (amount as u128 * rewards.treasury_bps as u128 / BPS_SCALE as u128) as u64;
+    // Shares above the whole amount are rejected by genesis validation; saturate anyway
+    let validators_share = amount.saturating_sub(proposer_share).saturating_sub(treasury_share);
+
+    // Address -> (amount, public key)
+    let mut shares: BTreeMap<String, (u64, String)> = BTreeMap::new();
+    match validator_set.get(proposer) {
+        Some(validator) => pay_validator(&mut shares, params, validator, proposer_share),
+        None => {
+            shares.insert(proposer.to_string(), (proposer_share, String::new()));
+        }
+    }
+
+    // Validators without any contribution yet share equally
+    let validators = validator_set.active_validators();
+    let total_contribution: u64 = validators.iter().map(|validator| validator.contribution_score as u64).sum();
+    let weight = |contribution_score: u32| {
+        if total_contribution == 0 { 1 } else { contribution_score as u64 }
+    };
+    let total_weight = if total_contribution == 0 { validators.len() as u64 } else { total_contribution };
+
+    let mut paid = 0;
+    for validator in validators {
+        let share = (validators_share as u128 * weight(validator.contribution_score) as u128 / total_weight as u128) as u64;
+        paid += share;
+        pay_validator(&mut shares, params, validator, share);
+    }
+
+    // Rounding leftovers, or everything if no validator is active, go to the treasury
+    let treasury_share = treasury_share + (validators_share - paid);
+
+    let mut outputs: Vec<TransactionOutput> = shares
+        .into_iter()
+        .filter(|(_, (amount, _))| *amount > 0)
+        .map(|(address, (amount, script_pubkey))| TransactionOutput {
+            address,
+            amount,
+            script_pubkey,
+        })
+        .collect();
+
+    if treasury_share > 0 {
+        // The treasury is spent through governance, not with a key
+        outputs.push(TransactionOutput {
+            address: rewards.treasury_address.clone(),
+            amount: treasury_share,
+            script_pubkey: String::new(),
+        });
+    }
+
+    outputs
+}
+
+// Credits what a validator earned to it and its delegators
+fn pay_validator(shares: &mut BTreeMap<String, (u64, String)>, params: &ChainParams, validator: &ValidatorInfo, amount: u64) {
+    let mut credit = |address: &str, public_key: &str, amount: u64| {
+        shares
+            .entry(address.to_string())
+            .or_insert_with(|| (0, public_key.to_string()))
+            .0 += amount;
+    };
+
+    let delegated: u128 = validator.delegations.iter().map(|delegation| delegation.amount as u128).sum();
+    if delegated == 0 {
+        credit(&validator.address, &validator.public_key, amount);
+        return;
+    }
+
+    let commission = (amount as u128 * params.staking.commission_bps as u128 / BPS_SCALE as u128) as u64;
+    let shared = amount.saturating_sub(commission);
+
+    let mut paid = 0;
+    for delegation in &validator.delegations {
+        let share = (shared as u128 * delegation.amount as u128 / delegated) as u64;
+        paid += share;
+        credit(&delegation.delegator, &delegation.public_key, share);
+    }
+
+    // The validator keeps its commission and the rounding leftovers
+    credit(&validator.address, &validator.public_key, commission + (shared - paid));
+}
+
+/// Builds the coinbase of the block at `height`. Its data is the height, so
+/// coinbases of different blocks never share a hash.
+pub fn coinbase(shard_id: u16, height: u64, outputs: Vec<TransactionOutput>) -> Transaction {
+    Transaction::new(
+        TransactionType::Coinbase,
+        Vec::new(),
+        outputs,
+        shard_id,
+        height.to_le_bytes().to_vec(),
+        0,
+    )
+}
+
+/// Checks that the first transaction of a block is a coinbase splitting what
+/// it pays out the way `coinbase_outputs` does. The amount itself is checked
+/// by the state.
+pub fn verify_coinbase_split(params: &ChainParams, validator_set: &ValidatorSet, proposer: &str, coinbase: &Transaction) -> Result<(), String> {
+    if !matches!(coinbase.tx_type, TransactionType::Coinbase) {
+        return Err(format!("Block starts with transaction {} instead of a coinbase", coinbase.hash));
+    }
+
+    let amount = coinbase.total_output()
+        .ok_or_else(|| format!("Coinbase {} outputs overflow", coinbase.hash))?;
+    let expected = coinbase_outputs(params, validator_set, proposer, amount);
+
+    let matches = coinbase.outputs.len() == expected.len() &&
+        coinbase.outputs.iter().zip(expected.iter()).all(|(output, expected)| {
+            output.address == expected.address &&
+                output.amount == expected.amount &&
+                output.script_pubkey == expected.script_pubkey
+        });
+
+    if !matches {
+        return Err(format!("Coinbase {} does not split the block reward between proposer, validators and treasury", coinbase.hash));
+    }
+
+    Ok(())
+}
@@ -1,8 +1,13 @@
 use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
-use log::{info, warn, error, debug};
+use log::{info, error, debug};
 use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
 use crate::core::block::Block;
+use crate::core::consensus::Committee;
+use crate::core::crypto;
+use crate::core::genesis;
+use crate::core::shard_map::ShardMap;
 use crate::core::transaction::Transaction;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,6 +32,9 @@ pub struct CrossShardTransaction {
     pub completion_time: Option<u64>,
 }
 
+// Progress of a cross-shard transfer: sent in a source block, which is then
+// finalized; claimed in a target block, which is then finalized. A transfer
+// whose receipt was refunded instead has failed.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum CrossShardStatus {
     Pending,
@@ -36,24 +44,39 @@ pub enum CrossShardStatus {
     Failed,
 }
 
+impl CrossShardStatus {
+    fn stage(&self) -> u8 {
+        match self {
+            CrossShardStatus::Pending => 0,
+            CrossShardStatus::SourceConfirmed => 1,
+            CrossShardStatus::TargetConfirmed => 2,
+            CrossShardStatus::Completed | CrossShardStatus::Failed => 3,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ShardingEngine {
     shards: HashMap<u16, ShardInfo>,
     node_shard_assignments: HashMap<String, u16>, // Node address -> shard_id
+    next_assignments: HashMap<String, u16>, // Committees of the epoch after committee_epoch
+    committee_epoch: u64, // Epoch of the committees in node_shard_assignments
     cross_shard_transactions: HashMap<String, CrossShardTransaction>,
+    shard_map: ShardMap, // Which shard owns which account
     max_shards: u16,
-    min_validators_per_shard: u32,
     shard_rebalance_threshold: f64, // Load imbalance threshold to trigger rebalancing
 }
 
 impl ShardingEngine {
-    pub fn new(max_shards: u16, min_validators_per_shard: u32, shard_rebalance_threshold: f64) -> Self {
+    pub fn new(max_shards: u16, shard_rebalance_threshold: f64) -> Self {
         let mut engine = ShardingEngine {
             shards: HashMap::new(),
             node_shard_assignments: HashMap::new(),
+            next_assignments: HashMap::new(),
+            committee_epoch: 0,
             cross_shard_transactions: HashMap::new(),
+            shard_map: genesis::get().shard_map(),
             max_shards,
-            min_validators_per_shard,
             shard_rebalance_threshold,
         };
         
@@ -65,14 +88,18 @@ impl ShardingEngine {
     
     pub fn create_shard(&mut self, name: String) -> u16 {
         let next_id = self.get_next_shard_id();
-        
+        self.add_shard(next_id, name);
+        next_id
+    }
+    
+    fn add_shard(&mut self, shard_id: u16, name: String) {
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .expect("Time went backwards")
             .as_secs();
         
         let shard = ShardInfo {
-            shard_id: next_id,
+            shard_id,
             name,
             validator_count: 0,
             transaction_count: 0,
@@ -82,10 +109,8 @@ impl ShardingEngine {
             is_active: true,
         };
         
-        self.shards.insert(next_id, shard.clone());
+        self.shards.insert(shard_id, shard.clone());
         info!("Created new shard: {} (ID: {})", shard.name, shard.shard_id);
-        
-        next_id
     }
     
     fn get_next_shard_id(&self) -> u16 {
@@ -106,8 +131,15 @@ impl ShardingEngine {
             return Err(format!("Shard {} does not exist", shard_id));
         }
         
-        // Update the node's shard assignment
-        self.node_shard_assignments.insert(node_address.clone(), shard_id);
+        // Update the node's shard assignment, moving it out of its old shard
+        if let Some(previous) = self.node_shard_assignments.insert(node_address.clone(), shard_id) {
+            if previous == shard_id {
+                return Ok(());
+            }
+            if let Some(shard) = self.shards.get_mut(&previous) {
+                shard.validator_count = shard.validator_count.saturating_sub(1);
+            }
+        }
         
         // Update the shard's validator count
         if let Some(shard) = self.shards.get_mut(&shard_id) {
@@ -118,6 +150,72 @@ impl ShardingEngine {
         Ok(())
     }
     
+    /// Takes over the committees serving `epoch` and those drawn for the
+    /// next epoch, whose incoming validators then have an epoch to sync their
+    /// new shard. Committees of an earlier epoch than the current ones are
+    /// ignored.
+    pub fn set_committees(&mut self, epoch: u64, current: &[Committee], next: &[Committee]) {
+        if epoch < self.committee_epoch {
+            return;
+        }
+        
+        if epoch > self.committee_epoch || self.node_shard_assignments.is_empty() {
+            self.set_assignments(assignments(current));
+            info!("Shard committees of epoch {}: {} validators assigned", epoch, self.node_shard_assignments.len());
+        }
+        self.committee_epoch = epoch;
+        self.next_assignments = assignments(next);
+    }
+    
+    // Replaces every assignment and recounts the validators of each shard
+    fn set_assignments(&mut self, assignments: HashMap<String, u16>) {
+        for shard in self.shards.values_mut() {
+            shard.validator_count = 0;
+        }
+        for shard_id in assignments.values() {
+            if let Some(shard) = self.shards.get_mut(shard_id) {
+                shard.validator_count += 1;
+            }
+        }
+        
+        self.node_shard_assignments = assignments;
+    }
+    
+    /// Returns the validators of each shard's committee, sorted.
+    pub fn get_committees(&self) -> HashMap<u16, Vec<String>> {
+        let mut committees: HashMap<u16, Vec<String>> = HashMap::new();
+        for (address, shard_id) in &self.node_shard_assignments {
+            committees.entry(*shard_id).or_default().push(address.clone());
+        }
+        for committee in committees.values_mut() {
+            committee.sort();
+        }
+        
+        committees
+    }
+    
+    pub fn get_committee_epoch(&self) -> u64 {
+        self.committee_epoch
+    }
+    
+    /// Shard `node_address` serves from the next epoch on, if committees
+    /// have been drawn for it.
+    pub fn get_next_node_shard(&self, node_address: &str) -> Option<u16> {
+        self.next_assignments.get(node_address).cloned()
+    }
+    
+    /// Validators joining the committee of `shard_id` next epoch, who are
+    /// syncing its state during this one.
+    pub fn get_incoming_validators(&self, shard_id: u16) -> Vec<String> {
+        let mut incoming: Vec<String> = self.next_assignments
+            .iter()
+            .filter(|(address, &next)| next == shard_id && self.get_node_shard(address) != Some(shard_id))
+            .map(|(address, _)| address.clone())
+            .collect();
+        incoming.sort();
+        incoming
+    }
+    
     pub fn get_node_shard(&self, node_address: &str) -> Option<u16> {
         self.node_shard_assignments.get(node_address).cloned()
     }
@@ -130,27 +228,43 @@ impl ShardingEngine {
         self.shards.values().cloned().collect()
     }
     
-    pub fn determine_transaction_shard(&self, tx: &Transaction) -> u16 {
-        // If the transaction already has a shard_id, use it
-        if tx.shard_id != 0 {
-            return tx.shard_id;
+    pub fn get_shard_map(&self) -> ShardMap {
+        self.shard_map.clone()
+    }
+    
+    /// Adopts a newer version of the shard map, older ones are ignored, and
+    /// brings the shard list in line with it: shards that gained addresses
+    /// are added and shards left without any are deactivated.
+    pub fn set_shard_map(&mut self, shard_map: ShardMap) {
+        if shard_map.version <= self.shard_map.version {
+            return;
         }
         
-        // For new transactions, assign based on a simple hash of the first input address
-        // In a real implementation, this would use a more sophisticated algorithm
-        if !tx.inputs.is_empty() {
-            let input_hash = tx.inputs[0].previous_tx.clone();
-            let hash_bytes = input_hash.as_bytes();
-            let hash_sum: u32 = hash_bytes.iter().map(|&b| b as u32).sum();
-            let shard_count = self.shards.len() as u16;
-            
-            if shard_count > 0 {
-                return (hash_sum % shard_count as u32) as u16;
+        let shard_ids = shard_map.shard_ids();
+        for &shard_id in &shard_ids {
+            if !self.shards.contains_key(&shard_id) {
+                self.add_shard(shard_id, format!("Shard {}", shard_id));
             }
         }
+        for shard in self.shards.values_mut() {
+            shard.is_active = shard_ids.contains(&shard.shard_id);
+        }
         
-        // Default to shard 0 (genesis shard)
-        0
+        self.shard_map = shard_map;
+    }
+    
+    pub fn get_address_shard(&self, address: &str) -> u16 {
+        self.shard_map.shard_of(address)
+    }
+    
+    /// Returns the shard that owns the account spending the transaction's
+    /// first input. Transactions without inputs, such as cross-shard claims,
+    /// stay in the shard they name.
+    pub fn determine_transaction_shard(&self, tx: &Transaction) -> u16 {
+        match tx.inputs.first().map(|input| hex::decode(&input.script_sig)) {
+            Some(Ok(public_key)) => self.get_address_shard(&crypto::public_key_to_address(&public_key)),
+            _ => tx.shard_id,
+        }
     }
     
     pub fn register_cross_shard_transaction(
@@ -211,6 +325,27 @@ impl ShardingEngine {
         }
     }
     
+    /// Moves a cross-shard transfer, identified by its send transaction, to
+    /// `new_status`, registering it first if needed. A transfer never moves
+    /// back to an earlier stage.
+    pub fn advance_cross_shard_transaction(
+        &mut self,
+        tx_hash: &str,
+        source_shard: u16,
+        target_shard: u16,
+        new_status: CrossShardStatus,
+    ) -> Result<(), String> {
+        if !self.cross_shard_transactions.contains_key(tx_hash) {
+            self.register_cross_shard_transaction(tx_hash.to_string(), source_shard, target_shard)?;
+        }
+        
+        if self.cross_shard_transactions[tx_hash].status.stage() >= new_status.stage() {
+            return Ok(());
+        }
+        
+        self.update_cross_shard_transaction_status(tx_hash, new_status)
+    }
+    
     pub fn get_cross_shard_transaction(&self, tx_hash: &str) -> Option<CrossShardTransaction> {
         self.cross_shard_transactions.get(tx_hash).cloned()
     }
@@ -261,52 +396,53 @@ impl ShardingEngine {
         
         false
     }
+}
+
+/// Shuffles `validators` with `seed` and deals them out to `shard_ids` in
+/// turn, so every shard gets at least `min_size` of them. The same seed,
+/// epoch and validators always give the same committees, ordered by shard
+/// with sorted members.
+pub fn draw_committees(validators: &[String], shard_ids: &[u16], min_size: usize, seed: &str, epoch: u64) -> Result<Vec<Committee>, String> {
+    let needed = shard_ids.len() * min_size;
+    if validators.len() < needed {
+        return Err(format!("{} validators can't fill {} committees of {}",
+                           validators.len(), shard_ids.len(), min_size));
+    }
     
-    pub fn rebalance_shards(&mut self) -> Result<(), String> {
-        info!("Rebalancing shards...");
-        
-        // In a real implementation, this would use a sophisticated algorithm to:
-        // 1. Identify overloaded shards
-        // 2. Create new shards if needed
-        // 3. Reassign nodes to balance the load
-        // 4. Plan and execute the migration of state
-        
-        // For this example, we'll just create a new shard if we're below the max
-        if self.shards.len() < self.max_shards as usize {
-            let new_shard_id = self.create_shard(format!("Shard-{}", self.shards.len()));
-            
-            // Reassign some nodes to the new shard
-            let overloaded_shards: Vec<u16> = self.shards
-                .values()
-                .filter(|s| s.validator_count > self.min_validators_per_shard)
-                .map(|s| s.shard_id)
-                .collect();
-            
-            if !overloaded_shards.is_empty() {
-                // Find nodes to reassign
-                let mut nodes_to_reassign: Vec<String> = Vec::new();
-                for (node, &shard_id) in &self.node_shard_assignments {
-                    if overloaded_shards.contains(&shard_id) {
-                        nodes_to_reassign.push(node.clone());
-                        if nodes_to_reassign.len() >= self.min_validators_per_shard as usize {
-                            break;
-                        }
-                    }
-                }
-                
-                // Reassign nodes to the new shard
-                for node in nodes_to_reassign {
-                    self.assign_node_to_shard(node, new_shard_id)?;
-                }
-                
-                info!("Created new shard {} and reassigned nodes during rebalancing", new_shard_id);
-            }
-        } else {
-            warn!("Maximum number of shards reached, cannot rebalance by creating new shards");
-        }
-        
-        Ok(())
+    let mut shuffled: Vec<(Vec<u8>, &String)> = validators
+        .iter()
+        .map(|address| {
+            let mut hasher = Sha256::new();
+            hasher.update(b"nexacore-committee");
+            hasher.update(seed.as_bytes());
+            hasher.update(epoch.to_le_bytes());
+            hasher.update(address.as_bytes());
+            (hasher.finalize().to_vec(), address)
+        })
+        .collect();
+    shuffled.sort();
+    
+    let mut committees: Vec<Committee> = shard_ids
+        .iter()
+        .map(|&shard_id| Committee { shard_id, members: Vec::new() })
+        .collect();
+    for (i, (_, address)) in shuffled.into_iter().enumerate() {
+        committees[i % shard_ids.len()].members.push(address.clone());
     }
+    for committee in committees.iter_mut() {
+        committee.members.sort();
+    }
+    committees.sort_by_key(|committee| committee.shard_id);
+    
+    Ok(committees)
+}
+
+// Shard of each member of `committees`
+fn assignments(committees: &[Committee]) -> HashMap<String, u16> {
+    committees
+        .iter()
+        .flat_map(|committee| committee.members.iter().map(move |member| (member.clone(), committee.shard_id)))
+        .collect()
 }
 
 // Global sharding engine instance
@@ -317,12 +453,16 @@ lazy_static::lazy_static! {
 pub fn initialize() -> Result<(), Box<dyn std::error::Error>> {
     info!("Initializing sharding engine...");
     
-    let engine = ShardingEngine::new(
-        256,    // Maximum number of shards
-        3,      // Minimum validators per shard
-        0.3,    // Rebalance threshold (30% imbalance)
+    let mut engine = ShardingEngine::new(
+        256, // Maximum number of shards
+        0.3, // Rebalance threshold (30% imbalance)
     );
     
+    // The genesis shard exists already; create the others of the genesis
+    for shard_id in 1..genesis::get().shard_count {
+        engine.create_shard(format!("Shard {}", shard_id));
+    }
+    
     let mut sharding_engine = SHARDING_ENGINE.lock().unwrap();
     *sharding_engine = Some(engine);
     
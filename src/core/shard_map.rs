@@ -0,0 +1,343 @@
+use std::collections::BTreeSet;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use crate::core::encoding::{Canonical, Decoder, Encoder};
+
+// Which shard owns which account.
+//
+// An address is placed by its prefix: the first two bytes, big-endian, of
+// SHA-256("nexacore-shard" || address). The shard map splits the prefix space
+// into contiguous ranges, each owned by one shard. An account's shard only
+// changes when the range holding its prefix is handed to another shard, not
+// whenever a shard is added.
+//
+// Every shard keeps the map in the contract storage of the shard map account
+// under its version, so the map is committed by the state root. The genesis
+// map is version 1, and any change to it is recorded as a new version, which
+// makes moving accounts between shards explicit. Next to each version a
+// shard records the height from which it is in force and, until they are
+// done, the imports it still has to take from other shards (see `migration`).
+//
+// System accounts, at addresses below 0x…0100, are not placed by the map:
+// every shard has its own.
+
+/// System account holding every version of the shard map.
+pub const SHARD_MAP_ADDRESS: &str = "0x0000000000000000000000000000000000000004";
+
+const VERSION_PREFIX: &str = "version/";
+const ACTIVATION_PREFIX: &str = "activation/";
+const IMPORT_PREFIX: &str = "import/";
+
+/// Number of address prefixes.
+pub const PREFIX_SPACE: u32 = 1 << 16;
+
+// Prefixes from `start` up to the next range's start, or to the end of the
+// prefix space for the last range
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardRange {
+    pub start: u16,
+    pub shard_id: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardMap {
+    pub version: u32,
+    pub ranges: Vec<ShardRange>, // Ordered by start, the first starting at 0
+}
+
+impl ShardMap {
+    /// First version of a map that splits the prefix space into
+    /// `shard_count` equal ranges, in shard order.
+    pub fn uniform(shard_count: u16) -> Self {
+        let ranges = (0..shard_count)
+            .map(|shard_id| ShardRange {
+                start: (shard_id as u32 * PREFIX_SPACE / shard_count as u32) as u16,
+                shard_id,
+            })
+            .collect();
+
+        ShardMap { version: 1, ranges }
+    }
+
+    /// Checks that the ranges cover the prefix space, in order, and belong to
+    /// existing shards. Neighbouring ranges of the same shard must be merged.
+    pub fn validate(&self, shard_count: u16) -> Result<(), String> {
+        if self.ranges.first().map(|range| range.start) != Some(0) {
+            return Err(format!("Shard map version {} doesn't start at prefix 0", self.version));
+        }
+
+        for pair in self.ranges.windows(2) {
+            if pair[0].start >= pair[1].start {
+                return Err(format!("Shard map version {} has ranges out of order at prefix {}", self.version, pair[1].start));
+            }
+
+            if pair[0].shard_id == pair[1].shard_id {
+                return Err(format!("Shard map version {} splits a range of shard {} at prefix {}",
+                                   self.version, pair[1].shard_id, pair[1].start));
+            }
+        }
+
+        if let Some(range) = self.ranges.iter().find(|range| range.shard_id >= shard_count) {
+            return Err(format!("Shard map version {} assigns prefix {} to shard {} of {}",
+                               self.version, range.start, range.shard_id, shard_count));
+        }
+
+        Ok(())
+    }
+
+    /// Shard that owns `address`.
+    pub fn shard_of(&self, address: &str) -> u16 {
+        self.shard_of_prefix(address_prefix(address))
+    }
+
+    pub fn shard_of_prefix(&self, prefix: u16) -> u16 {
+        // The last range starting at or before the prefix
+        let end = self.ranges.partition_point(|range| range.start <= prefix);
+        self.ranges[..end].last().map_or(0, |range| range.shard_id)
+    }
+
+    /// Next version of the map, in which `new_shard_id` takes over half of
+    /// the prefixes of `shard_id`: the upper half of its ranges or, if it has
+    /// a single range, the upper half of that range.
+    pub fn split(&self, shard_id: u16, new_shard_id: u16) -> Result<ShardMap, String> {
+        if self.ranges.iter().any(|range| range.shard_id == new_shard_id) {
+            return Err(format!("Shard {} already owns addresses", new_shard_id));
+        }
+
+        let owned: Vec<usize> = (0..self.ranges.len()).filter(|&i| self.ranges[i].shard_id == shard_id).collect();
+        let mut ranges = self.ranges.clone();
+        match owned.as_slice() {
+            [] => return Err(format!("Shard {} owns no addresses", shard_id)),
+            [index] => {
+                let start = ranges[*index].start as u32;
+                let middle = start + (self.range_end(*index) - start) / 2;
+                if middle == start {
+                    return Err(format!("Shard {} owns a single prefix", shard_id));
+                }
+                ranges.insert(index + 1, ShardRange {
+                    start: middle as u16,
+                    shard_id: new_shard_id,
+                });
+            }
+            _ => {
+                for &index in &owned[owned.len() / 2..] {
+                    ranges[index].shard_id = new_shard_id;
+                }
+            }
+        }
+
+        Ok(ShardMap::merged_ranges(self.version + 1, ranges))
+    }
+
+    /// Next version of the map, in which `into` takes over every prefix of
+    /// `shard_id`.
+    pub fn merge(&self, shard_id: u16, into: u16) -> Result<ShardMap, String> {
+        if shard_id == into {
+            return Err(format!("Shard {} can't be merged into itself", shard_id));
+        }
+
+        let shards = self.shard_ids();
+        if !shards.contains(&shard_id) || !shards.contains(&into) {
+            return Err(format!("Shards {} and {} must both own addresses to merge", shard_id, into));
+        }
+
+        let ranges = self.ranges
+            .iter()
+            .map(|range| ShardRange {
+                start: range.start,
+                shard_id: if range.shard_id == shard_id { into } else { range.shard_id },
+            })
+            .collect();
+
+        Ok(ShardMap::merged_ranges(self.version + 1, ranges))
+    }
+
+    // Builds a map from ordered ranges, joining neighbours of the same shard
+    fn merged_ranges(version: u32, ranges: Vec<ShardRange>) -> ShardMap {
+        let mut merged: Vec<ShardRange> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            if merged.last().map(|last| last.shard_id) != Some(range.shard_id) {
+                merged.push(range);
+            }
+        }
+
+        ShardMap { version, ranges: merged }
+    }
+
+    // One past the last prefix of the range at `index`
+    fn range_end(&self, index: usize) -> u32 {
+        self.ranges.get(index + 1).map_or(PREFIX_SPACE, |next| next.start as u32)
+    }
+
+    /// Shards that own at least one range.
+    pub fn shard_ids(&self) -> BTreeSet<u16> {
+        self.ranges.iter().map(|range| range.shard_id).collect()
+    }
+
+    /// Shards that hand prefixes to `shard_id` when this map gives way to
+    /// `next`.
+    pub fn sources(&self, next: &ShardMap, shard_id: u16) -> BTreeSet<u16> {
+        // Both maps are constant between consecutive range starts of either
+        let starts: BTreeSet<u16> = self.ranges.iter().chain(&next.ranges).map(|range| range.start).collect();
+        starts
+            .into_iter()
+            .filter(|&start| next.shard_of_prefix(start) == shard_id)
+            .map(|start| self.shard_of_prefix(start))
+            .filter(|&source| source != shard_id)
+            .collect()
+    }
+
+    pub fn storage_key(&self) -> String {
+        version_key(self.version)
+    }
+}
+
+/// Prefix that places `address` in the shard map.
+pub fn address_prefix(address: &str) -> u16 {
+    let mut hasher = Sha256::new();
+    hasher.update(b"nexacore-shard");
+    hasher.update(address.as_bytes());
+    let digest = hasher.finalize();
+    u16::from_be_bytes([digest[0], digest[1]])
+}
+
+/// Whether `address` is a system account, which every shard keeps its own of.
+pub fn is_system_address(address: &str) -> bool {
+    match address.strip_prefix("0x") {
+        Some(hex) => hex.len() == 40 && hex.bytes().take(38).all(|b| b == b'0'),
+        None => false,
+    }
+}
+
+/// Key of a shard map version in the shard map account. Zero-padded so keys
+/// sort by version.
+pub fn version_key(version: u32) -> String {
+    format!("{}{:010}", VERSION_PREFIX, version)
+}
+
+pub fn is_version_key(key: &str) -> bool {
+    key.starts_with(VERSION_PREFIX)
+}
+
+/// Key, in the shard map account, of the first height at which a version is
+/// in force.
+pub fn activation_key(version: u32) -> String {
+    format!("{}{:010}", ACTIVATION_PREFIX, version)
+}
+
+/// Key, in the shard map account, of an import from `source` that a version
+/// still waits for.
+pub fn import_key(version: u32, source: u16) -> String {
+    format!("{}{:010}/{:05}", IMPORT_PREFIX, version, source)
+}
+
+pub fn is_import_key(key: &str) -> bool {
+    key.starts_with(IMPORT_PREFIX)
+}
+
+impl Canonical for ShardRange {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_u16(self.start);
+        encoder.put_u16(self.shard_id);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(ShardRange {
+            start: decoder.get_u16()?,
+            shard_id: decoder.get_u16()?,
+        })
+    }
+}
+
+impl Canonical for ShardMap {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_u32(self.version);
+        encoder.put_seq(&self.ranges);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(ShardMap {
+            version: decoder.get_u32()?,
+            ranges: decoder.get_seq()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Map with ranges of the given starts and shards
+    fn map(ranges: &[(u16, u16)]) -> ShardMap {
+        ShardMap {
+            version: 1,
+            ranges: ranges.iter().map(|&(start, shard_id)| ShardRange { start, shard_id }).collect(),
+        }
+    }
+
+    #[test]
+    fn prefixes_belong_to_the_range_holding_them() {
+        let shard_map = ShardMap::uniform(3);
+        assert_eq!(shard_map, map(&[(0, 0), (21845, 1), (43690, 2)]));
+        assert_eq!(shard_map.validate(3), Ok(()));
+
+        for (prefix, shard_id) in [(0, 0), (21844, 0), (21845, 1), (43689, 1), (43690, 2), (u16::MAX, 2)] {
+            assert_eq!(shard_map.shard_of_prefix(prefix), shard_id, "prefix {}", prefix);
+        }
+
+        // An address always lands in the same shard, whatever other shards exist
+        let address = "0x34750f98bd59fcfc946da45aaabe933be154a4b5";
+        assert_eq!(address_prefix(address), address_prefix(address));
+        assert_eq!(shard_map.shard_of(address), shard_map.shard_of_prefix(address_prefix(address)));
+        assert_eq!(ShardMap::uniform(1).shard_of(address), 0);
+    }
+
+    #[test]
+    fn malformed_maps_are_rejected() {
+        let invalid = [
+            (map(&[]), "doesn't start at prefix 0"),
+            (map(&[(1, 0), (100, 1)]), "doesn't start at prefix 0"),
+            (map(&[(0, 0), (100, 1), (100, 2)]), "out of order"),
+            (map(&[(0, 0), (100, 0)]), "splits a range"),
+            (map(&[(0, 0), (100, 3)]), "of 3"),
+        ];
+        for (shard_map, reason) in invalid {
+            let error = shard_map.validate(3).unwrap_err();
+            assert!(error.contains(reason), "{}", error);
+        }
+    }
+
+    #[test]
+    fn splits_and_merges_move_whole_ranges() {
+        let shard_map = ShardMap::uniform(2);
+
+        // A single range is halved
+        let split = shard_map.split(1, 2).unwrap();
+        assert_eq!(split.version, 2);
+        assert_eq!(split.ranges, map(&[(0, 0), (32768, 1), (49152, 2)]).ranges);
+        assert_eq!(shard_map.sources(&split, 2), BTreeSet::from([1]));
+        assert!(shard_map.sources(&split, 1).is_empty());
+        assert!(shard_map.split(1, 0).unwrap_err().contains("already owns"));
+        assert!(shard_map.split(5, 6).unwrap_err().contains("owns no addresses"));
+
+        // Merging joins neighbouring ranges of the same shard
+        let merged = split.merge(2, 0).unwrap();
+        assert_eq!(merged.ranges, map(&[(0, 0), (32768, 1), (49152, 0)]).ranges);
+        let merged = merged.merge(1, 0).unwrap();
+        assert_eq!(merged.ranges, map(&[(0, 0)]).ranges);
+        assert_eq!(merged.version, 4);
+        assert!(merged.merge(0, 0).unwrap_err().contains("into itself"));
+
+        // Several ranges are split by handing over the upper half of them
+        let scattered = map(&[(0, 0), (100, 1), (200, 0), (300, 1), (400, 0)]);
+        assert_eq!(scattered.split(0, 2).unwrap().ranges, map(&[(0, 0), (100, 1), (200, 2), (300, 1), (400, 2)]).ranges);
+    }
+
+    #[test]
+    fn system_addresses_are_not_placed() {
+        assert!(is_system_address(SHARD_MAP_ADDRESS));
+        assert!(is_system_address("0x00000000000000000000000000000000000000ff"));
+        assert!(!is_system_address("0x0000000000000000000000000000000000000100"));
+        assert!(!is_system_address("0x34750f98bd59fcfc946da45aaabe933be154a4b5"));
+    }
+}
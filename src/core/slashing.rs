@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use log::{info, warn, debug};
+use serde::{Serialize, Deserialize};
+use crate::core::beacon::BEACON_SHARD;
+use crate::core::block::{Block, BlockHeader};
+use crate::core::crypto;
+use crate::core::encoding::{self, Canonical, Decoder, Encoder};
+use crate::core::finality::{Vote, VoteType};
+use crate::core::genesis;
+use crate::core::transaction::{Transaction, TransactionType};
+
+// Slashing evidence and its collection.
+//
+// A validator equivocates by signing two different blocks at the same height
+// (double-signing) or casting two different votes of the same type at the
+// same height (double-voting). Either pair of signed messages proves the
+// offence on its own, so anyone can submit it in a `SlashingEvidence`
+// transaction. The evidence carries the offender's public key; its address
+// must match the signer of both messages.
+//
+// Penalties only act on the beacon state, which holds all stake and records
+// the validator sets: evidence of offences in any shard is submitted there,
+// and missed proposals are counted there. Reverting a block reverts its
+// penalties with the rest of its state changes.
+
+const OFFENCE_PREFIX: &str = "slashed/";
+
+// Penalty applied to a validator's account by a block. It shows in the
+// validator sets recorded from then on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Penalty {
+    pub validator: String,
+    pub stake_bps: u64,        // Fraction of stake burned
+    pub contribution_bps: u64, // Fraction of contribution score removed
+    pub jailed_until_epoch: u64,
+}
+
+// A block header with the validator's signature over its hash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedHeader {
+    pub header: BlockHeader,
+    pub signature: String,
+}
+
+impl SignedHeader {
+    pub fn from_block(block: &Block) -> Self {
+        SignedHeader {
+            header: block.header.clone(),
+            signature: block.signature.clone(),
+        }
+    }
+
+    pub fn hash(&self) -> String {
+        Block::calculate_hash(&self.header)
+    }
+
+    fn verify_signature(&self, public_key: &[u8]) -> bool {
+        match hex::decode(self.hash()) {
+            Ok(message) => crypto::verify(public_key, &message, &self.signature),
+            Err(_) => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SlashingEvidence {
+    DoubleSign {
+        public_key: String,
+        first: Box<SignedHeader>,
+        second: Box<SignedHeader>,
+    },
+    DoubleVote {
+        public_key: String,
+        first: Vote,
+        second: Vote,
+    },
+}
+
+impl SlashingEvidence {
+    pub fn offender(&self) -> &str {
+        match self {
+            SlashingEvidence::DoubleSign { first, .. } => &first.header.validator,
+            SlashingEvidence::DoubleVote { first, .. } => &first.validator,
+        }
+    }
+
+    /// Height at which the offence was committed.
+    pub fn height(&self) -> u64 {
+        match self {
+            SlashingEvidence::DoubleSign { first, .. } => first.header.height,
+            SlashingEvidence::DoubleVote { first, .. } => first.height,
+        }
+    }
+
+    /// Key, in the staking account, recording that the offence was punished.
+    /// Evidence of the same offence, that is of the same validator signing
+    /// the same kind of message in the same shard and height, has the same
+    /// key.
+    pub fn offence_key(&self) -> String {
+        let (shard_id, kind) = match self {
+            SlashingEvidence::DoubleSign { first, .. } => (first.header.shard_id, "block"),
+            SlashingEvidence::DoubleVote { first, .. } => match first.vote_type {
+                VoteType::Prevote => (first.shard_id, "prevote"),
+                VoteType::Precommit => (first.shard_id, "precommit"),
+            },
+        };
+        format!("{}{}/{:05}/{:020}/{}", OFFENCE_PREFIX, self.offender(), shard_id, self.height(), kind)
+    }
+
+    /// Checks that the evidence proves an offence: two conflicting messages
+    /// for the same shard and height, both signed by the offender's key.
+    pub fn verify(&self) -> Result<(), String> {
+        match self {
+            SlashingEvidence::DoubleSign { public_key, first, second } => {
+                let public_key = hex::decode(public_key).map_err(|e| format!("Invalid public key encoding: {}", e))?;
+                if crypto::public_key_to_address(&public_key) != first.header.validator ||
+                    first.header.validator != second.header.validator {
+                    return Err("Double-sign evidence headers are not from the evidence key".to_string());
+                }
+
+                if first.header.shard_id != second.header.shard_id || first.header.height != second.header.height {
+                    return Err("Double-sign evidence headers are not at the same shard and height".to_string());
+                }
+
+                if first.hash() == second.hash() {
+                    return Err("Double-sign evidence headers are identical".to_string());
+                }
+
+                if !first.verify_signature(&public_key) || !second.verify_signature(&public_key) {
+                    return Err("Invalid signature in double-sign evidence".to_string());
+                }
+
+                Ok(())
+            }
+            SlashingEvidence::DoubleVote { public_key, first, second } => {
+                let public_key = hex::decode(public_key).map_err(|e| format!("Invalid public key encoding: {}", e))?;
+
+                if first.validator != second.validator {
+                    return Err("Double-vote evidence votes are from different validators".to_string());
+                }
+
+                if first.vote_type != second.vote_type || first.shard_id != second.shard_id || first.height != second.height {
+                    return Err("Double-vote evidence votes are not for the same round".to_string());
+                }
+
+                if first.block_hash == second.block_hash {
+                    return Err("Double-vote evidence votes are for the same block".to_string());
+                }
+
+                if !first.verify_signature(&public_key) || !second.verify_signature(&public_key) {
+                    return Err("Invalid signature in double-vote evidence".to_string());
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Wraps the evidence in a transaction for the beacon shard, which holds
+    /// the offender's stake whatever shard the offence was committed in. It
+    /// spends nothing, so any node can submit it.
+    pub fn to_transaction(&self) -> Transaction {
+        Transaction::new(
+            TransactionType::SlashingEvidence,
+            Vec::new(),
+            Vec::new(),
+            BEACON_SHARD,
+            encoding::to_bytes(self),
+            0,
+        )
+    }
+
+    /// Reads the evidence carried by a `SlashingEvidence` transaction.
+    pub fn from_transaction(tx: &Transaction) -> Result<Self, String> {
+        if !matches!(tx.tx_type, TransactionType::SlashingEvidence) {
+            return Err(format!("Transaction {} is not slashing evidence", tx.hash));
+        }
+
+        encoding::from_bytes(&tx.data)
+    }
+}
+
+impl Canonical for SignedHeader {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.header.encode(encoder);
+        encoder.put_str(&self.signature);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(SignedHeader {
+            header: BlockHeader::decode(decoder)?,
+            signature: decoder.get_string()?,
+        })
+    }
+}
+
+impl Canonical for SlashingEvidence {
+    fn encode(&self, encoder: &mut Encoder) {
+        match self {
+            SlashingEvidence::DoubleSign { public_key, first, second } => {
+                encoder.put_u8(0);
+                encoder.put_str(public_key);
+                first.encode(encoder);
+                second.encode(encoder);
+            }
+            SlashingEvidence::DoubleVote { public_key, first, second } => {
+                encoder.put_u8(1);
+                encoder.put_str(public_key);
+                first.encode(encoder);
+                second.encode(encoder);
+            }
+        }
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        match decoder.get_u8()? {
+            0 => Ok(SlashingEvidence::DoubleSign {
+                public_key: decoder.get_string()?,
+                first: Box::new(SignedHeader::decode(decoder)?),
+                second: Box::new(SignedHeader::decode(decoder)?),
+            }),
+            1 => Ok(SlashingEvidence::DoubleVote {
+                public_key: decoder.get_string()?,
+                first: Vote::decode(decoder)?,
+                second: Vote::decode(decoder)?,
+            }),
+            tag => Err(format!("Unknown slashing evidence type {}", tag)),
+        }
+    }
+}
+
+// Evidence of equivocation seen by this node, waiting to be submitted
+#[derive(Debug)]
+pub struct EvidencePool {
+    shard_id: u16,
+    proposals: HashMap<(u64, String), SignedHeader>, // (height, validator) -> first block seen
+    pending: Vec<SlashingEvidence>,
+}
+
+impl EvidencePool {
+    pub fn new(shard_id: u16) -> Self {
+        EvidencePool {
+            shard_id,
+            proposals: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Remembers a validly signed block. Returns new evidence if its
+    /// validator already signed a different block at the same height.
+    pub fn observe_block(&mut self, block: &Block, public_key: &[u8]) -> Option<SlashingEvidence> {
+        let key = (block.header.height, block.header.validator.clone());
+
+        let first = match self.proposals.get(&key) {
+            Some(first) if first.hash() != block.hash => Box::new(first.clone()),
+            Some(_) => return None,
+            None => {
+                self.proposals.insert(key, SignedHeader::from_block(block));
+                return None;
+            }
+        };
+
+        warn!("Validator {} signed two blocks at height {} in shard {}",
+              block.header.validator, block.header.height, self.shard_id);
+        let evidence = SlashingEvidence::DoubleSign {
+            public_key: hex::encode(public_key),
+            first,
+            second: Box::new(SignedHeader::from_block(block)),
+        };
+
+        if self.add(evidence.clone()) {
+            Some(evidence)
+        } else {
+            None
+        }
+    }
+
+    /// Records evidence. Returns false if evidence of the same offence is
+    /// already pending.
+    pub fn add(&mut self, evidence: SlashingEvidence) -> bool {
+        let duplicate = self.pending
+            .iter()
+            .any(|pending| pending.offender() == evidence.offender() && pending.height() == evidence.height());
+
+        if duplicate {
+            return false;
+        }
+
+        debug!("Recorded slashing evidence against {} at height {}", evidence.offender(), evidence.height());
+        self.pending.push(evidence);
+        true
+    }
+
+
+    pub fn pending(&self) -> Vec<SlashingEvidence> {
+        self.pending.clone()
+    }
+
+    /// Drops evidence that was submitted in `block`.
+    pub fn remove_for_block(&mut self, block: &Block) {
+        for tx in &block.transactions {
+            if let Ok(evidence) = SlashingEvidence::from_transaction(tx) {
+                self.pending
+                    .retain(|pending| pending.offender() != evidence.offender() || pending.height() != evidence.height());
+            }
+        }
+    }
+
+    /// Forgets proposals at or below a finalized height; no new block can
+    /// conflict with them.
+    pub fn prune(&mut self, finalized_height: u64) {
+        self.proposals.retain(|(height, _), _| *height > finalized_height);
+    }
+}
+
+// Global evidence pool instances (one per shard)
+lazy_static::lazy_static! {
+    static ref EVIDENCE_POOLS: Arc<RwLock<HashMap<u16, Arc<Mutex<EvidencePool>>>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+pub fn initialize() -> Result<(), Box<dyn std::error::Error>> {
+    info!("Initializing slashing evidence pool...");
+
+    // Evidence pool for every shard of the genesis
+    for shard_id in 0..genesis::get().shard_count {
+        create_evidence_pool(shard_id)?;
+    }
+
+    info!("Slashing evidence pool initialized successfully");
+    Ok(())
+}
+
+pub fn shutdown() -> Result<(), Box<dyn std::error::Error>> {
+    info!("Shutting down slashing evidence pool...");
+
+    let mut pools = EVIDENCE_POOLS.write().unwrap();
+    pools.clear();
+
+    info!("Slashing evidence pool shutdown complete");
+    Ok(())
+}
+
+pub fn get_evidence_pool(shard_id: u16) -> Option<Arc<Mutex<EvidencePool>>> {
+    let pools = EVIDENCE_POOLS.read().unwrap();
+    pools.get(&shard_id).cloned()
+}
+
+pub fn create_evidence_pool(shard_id: u16) -> Result<(), Box<dyn std::error::Error>> {
+    let mut pools = EVIDENCE_POOLS.write().unwrap();
+
+    if pools.contains_key(&shard_id) {
+        return Err(format!("Evidence pool for shard {} already exists", shard_id).into());
+    }
+
+    pools.insert(shard_id, Arc::new(Mutex::new(EvidencePool::new(shard_id))));
+    Ok(())
+}
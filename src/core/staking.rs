@@ -0,0 +1,163 @@
+use serde::{Serialize, Deserialize};
+use crate::core::encoding::{self, Canonical, Decoder, Encoder};
+use crate::core::transaction::{Transaction, TransactionType};
+
+// Staking: bonding, delegation and unbonding.
+//
+// A `StakeDeposit` transaction bonds its first output to the validator at
+// that output's address. The output doesn't become a UTXO; its amount is
+// locked in the staking account and credited to the delegation of the owner
+// of the transaction's first input, whose key receives rewards and, later,
+// the unbonded funds. Bonding to oneself makes one a validator candidate;
+// anyone can then delegate to it.
+//
+// A `StakeWithdraw` transaction carries an `UnbondRequest` in its `data` and
+// is authorized by the owner of its first input. The stake leaves the
+// validator at once but is only paid back, as a new UTXO, after the
+// unbonding period, so it can still be slashed for offences found meanwhile.
+//
+// Delegations and unbonding entries are kept in the contract storage of the
+// staking account, so they are committed by the state root and journaled
+// like any other account state. Stake is only kept in the beacon shard:
+// staking transactions are rejected everywhere else, so every shard's
+// committee is drawn from one complete validator set. Changes of stake count
+// from the validator set the beacon state records next, two epochs ahead.
+
+/// System account holding all bonded and unbonding funds.
+pub const STAKING_ADDRESS: &str = "0x0000000000000000000000000000000000000001";
+
+const DELEGATION_PREFIX: &str = "delegation/";
+const UNBONDING_PREFIX: &str = "unbonding/";
+
+// Stake bonded by a delegator to a validator. A validator's self-bond is the
+// delegation where both are the same address.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Delegation {
+    pub delegator: String,
+    pub validator: String,
+    pub amount: u64,
+    pub public_key: String, // Hex public key rewards and unbonded funds are paid to
+}
+
+impl Delegation {
+    pub fn storage_key(&self) -> String {
+        delegation_key(&self.validator, &self.delegator)
+    }
+}
+
+// Stake on its way back to a delegator
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Unbonding {
+    pub delegator: String,
+    pub validator: String,
+    pub amount: u64,
+    pub public_key: String,
+    pub release_height: u64, // First block that pays it back
+}
+
+impl Unbonding {
+    pub fn storage_key(&self) -> String {
+        // Zero-padded so keys sort by release height
+        format!("{}{:020}/{}/{}", UNBONDING_PREFIX, self.release_height, self.delegator, self.validator)
+    }
+
+    /// Hash of the UTXO that pays the entry back.
+    pub fn payout_hash(&self) -> String {
+        encoding::hash(self)
+    }
+}
+
+// Data of a `StakeWithdraw` transaction
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UnbondRequest {
+    pub validator: String,
+    pub amount: u64,
+}
+
+impl UnbondRequest {
+    pub fn from_transaction(tx: &Transaction) -> Result<Self, String> {
+        if !matches!(tx.tx_type, TransactionType::StakeWithdraw) {
+            return Err(format!("Transaction {} is not a stake withdrawal", tx.hash));
+        }
+
+        encoding::from_bytes(&tx.data)
+    }
+}
+
+// Stake bonded to a validator, which validator sets are recorded from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValidatorStake {
+    pub validator: String,
+    pub public_key: String, // From the validator's self-bond
+    pub delegations: Vec<Delegation>,
+}
+
+impl ValidatorStake {
+    pub fn total(&self) -> u64 {
+        self.delegations.iter().map(|delegation| delegation.amount).sum()
+    }
+}
+
+pub fn delegation_key(validator: &str, delegator: &str) -> String {
+    format!("{}{}/{}", DELEGATION_PREFIX, validator, delegator)
+}
+
+pub fn is_delegation_key(key: &str) -> bool {
+    key.starts_with(DELEGATION_PREFIX)
+}
+
+pub fn is_unbonding_key(key: &str) -> bool {
+    key.starts_with(UNBONDING_PREFIX)
+}
+
+impl Canonical for Delegation {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_str(&self.delegator);
+        encoder.put_str(&self.validator);
+        encoder.put_u64(self.amount);
+        encoder.put_str(&self.public_key);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(Delegation {
+            delegator: decoder.get_string()?,
+            validator: decoder.get_string()?,
+            amount: decoder.get_u64()?,
+            public_key: decoder.get_string()?,
+        })
+    }
+}
+
+impl Canonical for Unbonding {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_str(&self.delegator);
+        encoder.put_str(&self.validator);
+        encoder.put_u64(self.amount);
+        encoder.put_str(&self.public_key);
+        encoder.put_u64(self.release_height);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(Unbonding {
+            delegator: decoder.get_string()?,
+            validator: decoder.get_string()?,
+            amount: decoder.get_u64()?,
+            public_key: decoder.get_string()?,
+            release_height: decoder.get_u64()?,
+        })
+    }
+}
+
+impl Canonical for UnbondRequest {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_str(&self.validator);
+        encoder.put_u64(self.amount);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(UnbondRequest {
+            validator: decoder.get_string()?,
+            amount: decoder.get_u64()?,
+        })
+    }
+}
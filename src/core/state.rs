@@ -1,9 +1,27 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, RwLock};
 use log::{info, warn, error, debug};
 use serde::{Serialize, Deserialize};
-use crate::core::block::Block;
-use crate::core::transaction::{Transaction, TransactionOutput};
+use crate::core::beacon::{self, Crosslink, FinalityProof, BEACON_ADDRESS, BEACON_SHARD};
+use crate::core::block::{Block, BlockHeader};
+use crate::core::consensus::{ValidatorInfo, ValidatorSet, VALIDATOR_SET_LOOKAHEAD};
+use crate::core::contribution::{self, Attestation, ContributionReport, CONTRIBUTION_ADDRESS};
+use crate::core::crypto;
+use crate::core::cross_shard::{self, ClaimRecord, CrossShardAction, Receipt, ReceiptProof, CROSS_SHARD_ADDRESS};
+use crate::core::encoding::{self, Canonical, Decoder, Encoder};
+use crate::core::genesis::{self, Genesis};
+use crate::core::merkle::MerkleProof;
+use crate::core::migration::{self, Handover, MigrationAction, MigrationKind, MigrationProposal, StorageEntry};
+use crate::core::params::{self, BPS_SCALE};
+use crate::core::randao;
+use crate::core::rewards;
+use crate::core::shard_map::{self, ShardMap, SHARD_MAP_ADDRESS};
+use crate::core::slashing::{Penalty, SlashingEvidence};
+use crate::core::staking::{self, Delegation, UnbondRequest, Unbonding, ValidatorStake, STAKING_ADDRESS};
+use crate::core::state_tree::{self, AccountProof, SparseMerkleProof, StateTree, StorageProof, StorageSlotProof};
+use crate::core::storage::{ChainStore, StateChanges};
+use crate::core::transaction::{Transaction, TransactionInput, TransactionOutput};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Account {
@@ -15,6 +33,8 @@ pub struct Account {
     pub stake_amount: u64,      // Amount staked for validation
     pub contribution_score: u32, // Contribution score for APoC
     pub last_updated: u64,      // Timestamp of last update
+    pub jailed_until_epoch: u64, // Validator is excluded from the active set before this epoch
+    pub missed_proposals: u32,  // Proposals missed in the current epoch
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,6 +43,7 @@ pub struct UTXO {
     pub output_index: u32,
     pub amount: u64,
     pub owner: String,
+    pub script_pubkey: String, // Hex-encoded public key allowed to spend this output
     pub is_spent: bool,
     pub created_at: u64,
     pub spent_at: Option<u64>,
@@ -36,15 +57,46 @@ pub struct StateManager {
     // UTXO-based state (for regular transactions)
     utxos: HashMap<String, UTXO>, // Key: tx_hash:output_index
     
-    // Block metadata
+    // Block tree: metadata of every known block, on the best chain or not
     blocks: HashMap<String, BlockMetadata>, // Key: block_hash
     
+    // Bodies of known blocks that aren't in storage (side branches, and every
+    // block when the state is kept in memory)
+    branch_blocks: HashMap<String, Block>,
+    
     // Chain state
     current_height: u64,
     best_block_hash: String,
     
+    // Last finalized block; it and its ancestors are never reverted
+    finalized_height: u64,
+    finalized_hash: String,
+    
     // Shard-specific state
     shard_id: u16,
+    
+    // Persistent storage (None for in-memory state)
+    store: Option<Arc<ChainStore>>,
+    
+    // Pre-images of the entries touched by the block being applied
+    journal: StateChanges,
+    
+    // Undo journals of the blocks on the best chain, by block hash
+    undo_journals: HashMap<String, StateChanges>,
+    
+    // Authenticated tree over the state, and the entries changed since it
+    // was last brought up to date
+    tree: StateTree,
+    stale: StaleEntries,
+}
+
+// Entries changed since the state tree was last updated
+#[derive(Debug, Default)]
+struct StaleEntries {
+    accounts: HashSet<String>,
+    storage: HashSet<(String, String)>, // (address, storage key)
+    rebuilt: HashSet<String>,           // Accounts whose storage may have changed as a whole
+    utxos: HashSet<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -55,6 +107,74 @@ pub struct BlockMetadata {
     pub tx_count: usize,
     pub size: usize,
     pub validator: String,
+    pub parent_hash: String,
+    pub weight: u64,            // Validator weight of the proposer
+    pub cumulative_weight: u64, // Total weight of the chain ending at this block
+}
+
+// Reason a block could not be applied. The state is left exactly as it was
+// before the block.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockApplyError {
+    NotOnTip {
+        block_hash: String,
+        best_block_hash: String,
+    },
+    Coinbase {
+        block_hash: String,
+        reason: String,
+    },
+    Transaction {
+        block_hash: String,
+        tx_index: usize,
+        tx_hash: String,
+        reason: String,
+    },
+    StateRootMismatch {
+        block_hash: String,
+        header: String,
+        computed: String,
+    },
+    Storage {
+        block_hash: String,
+        reason: String,
+    },
+}
+
+impl std::fmt::Display for BlockApplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockApplyError::NotOnTip { block_hash, best_block_hash } => {
+                write!(f, "Block {} does not extend the best block {}", block_hash, best_block_hash)
+            }
+            BlockApplyError::Coinbase { block_hash, reason } => {
+                write!(f, "Coinbase of block {} is invalid: {}", block_hash, reason)
+            }
+            BlockApplyError::Transaction { block_hash, tx_index, tx_hash, reason } => {
+                write!(f, "Transaction {} ({}) in block {} failed: {}", tx_index, tx_hash, block_hash, reason)
+            }
+            BlockApplyError::StateRootMismatch { block_hash, header, computed } => {
+                write!(f, "Block {} commits to state root {} but applying it gives {}", block_hash, header, computed)
+            }
+            BlockApplyError::Storage { block_hash, reason } => {
+                write!(f, "Failed to persist block {}: {}", block_hash, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BlockApplyError {}
+
+// Outcome of importing a block into the block tree
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportResult {
+    AlreadyKnown,
+    Extended,  // Block was applied on top of the best chain
+    SideChain, // Block was stored on a branch that isn't heavier than the best chain
+    Reorganized {
+        reverted: Vec<String>,
+        applied: Vec<String>,
+    },
 }
 
 impl StateManager {
@@ -63,318 +183,2511 @@ impl StateManager {
             accounts: HashMap::new(),
             utxos: HashMap::new(),
             blocks: HashMap::new(),
+            branch_blocks: HashMap::new(),
             current_height: 0,
             best_block_hash: String::new(),
+            finalized_height: 0,
+            finalized_hash: String::new(),
             shard_id,
+            store: None,
+            journal: StateChanges::default(),
+            undo_journals: HashMap::new(),
+            tree: StateTree::default(),
+            stale: StaleEntries::default(),
         }
     }
     
-    pub fn apply_block(&mut self, block: &Block) -> Result<(), String> {
-        debug!("Applying block {} to state", block.hash);
+    /// Opens a state manager backed by `store`, resuming from the last
+    /// persisted block if there is one. A new database starts from the
+    /// genesis state; an existing one must come from the same genesis.
+    pub fn open(shard_id: u16, store: Arc<ChainStore>, genesis: &Genesis) -> Result<Self, String> {
+        let mut state_manager = StateManager::new(shard_id);
         
-        // Create block metadata
-        let metadata = BlockMetadata {
-            hash: block.hash.clone(),
-            height: self.current_height + 1,
-            timestamp: block.header.timestamp,
-            tx_count: block.transactions.len(),
-            size: serde_json::to_string(block).unwrap().len(),
-            validator: block.header.validator.clone(),
-        };
+        state_manager.accounts = store.load_accounts()?;
+        state_manager.utxos = store.load_utxos()?;
+        state_manager.blocks = store.load_block_metadata()?;
+        state_manager.undo_journals = store.load_undo_journals()?;
+        state_manager.tree = StateTree::new(&state_manager.accounts, &state_manager.utxos);
         
-        // Apply each transaction
-        for tx in &block.transactions {
-            self.apply_transaction(tx)?;
+        // A shard created by a split starts below its activation epoch
+        match store.load_chain_tip()? {
+            Some((height, best_block_hash)) => {
+                state_manager.current_height = height;
+                state_manager.best_block_hash = best_block_hash;
+            }
+            None => state_manager.current_height = state_manager.activation_height().saturating_sub(1),
         }
         
-        // Update block metadata
-        self.blocks.insert(block.hash.clone(), metadata);
+        if let Some((height, finalized_hash)) = store.load_finalized()? {
+            state_manager.finalized_height = height;
+            state_manager.finalized_hash = finalized_hash;
+        }
         
-        // Update chain state
-        self.current_height += 1;
-        self.best_block_hash = block.hash.clone();
+        let genesis_hash = genesis.hash();
+        match store.load_genesis_hash()? {
+            Some(stored) if stored != genesis_hash => {
+                return Err(format!("Database of shard {} was created from genesis {}, not {}", shard_id, stored, genesis_hash));
+            }
+            Some(_) => {}
+            None if state_manager.best_block_hash.is_empty() => {
+                state_manager.store = Some(store.clone());
+                state_manager.apply_genesis(genesis)?;
+            }
+            None => return Err(format!("Database of shard {} has blocks but no genesis hash", shard_id)),
+        }
         
-        debug!("Block {} applied successfully, new height: {}", block.hash, self.current_height);
-        Ok(())
+        info!("Loaded state for shard {} at height {} ({} accounts, {} UTXOs)", 
+              shard_id, state_manager.current_height, state_manager.accounts.len(), state_manager.utxos.len());
+        
+        state_manager.store = Some(store);
+        Ok(state_manager)
     }
     
-    pub fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), String> {
-        debug!("Applying transaction {} to state", tx.hash);
-        
-        // Check if transaction belongs to this shard
-        if tx.shard_id != self.shard_id {
-            return Err(format!("Transaction {} belongs to shard {}, not {}", 
-                              tx.hash, tx.shard_id, self.shard_id));
+    /// Creates the genesis state of this shard: records the genesis shard
+    /// map, funds the genesis accounts the map gives this shard and, in the
+    /// beacon shard, bonds the stake of the genesis validators and records
+    /// the validator sets of the first epochs. Only possible before the first
+    /// block.
+    pub fn apply_genesis(&mut self, genesis: &Genesis) -> Result<(), String> {
+        if !self.best_block_hash.is_empty() {
+            return Err(format!("Shard {} already has blocks", self.shard_id));
         }
         
-        // Mark inputs as spent
-        for input in &tx.inputs {
-            let utxo_key = format!("{}:{}", input.previous_tx, input.index);
-            
-            if let Some(utxo) = self.utxos.get_mut(&utxo_key) {
-                if utxo.is_spent {
-                    return Err(format!("UTXO {}:{} is already spent", input.previous_tx, input.index));
-                }
-                
-                utxo.is_spent = true;
-                utxo.spent_at = Some(tx.timestamp);
-            } else {
-                return Err(format!("UTXO {}:{} not found", input.previous_tx, input.index));
-            }
-        }
+        let timestamp = genesis.timestamp;
+        let shard_id = self.shard_id;
+        let shard_map = genesis.shard_map();
+        self.set_storage(SHARD_MAP_ADDRESS, &shard_map.storage_key(), Some(encoding::to_bytes(&shard_map)), timestamp);
         
-        // Create new UTXOs for outputs
-        for (i, output) in tx.outputs.iter().enumerate() {
-            let utxo_key = format!("{}:{}", tx.hash, i);
+        for account in &genesis.accounts {
+            let address = account.address()?;
+            if shard_map.shard_of(&address) != shard_id {
+                continue;
+            }
             
-            let utxo = UTXO {
-                tx_hash: tx.hash.clone(),
-                output_index: i as u32,
-                amount: output.amount,
-                owner: output.address.clone(),
+            let tx_hash = genesis.funding_hash(&address);
+            self.insert_utxo(format!("{}:0", tx_hash), UTXO {
+                tx_hash,
+                output_index: 0,
+                amount: account.balance,
+                owner: address.clone(),
+                script_pubkey: account.public_key.clone(),
                 is_spent: false,
-                created_at: tx.timestamp,
+                created_at: timestamp,
                 spent_at: None,
-            };
-            
-            self.utxos.insert(utxo_key, utxo);
-            
-            // Update account balance
-            self.update_account_balance(&output.address, output.amount, true)?;
+            });
+            self.update_account_balance(&address, account.balance, true, timestamp)?;
         }
         
-        // Handle special transaction types
-        match tx.tx_type {
-            crate::core::transaction::TransactionType::SmartContract => {
-                // Deploy or call smart contract
-                self.handle_smart_contract(tx)?;
-            },
-            crate::core::transaction::TransactionType::StakeDeposit => {
-                // Handle staking
-                self.handle_stake_deposit(tx)?;
-            },
-            crate::core::transaction::TransactionType::StakeWithdraw => {
-                // Handle unstaking
-                self.handle_stake_withdraw(tx)?;
-            },
-            crate::core::transaction::TransactionType::ContributionReport => {
-                // Update contribution score
-                self.handle_contribution_report(tx)?;
-            },
-            _ => {
-                // Regular transfer transaction, already handled above
+        if shard_id == BEACON_SHARD {
+            let mut computation_power = HashMap::new();
+            for validator in &genesis.validators {
+                let address = validator.address()?;
+                let delegation = Delegation {
+                    delegator: address.clone(),
+                    validator: address.clone(),
+                    amount: validator.stake,
+                    public_key: validator.public_key.clone(),
+                };
+                self.set_staking_entry(&delegation.storage_key(), Some(encoding::to_bytes(&delegation)), timestamp);
+                self.update_account_balance(STAKING_ADDRESS, validator.stake, true, timestamp)?;
+                self.account_mut(&address, timestamp).stake_amount += validator.stake;
+                computation_power.insert(address, validator.computation_power);
+            }
+            
+            for epoch in 0..VALIDATOR_SET_LOOKAHEAD {
+                self.record_validator_set(epoch, randao::INITIAL_MIX, &computation_power, &shard_map, timestamp);
             }
         }
         
-        debug!("Transaction {} applied successfully", tx.hash);
+        self.update_tree();
+        let touched = std::mem::take(&mut self.journal);
+        if let Some(store) = &self.store {
+            store.write_genesis(&genesis.hash(), &self.current_values(&touched))?;
+        }
+        
+        info!("Created genesis state of shard {} with root {}", self.shard_id, self.state_root());
         Ok(())
     }
     
-    fn update_account_balance(&mut self, address: &str, amount: u64, is_credit: bool) -> Result<(), String> {
-        let account = self.accounts.entry(address.to_string())
-            .or_insert_with(|| Account {
-                address: address.to_string(),
-                balance: 0,
-                nonce: 0,
-                code: Vec::new(),
-                storage: HashMap::new(),
-                stake_amount: 0,
-                contribution_score: 0,
-                last_updated: std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .expect("Time went backwards")
-                    .as_secs(),
-            });
+    /// Adds a block to the block tree and switches to its branch if that
+    /// branch is now the heaviest. `weight` is the proposer's validator weight.
+    pub fn import_block(&mut self, block: &Block, weight: u64) -> Result<ImportResult, String> {
+        if self.blocks.contains_key(&block.hash) {
+            return Ok(ImportResult::AlreadyKnown);
+        }
         
-        if is_credit {
-            account.balance += amount;
-        } else {
-            if account.balance < amount {
-                return Err(format!("Insufficient balance for account {}", address));
-            }
-            account.balance -= amount;
+        if self.best_block_hash.is_empty() || block.header.previous_hash == self.best_block_hash {
+            self.apply_block(block, weight).map_err(|e| e.to_string())?;
+            return Ok(ImportResult::Extended);
         }
         
-        account.last_updated = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs();
+        let parent = match self.blocks.get(&block.header.previous_hash) {
+            Some(parent) => parent.clone(),
+            None => return Err(format!("Parent {} of block {} is unknown", block.header.previous_hash, block.hash)),
+        };
         
-        Ok(())
+        // A branch forking off below the finalized block can never become best
+        if !self.descends_from_finalized(&parent.hash) {
+            return Err(format!("Block {} conflicts with finalized block {}", block.hash, self.finalized_hash));
+        }
+        
+        let metadata = self.block_metadata(block, &parent, weight);
+        let cumulative_weight = metadata.cumulative_weight;
+        
+        self.blocks.insert(block.hash.clone(), metadata);
+        self.branch_blocks.insert(block.hash.clone(), block.clone());
+        
+        // On equal weight the branch seen first is kept
+        if cumulative_weight <= self.best_cumulative_weight() {
+            debug!("Stored block {} on a side branch", block.hash);
+            return Ok(ImportResult::SideChain);
+        }
+        
+        self.reorganize(&block.hash)
     }
     
-    fn handle_smart_contract(&mut self, tx: &Transaction) -> Result<(), String> {
-        // In a real implementation, this would:
-        // 1. Parse the contract code from tx.data
-        // 2. Validate the code
-        // 3. Deploy the contract or execute the function call
-        // 4. Update the state accordingly
+    /// Applies a block on top of the current best block. Either every
+    /// transaction is applied and the block persisted, or the state is left
+    /// untouched.
+    pub fn apply_block(&mut self, block: &Block, weight: u64) -> Result<(), BlockApplyError> {
+        debug!("Applying block {} to state", block.hash);
         
-        // For this example, we'll just store the contract code in the account
-        if !tx.outputs.is_empty() {
-            let contract_address = &tx.outputs[0].address;
-            
-            let account = self.accounts.entry(contract_address.to_string())
-                .or_insert_with(|| Account {
-                    address: contract_address.to_string(),
-                    balance: 0,
-                    nonce: 0,
-                    code: Vec::new(),
-                    storage: HashMap::new(),
-                    stake_amount: 0,
-                    contribution_score: 0,
-                    last_updated: tx.timestamp,
+        let metadata = match self.blocks.get(&self.best_block_hash) {
+            Some(tip) => {
+                if block.header.previous_hash != self.best_block_hash {
+                    return Err(BlockApplyError::NotOnTip {
+                        block_hash: block.hash.clone(),
+                        best_block_hash: self.best_block_hash.clone(),
+                    });
+                }
+                let tip = tip.clone();
+                self.block_metadata(block, &tip, weight)
+            }
+            // First block of the chain
+            None => BlockMetadata {
+                hash: block.hash.clone(),
+                height: block.header.height,
+                timestamp: block.header.timestamp,
+                tx_count: block.transactions.len(),
+                size: encoding::to_bytes(block).len(),
+                validator: block.header.validator.clone(),
+                parent_hash: block.header.previous_hash.clone(),
+                weight,
+                cumulative_weight: weight,
+            },
+        };
+        
+        // Pay out the block reward and fees, then apply each other
+        // transaction, journaling what they touch so a failure part way
+        // through can be undone
+        if let Err(reason) = self.apply_coinbase(&block.transactions, block.header.height) {
+            self.rollback_journal();
+            return Err(BlockApplyError::Coinbase {
+                block_hash: block.hash.clone(),
+                reason,
+            });
+        }
+        
+        for (tx_index, tx) in block.transactions.iter().enumerate().skip(1) {
+            if let Err(reason) = self.apply_transaction(tx) {
+                self.rollback_journal();
+                return Err(BlockApplyError::Transaction {
+                    block_hash: block.hash.clone(),
+                    tx_index,
+                    tx_hash: tx.hash.clone(),
+                    reason,
                 });
-            
-            account.code = tx.data.clone();
-            account.last_updated = tx.timestamp;
-            
-            debug!("Deployed smart contract to address {}", contract_address);
+            }
+        }
+        
+        self.finish_block(&block.header);
+        
+        // The block must commit to the state it produces
+        let state_root = self.state_root();
+        if block.header.state_root != state_root {
+            self.rollback_journal();
+            return Err(BlockApplyError::StateRootMismatch {
+                block_hash: block.hash.clone(),
+                header: block.header.state_root.clone(),
+                computed: state_root,
+            });
         }
         
+        // Write the block and everything it touched through to storage
+        let undo = std::mem::take(&mut self.journal);
+        if let Err(reason) = self.persist_block(block, &metadata, &undo) {
+            self.restore(&undo);
+            return Err(BlockApplyError::Storage {
+                block_hash: block.hash.clone(),
+                reason,
+            });
+        }
+        
+        self.undo_journals.insert(block.hash.clone(), undo);
+        if self.store.is_some() {
+            self.branch_blocks.remove(&block.hash);
+        } else {
+            self.branch_blocks.insert(block.hash.clone(), block.clone());
+        }
+        
+        // Update chain state
+        self.current_height = metadata.height;
+        self.best_block_hash = block.hash.clone();
+        self.blocks.insert(block.hash.clone(), metadata);
+        
+        debug!("Block {} applied successfully, new height: {}", block.hash, self.current_height);
         Ok(())
     }
     
-    fn handle_stake_deposit(&mut self, tx: &Transaction) -> Result<(), String> {
-        if tx.outputs.is_empty() {
-            return Err("Stake deposit transaction has no outputs".to_string());
+    /// Applies the coinbase of a block made of `transactions`: checks that it
+    /// pays out exactly the reward for `height` plus the fees of the other
+    /// transactions, and creates its outputs. Block producers call it on
+    /// their scratch state before applying the other transactions.
+    pub fn apply_coinbase(&mut self, transactions: &[Transaction], height: u64) -> Result<(), String> {
+        let result = self.pay_coinbase(transactions, height);
+        self.update_tree();
+        result
+    }
+    
+    fn pay_coinbase(&mut self, transactions: &[Transaction], height: u64) -> Result<(), String> {
+        let coinbase = match transactions.first() {
+            Some(tx) if matches!(tx.tx_type, crate::core::transaction::TransactionType::Coinbase) => tx,
+            _ => return Err("block does not start with a coinbase".to_string()),
+        };
+        
+        if !coinbase.is_valid() || coinbase.shard_id != self.shard_id {
+            return Err(format!("coinbase {} is malformed", coinbase.hash));
         }
         
-        let staker_address = &tx.outputs[0].address;
-        let stake_amount = tx.outputs[0].amount;
+        if coinbase.data != height.to_le_bytes() {
+            return Err(format!("coinbase {} is not for height {}", coinbase.hash, height));
+        }
         
-        let account = self.accounts.entry(staker_address.to_string())
-            .or_insert_with(|| Account {
-                address: staker_address.to_string(),
-                balance: 0,
-                nonce: 0,
-                code: Vec::new(),
-                storage: HashMap::new(),
-                stake_amount: 0,
-                contribution_score: 0,
-                last_updated: tx.timestamp,
-            });
+        let fees = transactions[1..]
+            .iter()
+            .try_fold(0u64, |total, tx| total.checked_add(tx.fee()?))
+            .ok_or_else(|| "block fees overflow".to_string())?;
+        let expected = rewards::block_reward(&params::get(), height)
+            .checked_add(fees)
+            .ok_or_else(|| "block reward overflows".to_string())?;
+        let paid = coinbase.total_output()
+            .ok_or_else(|| format!("coinbase {} outputs overflow", coinbase.hash))?;
+        if paid != expected {
+            return Err(format!("coinbase {} pays {} instead of {}", coinbase.hash, paid, expected));
+        }
         
-        account.stake_amount += stake_amount;
-        account.last_updated = tx.timestamp;
+        self.create_outputs(coinbase)?;
         
-        debug!("Added stake of {} for account {}", stake_amount, staker_address);
+        debug!("Coinbase {} paid out {} ({} in fees)", coinbase.hash, paid, fees);
         Ok(())
     }
     
-    fn handle_stake_withdraw(&mut self, tx: &Transaction) -> Result<(), String> {
-        if tx.inputs.is_empty() {
-            return Err("Stake withdraw transaction has no inputs".to_string());
+    /// Applies the rules that run after a block's transactions, on top of its
+    /// parent. They only concern the beacon state: payout of unbonded stake,
+    /// proposal liveness tracking and, on the last block of an epoch,
+    /// downtime penalties, the decay of contribution scores, the decision on
+    /// the shard migrations approved in the epoch and the recording of the
+    /// validator set of the epoch after next. Block producers call it
+    /// on their scratch state before computing the state root.
+    pub fn finish_block(&mut self, header: &BlockHeader) {
+        self.end_block(header);
+        self.update_tree();
+    }
+    
+    fn end_block(&mut self, header: &BlockHeader) {
+        // Stake, contribution scores and liveness are only kept in the beacon
+        // state, where penalties take effect
+        if self.shard_id != BEACON_SHARD {
+            return;
         }
         
-        // Assume the first input is from the staker
-        let staker_address = tx.inputs[0].script_sig.clone(); // In a real implementation, this would be properly extracted
-        let withdraw_amount = tx.inputs[0].amount;
+        let params = params::get();
+        self.release_unbonded(header.height, header.timestamp);
         
-        if let Some(account) = self.accounts.get_mut(&staker_address) {
-            if account.stake_amount < withdraw_amount {
-                return Err(format!("Insufficient stake for account {}", staker_address));
-            }
-            
-            account.stake_amount -= withdraw_amount;
-            account.last_updated = tx.timestamp;
-            
-            debug!("Withdrew stake of {} for account {}", withdraw_amount, staker_address);
-            Ok(())
-        } else {
-            Err(format!("Account {} not found", staker_address))
+        // Every proposer that let its turn pass before this block missed a
+        // proposal
+        for proposer in &header.missed_proposers {
+            let account = self.account_mut(proposer, header.timestamp);
+            account.missed_proposals = account.missed_proposals.saturating_add(1);
+            debug!("Validator {} missed its turn before block {}", proposer, header.height);
         }
-    }
-    
-    fn handle_contribution_report(&mut self, tx: &Transaction) -> Result<(), String> {
-        // Parse contribution data from tx.data
-        // In a real implementation, this would validate the contribution proof
         
-        if tx.inputs.is_empty() {
-            return Err("Contribution report transaction has no inputs".to_string());
+        if !(header.height + 1).is_multiple_of(params.epoch_length) {
+            return;
         }
         
-        let contributor_address = tx.inputs[0].script_sig.clone(); // In a real implementation, this would be properly extracted
+        // Epoch ends: penalize validators that missed too many proposals and
+        // start counting afresh
+        let missing: Vec<(String, u32)> = self.accounts
+            .values()
+            .filter(|account| account.missed_proposals > 0)
+            .map(|account| (account.address.clone(), account.missed_proposals))
+            .collect();
         
-        // Simple parsing of contribution score from data
-        if tx.data.len() < 4 {
-            return Err("Invalid contribution data".to_string());
+        let jailed_until_epoch = params.epoch_at(header.height) + 1 + params.slashing.downtime_jail_epochs;
+        for (address, missed) in missing {
+            if let Some(account) = self.existing_account_mut(&address) {
+                account.missed_proposals = 0;
+            }
+            
+            if missed > params.slashing.max_missed_proposals {
+                info!("Penalizing validator {} for missing {} proposals", address, missed);
+                self.penalize(Penalty {
+                    validator: address,
+                    stake_bps: params.slashing.downtime_stake_bps,
+                    contribution_bps: params.slashing.downtime_contribution_bps,
+                    jailed_until_epoch,
+                }, header.timestamp);
+            }
         }
         
-        let contribution_score = u32::from_le_bytes([
-            tx.data[0], tx.data[1], tx.data[2], tx.data[3]
-        ]);
-        
-        let account = self.accounts.entry(contributor_address.clone())
-            .or_insert_with(|| Account {
-                address: contributor_address.clone(),
-                balance: 0,
-                nonce: 0,
-                code: Vec::new(),
-                storage: HashMap::new(),
-                stake_amount: 0,
-                contribution_score: 0,
-                last_updated: tx.timestamp,
-            });
+        let ending = params.epoch_at(header.height);
+        self.decay_contributions(ending, header.timestamp);
         
-        account.contribution_score += contribution_score;
-        account.last_updated = tx.timestamp;
+        // The set of the epoch after next gets the shard map of the next
+        // epoch's set, with the migration approved in this epoch applied
+        let next_set = self.validator_set(ending + 1);
+        let next_map = next_set.as_ref().map(|set| set.shard_map.clone()).unwrap_or_else(|| self.shard_map());
+        let shard_map = match self.approved_migration(ending) {
+            Some(proposal) => match proposal.kind.apply(&next_map) {
+                Ok(shard_map) => {
+                    info!("Approved {:?}, active from epoch {}", proposal.kind, ending + VALIDATOR_SET_LOOKAHEAD);
+                    shard_map
+                }
+                Err(e) => {
+                    warn!("Dropping approved {:?}: {}", proposal.kind, e);
+                    next_map
+                }
+            },
+            None => next_map,
+        };
+        self.clear_approvals(ending, header.timestamp);
         
-        debug!("Updated contribution score for account {}: +{}", contributor_address, contribution_score);
-        Ok(())
+        let computation_power: HashMap<String, u32> = next_set
+            .map(|set| set.validators.into_iter().map(|info| (info.address, info.computation_power)).collect())
+            .unwrap_or_default();
+        self.record_validator_set(ending + VALIDATOR_SET_LOOKAHEAD, &header.randao_mix, &computation_power, &shard_map, header.timestamp);
     }
     
-    pub fn get_account(&self, address: &str) -> Option<Account> {
-        self.accounts.get(address).cloned()
+    // Proposal of `epoch` approved by more than 2/3 of the weight of the
+    // epoch's active set, if any
+    fn approved_migration(&self, epoch: u64) -> Option<MigrationProposal> {
+        let validator_set = self.validator_set(epoch)?;
+        let prefix = migration::approval_prefix(epoch);
+        let mut weights: BTreeMap<&Vec<u8>, u128> = BTreeMap::new();
+        for (key, proposal) in &self.accounts.get(BEACON_ADDRESS)?.storage {
+            if let Some(validator) = key.strip_prefix(&prefix) {
+                *weights.entry(proposal).or_default() += validator_set.weight(validator) as u128;
+            }
+        }
+        
+        let total = validator_set.total_weight();
+        weights
+            .into_iter()
+            .find(|(_, weight)| weight * 3 > total * 2)
+            .and_then(|(proposal, _)| encoding::from_bytes(proposal).ok())
     }
     
-    pub fn get_utxo(&self, tx_hash: &str, output_index: u32) -> Option<UTXO> {
-        let key = format!("{}:{}", tx_hash, output_index);
-        self.utxos.get(&key).cloned()
+    // Removes the approvals of `epoch` once it ends
+    fn clear_approvals(&mut self, epoch: u64, timestamp: u64) {
+        let prefix = migration::approval_prefix(epoch);
+        let keys: Vec<String> = self.accounts
+            .get(BEACON_ADDRESS)
+            .map(|account| account.storage.keys().filter(|key| key.starts_with(&prefix)).cloned().collect())
+            .unwrap_or_default();
+        for key in keys {
+            self.set_storage(BEACON_ADDRESS, &key, None, timestamp);
+        }
     }
     
-    pub fn get_unspent_utxos_for_address(&self, address: &str) -> Vec<UTXO> {
-        self.utxos.values()
-            .filter(|utxo| utxo.owner == address && !utxo.is_spent)
-            .cloned()
-            .collect()
+    // Records the validator set of `epoch` from the stake, contribution
+    // scores and jailing in this beacon state, drawing its committees over
+    // the shards of `shard_map` from `seed`. Computation power isn't kept on
+    // chain and is carried over.
+    fn record_validator_set(&mut self, epoch: u64, seed: &str, computation_power: &HashMap<String, u32>, shard_map: &ShardMap, timestamp: u64) {
+        let candidates: Vec<ValidatorInfo> = self.validator_stakes()
+            .into_iter()
+            .map(|stake| {
+                let account = self.accounts.get(&stake.validator);
+                ValidatorInfo {
+                    stake_amount: stake.total(),
+                    computation_power: computation_power.get(&stake.validator).copied().unwrap_or(0),
+                    contribution_score: account.map(|account| account.contribution_score).unwrap_or(0),
+                    jailed_until_epoch: account.map(|account| account.jailed_until_epoch).unwrap_or(0),
+                    weight: 0,
+                    address: stake.validator,
+                    public_key: stake.public_key,
+                    delegations: stake.delegations,
+                }
+            })
+            .collect();
+        
+        let set = ValidatorSet::new(&params::get(), epoch, candidates, shard_map, seed);
+        
+        debug!("Recorded validator set of epoch {}: {} active validators", epoch, set.active.len());
+        self.set_storage(BEACON_ADDRESS, &beacon::validator_set_key(epoch), Some(encoding::to_bytes(&set)), timestamp);
     }
     
-    pub fn get_block_metadata(&self, block_hash: &str) -> Option<BlockMetadata> {
-        self.blocks.get(block_hash).cloned()
+    /// Returns the validator set of `epoch` recorded in the beacon state.
+    pub fn validator_set(&self, epoch: u64) -> Option<ValidatorSet> {
+        self.storage_value(BEACON_ADDRESS, &beacon::validator_set_key(epoch))
+            .and_then(|value| encoding::from_bytes(value).ok())
     }
     
-    pub fn get_current_height(&self) -> u64 {
-        self.current_height
-    }
     
-    pub fn get_best_block_hash(&self) -> String {
-        self.best_block_hash.clone()
+    // Burns stake and contribution score of a validator and jails it
+    fn penalize(&mut self, penalty: Penalty, timestamp: u64) {
+        let burned = self.burn_stake(&penalty.validator, penalty.stake_bps, timestamp);
+        let account = self.account_mut(&penalty.validator, timestamp);
+        account.stake_amount = account.stake_amount.saturating_sub(burned);
+        
+        let removed = (account.contribution_score as u64 * penalty.contribution_bps / BPS_SCALE) as u32;
+        account.contribution_score -= removed;
+        
+        account.jailed_until_epoch = account.jailed_until_epoch.max(penalty.jailed_until_epoch);
+        account.last_updated = timestamp;
+        
+        debug!("Burned {} stake of validator {}, jailed until epoch {}", burned, penalty.validator, account.jailed_until_epoch);
     }
     
-    pub fn get_shard_id(&self) -> u16 {
-        self.shard_id
+    // Burns a fraction of every delegation to a validator, its self-bond
+    // included. Returns the amount burned.
+    fn burn_stake(&mut self, validator: &str, stake_bps: u64, timestamp: u64) -> u64 {
+        let delegations: Vec<Delegation> = self.validator_stakes()
+            .into_iter()
+            .filter(|stake| stake.validator == validator)
+            .flat_map(|stake| stake.delegations)
+            .collect();
+        
+        let mut burned = 0;
+        for mut delegation in delegations {
+            let cut = (delegation.amount as u128 * stake_bps as u128 / BPS_SCALE as u128) as u64;
+            delegation.amount -= cut;
+            burned += cut;
+            
+            let value = if delegation.amount > 0 { Some(encoding::to_bytes(&delegation)) } else { None };
+            self.set_staking_entry(&delegation.storage_key(), value, timestamp);
+        }
+        
+        // Burned stake leaves the staking account for good
+        if burned > 0 {
+            let _ = self.update_account_balance(STAKING_ADDRESS, burned, false, timestamp);
+        }
+        
+        burned
     }
-}
-
-// Global state manager instances (one per shard)
-lazy_static::lazy_static! {
-    static ref STATE_MANAGERS: Arc<RwLock<HashMap<u16, Arc<Mutex<StateManager>>>>> = 
-        Arc::new(RwLock::new(HashMap::new()));
-}
-
-pub fn initialize() -> Result<(), Box<dyn std::error::Error>> {
-    info!("Initializing state management system...");
     
-    // Initialize state manager for genesis shard (0)
-    let genesis_state_manager = StateManager::new(0);
+    fn block_metadata(&self, block: &Block, parent: &BlockMetadata, weight: u64) -> BlockMetadata {
+        BlockMetadata {
+            hash: block.hash.clone(),
+            height: block.header.height,
+            timestamp: block.header.timestamp,
+            tx_count: block.transactions.len(),
+            size: encoding::to_bytes(block).len(),
+            validator: block.header.validator.clone(),
+            parent_hash: parent.hash.clone(),
+            weight,
+            cumulative_weight: parent.cumulative_weight.saturating_add(weight),
+        }
+    }
     
-    let mut state_managers = STATE_MANAGERS.write().unwrap();
-    state_managers.insert(0, Arc::new(Mutex::new(genesis_state_manager)));
+    fn best_cumulative_weight(&self) -> u64 {
+        self.blocks
+            .get(&self.best_block_hash)
+            .map(|tip| tip.cumulative_weight)
+            .unwrap_or(0)
+    }
     
-    info!("State management system initialized successfully");
-    Ok(())
-}
+    /// Reverts the best block using its undo journal, making its parent the
+    /// new best block. Returns the reverted block.
+    pub fn revert_block(&mut self) -> Result<Block, String> {
+        let hash = self.best_block_hash.clone();
+        
+        let metadata = self.blocks
+            .get(&hash)
+            .cloned()
+            .ok_or_else(|| "No block to revert".to_string())?;
+        if metadata.height <= self.finalized_height {
+            return Err(format!("Block {} is finalized and can't be reverted", hash));
+        }
+        let block = self.load_block(&hash)
+            .ok_or_else(|| format!("Block {} not found", hash))?;
+        let undo = self.undo_journals
+            .remove(&hash)
+            .ok_or_else(|| format!("No undo journal for block {}", hash))?;
+        
+        if let Some(store) = &self.store {
+            if let Err(e) = store.revert_block(&block, &undo, metadata.height - 1, &metadata.parent_hash) {
+                self.undo_journals.insert(hash, undo);
+                return Err(e);
+            }
+        }
+        
+        self.restore(&undo);
+        self.branch_blocks.insert(hash.clone(), block.clone());
+        
+        self.current_height = metadata.height - 1;
+        self.best_block_hash = metadata.parent_hash;
+        
+        debug!("Reverted block {}, new height: {}", hash, self.current_height);
+        Ok(block)
+    }
+    
+    // Switches the best chain to the branch ending at `target`
+    fn reorganize(&mut self, target: &str) -> Result<ImportResult, String> {
+        let ancestor = self.common_ancestor(target, &self.best_block_hash)
+            .ok_or_else(|| format!("Block {} does not share an ancestor with the best chain", target))?;
+        if self.blocks[&ancestor].height < self.finalized_height {
+            return Err(format!("Block {} conflicts with finalized block {}", target, self.finalized_hash));
+        }
+        
+        // Blocks of the new branch, from the ancestor's child up to the target
+        let mut branch = Vec::new();
+        let mut hash = target.to_string();
+        while hash != ancestor {
+            let parent_hash = self.blocks[&hash].parent_hash.clone();
+            branch.push(hash);
+            hash = parent_hash;
+        }
+        branch.reverse();
+        
+        info!("Reorganizing shard {} from {} to {} (common ancestor {})", 
+              self.shard_id, self.best_block_hash, target, ancestor);
+        
+        let mut reverted = Vec::new();
+        while self.best_block_hash != ancestor {
+            reverted.push(self.revert_block()?.hash);
+        }
+        
+        let mut applied = Vec::new();
+        for hash in &branch {
+            let result = match self.load_block(hash) {
+                Some(block) => {
+                    let weight = self.blocks[hash].weight;
+                    self.apply_block(&block, weight).map_err(|e| e.to_string())
+                }
+                None => Err(format!("Block {} not found", hash)),
+            };
+            
+            if let Err(e) = result {
+                warn!("Block {} is invalid, staying on the previous chain: {}", hash, e);
+                self.remove_subtree(hash);
+                self.restore_chain(&ancestor, &reverted)?;
+                return Err(format!("Reorganization to {} failed at block {}: {}", target, hash, e));
+            }
+            
+            applied.push(hash.clone());
+        }
+        
+        Ok(ImportResult::Reorganized { reverted, applied })
+    }
+    
+    // Reverts back to `ancestor` and reapplies the previously best blocks
+    // (listed newest first, as they were reverted)
+    fn restore_chain(&mut self, ancestor: &str, reverted: &[String]) -> Result<(), String> {
+        while self.best_block_hash != ancestor {
+            self.revert_block()?;
+        }
+        
+        for hash in reverted.iter().rev() {
+            let block = self.load_block(hash).ok_or_else(|| format!("Block {} not found", hash))?;
+            let weight = self.blocks[hash].weight;
+            self.apply_block(&block, weight).map_err(|e| e.to_string())?;
+        }
+        
+        Ok(())
+    }
+    
+    fn common_ancestor(&self, a: &str, b: &str) -> Option<String> {
+        let mut a = self.blocks.get(a)?;
+        let mut b = self.blocks.get(b)?;
+        
+        while a.hash != b.hash {
+            if a.height >= b.height {
+                a = self.blocks.get(&a.parent_hash)?;
+            } else {
+                b = self.blocks.get(&b.parent_hash)?;
+            }
+        }
+        
+        Some(a.hash.clone())
+    }
+    
+    // Hash of the ancestor of `block_hash` at `height`, which is the block
+    // itself at its own height
+    fn ancestor_at(&self, block_hash: &str, height: u64) -> Option<String> {
+        let mut metadata = self.blocks.get(block_hash)?;
+        while metadata.height > height {
+            metadata = self.blocks.get(&metadata.parent_hash)?;
+        }
+        
+        if metadata.height == height {
+            Some(metadata.hash.clone())
+        } else {
+            None
+        }
+    }
+    
+    fn descends_from_finalized(&self, block_hash: &str) -> bool {
+        self.finalized_hash.is_empty() ||
+            self.ancestor_at(block_hash, self.finalized_height).as_deref() == Some(self.finalized_hash.as_str())
+    }
+    
+    /// Marks a block and all of its ancestors as final, so they are never
+    /// reverted. If the block isn't on the best chain, the chain first
+    /// switches to it and the reorganization is returned.
+    pub fn finalize_block(&mut self, block_hash: &str) -> Result<Option<ImportResult>, String> {
+        let metadata = self.blocks
+            .get(block_hash)
+            .cloned()
+            .ok_or_else(|| format!("Block {} is unknown", block_hash))?;
+        
+        if metadata.height <= self.finalized_height {
+            return Ok(None);
+        }
+        
+        if !self.descends_from_finalized(block_hash) {
+            return Err(format!("Block {} conflicts with finalized block {}", block_hash, self.finalized_hash));
+        }
+        
+        let reorganization = if self.ancestor_at(&self.best_block_hash, metadata.height).as_deref() != Some(block_hash) {
+            Some(self.reorganize(block_hash)?)
+        } else {
+            None
+        };
+        
+        // Undo journals of the newly final blocks are no longer needed
+        let pruned: Vec<String> = self.undo_journals
+            .keys()
+            .filter(|hash| self.blocks.get(*hash).map(|m| m.height <= metadata.height).unwrap_or(true))
+            .cloned()
+            .collect();
+        
+        if let Some(store) = &self.store {
+            store.write_finalized(metadata.height, block_hash, &pruned)?;
+        }
+        
+        for hash in &pruned {
+            self.undo_journals.remove(hash);
+        }
+        
+        let previous_finalized_height = self.finalized_height;
+        self.finalized_height = metadata.height;
+        self.finalized_hash = block_hash.to_string();
+        
+        // Forget the side branches that conflict with the finalized block. Ones
+        // already on disk are rejected again on import after a restart.
+        let conflicting: Vec<String> = self.blocks
+            .values()
+            .filter(|m| m.height > previous_finalized_height)
+            .filter(|m| {
+                if m.height >= metadata.height {
+                    !self.descends_from_finalized(&m.hash)
+                } else {
+                    self.ancestor_at(block_hash, m.height).as_deref() != Some(m.hash.as_str())
+                }
+            })
+            .map(|m| m.hash.clone())
+            .collect();
+        
+        for hash in &conflicting {
+            self.blocks.remove(hash);
+            self.branch_blocks.remove(hash);
+        }
+        
+        info!("Finalized block {} at height {} in shard {}", block_hash, metadata.height, self.shard_id);
+        Ok(reorganization)
+    }
+    
+    // Forgets an invalid block and every known block built on top of it
+    fn remove_subtree(&mut self, root: &str) {
+        let mut removed: HashSet<String> = HashSet::new();
+        removed.insert(root.to_string());
+        
+        loop {
+            let children: Vec<String> = self.blocks
+                .values()
+                .filter(|metadata| removed.contains(&metadata.parent_hash) && !removed.contains(&metadata.hash))
+                .map(|metadata| metadata.hash.clone())
+                .collect();
+            
+            if children.is_empty() {
+                break;
+            }
+            removed.extend(children);
+        }
+        
+        for hash in &removed {
+            self.blocks.remove(hash);
+            self.branch_blocks.remove(hash);
+        }
+    }
+    
+    // Restores the entries of an undo journal in memory
+    fn restore(&mut self, undo: &StateChanges) {
+        for (address, account) in &undo.accounts {
+            match account {
+                Some(account) => self.accounts.insert(address.clone(), account.clone()),
+                None => self.accounts.remove(address),
+            };
+            self.stale.rebuilt.insert(address.clone());
+        }
+        
+        for (key, utxo) in &undo.utxos {
+            match utxo {
+                Some(utxo) => self.utxos.insert(key.clone(), utxo.clone()),
+                None => self.utxos.remove(key),
+            };
+            self.stale.utxos.insert(key.clone());
+        }
+        
+        self.update_tree();
+    }
+    
+    // Undoes the changes made so far by the block being applied
+    fn rollback_journal(&mut self) {
+        let journal = std::mem::take(&mut self.journal);
+        self.restore(&journal);
+    }
+    
+    fn load_block(&self, block_hash: &str) -> Option<Block> {
+        if let Some(block) = self.branch_blocks.get(block_hash) {
+            return Some(block.clone());
+        }
+        
+        let store = self.store.as_ref()?;
+        match store.get_block(block_hash) {
+            Ok(block) => block,
+            Err(e) => {
+                error!("Failed to load block {}: {}", block_hash, e);
+                None
+            }
+        }
+    }
+    
+    /// Checks a transaction against the current state without applying it:
+    /// shard, structure, and that every input spends an unspent output with
+    /// a signature from that output's owner.
+    pub fn validate_transaction(&self, tx: &Transaction) -> Result<(), String> {
+        // Check if transaction belongs to this shard
+        if tx.shard_id != self.shard_id {
+            return Err(format!("Transaction {} belongs to shard {}, not {}", 
+                              tx.hash, tx.shard_id, self.shard_id));
+        }
+        
+        if matches!(tx.tx_type, crate::core::transaction::TransactionType::Coinbase) {
+            return Err(format!("Coinbase {} is only valid as the first transaction of a block", tx.hash));
+        }
+        
+        // Stake and contribution scores are only kept, and penalized, in the
+        // beacon state
+        let beacon_only = matches!(
+            tx.tx_type,
+            crate::core::transaction::TransactionType::StakeDeposit |
+            crate::core::transaction::TransactionType::StakeWithdraw |
+            crate::core::transaction::TransactionType::ContributionReport |
+            crate::core::transaction::TransactionType::SlashingEvidence
+        );
+        if beacon_only && self.shard_id != BEACON_SHARD {
+            return Err(format!("Transaction {} is only valid in beacon shard {}", tx.hash, BEACON_SHARD));
+        }
+        
+        // Check structure, amounts and that each input is signed by the key in its script_sig
+        if !tx.is_valid() {
+            return Err(format!("Transaction {} is invalid", tx.hash));
+        }
+        
+        // Resolve the owner key of every spent output before touching any state
+        let mut owner_keys: Vec<Vec<u8>> = Vec::with_capacity(tx.inputs.len());
+        for input in &tx.inputs {
+            let utxo_key = format!("{}:{}", input.previous_tx, input.index);
+            
+            let utxo = match self.utxos.get(&utxo_key) {
+                Some(utxo) => utxo,
+                None => return Err(format!("UTXO {}:{} not found", input.previous_tx, input.index)),
+            };
+            
+            if utxo.is_spent {
+                return Err(format!("UTXO {}:{} is already spent", input.previous_tx, input.index));
+            }
+            
+            if utxo.amount != input.amount {
+                return Err(format!("Input amount {} does not match UTXO {}:{} amount {}", 
+                                  input.amount, input.previous_tx, input.index, utxo.amount));
+            }
+            
+            let owner_key = crypto::verifying_key_from_hex(&utxo.script_pubkey)
+                .map_err(|e| format!("UTXO {}:{} has no spendable owner key: {}", input.previous_tx, input.index, e))?;
+            
+            if crypto::public_key_to_address(owner_key.as_bytes()) != utxo.owner {
+                return Err(format!("UTXO {}:{} owner key does not match owner {}", 
+                                  input.previous_tx, input.index, utxo.owner));
+            }
+            
+            if input.script_sig != utxo.script_pubkey {
+                return Err(format!("Input {}:{} is not signed by the UTXO owner {}", 
+                                  input.previous_tx, input.index, utxo.owner));
+            }
+            
+            owner_keys.push(owner_key.as_bytes().to_vec());
+        }
+        
+        let key_refs: Vec<&[u8]> = owner_keys.iter().map(|key| key.as_slice()).collect();
+        if !tx.verify_signatures(&key_refs) {
+            return Err(format!("Transaction {} has a signature that does not match the UTXO owner", tx.hash));
+        }
+        
+        // Only accounts this shard owns can receive UTXOs here
+        let shard_map = self.shard_map();
+        for output in tx.outputs.iter().skip(Self::held_outputs(tx)) {
+            let owner_shard = shard_map.shard_of(&output.address);
+            if owner_shard != self.shard_id && !shard_map::is_system_address(&output.address) {
+                return Err(format!("Transaction {} pays {}, which belongs to shard {}", tx.hash, output.address, owner_shard));
+            }
+        }
+        
+        if matches!(tx.tx_type, crate::core::transaction::TransactionType::SlashingEvidence) {
+            let evidence = SlashingEvidence::from_transaction(tx)?;
+            evidence.verify()?;
+            
+            if self.storage_value(STAKING_ADDRESS, &evidence.offence_key()).is_some() {
+                return Err(format!("Validator {} was already slashed for this offence at height {}", evidence.offender(), evidence.height()));
+            }
+        }
+        
+        if matches!(tx.tx_type, crate::core::transaction::TransactionType::ContributionReport) {
+            let contributor = self.get_input_owner(&tx.inputs[0])?;
+            self.check_contribution_report(tx, &contributor)?;
+        }
+        
+        if matches!(tx.tx_type, crate::core::transaction::TransactionType::ShardCrossing) {
+            self.check_shard_crossing(tx)?;
+        }
+        
+        if matches!(tx.tx_type, crate::core::transaction::TransactionType::Crosslink) {
+            self.check_crosslink(tx)?;
+        }
+        
+        if matches!(tx.tx_type, crate::core::transaction::TransactionType::ShardMigration) {
+            self.check_shard_migration(tx)?;
+        }
+        
+        Ok(())
+    }
+    
+    /// Applies a transaction on top of the current state. A transaction that
+    /// fails leaves the state as it was before it.
+    pub fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), String> {
+        debug!("Applying transaction {} to state", tx.hash);
+        
+        // Journal the transaction on its own, so a failure only undoes it
+        let outer = std::mem::take(&mut self.journal);
+        let result = self.execute_transaction(tx);
+        let journal = std::mem::replace(&mut self.journal, outer);
+        
+        if result.is_err() {
+            self.restore(&journal);
+            return result;
+        }
+        
+        // Pre-images already journaled by the block come from before it
+        for (address, account) in journal.accounts {
+            self.journal.accounts.entry(address).or_insert(account);
+        }
+        for (key, utxo) in journal.utxos {
+            self.journal.utxos.entry(key).or_insert(utxo);
+        }
+        
+        self.update_tree();
+        result
+    }
+    
+    /// Runs `trial` against the current state and undoes everything it
+    /// changed afterwards. Block producers use it to try out transactions and
+    /// compute the state root of the block they propose without copying the
+    /// state.
+    pub fn trial<T>(&mut self, trial: impl FnOnce(&mut StateManager) -> T) -> T {
+        let outer = std::mem::take(&mut self.journal);
+        let result = trial(self);
+        
+        self.rollback_journal();
+        self.journal = outer;
+        result
+    }
+    
+    fn execute_transaction(&mut self, tx: &Transaction) -> Result<(), String> {
+        self.validate_transaction(tx)?;
+        
+        // The sender owns the first spent output, which is gone once spent
+        let sender = tx.inputs
+            .first()
+            .map(|input| self.get_input_owner(input))
+            .transpose()?
+            .unwrap_or_default();
+        
+        // Spent outputs leave the state; debit their owners
+        for input in &tx.inputs {
+            let utxo_key = format!("{}:{}", input.previous_tx, input.index);
+            
+            let owner = match self.remove_utxo(&utxo_key) {
+                Some(utxo) => utxo.owner,
+                None => return Err(format!("UTXO {}:{} not found", input.previous_tx, input.index)),
+            };
+            
+            self.update_account_balance(&owner, input.amount, false, tx.timestamp)?;
+        }
+        
+        self.create_outputs(tx)?;
+        
+        // Handle special transaction types
+        match tx.tx_type {
+            crate::core::transaction::TransactionType::SmartContract => {
+                // Deploy or call smart contract
+                self.handle_smart_contract(tx)?;
+            },
+            crate::core::transaction::TransactionType::StakeDeposit => {
+                // Handle staking
+                self.handle_stake_deposit(tx, &sender)?;
+            },
+            crate::core::transaction::TransactionType::StakeWithdraw => {
+                // Handle unstaking
+                self.handle_stake_withdraw(tx, &sender)?;
+            },
+            crate::core::transaction::TransactionType::ContributionReport => {
+                // Update contribution score
+                self.handle_contribution_report(tx, &sender)?;
+            },
+            crate::core::transaction::TransactionType::SlashingEvidence => {
+                // Slash the equivocating validator
+                self.handle_slashing_evidence(tx)?;
+            },
+            crate::core::transaction::TransactionType::ShardCrossing => {
+                // Lock, deliver or refund a cross-shard transfer
+                self.handle_shard_crossing(tx)?;
+            },
+            crate::core::transaction::TransactionType::Crosslink => {
+                // Checkpoint another shard's finalized header
+                self.handle_crosslink(tx)?;
+            },
+            crate::core::transaction::TransactionType::ShardMigration => {
+                // Approve a migration, or switch to or import from a new shard map
+                self.handle_shard_migration(tx)?;
+            },
+            _ => {
+                // Regular transfer transaction, already handled above
+            }
+        }
+        
+        debug!("Transaction {} applied successfully", tx.hash);
+        Ok(())
+    }
+    
+    // Number of leading outputs that don't become UTXOs: the first output of
+    // a stake deposit is bonded, and that of a cross-shard send is paid on
+    // another shard, instead.
+    fn held_outputs(tx: &Transaction) -> usize {
+        matches!(
+            tx.tx_type,
+            crate::core::transaction::TransactionType::StakeDeposit | crate::core::transaction::TransactionType::ShardCrossing
+        ) as usize
+    }
+    
+    // Creates the UTXOs of a transaction's outputs and credits their owners
+    fn create_outputs(&mut self, tx: &Transaction) -> Result<(), String> {
+        for (i, output) in tx.outputs.iter().enumerate().skip(Self::held_outputs(tx)) {
+            let utxo_key = format!("{}:{}", tx.hash, i);
+            
+            let utxo = UTXO {
+                tx_hash: tx.hash.clone(),
+                output_index: i as u32,
+                amount: output.amount,
+                owner: output.address.clone(),
+                script_pubkey: output.script_pubkey.clone(),
+                is_spent: false,
+                created_at: tx.timestamp,
+                spent_at: None,
+            };
+            
+            self.insert_utxo(utxo_key, utxo);
+            
+            // Update account balance
+            self.update_account_balance(&output.address, output.amount, true, tx.timestamp)?;
+        }
+        
+        Ok(())
+    }
+    
+    fn update_account_balance(&mut self, address: &str, amount: u64, is_credit: bool, timestamp: u64) -> Result<(), String> {
+        let account = self.account_mut(address, timestamp);
+        
+        if is_credit {
+            account.balance += amount;
+        } else {
+            if account.balance < amount {
+                return Err(format!("Insufficient balance for account {}", address));
+            }
+            account.balance -= amount;
+        }
+        
+        account.last_updated = timestamp;
+        
+        Ok(())
+    }
+    
+    // Returns the account at `address`, creating an empty one if needed, and
+    // marks it as modified
+    fn account_mut(&mut self, address: &str, timestamp: u64) -> &mut Account {
+        self.record_account(address);
+        
+        self.accounts.entry(address.to_string())
+            .or_insert_with(|| Account {
+                address: address.to_string(),
+                balance: 0,
+                nonce: 0,
+                code: Vec::new(),
+                storage: HashMap::new(),
+                stake_amount: 0,
+                contribution_score: 0,
+                last_updated: timestamp,
+                jailed_until_epoch: 0,
+                missed_proposals: 0,
+            })
+    }
+    
+    fn existing_account_mut(&mut self, address: &str) -> Option<&mut Account> {
+        if !self.accounts.contains_key(address) {
+            return None;
+        }
+        self.record_account(address);
+        self.accounts.get_mut(address)
+    }
+    
+    fn remove_utxo(&mut self, key: &str) -> Option<UTXO> {
+        if !self.utxos.contains_key(key) {
+            return None;
+        }
+        self.record_utxo(key);
+        self.utxos.remove(key)
+    }
+    
+    fn insert_utxo(&mut self, key: String, utxo: UTXO) {
+        self.record_utxo(&key);
+        self.utxos.insert(key, utxo);
+    }
+    
+    // Reads an entry of the contract storage of `address`
+    fn storage_value(&self, address: &str, key: &str) -> Option<&Vec<u8>> {
+        self.accounts
+            .get(address)
+            .and_then(|account| account.storage.get(key))
+    }
+    
+    // Writes or, with None, removes an entry of the contract storage of
+    // `address`. Storage only changes through here (or wholesale in a
+    // migration), so the state tree can follow single entries.
+    fn set_storage(&mut self, address: &str, key: &str, value: Option<Vec<u8>>, timestamp: u64) {
+        self.stale.storage.insert((address.to_string(), key.to_string()));
+        let account = self.account_mut(address, timestamp);
+        match value {
+            Some(value) => account.storage.insert(key.to_string(), value),
+            None => account.storage.remove(key),
+        };
+        account.last_updated = timestamp;
+    }
+    
+    // Journal the pre-image of an entry the first time the current block
+    // touches it, and mark the entry for the state tree
+    fn record_account(&mut self, address: &str) {
+        if !self.journal.accounts.contains_key(address) {
+            let account = self.accounts.get(address).cloned();
+            self.journal.accounts.insert(address.to_string(), account);
+        }
+        self.stale.accounts.insert(address.to_string());
+    }
+    
+    fn record_utxo(&mut self, key: &str) {
+        if !self.journal.utxos.contains_key(key) {
+            let utxo = self.utxos.get(key).cloned();
+            self.journal.utxos.insert(key.to_string(), utxo);
+        }
+        self.stale.utxos.insert(key.to_string());
+    }
+    
+    // Brings the state tree up to date with the entries changed since its
+    // last update. Every public method that changes the state ends with it.
+    fn update_tree(&mut self) {
+        let stale = std::mem::take(&mut self.stale);
+        
+        for address in &stale.rebuilt {
+            self.tree.reset_storage(address, self.accounts.get(address));
+        }
+        
+        for (address, key) in &stale.storage {
+            if !stale.rebuilt.contains(address) {
+                let value = self.accounts
+                    .get(address)
+                    .and_then(|account| account.storage.get(key))
+                    .map(|value| value.as_slice());
+                self.tree.set_storage(address, key, value);
+            }
+        }
+        
+        // An account's leaf covers its storage root, so accounts come last
+        let addresses: HashSet<&String> = stale.accounts
+            .iter()
+            .chain(&stale.rebuilt)
+            .chain(stale.storage.iter().map(|(address, _)| address))
+            .collect();
+        for address in addresses {
+            self.tree.set_account(address, self.accounts.get(address));
+        }
+        
+        for key in &stale.utxos {
+            self.tree.set_utxo(key, self.utxos.get(key));
+        }
+    }
+    
+    fn persist_block(&self, block: &Block, metadata: &BlockMetadata, undo: &StateChanges) -> Result<(), String> {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+        
+        // Every touched entry is in the journal; write out its current value
+        store.write_block(block, metadata, &self.current_values(undo), undo)
+    }
+    
+    // Current value of every entry in a journal
+    fn current_values(&self, journal: &StateChanges) -> StateChanges {
+        StateChanges {
+            accounts: journal.accounts
+                .keys()
+                .map(|address| (address.clone(), self.accounts.get(address).cloned()))
+                .collect(),
+            utxos: journal.utxos
+                .keys()
+                .map(|key| (key.clone(), self.utxos.get(key).cloned()))
+                .collect(),
+        }
+    }
+    
+    fn handle_smart_contract(&mut self, tx: &Transaction) -> Result<(), String> {
+        // In a real implementation, this would:
+        // 1. Parse the contract code from tx.data
+        // 2. Validate the code
+        // 3. Deploy the contract or execute the function call
+        // 4. Update the state accordingly
+        
+        // For this example, we'll just store the contract code in the account
+        if !tx.outputs.is_empty() {
+            let contract_address = &tx.outputs[0].address;
+            
+            let account = self.account_mut(contract_address, tx.timestamp);
+            
+            account.code = tx.data.clone();
+            account.last_updated = tx.timestamp;
+            
+            debug!("Deployed smart contract to address {}", contract_address);
+        }
+        
+        Ok(())
+    }
+    
+    fn handle_stake_deposit(&mut self, tx: &Transaction, sender: &str) -> Result<(), String> {
+        if tx.inputs.is_empty() || tx.outputs.is_empty() {
+            return Err("Stake deposit transaction has no inputs or outputs".to_string());
+        }
+        
+        // The first output is the bond; the staker is the owner of the first
+        // spent output and gets paid with the key that signed it
+        let validator = tx.outputs[0].address.clone();
+        let amount = tx.outputs[0].amount;
+        let delegator = sender.to_string();
+        let public_key = tx.inputs[0].script_sig.clone();
+        
+        if amount == 0 {
+            return Err("Stake deposit bonds nothing".to_string());
+        }
+        
+        // Only validators that bonded to themselves take delegations
+        if delegator != validator && self.staking_entry::<Delegation>(&staking::delegation_key(&validator, &validator)).is_none() {
+            return Err(format!("Validator {} has no self-bond to delegate to", validator));
+        }
+        
+        let key = staking::delegation_key(&validator, &delegator);
+        let mut delegation = self.staking_entry::<Delegation>(&key).unwrap_or(Delegation {
+            delegator: delegator.clone(),
+            validator: validator.clone(),
+            amount: 0,
+            public_key,
+        });
+        delegation.amount += amount;
+        self.set_staking_entry(&key, Some(encoding::to_bytes(&delegation)), tx.timestamp);
+        
+        // The bonded amount is held by the staking account
+        self.update_account_balance(STAKING_ADDRESS, amount, true, tx.timestamp)?;
+        
+        let account = self.account_mut(&validator, tx.timestamp);
+        account.stake_amount += amount;
+        account.last_updated = tx.timestamp;
+        
+        debug!("Bonded {} from {} to validator {}", amount, delegator, validator);
+        Ok(())
+    }
+    
+    fn handle_stake_withdraw(&mut self, tx: &Transaction, sender: &str) -> Result<(), String> {
+        if tx.inputs.is_empty() {
+            return Err("Stake withdraw transaction has no inputs".to_string());
+        }
+        
+        // The staker is the owner of the first spent output
+        let delegator = sender.to_string();
+        let request = UnbondRequest::from_transaction(tx)?;
+        
+        let key = staking::delegation_key(&request.validator, &delegator);
+        let mut delegation = self.staking_entry::<Delegation>(&key)
+            .ok_or_else(|| format!("Account {} has no stake with validator {}", delegator, request.validator))?;
+        
+        if request.amount == 0 || delegation.amount < request.amount {
+            return Err(format!("Insufficient stake for account {} with validator {}", delegator, request.validator));
+        }
+        
+        delegation.amount -= request.amount;
+        let value = if delegation.amount > 0 { Some(encoding::to_bytes(&delegation)) } else { None };
+        self.set_staking_entry(&key, value, tx.timestamp);
+        
+        if let Some(account) = self.existing_account_mut(&request.validator) {
+            account.stake_amount = account.stake_amount.saturating_sub(request.amount);
+            account.last_updated = tx.timestamp;
+        }
+        
+        // Queue the payout; requests released at the same height add up
+        let mut unbonding = Unbonding {
+            delegator: delegator.clone(),
+            validator: request.validator.clone(),
+            amount: request.amount,
+            public_key: delegation.public_key,
+            release_height: self.current_height + 1 + params::get().staking.unbonding_blocks,
+        };
+        let key = unbonding.storage_key();
+        if let Some(queued) = self.staking_entry::<Unbonding>(&key) {
+            unbonding.amount += queued.amount;
+        }
+        self.set_staking_entry(&key, Some(encoding::to_bytes(&unbonding)), tx.timestamp);
+        
+        debug!("Unbonding {} of {} from validator {} until height {}", 
+               request.amount, delegator, request.validator, unbonding.release_height);
+        Ok(())
+    }
+    
+    // Pays back the unbonding entries that are due at `height`
+    fn release_unbonded(&mut self, height: u64, timestamp: u64) {
+        let mut due: Vec<Unbonding> = match self.accounts.get(STAKING_ADDRESS) {
+            Some(account) => account.storage
+                .iter()
+                .filter(|(key, _)| staking::is_unbonding_key(key))
+                .filter_map(|(_, value)| encoding::from_bytes::<Unbonding>(value).ok())
+                .filter(|unbonding| unbonding.release_height <= height)
+                .collect(),
+            None => return,
+        };
+        due.sort_by_key(|unbonding| unbonding.storage_key());
+        
+        for unbonding in due {
+            self.set_staking_entry(&unbonding.storage_key(), None, timestamp);
+            
+            let owner = unbonding.delegator.clone();
+            let tx_hash = unbonding.payout_hash();
+            self.insert_utxo(format!("{}:0", tx_hash), UTXO {
+                tx_hash,
+                output_index: 0,
+                amount: unbonding.amount,
+                owner: owner.clone(),
+                script_pubkey: unbonding.public_key.clone(),
+                is_spent: false,
+                created_at: timestamp,
+                spent_at: None,
+            });
+            
+            // Both succeed: the staking account holds every unbonding amount
+            let _ = self.update_account_balance(STAKING_ADDRESS, unbonding.amount, false, timestamp);
+            let _ = self.update_account_balance(&owner, unbonding.amount, true, timestamp);
+            
+            debug!("Released {} of unbonded stake to {}", unbonding.amount, owner);
+        }
+    }
+    
+    // Reads an entry of the staking account's storage
+    fn staking_entry<T: encoding::Canonical>(&self, key: &str) -> Option<T> {
+        self.storage_value(STAKING_ADDRESS, key)
+            .and_then(|value| encoding::from_bytes(value).ok())
+    }
+    
+    // Writes or, with None, removes an entry of the staking account's storage
+    fn set_staking_entry(&mut self, key: &str, value: Option<Vec<u8>>, timestamp: u64) {
+        self.set_storage(STAKING_ADDRESS, key, value, timestamp);
+    }
+    
+    /// Returns the stake bonded to every validator with a self-bond, from
+    /// which validator sets are recorded, ordered by validator address.
+    pub fn validator_stakes(&self) -> Vec<ValidatorStake> {
+        let account = match self.accounts.get(STAKING_ADDRESS) {
+            Some(account) => account,
+            None => return Vec::new(),
+        };
+        
+        let mut by_validator: BTreeMap<String, Vec<Delegation>> = BTreeMap::new();
+        for (key, value) in &account.storage {
+            if !staking::is_delegation_key(key) {
+                continue;
+            }
+            if let Ok(delegation) = encoding::from_bytes::<Delegation>(value) {
+                by_validator.entry(delegation.validator.clone()).or_default().push(delegation);
+            }
+        }
+        
+        by_validator
+            .into_iter()
+            .filter_map(|(validator, mut delegations)| {
+                delegations.sort_by(|a, b| a.delegator.cmp(&b.delegator));
+                let public_key = delegations
+                    .iter()
+                    .find(|delegation| delegation.delegator == validator)?
+                    .public_key
+                    .clone();
+                Some(ValidatorStake {
+                    validator,
+                    public_key,
+                    delegations,
+                })
+            })
+            .collect()
+    }
+    
+    // Checks a contribution report sent by `contributor`, the owner of its
+    // first spent output, against the current state and returns it with the
+    // number of units it can still be credited
+    fn check_contribution_report(&self, tx: &Transaction, contributor: &str) -> Result<(ContributionReport, u32), String> {
+        let params = params::get();
+        let report = ContributionReport::from_transaction(tx)?;
+        let claim = &report.claim;
+        
+        if claim.contributor != contributor {
+            return Err(format!("Contribution report {} claims work of {} but is sent by {}", 
+                              tx.hash, claim.contributor, contributor));
+        }
+        
+        // Work can be reported during its epoch and the next one
+        let epoch = params.epoch_at(self.current_height + 1);
+        if claim.epoch > epoch || claim.epoch + 1 < epoch {
+            return Err(format!("Contribution report {} is for epoch {}, not {} or the one before", 
+                              tx.hash, claim.epoch, epoch));
+        }
+        
+        // Only bonded validators can vouch for the work
+        let attesters = report.verify_attestations()?;
+        if let Some(attester) = attesters.iter().find(|attester| {
+            self.staking_entry::<Delegation>(&staking::delegation_key(attester, attester)).is_none()
+        }) {
+            return Err(format!("Attester {} of contribution report {} is not a bonded validator", attester, tx.hash));
+        }
+        
+        if attesters.len() < params.contribution.min_attestations {
+            return Err(format!("Contribution report {} has {} attestations, {} needed", 
+                              tx.hash, attesters.len(), params.contribution.min_attestations));
+        }
+        
+        let cap = params.contribution.for_kind(claim.kind).max_units_per_epoch;
+        let credited = self.claimed_units(&claim.claimed_key());
+        let units = claim.units.min(cap.saturating_sub(credited));
+        if units == 0 {
+            return Err(format!("Contributor {} has no {:?} units left to claim in epoch {}", 
+                              contributor, claim.kind, claim.epoch));
+        }
+        
+        Ok((report, units))
+    }
+    
+    fn handle_contribution_report(&mut self, tx: &Transaction, sender: &str) -> Result<(), String> {
+        // Already checked by validate_transaction; claims beyond the cap are cut
+        let (report, units) = self.check_contribution_report(tx, sender)?;
+        let claim = &report.claim;
+        
+        let key = claim.claimed_key();
+        let credited = self.claimed_units(&key) + units;
+        self.set_storage(CONTRIBUTION_ADDRESS, &key, Some(credited.to_le_bytes().to_vec()), tx.timestamp);
+        
+        let points = units.saturating_mul(params::get().contribution.for_kind(claim.kind).points_per_unit);
+        let account = self.account_mut(&claim.contributor, tx.timestamp);
+        account.contribution_score = account.contribution_score.saturating_add(points);
+        account.last_updated = tx.timestamp;
+        
+        debug!("Credited {} {:?} units of epoch {} to {}: +{} contribution", 
+               units, claim.kind, claim.epoch, claim.contributor, points);
+        Ok(())
+    }
+    
+    // Units already credited under a claimed-units key of the contribution account
+    fn claimed_units(&self, key: &str) -> u32 {
+        self.storage_value(CONTRIBUTION_ADDRESS, key)
+            .and_then(|value| value.as_slice().try_into().ok())
+            .map(u32::from_le_bytes)
+            .unwrap_or(0)
+    }
+    
+    // Ages every contribution score at the end of `epoch` and forgets units
+    // credited for epochs that can no longer be reported
+    fn decay_contributions(&mut self, epoch: u64, timestamp: u64) {
+        let decay_bps = params::get().contribution.decay_bps;
+        
+        let contributors: Vec<String> = self.accounts
+            .values()
+            .filter(|account| account.contribution_score > 0)
+            .map(|account| account.address.clone())
+            .collect();
+        
+        for address in contributors {
+            if let Some(account) = self.existing_account_mut(&address) {
+                let decayed = (account.contribution_score as u64 * decay_bps / BPS_SCALE) as u32;
+                account.contribution_score -= decayed;
+                account.last_updated = timestamp;
+            }
+        }
+        
+        let expired: Vec<String> = self.accounts
+            .get(CONTRIBUTION_ADDRESS)
+            .map(|account| account.storage.keys().filter(|key| Self::is_expired_claim(key, epoch)).cloned().collect())
+            .unwrap_or_default();
+        
+        for key in expired {
+            self.set_storage(CONTRIBUTION_ADDRESS, &key, None, timestamp);
+        }
+    }
+    
+    // Whether a contribution account key holds units of an epoch before `epoch`
+    fn is_expired_claim(key: &str, epoch: u64) -> bool {
+        contribution::is_claimed_key(key) && contribution::claimed_key_epoch(key).is_none_or(|claimed| claimed < epoch)
+    }
+    
+    fn handle_slashing_evidence(&mut self, tx: &Transaction) -> Result<(), String> {
+        // Already checked by validate_transaction
+        let evidence = SlashingEvidence::from_transaction(tx)?;
+        
+        let params = params::get();
+        let jailed_until_epoch = params.epoch_at(self.current_height + 1) + 1 + params.slashing.double_sign_jail_epochs;
+        
+        info!("Slashing validator {} for equivocating at height {}", evidence.offender(), evidence.height());
+        self.penalize(Penalty {
+            validator: evidence.offender().to_string(),
+            stake_bps: params.slashing.double_sign_stake_bps,
+            contribution_bps: params.slashing.double_sign_contribution_bps,
+            jailed_until_epoch,
+        }, tx.timestamp);
+        
+        // Each offence is only punished once; others of the same validator,
+        // older ones included, still are
+        let punished_at = (self.current_height + 1).to_le_bytes().to_vec();
+        self.set_staking_entry(&evidence.offence_key(), Some(punished_at), tx.timestamp);
+        Ok(())
+    }
+    
+    // Checks a shard crossing against the current state. Returns its action
+    // and, for claims and refunds, the receipt they settle.
+    fn check_shard_crossing(&self, tx: &Transaction) -> Result<(CrossShardAction, Option<Receipt>), String> {
+        let action = CrossShardAction::from_transaction(tx)?;
+        let receipt_timeout = params::get().cross_shard.receipt_timeout;
+        
+        let receipt = match &action {
+            CrossShardAction::Send { target_shard } => {
+                if tx.inputs.is_empty() {
+                    return Err(format!("Cross-shard send {} spends nothing", tx.hash));
+                }
+                
+                if *target_shard == self.shard_id {
+                    return Err(format!("Cross-shard send {} targets its own shard", tx.hash));
+                }
+                
+                // The recipient's own shard claims it
+                let recipient = match tx.outputs.first() {
+                    Some(output) => &output.address,
+                    None => return Err(format!("Cross-shard send {} pays nothing", tx.hash)),
+                };
+                
+                let recipient_shard = self.shard_map().shard_of(recipient);
+                if recipient_shard != *target_shard {
+                    return Err(format!("Cross-shard send {} pays {} of shard {} in shard {}",
+                                       tx.hash, recipient, recipient_shard, target_shard));
+                }
+                
+                None
+            }
+            CrossShardAction::Claim { receipt: proof } => {
+                // Claimed by the shard that owns the recipient now, which
+                // differs from the target if a migration moved the recipient
+                let receipt = proof.verify(receipt_timeout)?;
+                let owner_shard = self.shard_map().shard_of(&receipt.recipient);
+                if owner_shard != self.shard_id {
+                    return Err(format!("Receipt {} pays {} of shard {}, not {}", receipt.id(), receipt.recipient, owner_shard, self.shard_id));
+                }
+                
+                // The source block is final once linked to an anchored block
+                self.check_anchored_here(proof.anchor()?)?;
+                
+                // Claim records of the recipient may still be on their way
+                // from the shard that owned it before
+                if self.has_pending_imports() {
+                    return Err(format!("Receipt {} can't be claimed before shard {} has imported its new addresses", receipt.id(), self.shard_id));
+                }
+                
+                // Once a block past the deadline exists the sender may be refunded
+                if self.best_block_timestamp() >= receipt.deadline {
+                    return Err(format!("Receipt {} expired at {}", receipt.id(), receipt.deadline));
+                }
+                
+                if self.storage_value(CROSS_SHARD_ADDRESS, &cross_shard::claimed_key(&receipt.id())).is_some() {
+                    return Err(format!("Receipt {} was already claimed", receipt.id()));
+                }
+                
+                Some(receipt)
+            }
+            CrossShardAction::Refund { receipt: proof, target_header, absence } => {
+                // The amount stays locked in the source shard, or in the
+                // shard it was merged into
+                let receipt = proof.verify(receipt_timeout)?;
+                self.check_anchored_here(proof.anchor()?)?;
+                
+                if self.locked_amount(&receipt.tx_hash).is_none() {
+                    return Err(format!("Receipt {} has nothing locked to refund", receipt.id()));
+                }
+                
+                // Only the shard owning the recipient can hold the delivery UTXO:
+                // a migration moves it along with the recipient
+                let owner_shard = self.shard_map().shard_of(&receipt.recipient);
+                if target_header.shard_id != owner_shard {
+                    return Err(format!("Target header of refund {} is from shard {}, not {}", tx.hash, target_header.shard_id, owner_shard));
+                }
+                self.check_anchored_here(target_header)?;
+                
+                // The recipient's shard holds every claim record of the
+                // recipient from the activation of this shard's map until
+                // both switch to the next one, at the same height
+                let activation_height = self.activation_height();
+                if target_header.height < activation_height || target_header.height > self.current_height {
+                    return Err(format!("Target header of refund {} is at height {}, outside {}..={}",
+                                       tx.hash, target_header.height, activation_height, self.current_height));
+                }
+                
+                if target_header.timestamp < receipt.deadline {
+                    return Err(format!("Receipt {} can still be claimed until {}", receipt.id(), receipt.deadline));
+                }
+                
+                if !receipt.verify_undelivered(&target_header.state_root, absence) {
+                    return Err(format!("No proof that receipt {} was left unclaimed in block {}", receipt.id(), Block::calculate_hash(target_header)));
+                }
+                
+                Some(receipt)
+            }
+        };
+        
+        if !matches!(action, CrossShardAction::Send { .. }) && !tx.inputs.is_empty() {
+            return Err(format!("Receipt claim or refund {} spends inputs", tx.hash));
+        }
+        
+        Ok((action, receipt))
+    }
+    
+    fn handle_shard_crossing(&mut self, tx: &Transaction) -> Result<(), String> {
+        // Already checked by validate_transaction
+        let (action, receipt) = self.check_shard_crossing(tx)?;
+        
+        match (action, receipt) {
+            (CrossShardAction::Send { target_shard }, _) => {
+                // Lock the first output until the target shard claims it
+                let amount = tx.outputs[0].amount;
+                self.update_account_balance(CROSS_SHARD_ADDRESS, amount, true, tx.timestamp)?;
+                self.set_storage(CROSS_SHARD_ADDRESS, &cross_shard::outgoing_key(&tx.hash), Some(amount.to_le_bytes().to_vec()), tx.timestamp);
+                
+                debug!("Locked {} sent to {} in shard {}", amount, tx.outputs[0].address, target_shard);
+            }
+            (CrossShardAction::Claim { .. }, Some(receipt)) => {
+                // The claim is remembered with its recipient, whose shard
+                // keeps it across migrations; the delivered output leaves the
+                // state once spent
+                let record = ClaimRecord {
+                    recipient: receipt.recipient.clone(),
+                    claimed_at: self.current_height + 1,
+                };
+                self.set_storage(CROSS_SHARD_ADDRESS, &cross_shard::claimed_key(&receipt.id()), Some(encoding::to_bytes(&record)), tx.timestamp);
+                self.insert_utxo(receipt.delivery_utxo_key(), UTXO {
+                    tx_hash: receipt.id(),
+                    output_index: cross_shard::DELIVERY_INDEX,
+                    amount: receipt.amount,
+                    owner: receipt.recipient.clone(),
+                    script_pubkey: receipt.script_pubkey.clone(),
+                    is_spent: false,
+                    created_at: tx.timestamp,
+                    spent_at: None,
+                });
+                self.update_account_balance(&receipt.recipient, receipt.amount, true, tx.timestamp)?;
+                
+                debug!("Delivered {} from shard {} to {}", receipt.amount, receipt.source_shard, receipt.recipient);
+            }
+            (CrossShardAction::Refund { .. }, Some(receipt)) => {
+                self.set_storage(CROSS_SHARD_ADDRESS, &cross_shard::outgoing_key(&receipt.tx_hash), None, tx.timestamp);
+                self.update_account_balance(CROSS_SHARD_ADDRESS, receipt.amount, false, tx.timestamp)?;
+                
+                self.insert_utxo(receipt.refund_utxo_key(), UTXO {
+                    tx_hash: receipt.id(),
+                    output_index: cross_shard::REFUND_INDEX,
+                    amount: receipt.amount,
+                    owner: receipt.refund_address.clone(),
+                    script_pubkey: receipt.refund_key.clone(),
+                    is_spent: false,
+                    created_at: tx.timestamp,
+                    spent_at: None,
+                });
+                self.update_account_balance(&receipt.refund_address, receipt.amount, true, tx.timestamp)?;
+                
+                debug!("Refunded {} unclaimed by shard {} to {}", receipt.amount, receipt.target_shard, receipt.refund_address);
+            }
+            _ => return Err(format!("Shard crossing {} settles no receipt", tx.hash)),
+        }
+        
+        Ok(())
+    }
+    
+    // Checks a crosslink against the current state: only beacon blocks carry
+    // them, and each one moves another shard's checkpoint forward to a
+    // header this node has seen finalized
+    fn check_crosslink(&self, tx: &Transaction) -> Result<FinalityProof, String> {
+        let proof = FinalityProof::from_transaction(tx)?;
+        let shard_id = proof.header.shard_id;
+        let height = proof.header.height;
+        
+        if self.shard_id != BEACON_SHARD {
+            return Err(format!("Crosslink {} is only valid in beacon shard {}", tx.hash, BEACON_SHARD));
+        }
+        
+        if shard_id == BEACON_SHARD {
+            return Err(format!("Crosslink {} links the beacon shard to itself", tx.hash));
+        }
+        
+        if let Some(latest) = self.crosslink(shard_id) {
+            if height <= latest.height {
+                return Err(format!("Crosslink {} for shard {} at height {} is not past height {}",
+                                   tx.hash, shard_id, height, latest.height));
+            }
+        }
+        
+        // The block's committee is the one this state recorded for its epoch
+        let epoch = params::get().epoch_at(height);
+        let validator_set = self.validator_set(epoch)
+            .ok_or_else(|| format!("Crosslink {} is for epoch {}, which has no recorded validator set", tx.hash, epoch))?;
+        
+        proof.verify(&validator_set)
+            .map_err(|e| format!("Crosslink {} does not prove block {} of shard {} final: {}", tx.hash, Block::calculate_hash(&proof.header), shard_id, e))?;
+        Ok(proof)
+    }
+    
+    fn handle_crosslink(&mut self, tx: &Transaction) -> Result<(), String> {
+        // Already checked by validate_transaction
+        let crosslink = self.check_crosslink(tx)?.crosslink(self.current_height + 1);
+        
+        let value = encoding::to_bytes(&crosslink);
+        self.set_storage(BEACON_ADDRESS, &beacon::crosslinked_key(crosslink.shard_id, crosslink.height), Some(value.clone()), tx.timestamp);
+        self.set_storage(BEACON_ADDRESS, &crosslink.storage_key(), Some(value), tx.timestamp);
+        
+        debug!("Crosslinked block {} of shard {} at height {}", crosslink.block_hash, crosslink.shard_id, crosslink.height);
+        Ok(())
+    }
+    
+    /// Returns the latest crosslink of `shard_id` in the beacon state.
+    pub fn crosslink(&self, shard_id: u16) -> Option<Crosslink> {
+        self.storage_value(BEACON_ADDRESS, &beacon::crosslink_key(shard_id))
+            .and_then(|value| encoding::from_bytes(value).ok())
+    }
+    
+    /// Returns the crosslink of the block of `shard_id` at `height` in the
+    /// beacon state, if that block was crosslinked.
+    pub fn crosslink_at(&self, shard_id: u16, height: u64) -> Option<Crosslink> {
+        self.storage_value(BEACON_ADDRESS, &beacon::crosslinked_key(shard_id, height))
+            .and_then(|value| encoding::from_bytes(value).ok())
+    }
+    
+    /// Checks, in the beacon state, that the beacon block at `beacon_height`
+    /// anchors `header`: a block of another shard crosslinked by it or an
+    /// earlier beacon block, or a beacon block it descends from. Only a
+    /// finalized beacon block can anchor, so every node that has finalized
+    /// it gets the same answer.
+    pub fn check_anchored(&self, header: &BlockHeader, beacon_height: u64) -> Result<(), String> {
+        if beacon_height > self.finalized_height {
+            return Err(format!("Beacon block at height {} is not final yet", beacon_height));
+        }
+        
+        self.check_anchored_by(header, beacon_height, &self.finalized_hash)
+    }
+    
+    /// Checks the headers a claim, refund or import of another shard relies
+    /// on against the beacon block at `beacon_height`, as `check_anchored`.
+    pub fn check_anchors(&self, tx: &Transaction, beacon_height: u64) -> Result<(), String> {
+        if let Ok(action) = CrossShardAction::from_transaction(tx) {
+            for header in action.anchor_headers()? {
+                self.check_anchored(header, beacon_height)?;
+            }
+        }
+        
+        if let Ok(action) = MigrationAction::from_transaction(tx) {
+            for header in action.anchor_headers()? {
+                self.check_anchored(header, beacon_height)?;
+            }
+        }
+        Ok(())
+    }
+    
+    // Checks that `header` is anchored by the beacon block at `beacon_height`
+    // on the chain ending at `tip`
+    fn check_anchored_by(&self, header: &BlockHeader, beacon_height: u64, tip: &str) -> Result<(), String> {
+        let block_hash = Block::calculate_hash(header);
+        let anchored = if header.shard_id == BEACON_SHARD {
+            header.height <= beacon_height && self.ancestor_at(tip, header.height).as_deref() == Some(block_hash.as_str())
+        } else {
+            self.crosslink_at(header.shard_id, header.height)
+                .is_some_and(|crosslink| crosslink.block_hash == block_hash && crosslink.linked_at <= beacon_height)
+        };
+        
+        if !anchored {
+            return Err(format!("Block {} of shard {} at height {} is not anchored by beacon block {}",
+                               block_hash, header.shard_id, header.height, beacon_height));
+        }
+        Ok(())
+    }
+    
+    // Checks that a header a claim or refund relies on is anchored. The
+    // beacon shard reads its own state, crosslinks the block being applied
+    // recorded so far included; other shards can't see the beacon state, so
+    // the chain layer checks their blocks with `check_anchors` before they
+    // are imported.
+    fn check_anchored_here(&self, header: &BlockHeader) -> Result<(), String> {
+        if self.shard_id != BEACON_SHARD {
+            return Ok(());
+        }
+        
+        self.check_anchored_by(header, self.current_height + 1, &self.best_block_hash)
+    }
+    
+    /// Returns the latest crosslink of `shard_id` that the beacon block at
+    /// `beacon_height` or an earlier one recorded, for linking receipts to.
+    pub fn anchor_of(&self, shard_id: u16, beacon_height: u64) -> Option<Crosslink> {
+        let account = self.accounts.get(BEACON_ADDRESS)?;
+        let first = beacon::crosslinked_key(shard_id, 0);
+        let last = beacon::crosslinked_key(shard_id, u64::MAX);
+        account.storage
+            .iter()
+            .filter(|(key, _)| first.as_str() <= key.as_str() && key.as_str() <= last.as_str())
+            .filter_map(|(_, value)| encoding::from_bytes::<Crosslink>(value).ok())
+            .filter(|crosslink| crosslink.linked_at <= beacon_height)
+            .max_by_key(|crosslink| crosslink.height)
+    }
+    
+    /// Returns the latest crosslink of every shard in the beacon state,
+    /// ordered by shard.
+    pub fn crosslinks(&self) -> Vec<Crosslink> {
+        let account = match self.accounts.get(BEACON_ADDRESS) {
+            Some(account) => account,
+            None => return Vec::new(),
+        };
+        
+        let mut crosslinks: Vec<Crosslink> = account.storage
+            .iter()
+            .filter(|(key, _)| beacon::is_crosslink_key(key))
+            .filter_map(|(_, value)| encoding::from_bytes(value).ok())
+            .collect();
+        crosslinks.sort_by_key(|crosslink| crosslink.shard_id);
+        crosslinks
+    }
+    
+    // Amount a cross-shard send locked, until it is refunded
+    fn locked_amount(&self, tx_hash: &str) -> Option<u64> {
+        self.storage_value(CROSS_SHARD_ADDRESS, &cross_shard::outgoing_key(tx_hash))
+            .and_then(|value| value.as_slice().try_into().ok())
+            .map(u64::from_le_bytes)
+    }
+    
+    fn best_block_timestamp(&self) -> u64 {
+        self.blocks
+            .get(&self.best_block_hash)
+            .map(|tip| tip.timestamp)
+            .unwrap_or(0)
+    }
+    
+    // Checks a step of a shard migration against the current state. An
+    // import also returns the handover it takes its part of.
+    fn check_shard_migration(&self, tx: &Transaction) -> Result<(MigrationAction, Option<Handover>), String> {
+        let action = MigrationAction::from_transaction(tx)?;
+        let handover = match &action {
+            MigrationAction::Approve { proposal, .. } if self.shard_id != BEACON_SHARD => {
+                return Err(format!("Approval of {:?} is only valid in beacon shard {}", proposal.kind, BEACON_SHARD));
+            }
+            MigrationAction::Approve { proposal, approval } => {
+                self.check_approval(proposal, approval)?;
+                None
+            }
+            MigrationAction::Switch { shard_map, handover } => {
+                let current = self.shard_map();
+                if shard_map.version != current.version + 1 {
+                    return Err(format!("Switch {} is to shard map version {}, not {}", tx.hash, shard_map.version, current.version + 1));
+                }
+                
+                // Every validator of the shard hands over the same state
+                if encoding::to_bytes(handover.as_ref()) != encoding::to_bytes(&self.handover(shard_map)) {
+                    return Err(format!("Switch {} hands over other state than shard {} leaves", tx.hash, self.shard_id));
+                }
+                None
+            }
+            MigrationAction::Import { switch } => Some(self.check_import(tx, switch)?),
+        };
+        
+        Ok((action, handover))
+    }
+    
+    // Checks a migration approval in the beacon state: from a validator
+    // active in the epoch of the block being applied, once per epoch, for a
+    // split or merge of the next epoch's map while no other one is under way.
+    // Returns the approving validator.
+    fn check_approval(&self, proposal: &MigrationProposal, approval: &Attestation) -> Result<String, String> {
+        let epoch = params::get().epoch_at(self.current_height + 1);
+        if proposal.epoch != epoch {
+            return Err(format!("Approval of {:?} is for epoch {}, not {}", proposal.kind, proposal.epoch, epoch));
+        }
+        
+        let validator_set = self.validator_set(epoch)
+            .ok_or_else(|| format!("Epoch {} has no recorded validator set", epoch))?;
+        let next_set = self.validator_set(epoch + 1)
+            .ok_or_else(|| format!("Epoch {} has no recorded validator set", epoch + 1))?;
+        if next_set.shard_map != validator_set.shard_map {
+            return Err(format!("Shard map version {} is still to take effect in epoch {}", next_set.shard_map.version, epoch + 1));
+        }
+        
+        let validator = proposal.verify_approval(approval)?;
+        if !validator_set.is_active(&validator) {
+            return Err(format!("Approver {} is not an active validator in epoch {}", validator, epoch));
+        }
+        
+        if self.storage_value(BEACON_ADDRESS, &migration::approval_key(epoch, &validator)).is_some() {
+            return Err(format!("Validator {} already approved a migration in epoch {}", validator, epoch));
+        }
+        
+        proposal.kind.apply(&next_set.shard_map)?;
+        if let MigrationKind::Split { new_shard_id, .. } = proposal.kind {
+            let expected = self.next_shard_id(&next_set.shard_map);
+            if new_shard_id != expected {
+                return Err(format!("Split creates shard {}, not {}", new_shard_id, expected));
+            }
+        }
+        
+        Ok(validator)
+    }
+    
+    // Lowest shard id above those of every shard map version recorded here
+    // and of `latest`. Retired shards keep their chains, so their ids are
+    // never reused.
+    fn next_shard_id(&self, latest: &ShardMap) -> u16 {
+        let recorded: Vec<ShardMap> = self.accounts
+            .get(SHARD_MAP_ADDRESS)
+            .map(|account| {
+                account.storage
+                    .iter()
+                    .filter(|(key, _)| shard_map::is_version_key(key))
+                    .filter_map(|(_, value)| encoding::from_bytes(value).ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        
+        recorded
+            .iter()
+            .chain(std::iter::once(latest))
+            .flat_map(|shard_map| shard_map.shard_ids())
+            .max()
+            .map_or(0, |shard_id| shard_id.saturating_add(1))
+    }
+    
+    // Checks an import of another shard's switch: the switch must be to the
+    // map this shard is on, in the block of the source just below the one
+    // being applied, and one this shard still waits for
+    fn check_import(&self, tx: &Transaction, switch: &ReceiptProof) -> Result<Handover, String> {
+        switch.verify_inclusion()?;
+        let source = switch.header.shard_id;
+        let (shard_map, handover) = match MigrationAction::from_transaction(&switch.tx)? {
+            MigrationAction::Switch { shard_map, handover } => (shard_map, handover),
+            _ => return Err(format!("Import {} carries no switch of shard {}", tx.hash, source)),
+        };
+        
+        let current = self.shard_map();
+        if shard_map != current {
+            return Err(format!("Import {} is of a switch to shard map version {}, not {}", tx.hash, shard_map.version, current.version));
+        }
+        
+        if switch.header.height != self.current_height {
+            return Err(format!("Import {} is of a switch at height {}, not {}", tx.hash, switch.header.height, self.current_height));
+        }
+        
+        if self.storage_value(SHARD_MAP_ADDRESS, &shard_map::import_key(current.version, source)).is_none() {
+            return Err(format!("Shard {} takes no import from shard {} for shard map version {}", self.shard_id, source, current.version));
+        }
+        
+        self.check_anchored_here(switch.anchor()?)?;
+        Ok(*handover)
+    }
+    
+    fn handle_shard_migration(&mut self, tx: &Transaction) -> Result<(), String> {
+        // Already checked by validate_transaction
+        let (action, handover) = self.check_shard_migration(tx)?;
+        
+        match (action, handover) {
+            (MigrationAction::Approve { proposal, approval }, _) => {
+                let validator = approval.attester()?;
+                self.set_storage(BEACON_ADDRESS, &migration::approval_key(proposal.epoch, &validator), Some(encoding::to_bytes(&proposal)), tx.timestamp);
+                
+                debug!("Validator {} approved {:?} in epoch {}", validator, proposal.kind, proposal.epoch);
+            }
+            (MigrationAction::Switch { shard_map, handover }, _) => {
+                self.switch_shard_map(&shard_map, &handover, tx.timestamp);
+                
+                info!("Shard {} switched to shard map version {}, handing over {} accounts and {} UTXOs",
+                      self.shard_id, shard_map.version, handover.accounts.len(), handover.utxos.len());
+            }
+            (MigrationAction::Import { switch }, Some(handover)) => {
+                self.import_handover(switch.header.shard_id, &handover, tx.timestamp)?;
+                
+                info!("Shard {} imported the handover of shard {}", self.shard_id, switch.header.shard_id);
+            }
+            _ => return Err(format!("Import {} carries no handover", tx.hash)),
+        }
+        
+        Ok(())
+    }
+    
+    /// Returns what this shard hands over when it switches to `next`: the
+    /// accounts and UTXOs of the addresses it gives up, and the claim records
+    /// of their receipts. A shard `next` retires also hands over its
+    /// cross-shard account whole, with the amounts it locked, so they can
+    /// still be claimed or refunded.
+    pub fn handover(&self, next: &ShardMap) -> Handover {
+        let current = self.shard_map();
+        let shard_id = self.shard_id;
+        let leaves = |owner: &str| {
+            !shard_map::is_system_address(owner) && current.shard_of(owner) == shard_id && next.shard_of(owner) != shard_id
+        };
+        
+        let mut accounts: Vec<Account> = self.accounts.values().filter(|account| leaves(&account.address)).cloned().collect();
+        accounts.sort_by(|a, b| a.address.cmp(&b.address));
+        
+        let mut utxos: Vec<(&String, &UTXO)> = self.utxos.iter().filter(|(_, utxo)| leaves(&utxo.owner)).collect();
+        utxos.sort_by(|a, b| a.0.cmp(b.0));
+        
+        let retires = !next.shard_ids().contains(&shard_id);
+        let escrow = self.accounts.get(CROSS_SHARD_ADDRESS);
+        let mut escrow_entries: Vec<StorageEntry> = escrow
+            .map(|escrow| {
+                escrow.storage
+                    .iter()
+                    .filter(|(key, value)| {
+                        retires || (cross_shard::is_claimed_key(key)
+                            && encoding::from_bytes::<ClaimRecord>(value).is_ok_and(|record| leaves(&record.recipient)))
+                    })
+                    .map(|(key, value)| StorageEntry { key: key.clone(), value: value.clone() })
+                    .collect()
+            })
+            .unwrap_or_default();
+        escrow_entries.sort_by(|a, b| a.key.cmp(&b.key));
+        
+        Handover {
+            accounts,
+            utxos: utxos.into_iter().map(|(_, utxo)| utxo.clone()).collect(),
+            escrow_balance: if retires { escrow.map(|escrow| escrow.balance).unwrap_or(0) } else { 0 },
+            escrow_entries,
+        }
+    }
+    
+    // Removes what this shard hands over and records the next version of the
+    // shard map. The version is in force from the block after this one, the
+    // first of its activation epoch, which also takes the imports from the
+    // shards that hand addresses over to this one.
+    fn switch_shard_map(&mut self, shard_map: &ShardMap, handover: &Handover, timestamp: u64) {
+        let current = self.shard_map();
+        for account in &handover.accounts {
+            self.record_account(&account.address);
+            self.accounts.remove(&account.address);
+        }
+        for utxo in &handover.utxos {
+            self.remove_utxo(&format!("{}:{}", utxo.tx_hash, utxo.output_index));
+        }
+        
+        // The handover was checked against this state, so the whole locked
+        // amount leaves with a retiring shard
+        if handover.escrow_balance > 0 {
+            if let Some(escrow) = self.existing_account_mut(CROSS_SHARD_ADDRESS) {
+                escrow.balance -= handover.escrow_balance;
+            }
+        }
+        for entry in &handover.escrow_entries {
+            self.set_storage(CROSS_SHARD_ADDRESS, &entry.key, None, timestamp);
+        }
+        
+        self.record_shard_map(&current, shard_map, self.current_height + 2, timestamp);
+    }
+    
+    // Records `shard_map`, in force from `activation_height`, with an import
+    // pending from every shard that `previous` gave addresses this shard owns
+    // in `shard_map`
+    fn record_shard_map(&mut self, previous: &ShardMap, shard_map: &ShardMap, activation_height: u64, timestamp: u64) {
+        let version = shard_map.version;
+        self.set_storage(SHARD_MAP_ADDRESS, &shard_map.storage_key(), Some(encoding::to_bytes(shard_map)), timestamp);
+        self.set_storage(SHARD_MAP_ADDRESS, &shard_map::activation_key(version), Some(activation_height.to_le_bytes().to_vec()), timestamp);
+        for source in previous.sources(shard_map, self.shard_id) {
+            self.set_storage(SHARD_MAP_ADDRESS, &shard_map::import_key(version, source), Some(source.to_le_bytes().to_vec()), timestamp);
+        }
+    }
+    
+    // Adds the part of a source shard's handover that this shard owns now. An
+    // account it already has, such as the cross-shard account when a retired
+    // shard is merged into it, gets the other's balances and storage added.
+    fn import_handover(&mut self, source: u16, handover: &Handover, timestamp: u64) -> Result<(), String> {
+        let shard_map = self.shard_map();
+        let shard_id = self.shard_id;
+        let owned = |owner: &str| shard_map.shard_of(owner) == shard_id;
+        
+        for account in handover.accounts.iter().filter(|account| owned(&account.address)) {
+            self.add_account(account)?;
+        }
+        
+        for utxo in handover.utxos.iter().filter(|utxo| owned(&utxo.owner)) {
+            let key = format!("{}:{}", utxo.tx_hash, utxo.output_index);
+            if self.utxos.contains_key(&key) {
+                return Err(format!("UTXO {} already exists in shard {}", key, shard_id));
+            }
+            self.insert_utxo(key, utxo.clone());
+        }
+        
+        // A retired shard's escrow goes whole to the shard it was merged into
+        let retired = !shard_map.shard_ids().contains(&source);
+        if retired && handover.escrow_balance > 0 {
+            let escrow = self.account_mut(CROSS_SHARD_ADDRESS, timestamp);
+            escrow.balance = escrow.balance
+                .checked_add(handover.escrow_balance)
+                .ok_or_else(|| format!("Cross-shard balance overflows in shard {}", shard_id))?;
+        }
+        
+        for entry in &handover.escrow_entries {
+            let taken = retired || encoding::from_bytes::<ClaimRecord>(&entry.value).is_ok_and(|record| owned(&record.recipient));
+            if taken {
+                self.set_storage(CROSS_SHARD_ADDRESS, &entry.key, Some(entry.value.clone()), timestamp);
+            }
+        }
+        
+        self.set_storage(SHARD_MAP_ADDRESS, &shard_map::import_key(shard_map.version, source), None, timestamp);
+        Ok(())
+    }
+    
+    // Adds an account handed over by another shard to the one kept here, if
+    // any
+    fn add_account(&mut self, account: &Account) -> Result<(), String> {
+        let address = &account.address;
+        self.record_account(address);
+        self.stale.rebuilt.insert(address.clone());
+        
+        let existing = match self.accounts.get_mut(address) {
+            Some(existing) => existing,
+            None => {
+                self.accounts.insert(address.clone(), account.clone());
+                return Ok(());
+            }
+        };
+        
+        existing.balance = existing.balance
+            .checked_add(account.balance)
+            .ok_or_else(|| format!("Balance of {} overflows in shard {}", address, self.shard_id))?;
+        existing.stake_amount = existing.stake_amount
+            .checked_add(account.stake_amount)
+            .ok_or_else(|| format!("Stake of {} overflows in shard {}", address, self.shard_id))?;
+        existing.nonce = existing.nonce.max(account.nonce);
+        existing.storage.extend(account.storage.clone());
+        Ok(())
+    }
+    
+    // First height at which this shard's map is in force; 0 for the genesis
+    // map
+    fn activation_height(&self) -> u64 {
+        self.storage_value(SHARD_MAP_ADDRESS, &shard_map::activation_key(self.shard_map().version))
+            .and_then(|value| value.as_slice().try_into().ok())
+            .map(u64::from_le_bytes)
+            .unwrap_or(0)
+    }
+    
+    // Whether this shard still waits for the imports of its map
+    fn has_pending_imports(&self) -> bool {
+        self.accounts
+            .get(SHARD_MAP_ADDRESS)
+            .is_some_and(|account| account.storage.keys().any(|key| shard_map::is_import_key(key)))
+    }
+    
+    /// Starts a shard that a split creates, from the genesis state: records
+    /// the map that creates it, in force from `activation_height`, and the
+    /// imports it waits for from the shards of `previous`, at the height
+    /// before. Only possible before the first block; a shard started already
+    /// is left as it is.
+    pub fn start_shard(&mut self, previous: &ShardMap, shard_map: &ShardMap, activation_height: u64) -> Result<(), String> {
+        if self.shard_map().version >= shard_map.version {
+            return Ok(());
+        }
+        
+        if !self.best_block_hash.is_empty() {
+            return Err(format!("Shard {} already has blocks", self.shard_id));
+        }
+        
+        self.record_shard_map(previous, shard_map, activation_height, genesis::get().timestamp);
+        self.current_height = activation_height.saturating_sub(1);
+        
+        info!("Started shard {} at height {} for shard map version {}", self.shard_id, self.current_height, shard_map.version);
+        self.persist_state()
+    }
+    
+    // Writes out the state changes made outside of a block
+    fn persist_state(&mut self) -> Result<(), String> {
+        self.update_tree();
+        let touched = std::mem::take(&mut self.journal);
+        match &self.store {
+            Some(store) => store.write_state(&self.current_values(&touched)),
+            None => Ok(()),
+        }
+    }
+    
+    /// Returns the latest version of the shard map recorded in this shard's
+    /// state. States built without a genesis follow the genesis map.
+    pub fn shard_map(&self) -> ShardMap {
+        self.accounts
+            .get(SHARD_MAP_ADDRESS)
+            .and_then(|account| {
+                account.storage
+                    .iter()
+                    .filter(|(key, _)| shard_map::is_version_key(key))
+                    .max_by_key(|(key, _)| *key)
+                    .and_then(|(_, value)| encoding::from_bytes(value).ok())
+            })
+            .unwrap_or_else(|| genesis::get().shard_map())
+    }
+    
+    fn get_input_owner(&self, input: &TransactionInput) -> Result<String, String> {
+        let utxo_key = format!("{}:{}", input.previous_tx, input.index);
+        self.utxos
+            .get(&utxo_key)
+            .map(|utxo| utxo.owner.clone())
+            .ok_or_else(|| format!("UTXO {}:{} not found", input.previous_tx, input.index))
+    }
+    
+    /// Hex-encoded root committing to the current state, as stored in block headers.
+    pub fn state_root(&self) -> String {
+        hex::encode(self.tree.root())
+    }
+    
+    /// Proves the account at `address` (or its absence) against `state_root()`.
+    pub fn prove_account(&self, address: &str) -> AccountProof {
+        AccountProof {
+            address: address.to_string(),
+            account: self.accounts.get(address).map(|account| Account {
+                storage: HashMap::new(),
+                ..account.clone()
+            }),
+            storage_root: hex::encode(self.tree.storage_root(address)),
+            proof: self.tree.prove(&state_tree::account_key(address)),
+        }
+    }
+    
+    /// Proves the UTXO at `key` (`tx_hash:output_index`), or its absence,
+    /// against `state_root()`.
+    pub fn prove_utxo(&self, key: &str) -> SparseMerkleProof {
+        self.tree.prove(&state_tree::utxo_key(key))
+    }
+    
+    /// Proves the given contract storage slots of `address` against `state_root()`.
+    pub fn prove_storage(&self, address: &str, keys: &[String]) -> StorageProof {
+        let slots = keys
+            .iter()
+            .map(|key| StorageSlotProof {
+                key: key.clone(),
+                value: self.storage_value(address, key).map(hex::encode),
+                proof: self.tree.prove_storage(address, key),
+            })
+            .collect();
+        
+        StorageProof {
+            account_proof: self.prove_account(address),
+            slots,
+        }
+    }
+    
+    /// Proves, for a refund of `receipt`, that this shard has not claimed it.
+    pub fn prove_unclaimed(&self, receipt: &Receipt) -> StorageProof {
+        self.prove_storage(CROSS_SHARD_ADDRESS, &[cross_shard::claimed_key(&receipt.id())])
+    }
+    
+    /// Returns an in-memory copy of the state as of `block_hash`, rebuilt by
+    /// undoing the blocks above it. Only the states of best-chain blocks from
+    /// the finalized block up can be rebuilt; older undo journals are pruned.
+    pub fn state_at(&self, block_hash: &str) -> Option<StateManager> {
+        let metadata = self.blocks.get(block_hash)?;
+        if metadata.height < self.finalized_height
+            || self.ancestor_at(&self.best_block_hash, metadata.height).as_deref() != Some(block_hash) {
+            return None;
+        }
+        
+        let mut state = self.scratch_copy();
+        while state.best_block_hash != block_hash {
+            let undone = self.blocks.get(&state.best_block_hash)?;
+            state.restore(self.undo_journals.get(&undone.hash)?);
+            state.current_height = undone.height - 1;
+            state.best_block_hash = undone.parent_hash.clone();
+        }
+        
+        Some(state)
+    }
+    
+    /// Returns an in-memory copy of the state that isn't backed by storage,
+    /// for applying blocks speculatively on top of the best block.
+    pub fn scratch_copy(&self) -> StateManager {
+        StateManager {
+            accounts: self.accounts.clone(),
+            utxos: self.utxos.clone(),
+            blocks: self.blocks.clone(),
+            branch_blocks: HashMap::new(),
+            current_height: self.current_height,
+            best_block_hash: self.best_block_hash.clone(),
+            finalized_height: self.finalized_height,
+            finalized_hash: self.finalized_hash.clone(),
+            shard_id: self.shard_id,
+            store: None,
+            journal: StateChanges::default(),
+            undo_journals: HashMap::new(),
+            tree: self.tree.clone(),
+            stale: StaleEntries::default(),
+        }
+    }
+    
+    pub fn get_account(&self, address: &str) -> Option<Account> {
+        self.accounts.get(address).cloned()
+    }
+    
+    pub fn get_utxo(&self, tx_hash: &str, output_index: u32) -> Option<UTXO> {
+        let key = format!("{}:{}", tx_hash, output_index);
+        self.utxos.get(&key).cloned()
+    }
+    
+    pub fn get_unspent_utxos_for_address(&self, address: &str) -> Vec<UTXO> {
+        self.utxos.values()
+            .filter(|utxo| utxo.owner == address && !utxo.is_spent)
+            .cloned()
+            .collect()
+    }
+    
+    /// Returns the headers from the child of `block_hash` up to
+    /// `descendant_hash`, oldest first, if the latter descends from the
+    /// former. Receipt proofs link their block to an anchored one with them.
+    pub fn descendant_headers(&self, block_hash: &str, descendant_hash: &str) -> Option<Vec<BlockHeader>> {
+        let ancestor_height = self.blocks.get(block_hash)?.height;
+        let mut headers = Vec::new();
+        let mut hash = descendant_hash.to_string();
+        while hash != block_hash {
+            let block = self.get_block(&hash)?;
+            if block.header.height <= ancestor_height {
+                return None;
+            }
+            hash = block.header.previous_hash.clone();
+            headers.push(block.header);
+        }
+        
+        headers.reverse();
+        Some(headers)
+    }
+    
+    pub fn get_block(&self, block_hash: &str) -> Option<Block> {
+        self.load_block(block_hash)
+    }
+    
+    /// Finds a stored transaction and returns it with the block that includes
+    /// it. Without a store, the best chain kept in memory is searched.
+    pub fn get_transaction_with_block(&self, tx_hash: &str) -> Option<(Transaction, Block)> {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return self.find_transaction(tx_hash),
+        };
+        let location = match store.get_tx_location(tx_hash) {
+            Ok(location) => location?,
+            Err(e) => {
+                error!("Failed to look up transaction {}: {}", tx_hash, e);
+                return None;
+            }
+        };
+        
+        let block = self.get_block(&location.block_hash)?;
+        let tx = block.transactions.get(location.index as usize)?.clone();
+        Some((tx, block))
+    }
+    
+    // Searches the best chain, newest block first, for a transaction. Blocks
+    // stay in memory when there is no store.
+    fn find_transaction(&self, tx_hash: &str) -> Option<(Transaction, Block)> {
+        let mut hash = self.best_block_hash.as_str();
+        while let Some(block) = self.branch_blocks.get(hash) {
+            if let Some(tx) = block.transactions.iter().find(|tx| tx.hash == tx_hash) {
+                return Some((tx.clone(), block.clone()));
+            }
+            hash = &block.header.previous_hash;
+        }
+        None
+    }
+    
+    /// Proves that a transaction of the best chain is included under the
+    /// merkle root of its block, returned with the proof.
+    pub fn prove_transaction(&self, tx_hash: &str) -> Option<(Block, MerkleProof)> {
+        let (_, block) = self.get_transaction_with_block(tx_hash)?;
+        let proof = block.transaction_proof(tx_hash)?;
+        Some((block, proof))
+    }
+    
+    /// Returns the blocks of the best chain from `from_height` to
+    /// `to_height`, lowest first.
+    pub fn get_best_chain_blocks(&self, from_height: u64, to_height: u64) -> Vec<Block> {
+        let mut hashes = Vec::new();
+        let mut hash = self.best_block_hash.clone();
+        while let Some(metadata) = self.blocks.get(&hash) {
+            if metadata.height < from_height {
+                break;
+            }
+            if metadata.height <= to_height {
+                hashes.push(hash.clone());
+            }
+            hash = metadata.parent_hash.clone();
+        }
+        
+        hashes.iter().rev().filter_map(|hash| self.load_block(hash)).collect()
+    }
+    
+    pub fn get_block_metadata(&self, block_hash: &str) -> Option<BlockMetadata> {
+        self.blocks.get(block_hash).cloned()
+    }
+    
+    pub fn get_current_height(&self) -> u64 {
+        self.current_height
+    }
+    
+    pub fn get_best_block_hash(&self) -> String {
+        self.best_block_hash.clone()
+    }
+    
+    /// Returns the last finalized block as (height, block hash), if any.
+    pub fn get_finalized_head(&self) -> Option<(u64, String)> {
+        if self.finalized_hash.is_empty() {
+            None
+        } else {
+            Some((self.finalized_height, self.finalized_hash.clone()))
+        }
+    }
+    
+    pub fn get_finalized_height(&self) -> u64 {
+        self.finalized_height
+    }
+    
+    pub fn get_shard_id(&self) -> u16 {
+        self.shard_id
+    }
+}
+
+// Global state manager instances (one per shard)
+// Accounts and UTXOs are encoded whole when a migration hands them over to
+// another shard; storage entries are ordered by key
+impl Canonical for Account {
+    fn encode(&self, encoder: &mut Encoder) {
+        let mut storage: Vec<StorageEntry> = self.storage
+            .iter()
+            .map(|(key, value)| StorageEntry { key: key.clone(), value: value.clone() })
+            .collect();
+        storage.sort_by(|a, b| a.key.cmp(&b.key));
+        
+        encoder.put_str(&self.address);
+        encoder.put_u64(self.balance);
+        encoder.put_u64(self.nonce);
+        encoder.put_bytes(&self.code);
+        encoder.put_seq(&storage);
+        encoder.put_u64(self.stake_amount);
+        encoder.put_u32(self.contribution_score);
+        encoder.put_u64(self.last_updated);
+        encoder.put_u64(self.jailed_until_epoch);
+        encoder.put_u32(self.missed_proposals);
+    }
+    
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(Account {
+            address: decoder.get_string()?,
+            balance: decoder.get_u64()?,
+            nonce: decoder.get_u64()?,
+            code: decoder.get_bytes()?,
+            storage: decoder.get_seq::<StorageEntry>()?.into_iter().map(|entry| (entry.key, entry.value)).collect(),
+            stake_amount: decoder.get_u64()?,
+            contribution_score: decoder.get_u32()?,
+            last_updated: decoder.get_u64()?,
+            jailed_until_epoch: decoder.get_u64()?,
+            missed_proposals: decoder.get_u32()?,
+        })
+    }
+}
+
+impl Canonical for UTXO {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_str(&self.tx_hash);
+        encoder.put_u32(self.output_index);
+        encoder.put_u64(self.amount);
+        encoder.put_str(&self.owner);
+        encoder.put_str(&self.script_pubkey);
+        encoder.put_bool(self.is_spent);
+        encoder.put_u64(self.created_at);
+        encoder.put_option(&self.spent_at, |encoder, height| encoder.put_u64(*height));
+    }
+    
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(UTXO {
+            tx_hash: decoder.get_string()?,
+            output_index: decoder.get_u32()?,
+            amount: decoder.get_u64()?,
+            owner: decoder.get_string()?,
+            script_pubkey: decoder.get_string()?,
+            is_spent: decoder.get_bool()?,
+            created_at: decoder.get_u64()?,
+            spent_at: decoder.get_option(|decoder| decoder.get_u64())?,
+        })
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref STATE_MANAGERS: Arc<RwLock<HashMap<u16, Arc<Mutex<StateManager>>>>> = 
+        Arc::new(RwLock::new(HashMap::new()));
+    
+    // Root directory for persistent shard databases (None keeps state in memory)
+    static ref DATA_DIR: RwLock<Option<PathBuf>> = RwLock::new(None);
+}
+
+pub fn initialize() -> Result<(), Box<dyn std::error::Error>> {
+    info!("Initializing state management system...");
+    
+    // Initialize a state manager for every shard of the genesis
+    let mut state_managers = STATE_MANAGERS.write().unwrap();
+    for shard_id in 0..genesis::get().shard_count {
+        let state_manager = new_state_manager(shard_id)?;
+        state_managers.insert(shard_id, Arc::new(Mutex::new(state_manager)));
+    }
+    
+    info!("State management system initialized successfully");
+    Ok(())
+}
 
 pub fn shutdown() -> Result<(), Box<dyn std::error::Error>> {
     info!("Shutting down state management system...");
@@ -386,21 +2699,957 @@ pub fn shutdown() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Persists shard state under `data_dir`, one database per shard. Shards that
+/// are already loaded are reopened from disk so a restarted node resumes at
+/// its last persisted height.
+pub fn open_storage(data_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Using data directory {}", data_dir.display());
+    
+    std::fs::create_dir_all(data_dir)?;
+    *DATA_DIR.write().unwrap() = Some(data_dir.to_path_buf());
+    
+    let mut state_managers = STATE_MANAGERS.write().unwrap();
+    let shard_ids: Vec<u16> = state_managers.keys().cloned().collect();
+    for shard_id in shard_ids {
+        let state_manager = new_state_manager(shard_id)?;
+        state_managers.insert(shard_id, Arc::new(Mutex::new(state_manager)));
+    }
+    
+    Ok(())
+}
+
+fn new_state_manager(shard_id: u16) -> Result<StateManager, String> {
+    let genesis = genesis::get();
+    match DATA_DIR.read().unwrap().as_ref() {
+        Some(data_dir) => {
+            let store = ChainStore::open(&data_dir.join(format!("shard-{}", shard_id)))?;
+            StateManager::open(shard_id, Arc::new(store), &genesis)
+        }
+        None => {
+            let mut state_manager = StateManager::new(shard_id);
+            state_manager.apply_genesis(&genesis)?;
+            Ok(state_manager)
+        }
+    }
+}
+
 pub fn get_state_manager(shard_id: u16) -> Option<Arc<Mutex<StateManager>>> {
     let state_managers = STATE_MANAGERS.read().unwrap();
     state_managers.get(&shard_id).cloned()
 }
 
-pub fn create_state_manager(shard_id: u16) -> Result<(), Box<dyn std::error::Error>> {
-    let mut state_managers = STATE_MANAGERS.write().unwrap();
+/// Height of the latest finalized beacon block, which new blocks and
+/// transactions of other shards anchor their claims and refunds to.
+pub fn finalized_beacon_height() -> u64 {
+    get_state_manager(BEACON_SHARD).map_or(0, |beacon_state| beacon_state.lock().unwrap().get_finalized_height())
+}
+
+/// Checks that the beacon block at `beacon_height` is final and anchors the
+/// claims and refunds among `transactions` of `shard_id` (see
+/// `StateManager::check_anchored`). Must be called with no shard state
+/// locked, since the beacon shard's lock comes first. Beacon blocks check
+/// their own as they are applied.
+pub fn check_anchors(shard_id: u16, beacon_height: u64, transactions: &[Transaction]) -> Result<(), String> {
+    if shard_id == BEACON_SHARD {
+        return Ok(());
+    }
     
-    if state_managers.contains_key(&shard_id) {
-        return Err(format!("State manager for shard {} already exists", shard_id).into());
+    let beacon_state = get_state_manager(BEACON_SHARD).ok_or("Beacon shard not loaded")?;
+    let beacon_state = beacon_state.lock().unwrap();
+    if beacon_height > beacon_state.get_finalized_height() {
+        return Err(format!("Beacon block at height {} is not final yet", beacon_height));
+    }
+    
+    for tx in transactions {
+        beacon_state.check_anchors(tx, beacon_height)?;
+    }
+    Ok(())
+}
+
+/// Registers the state manager of a shard that `shard_map` creates, taking
+/// over addresses of the shards of `previous` from `activation_height` (see
+/// `StateManager::start_shard`). A shard registered already is left as it is,
+/// so this can be called again for every finalized validator set. Must be
+/// called with no shard state locked.
+pub fn create_state_manager(shard_id: u16, previous: &ShardMap, shard_map: &ShardMap, activation_height: u64) -> Result<(), Box<dyn std::error::Error>> {
+    if get_state_manager(shard_id).is_some() {
+        return Ok(());
     }
     
-    let state_manager = StateManager::new(shard_id);
-    state_managers.insert(shard_id, Arc::new(Mutex::new(state_manager)));
+    let mut state_manager = new_state_manager(shard_id)?;
+    state_manager.start_shard(previous, shard_map, activation_height)?;
+    
+    let mut state_managers = STATE_MANAGERS.write().unwrap();
+    state_managers.entry(shard_id).or_insert_with(|| Arc::new(Mutex::new(state_manager)));
     
     info!("Created state manager for shard {}", shard_id);
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::finality::{Vote, VoteType};
+    use crate::core::genesis::GenesisAccount;
+    use crate::core::slashing::{SignedHeader, SlashingEvidence};
+    use crate::core::transaction::TransactionType;
+    
+    const ALICE: [u8; 32] = [1; 32];
+    const BOB: [u8; 32] = [2; 32];
+    const VALIDATOR: &str = "0x00000000000000000000000000000000000000aa";
+    
+    // Hex public key and address of a test key
+    fn identity(private_key: &[u8; 32]) -> (String, String) {
+        let public_key = crypto::signing_key_from_bytes(private_key).unwrap().verifying_key().to_bytes();
+        (hex::encode(public_key), crypto::public_key_to_address(&public_key))
+    }
+    
+    // Shard state in which ALICE owns UTXO `funding:0`
+    fn funded_state() -> StateManager {
+        let mut state = StateManager::new(0);
+        fund(&mut state, &ALICE, "funding");
+        state
+    }
+    
+    // Gives the owner of `private_key` UTXO `tx_hash:0` of 1000
+    fn fund(state: &mut StateManager, private_key: &[u8; 32], tx_hash: &str) {
+        let (public_key, address) = identity(private_key);
+        state.insert_utxo(format!("{}:0", tx_hash), UTXO {
+            tx_hash: tx_hash.to_string(),
+            output_index: 0,
+            amount: 1000,
+            owner: address.clone(),
+            script_pubkey: public_key,
+            is_spent: false,
+            created_at: 0,
+            spent_at: None,
+        });
+        state.update_account_balance(&address, 1000, true, 0).unwrap();
+        state.persist_state().unwrap();
+    }
+    
+    // Transaction signed by `private_key` spending output `previous_tx:index`
+    // of `amount`, paying the owners of the given keys
+    fn spend(tx_type: TransactionType, private_key: &[u8; 32], previous_tx: &str, index: u32, amount: u64, outputs: Vec<(&[u8; 32], u64)>) -> Transaction {
+        let input = TransactionInput {
+            previous_tx: previous_tx.to_string(),
+            index,
+            script_sig: String::new(),
+            amount,
+        };
+        let outputs = outputs
+            .into_iter()
+            .map(|(owner, amount)| {
+                let (public_key, address) = identity(owner);
+                TransactionOutput {
+                    address,
+                    amount,
+                    script_pubkey: public_key,
+                }
+            })
+            .collect();
+        
+        let mut tx = Transaction::new(tx_type, vec![input], outputs, 0, Vec::new(), 0);
+        tx.sign(&[private_key]).unwrap();
+        tx
+    }
+    
+    // Block on top of the best block of `state`, paying the block reward
+    // to VALIDATOR, without a state root
+    fn new_block(state: &StateManager, transactions: Vec<Transaction>) -> Block {
+        let height = state.current_height + 1;
+        let reward = TransactionOutput {
+            address: VALIDATOR.to_string(),
+            amount: rewards::block_reward(&params::get(), height),
+            script_pubkey: String::new(),
+        };
+        
+        let mut all = vec![rewards::coinbase(0, height, vec![reward])];
+        all.extend(transactions);
+        Block::new(state.best_block_hash.clone(), height, all, 0, VALIDATOR.to_string(), 0)
+    }
+    
+    // Like `new_block`, committing to the state the block produces
+    fn block_on(state: &StateManager, transactions: Vec<Transaction>) -> Block {
+        let mut block = new_block(state, transactions);
+        
+        let mut scratch = state.scratch_copy();
+        scratch.apply_coinbase(&block.transactions, block.header.height).unwrap();
+        for tx in &block.transactions[1..] {
+            scratch.apply_transaction(tx).unwrap();
+        }
+        scratch.finish_block(&block.header);
+        
+        block.set_state_root(scratch.state_root());
+        block
+    }
+    
+    // Every account and UTXO, serialized with ordered keys and sorted by key
+    fn snapshot(state: &StateManager) -> BTreeMap<String, Vec<u8>> {
+        fn serialize<T: Serialize>(value: &T) -> Vec<u8> {
+            serde_json::to_vec(&serde_json::to_value(value).unwrap()).unwrap()
+        }
+        
+        let accounts = state.accounts
+            .iter()
+            .map(|(address, account)| (format!("account:{}", address), serialize(account)));
+        let utxos = state.utxos
+            .iter()
+            .map(|(key, utxo)| (format!("utxo:{}", key), serialize(utxo)));
+        accounts.chain(utxos).collect()
+    }
+    
+    #[test]
+    fn failed_block_leaves_state_untouched() {
+        let mut state = funded_state();
+        let first = block_on(&state, Vec::new());
+        state.import_block(&first, 1).unwrap();
+        
+        let transfer = spend(TransactionType::Transfer, &ALICE, "funding", 0, 1000, vec![(&BOB, 600), (&ALICE, 400)]);
+        // Spends its input before failing: BOB has no self-bond to delegate to
+        let deposit = spend(TransactionType::StakeDeposit, &ALICE, &transfer.hash, 1, 400, vec![(&BOB, 400)]);
+        
+        let before = snapshot(&state);
+        let state_root = state.state_root();
+        
+        let mut wrong_root = block_on(&state, vec![transfer.clone()]);
+        wrong_root.set_state_root("00".repeat(32));
+        let failing = [
+            (new_block(&state, vec![transfer.clone(), deposit]), "no self-bond"),
+            (wrong_root, "commits to state root"),
+        ];
+        
+        for (block, reason) in &failing {
+            assert!(state.import_block(block, 1).unwrap_err().contains(reason));
+            
+            assert_eq!(snapshot(&state), before);
+            assert_eq!(state.state_root(), state_root);
+            assert_eq!(state.state_root(), hex::encode(StateTree::new(&state.accounts, &state.utxos).root()));
+            assert!(state.journal.accounts.is_empty() && state.journal.utxos.is_empty());
+            assert_eq!(state.get_best_block_hash(), first.hash);
+            assert!(state.get_block_metadata(&block.hash).is_none());
+        }
+        
+        // The transfer on its own still applies
+        let second = block_on(&state, vec![transfer]);
+        assert_eq!(state.import_block(&second, 1).unwrap(), ImportResult::Extended);
+        assert_ne!(state.state_root(), state_root);
+    }
+    
+    #[test]
+    fn state_is_resumed_from_the_store() {
+        let path = std::env::temp_dir().join(format!("nexacore-resume-{}", std::process::id()));
+        let (public_key, address) = identity(&ALICE);
+        let genesis = Genesis {
+            accounts: vec![GenesisAccount { public_key, balance: 1000 }],
+            ..Genesis::default()
+        };
+        
+        let (before, state_root, second) = {
+            let store = Arc::new(ChainStore::open(&path).unwrap());
+            let mut state = StateManager::open(0, store, &genesis).unwrap();
+            let first = block_on(&state, Vec::new());
+            state.import_block(&first, 1).unwrap();
+            let transfer = spend(TransactionType::Transfer, &ALICE, &genesis.funding_hash(&address), 0, 1000, vec![(&BOB, 600), (&ALICE, 400)]);
+            let second = block_on(&state, vec![transfer]);
+            state.import_block(&second, 1).unwrap();
+            (snapshot(&state), state.state_root(), second)
+        };
+        
+        // Reopened, the shard is where it stopped and extends from there
+        let store = Arc::new(ChainStore::open(&path).unwrap());
+        let mut state = StateManager::open(0, store.clone(), &genesis).unwrap();
+        assert_eq!(state.get_current_height(), 2);
+        assert_eq!(state.get_best_block_hash(), second.hash);
+        assert_eq!(snapshot(&state), before);
+        assert_eq!(state.state_root(), state_root);
+        assert_eq!(store.get_block(&second.hash).unwrap().map(|block| block.hash), Some(second.hash.clone()));
+        
+        let third = block_on(&state, Vec::new());
+        assert_eq!(third.header.height, 3);
+        assert_eq!(state.import_block(&third, 1).unwrap(), ImportResult::Extended);
+        assert_eq!(state.get_account(&identity(&BOB).1).unwrap().balance, 600);
+        
+        // The database belongs to its genesis
+        let other = Genesis { chain_id: "other".to_string(), ..genesis };
+        let error = StateManager::open(0, store, &other).err().unwrap();
+        assert!(error.contains("was created from genesis"), "{}", error);
+        
+        drop(state);
+        std::fs::remove_dir_all(&path).ok();
+    }
+    
+    #[test]
+    fn failed_transaction_is_undone_on_its_own() {
+        let mut state = funded_state();
+        let transfer = spend(TransactionType::Transfer, &ALICE, "funding", 0, 1000, vec![(&BOB, 600), (&ALICE, 400)]);
+        let deposit = spend(TransactionType::StakeDeposit, &ALICE, &transfer.hash, 1, 400, vec![(&BOB, 400)]);
+        
+        let before = snapshot(&state);
+        let state_root = state.trial(|state| {
+            state.apply_transaction(&transfer).unwrap();
+            let applied = snapshot(state);
+            let applied_root = state.state_root();
+            
+            // The deposit spends its input before failing; only it is undone
+            assert!(state.apply_transaction(&deposit).is_err());
+            assert_eq!(snapshot(state), applied);
+            assert_eq!(state.state_root(), applied_root);
+            applied_root
+        });
+        
+        // Everything the trial applied is gone again
+        assert_eq!(snapshot(&state), before);
+        assert_ne!(state.state_root(), state_root);
+        assert!(state.journal.accounts.is_empty() && state.journal.utxos.is_empty());
+        
+        let block = block_on(&state, vec![transfer]);
+        state.import_block(&block, 1).unwrap();
+        assert_eq!(state.get_account(&identity(&BOB).1).unwrap().balance, 600);
+    }
+    
+    #[test]
+    fn stake_is_only_bonded_in_the_beacon_shard() {
+        let deposit = spend(TransactionType::StakeDeposit, &ALICE, "funding", 0, 1000, vec![(&ALICE, 1000)]);
+        
+        let mut elsewhere = deposit.clone();
+        elsewhere.shard_id = 1;
+        let error = StateManager::new(1).validate_transaction(&elsewhere).unwrap_err();
+        assert!(error.contains("only valid in beacon shard"), "{}", error);
+        
+        let mut state = funded_state();
+        state.apply_transaction(&deposit).unwrap();
+        let stakes = state.validator_stakes();
+        assert_eq!(stakes.len(), 1);
+        assert_eq!((stakes[0].validator.as_str(), stakes[0].total()), (identity(&ALICE).1.as_str(), 1000));
+    }
+    
+    #[test]
+    fn unbonded_stake_is_paid_out_once_mature() {
+        let mut state = funded_state();
+        fund(&mut state, &ALICE, "fees");
+        let (_, address) = identity(&ALICE);
+        let deposit = spend(TransactionType::StakeDeposit, &ALICE, "funding", 0, 1000, vec![(&ALICE, 1000)]);
+        state.apply_transaction(&deposit).unwrap();
+        
+        let withdraw = |amount: u64| {
+            let mut tx = spend(TransactionType::StakeWithdraw, &ALICE, "fees", 0, 1000, vec![(&ALICE, 1000)]);
+            tx.data = encoding::to_bytes(&UnbondRequest { validator: address.clone(), amount });
+            tx.hash = tx.calculate_hash();
+            tx.sign(&[&ALICE]).unwrap();
+            tx
+        };
+        let error = state.scratch_copy().apply_transaction(&withdraw(1001)).unwrap_err();
+        assert!(error.contains("Insufficient stake"), "{}", error);
+        state.apply_transaction(&withdraw(400)).unwrap();
+        assert_eq!(state.get_account(&address).unwrap().stake_amount, 600);
+        assert_eq!(state.get_account(&address).unwrap().balance, 1000);
+        
+        let release_height = state.current_height + 1 + params::get().staking.unbonding_blocks;
+        let (public_key, _) = identity(&ALICE);
+        let unbonding = Unbonding {
+            delegator: address.clone(),
+            validator: address.clone(),
+            amount: 400,
+            public_key,
+            release_height,
+        };
+        let key = unbonding.storage_key();
+        assert_eq!(state.staking_entry::<Unbonding>(&key), Some(unbonding.clone()));
+        
+        // Nothing is paid before the release height
+        let mut block = new_block(&state, Vec::new());
+        block.header.height = release_height - 1;
+        state.finish_block(&block.header);
+        assert_eq!(state.get_account(&address).unwrap().balance, 1000);
+        assert!(state.staking_entry::<Unbonding>(&key).is_some());
+        
+        block.header.height = release_height;
+        state.finish_block(&block.header);
+        assert_eq!(state.get_account(&address).unwrap().balance, 1400);
+        assert_eq!(state.get_utxo(&unbonding.payout_hash(), 0).unwrap().amount, 400);
+        assert!(state.staking_entry::<Unbonding>(&key).is_none());
+        assert_eq!(state.get_account(STAKING_ADDRESS).map_or(0, |account| account.balance), 600);
+    }
+    
+    #[test]
+    fn validator_set_is_recorded_two_epochs_ahead() {
+        let mut state = funded_state();
+        let deposit = spend(TransactionType::StakeDeposit, &ALICE, "funding", 0, 1000, vec![(&ALICE, 1000)]);
+        state.apply_transaction(&deposit).unwrap();
+        
+        // Only the last block of an epoch records a set
+        let mut block = new_block(&state, Vec::new());
+        block.header.randao_mix = "ab".repeat(32);
+        state.finish_block(&block.header);
+        assert!(state.validator_set(VALIDATOR_SET_LOOKAHEAD).is_none());
+        
+        block.header.height = params::get().epoch_length - 1;
+        state.finish_block(&block.header);
+        let set = state.validator_set(VALIDATOR_SET_LOOKAHEAD).unwrap();
+        let (_, address) = identity(&ALICE);
+        assert_eq!(set.epoch, VALIDATOR_SET_LOOKAHEAD);
+        assert!(set.is_active(&address));
+        assert_eq!(set.get(&address).unwrap().stake_amount, 1000);
+        
+        // A later penalty leaves the recorded set alone and shows in the next
+        state.penalize(Penalty {
+            validator: address.clone(),
+            stake_bps: 0,
+            contribution_bps: 0,
+            jailed_until_epoch: VALIDATOR_SET_LOOKAHEAD + 2,
+        }, 0);
+        block.header.height += params::get().epoch_length;
+        state.finish_block(&block.header);
+        assert_eq!(state.validator_set(VALIDATOR_SET_LOOKAHEAD), Some(set));
+        assert!(!state.validator_set(VALIDATOR_SET_LOOKAHEAD + 1).unwrap().is_active(&address));
+    }
+    
+    #[test]
+    fn recorded_contribution_score_ignores_later_reports() {
+        let mut state = funded_state();
+        let deposit = spend(TransactionType::StakeDeposit, &ALICE, "funding", 0, 1000, vec![(&ALICE, 1000)]);
+        state.apply_transaction(&deposit).unwrap();
+        let (_, address) = identity(&ALICE);
+        state.account_mut(&address, 0).contribution_score = 500;
+        
+        // The set takes the score after the epoch's decay
+        let mut block = new_block(&state, Vec::new());
+        block.header.height = params::get().epoch_length - 1;
+        state.finish_block(&block.header);
+        let recorded = |state: &StateManager| {
+            state.validator_set(VALIDATOR_SET_LOOKAHEAD).unwrap().get(&address).unwrap().contribution_score
+        };
+        assert_eq!(recorded(&state), 450);
+        
+        state.account_mut(&address, 0).contribution_score = 900;
+        assert_eq!(recorded(&state), 450);
+    }
+    
+    #[test]
+    fn crosslink_needs_a_precommit_quorum() {
+        let mut state = funded_state();
+        let deposit = spend(TransactionType::StakeDeposit, &ALICE, "funding", 0, 1000, vec![(&ALICE, 1000)]);
+        state.apply_transaction(&deposit).unwrap();
+        let mut block = new_block(&state, Vec::new());
+        block.header.height = params::get().epoch_length - 1;
+        state.finish_block(&block.header);
+        
+        // A block of shard 1 in the epoch of the recorded set
+        let mut header = block.header.clone();
+        header.shard_id = 1;
+        header.height = VALIDATOR_SET_LOOKAHEAD * params::get().epoch_length;
+        let mut proof = FinalityProof::new(header.clone(), Vec::new());
+        let error = state.validate_transaction(&proof.to_transaction()).unwrap_err();
+        assert!(error.contains("not more than 2/3"), "{}", error);
+        
+        // Precommits only count from the committee recorded for the epoch
+        let (_, outsider) = identity(&BOB);
+        let mut precommit = Vote::new(VoteType::Precommit, 1, header.height, Block::calculate_hash(&header), outsider);
+        precommit.sign(&BOB).unwrap();
+        let foreign = FinalityProof::new(header.clone(), vec![precommit]);
+        let error = state.validate_transaction(&foreign.to_transaction()).unwrap_err();
+        assert!(error.contains("not a validator of shard 1"), "{}", error);
+        
+        let mut unrecorded = header.clone();
+        unrecorded.height += params::get().epoch_length;
+        let error = state.validate_transaction(&FinalityProof::new(unrecorded, Vec::new()).to_transaction()).unwrap_err();
+        assert!(error.contains("no recorded validator set"), "{}", error);
+        
+        let (_, address) = identity(&ALICE);
+        let mut precommit = Vote::new(VoteType::Precommit, 1, header.height, Block::calculate_hash(&header), address);
+        precommit.sign(&ALICE).unwrap();
+        proof.precommits.push(precommit);
+        state.apply_transaction(&proof.to_transaction()).unwrap();
+        assert_eq!(state.crosslink(1), Some(proof.crosslink(1)));
+        assert_eq!(state.anchor_of(1, 1), Some(proof.crosslink(1)));
+        
+        // The crosslinked block is anchored by the beacon block that recorded
+        // it and every later one, once final, and by no earlier one
+        assert!(state.check_anchored_by(&header, 1, "").is_ok());
+        assert!(state.check_anchored_by(&header, 5, "").is_ok());
+        assert!(state.check_anchored_by(&header, 0, "").unwrap_err().contains("not anchored"));
+        assert_eq!(state.anchor_of(1, 0), None);
+        let mut other = header.clone();
+        other.height -= 1;
+        assert!(state.check_anchored_by(&other, 1, "").is_err());
+        assert!(state.check_anchored(&header, 1).unwrap_err().contains("not final yet"));
+    }
+    
+    const CAROL: [u8; 32] = [3; 32];
+    
+    // Block of `source_shard` in which ALICE sends 300 to the owner of
+    // `recipient` in `target_shard`
+    fn receipt_source(recipient: &[u8; 32], source_shard: u16, target_shard: u16) -> Block {
+        let (public_key, sender) = identity(&ALICE);
+        let (_, recipient) = identity(recipient);
+        
+        let input = TransactionInput {
+            previous_tx: "funding".to_string(),
+            index: 0,
+            script_sig: String::new(),
+            amount: 1000,
+        };
+        let outputs = vec![
+            TransactionOutput { address: recipient, amount: 300, script_pubkey: String::new() },
+            TransactionOutput { address: sender, amount: 700, script_pubkey: public_key },
+        ];
+        let data = encoding::to_bytes(&CrossShardAction::Send { target_shard });
+        let mut send = Transaction::new(TransactionType::ShardCrossing, vec![input], outputs, source_shard, data, 0);
+        send.sign(&[&ALICE]).unwrap();
+        let mut source = Block::new("00".to_string(), 1, vec![send], source_shard, VALIDATOR.to_string(), 0);
+        source.hash = Block::calculate_hash(&source.header);
+        source
+    }
+    
+    // Claim in `shard_id` of the receipt of the send in `source`, and the receipt
+    fn claim_of(source: &Block, shard_id: u16) -> (Transaction, Receipt) {
+        let proof = cross_shard::ReceiptProof::new(source, &source.transactions[0].hash, Vec::new()).unwrap();
+        let receipt = proof.verify(params::get().cross_shard.receipt_timeout).unwrap();
+        let claim = Transaction::new(TransactionType::ShardCrossing, Vec::new(), Vec::new(), shard_id,
+                                     encoding::to_bytes(&CrossShardAction::Claim { receipt: Box::new(proof) }), 0);
+        (claim, receipt)
+    }
+    
+    // Records a crosslink of `block` in the beacon state
+    fn crosslink_block(state: &mut StateManager, block: &Block) {
+        let crosslink = Crosslink {
+            shard_id: block.header.shard_id,
+            height: block.header.height,
+            block_hash: block.hash.clone(),
+            state_root: block.header.state_root.clone(),
+            linked_at: state.current_height + 1,
+        };
+        state.set_storage(BEACON_ADDRESS, &beacon::crosslinked_key(crosslink.shard_id, crosslink.height), Some(encoding::to_bytes(&crosslink)), 0);
+    }
+    
+    #[test]
+    fn receipts_are_claimed_once() {
+        let mut state = funded_state();
+        let (_, recipient) = identity(&BOB);
+        let source = receipt_source(&BOB, 1, BEACON_SHARD);
+        let (claim, receipt) = claim_of(&source, BEACON_SHARD);
+        let error = state.validate_transaction(&claim).unwrap_err();
+        assert!(error.contains("not anchored"), "{}", error);
+        
+        // Once the source block is crosslinked the claim delivers, and only once
+        crosslink_block(&mut state, &source);
+        state.apply_transaction(&claim).unwrap();
+        assert_eq!(state.get_account(&recipient).unwrap().balance, 300);
+        assert_eq!(state.get_utxo(&receipt.id(), cross_shard::DELIVERY_INDEX).unwrap().amount, 300);
+        
+        let error = state.apply_transaction(&claim).unwrap_err();
+        assert!(error.contains("already claimed"), "{}", error);
+        assert_eq!(state.get_account(&recipient).unwrap().balance, 300);
+    }
+    
+    #[test]
+    fn unclaimed_receipts_are_refunded_once() {
+        // BOB sends 300 from shard 1 of two to ALICE in the beacon shard
+        let mut source = shard_state(1, &ShardMap::uniform(2));
+        fund(&mut source, &BOB, "funding");
+        let mut send = spend(TransactionType::ShardCrossing, &BOB, "funding", 0, 1000, vec![(&ALICE, 300), (&BOB, 700)]);
+        send.shard_id = 1;
+        send.data = encoding::to_bytes(&CrossShardAction::Send { target_shard: BEACON_SHARD });
+        send.hash = send.calculate_hash();
+        send.sign(&[&BOB]).unwrap();
+        source.apply_transaction(&send).unwrap();
+        
+        let mut block = Block::new("00".to_string(), 1, vec![send.clone()], 1, VALIDATOR.to_string(), 0);
+        block.hash = Block::calculate_hash(&block.header);
+        let (claim, receipt) = claim_of(&block, BEACON_SHARD);
+        
+        // A beacon header at the deadline, with a proof that its state has
+        // no claim of the receipt
+        let target = StateManager::new(BEACON_SHARD);
+        let refund = |timestamp: u64, state: &StateManager| {
+            let mut header = Block::new("00".to_string(), 0, Vec::new(), BEACON_SHARD, VALIDATOR.to_string(), 0).header;
+            header.timestamp = timestamp;
+            header.state_root = state.state_root();
+            let action = CrossShardAction::Refund {
+                receipt: Box::new(cross_shard::ReceiptProof::new(&block, &send.hash, Vec::new()).unwrap()),
+                target_header: Box::new(header),
+                absence: Box::new(state.prove_unclaimed(&receipt)),
+            };
+            Transaction::new(TransactionType::ShardCrossing, Vec::new(), Vec::new(), 1, encoding::to_bytes(&action), 0)
+        };
+        
+        let error = source.validate_transaction(&refund(receipt.deadline - 1, &target)).unwrap_err();
+        assert!(error.contains("still be claimed"), "{}", error);
+        
+        // Once claimed, the receipt can't be refunded
+        let mut claimed = funded_state();
+        crosslink_block(&mut claimed, &block);
+        claimed.apply_transaction(&claim).unwrap();
+        let error = source.validate_transaction(&refund(receipt.deadline, &claimed)).unwrap_err();
+        assert!(error.contains("unclaimed"), "{}", error);
+        
+        source.apply_transaction(&refund(receipt.deadline, &target)).unwrap();
+        let (public_key, sender) = identity(&BOB);
+        assert_eq!(source.get_account(&sender).unwrap().balance, 1000);
+        assert_eq!(source.get_account(CROSS_SHARD_ADDRESS).unwrap().balance, 0);
+        let refunded = source.get_utxo(&receipt.id(), cross_shard::REFUND_INDEX).unwrap();
+        assert_eq!((refunded.amount, refunded.script_pubkey.as_str()), (300, public_key.as_str()));
+        
+        let error = source.apply_transaction(&refund(receipt.deadline, &target)).unwrap_err();
+        assert!(error.contains("nothing locked"), "{}", error);
+    }
+    
+    // State of `shard_id` with `shard_map` in force from the start, as if
+    // the genesis map were `shard_map`
+    fn shard_state(shard_id: u16, shard_map: &ShardMap) -> StateManager {
+        let mut state = StateManager::new(shard_id);
+        state.record_shard_map(shard_map, shard_map, 0, 0);
+        state
+    }
+    
+    // Block of `state` at `height` with the switch `tx`
+    fn switch_block(state: &StateManager, height: u64, tx: &Transaction) -> Block {
+        let mut block = Block::new("00".to_string(), height, vec![tx.clone()], state.shard_id, VALIDATOR.to_string(), 0);
+        block.hash = Block::calculate_hash(&block.header);
+        block
+    }
+    
+    // Import into `shard_id` of the switch in `block`
+    fn import_of(block: &Block, shard_id: u16) -> Transaction {
+        let switch = cross_shard::ReceiptProof::new(block, &block.transactions[0].hash, Vec::new()).unwrap();
+        MigrationAction::Import { switch: Box::new(switch) }.to_transaction(shard_id)
+    }
+    
+    #[test]
+    fn split_hands_claim_records_over_with_their_recipients() {
+        // CAROL and BOB both are in shard 1 of two; the split gives CAROL's
+        // upper quarter of the prefixes to shard 2
+        let uniform = ShardMap::uniform(2);
+        let mut state = shard_state(1, &uniform);
+        fund(&mut state, &BOB, "funding");
+        let (_, recipient) = identity(&CAROL);
+        let source = receipt_source(&CAROL, BEACON_SHARD, 1);
+        let (claim, receipt) = claim_of(&source, 1);
+        state.apply_transaction(&claim).unwrap();
+        
+        let next = uniform.split(1, 2).unwrap();
+        let handover = state.handover(&next);
+        assert_eq!(handover.accounts.iter().map(|account| account.address.as_str()).collect::<Vec<_>>(), vec![recipient.as_str()]);
+        assert_eq!(handover.utxos.len(), 1);
+        assert_eq!(handover.utxos[0].tx_hash, receipt.id());
+        assert_eq!(handover.escrow_entries.len(), 1);
+        assert_eq!(handover.escrow_entries[0].key, cross_shard::claimed_key(&receipt.id()));
+        assert_eq!(handover.escrow_balance, 0);
+        
+        // The switch has to hand over exactly that
+        let mut partial = handover.clone();
+        partial.escrow_entries.clear();
+        let forged = MigrationAction::Switch { shard_map: next.clone(), handover: Box::new(partial) }.to_transaction(1);
+        let error = state.validate_transaction(&forged).unwrap_err();
+        assert!(error.contains("hands over other state"), "{}", error);
+        
+        let switch = MigrationAction::Switch { shard_map: next.clone(), handover: Box::new(handover) }.to_transaction(1);
+        state.apply_transaction(&switch).unwrap();
+        assert_eq!(state.shard_map(), next);
+        assert_eq!(state.activation_height(), 2);
+        assert!(state.get_account(&recipient).is_none());
+        assert!(state.get_utxo(&receipt.id(), cross_shard::DELIVERY_INDEX).is_none());
+        assert!(state.storage_value(CROSS_SHARD_ADDRESS, &cross_shard::claimed_key(&receipt.id())).is_none());
+        assert_eq!(state.get_account(&identity(&BOB).1).unwrap().balance, 1000);
+        
+        // The new shard starts just below the activation height, and takes
+        // no claim until it has imported the switch of the block before
+        let (claim, _) = claim_of(&source, 2);
+        let mut target = StateManager::new(2);
+        target.start_shard(&uniform, &next, 2).unwrap();
+        assert_eq!(target.current_height, 1);
+        let error = target.validate_transaction(&claim).unwrap_err();
+        assert!(error.contains("imported its new addresses"), "{}", error);
+        
+        let error = target.validate_transaction(&import_of(&switch_block(&state, 2, &switch), 2)).unwrap_err();
+        assert!(error.contains("not 1"), "{}", error);
+        let import = import_of(&switch_block(&state, 1, &switch), 2);
+        target.apply_transaction(&import).unwrap();
+        assert!(!target.has_pending_imports());
+        assert_eq!(target.get_account(&recipient).unwrap().balance, 300);
+        assert_eq!(target.get_utxo(&receipt.id(), cross_shard::DELIVERY_INDEX).unwrap().owner, recipient);
+        
+        // The receipt claimed before the split stays claimed
+        let error = target.validate_transaction(&claim).unwrap_err();
+        assert!(error.contains("already claimed"), "{}", error);
+        let error = target.validate_transaction(&import).unwrap_err();
+        assert!(error.contains("takes no import"), "{}", error);
+    }
+    
+    #[test]
+    fn merge_hands_the_escrow_over_whole() {
+        // BOB sends 300 from shard 1 of two to ALICE in the beacon shard
+        let uniform = ShardMap::uniform(2);
+        let mut retiring = shard_state(1, &uniform);
+        fund(&mut retiring, &BOB, "funding");
+        
+        let mut send = spend(TransactionType::ShardCrossing, &BOB, "funding", 0, 1000, vec![(&ALICE, 300), (&BOB, 700)]);
+        send.shard_id = 1;
+        send.data = encoding::to_bytes(&CrossShardAction::Send { target_shard: BEACON_SHARD });
+        send.hash = send.calculate_hash();
+        send.sign(&[&BOB]).unwrap();
+        retiring.apply_transaction(&send).unwrap();
+        assert_eq!(retiring.locked_amount(&send.hash), Some(300));
+        
+        // Merged away, shard 1 hands over everything it has, escrow included
+        let merged = uniform.merge(1, BEACON_SHARD).unwrap();
+        let handover = retiring.handover(&merged);
+        assert_eq!(handover.escrow_balance, 300);
+        assert_eq!(handover.escrow_entries.len(), 1);
+        assert_eq!(handover.escrow_entries[0].key, cross_shard::outgoing_key(&send.hash));
+        
+        let switch = MigrationAction::Switch { shard_map: merged.clone(), handover: Box::new(handover) }.to_transaction(1);
+        retiring.apply_transaction(&switch).unwrap();
+        assert_eq!(retiring.get_account(CROSS_SHARD_ADDRESS).unwrap().balance, 0);
+        assert_eq!(retiring.locked_amount(&send.hash), None);
+        assert!(retiring.get_account(&identity(&BOB).1).is_none());
+        
+        // The beacon shard adds it to its own, once the switch is crosslinked
+        let mut state = funded_state();
+        state.update_account_balance(CROSS_SHARD_ADDRESS, 50, true, 0).unwrap();
+        state.record_shard_map(&uniform, &merged, 1, 0);
+        let block = switch_block(&retiring, 0, &switch);
+        let error = state.validate_transaction(&import_of(&block, BEACON_SHARD)).unwrap_err();
+        assert!(error.contains("not anchored"), "{}", error);
+        
+        crosslink_block(&mut state, &block);
+        state.apply_transaction(&import_of(&block, BEACON_SHARD)).unwrap();
+        assert_eq!(state.get_account(CROSS_SHARD_ADDRESS).unwrap().balance, 350);
+        assert_eq!(state.locked_amount(&send.hash), Some(300));
+        assert_eq!(state.get_account(&identity(&BOB).1).unwrap().balance, 700);
+        assert_eq!(state.get_account(&identity(&ALICE).1).unwrap().balance, 1000);
+    }
+    
+    #[test]
+    fn migration_needs_two_thirds_of_the_weight() {
+        let mut state = funded_state();
+        fund(&mut state, &BOB, "bob-funding");
+        for (private_key, previous_tx) in [(&ALICE, "funding"), (&BOB, "bob-funding")] {
+            let deposit = spend(TransactionType::StakeDeposit, private_key, previous_tx, 0, 1000, vec![(private_key, 1000)]);
+            state.apply_transaction(&deposit).unwrap();
+        }
+        let uniform = ShardMap::uniform(2);
+        for epoch in 0..2 {
+            state.record_validator_set(epoch, randao::INITIAL_MIX, &HashMap::new(), &uniform, 0);
+        }
+        
+        let approve = |kind: MigrationKind, private_key: &[u8; 32]| {
+            let proposal = MigrationProposal { kind, epoch: 0 };
+            let approval = proposal.approve(private_key).unwrap();
+            MigrationAction::Approve { proposal, approval }.to_transaction(BEACON_SHARD)
+        };
+        let split = MigrationKind::Split { shard_id: 1, new_shard_id: 2 };
+        for (kind, reason) in [
+            (MigrationKind::Split { shard_id: 1, new_shard_id: 3 }, "not 2"),
+            (MigrationKind::Split { shard_id: BEACON_SHARD, new_shard_id: 2 }, "keeps its addresses"),
+        ] {
+            let error = state.validate_transaction(&approve(kind, &ALICE)).unwrap_err();
+            assert!(error.contains(reason), "{}", error);
+        }
+        state.apply_transaction(&approve(split.clone(), &ALICE)).unwrap();
+        let error = state.validate_transaction(&approve(split.clone(), &ALICE)).unwrap_err();
+        assert!(error.contains("already approved"), "{}", error);
+        
+        // With half the weight, the last block of epoch 0 records the set it
+        // draws with the map as it is
+        let mut block = new_block(&state, Vec::new());
+        block.header.height = params::get().epoch_length - 1;
+        let mut scratch = state.scratch_copy();
+        scratch.finish_block(&block.header);
+        assert_eq!(scratch.validator_set(VALIDATOR_SET_LOOKAHEAD).unwrap().shard_map, uniform);
+        
+        state.apply_transaction(&approve(split.clone(), &BOB)).unwrap();
+        state.finish_block(&block.header);
+        assert_eq!(state.validator_set(VALIDATOR_SET_LOOKAHEAD).unwrap().shard_map, uniform.split(1, 2).unwrap());
+        assert!(state.storage_value(BEACON_ADDRESS, &migration::approval_key(0, &identity(&ALICE).1)).is_none());
+    }
+    
+    // Evidence of ALICE casting two precommits at `height` of shard 1
+    fn double_vote(height: u64) -> SlashingEvidence {
+        let (public_key, address) = identity(&ALICE);
+        let vote = |block_hash: &str| {
+            let mut vote = Vote::new(VoteType::Precommit, 1, height, block_hash.to_string(), address.clone());
+            vote.sign(&ALICE).unwrap();
+            vote
+        };
+        SlashingEvidence::DoubleVote {
+            public_key,
+            first: vote(&"11".repeat(32)),
+            second: vote(&"22".repeat(32)),
+        }
+    }
+    
+    #[test]
+    fn each_offence_is_slashed_once() {
+        let mut state = funded_state();
+        let deposit = spend(TransactionType::StakeDeposit, &ALICE, "funding", 0, 1000, vec![(&ALICE, 1000)]);
+        state.apply_transaction(&deposit).unwrap();
+        
+        // Punishing a later offence first doesn't cover an earlier one
+        state.apply_transaction(&double_vote(9).to_transaction()).unwrap();
+        let stake = state.get_account(&identity(&ALICE).1).unwrap().stake_amount;
+        state.apply_transaction(&double_vote(5).to_transaction()).unwrap();
+        assert!(state.get_account(&identity(&ALICE).1).unwrap().stake_amount < stake);
+        
+        // Other evidence of a punished offence is turned away
+        let mut again = double_vote(5);
+        if let SlashingEvidence::DoubleVote { second, .. } = &mut again {
+            second.block_hash = "33".repeat(32);
+            second.sign(&ALICE).unwrap();
+        }
+        assert_eq!(again.offence_key(), double_vote(5).offence_key());
+        let error = state.validate_transaction(&again.to_transaction()).unwrap_err();
+        assert!(error.contains("already slashed"), "{}", error);
+    }
+    
+    // Extends `state` by a block with `transactions`, imported with `weight`
+    fn extend(state: &mut StateManager, transactions: Vec<Transaction>, weight: u64) -> (Block, ImportResult) {
+        let block = block_on(state, transactions);
+        let result = state.import_block(&block, weight).unwrap();
+        (block, result)
+    }
+    
+    #[test]
+    fn heavier_branch_wins() {
+        let mut state = funded_state();
+        let (first, _) = extend(&mut state, Vec::new(), 1);
+        let (ours, result) = extend(&mut state, Vec::new(), 1);
+        assert_eq!(result, ImportResult::Extended);
+        
+        // A branch of equal weight doesn't replace the one seen first
+        let mut fork = state.state_at(&first.hash).unwrap();
+        let transfer = spend(TransactionType::Transfer, &ALICE, "funding", 0, 1000, vec![(&BOB, 1000)]);
+        let (theirs, _) = extend(&mut fork, vec![transfer], 1);
+        assert_eq!(state.import_block(&theirs, 1).unwrap(), ImportResult::SideChain);
+        assert_eq!(state.import_block(&theirs, 1).unwrap(), ImportResult::AlreadyKnown);
+        assert_eq!(state.get_best_block_hash(), ours.hash);
+        
+        // Once heavier, it does
+        let (next, _) = extend(&mut fork, Vec::new(), 1);
+        assert_eq!(state.import_block(&next, 1).unwrap(), ImportResult::Reorganized {
+            reverted: vec![ours.hash.clone()],
+            applied: vec![theirs.hash.clone(), next.hash.clone()],
+        });
+        assert_eq!(state.get_best_block_hash(), next.hash);
+        assert_eq!(state.get_current_height(), 3);
+        assert_eq!(snapshot(&state), snapshot(&fork));
+        assert_eq!(state.state_root(), fork.state_root());
+    }
+    
+    #[test]
+    fn reorganization_reverts_and_applies_several_blocks() {
+        let mut state = funded_state();
+        let (first, _) = extend(&mut state, Vec::new(), 1);
+        let before = snapshot(&state);
+        
+        let transfer = spend(TransactionType::Transfer, &ALICE, "funding", 0, 1000, vec![(&BOB, 1000)]);
+        let (second, _) = extend(&mut state, vec![transfer.clone()], 1);
+        let back = spend(TransactionType::Transfer, &BOB, &transfer.hash, 0, 1000, vec![(&ALICE, 1000)]);
+        let (third, _) = extend(&mut state, vec![back], 1);
+        
+        // A heavier branch spending the same output differently
+        let mut fork = state.state_at(&first.hash).unwrap();
+        assert_eq!(snapshot(&fork), before);
+        let split = spend(TransactionType::Transfer, &ALICE, "funding", 0, 1000, vec![(&ALICE, 300), (&BOB, 700)]);
+        let (fork_second, _) = extend(&mut fork, vec![split], 1);
+        let (fork_third, _) = extend(&mut fork, Vec::new(), 5);
+        
+        assert_eq!(state.import_block(&fork_second, 1).unwrap(), ImportResult::SideChain);
+        assert_eq!(state.import_block(&fork_third, 5).unwrap(), ImportResult::Reorganized {
+            reverted: vec![third.hash.clone(), second.hash.clone()],
+            applied: vec![fork_second.hash.clone(), fork_third.hash.clone()],
+        });
+        assert_eq!(snapshot(&state), snapshot(&fork));
+        assert_eq!(state.state_root(), fork.state_root());
+        assert!(state.get_utxo(&transfer.hash, 0).is_none());
+        
+        // Reverting the branch restores the common ancestor exactly
+        state.revert_block().unwrap();
+        state.revert_block().unwrap();
+        assert_eq!(state.get_best_block_hash(), first.hash);
+        assert_eq!(snapshot(&state), before);
+        assert_eq!(state.state_root(), first.header.state_root);
+    }
+    
+    #[test]
+    fn reorganization_reverts_penalties() {
+        let mut state = funded_state();
+        let deposit = spend(TransactionType::StakeDeposit, &ALICE, "funding", 0, 1000, vec![(&ALICE, 1000)]);
+        let (first, _) = extend(&mut state, vec![deposit], 1);
+        
+        // ALICE signs two different blocks at the same height
+        let (public_key, address) = identity(&ALICE);
+        let signed = |previous_hash: &str| {
+            let mut block = Block::new(previous_hash.to_string(), 1, Vec::new(), 0, address.clone(), 0);
+            block.sign(&ALICE).unwrap();
+            Box::new(SignedHeader::from_block(&block))
+        };
+        let evidence = SlashingEvidence::DoubleSign {
+            public_key,
+            first: signed("aa"),
+            second: signed("bb"),
+        };
+        let (slashed, _) = extend(&mut state, vec![evidence.to_transaction()], 1);
+        let account = state.get_account(&address).unwrap();
+        assert!(account.stake_amount < 1000 && account.jailed_until_epoch > 0);
+        
+        // A heavier branch without the evidence undoes the penalty
+        let mut fork = state.state_at(&first.hash).unwrap();
+        let (fork_second, _) = extend(&mut fork, Vec::new(), 5);
+        assert_eq!(state.import_block(&fork_second, 5).unwrap(), ImportResult::Reorganized {
+            reverted: vec![slashed.hash.clone()],
+            applied: vec![fork_second.hash.clone()],
+        });
+        assert_eq!(state.get_account(&address).unwrap().stake_amount, 1000);
+        assert_eq!(state.get_account(&address).unwrap().jailed_until_epoch, 0);
+        assert_eq!(state.validator_stakes()[0].total(), 1000);
+        assert_eq!(snapshot(&state), snapshot(&fork));
+    }
+    
+    #[test]
+    fn finalized_blocks_are_never_reverted() {
+        let mut state = funded_state();
+        let mut fork = funded_state();
+        let (first, _) = extend(&mut state, Vec::new(), 1);
+        fork.import_block(&first, 1).unwrap();
+        let (second, _) = extend(&mut state, Vec::new(), 1);
+        extend(&mut state, Vec::new(), 1);
+        
+        assert_eq!(state.finalize_block(&second.hash).unwrap(), None);
+        assert_eq!(state.get_finalized_head(), Some((2, second.hash.clone())));
+        
+        // No branch off below the finalized block is accepted, however heavy
+        let transfer = spend(TransactionType::Transfer, &ALICE, "funding", 0, 1000, vec![(&BOB, 1000)]);
+        let (conflicting, _) = extend(&mut fork, vec![transfer], 100);
+        assert!(state.import_block(&conflicting, 100).unwrap_err().contains("conflicts with finalized block"));
+        assert!(state.get_block_metadata(&conflicting.hash).is_none());
+        
+        // Blocks above it can be reverted, the finalized block itself can't
+        state.revert_block().unwrap();
+        let root = state.state_root();
+        assert!(state.revert_block().unwrap_err().contains("finalized"));
+        assert_eq!(state.get_best_block_hash(), second.hash);
+        assert_eq!(state.state_root(), root);
+        assert!(state.state_at(&first.hash).is_none());
+    }
+    
+    #[test]
+    fn transaction_proofs_verify_against_merkle_root() {
+        let mut state = funded_state();
+        let transfer = spend(TransactionType::Transfer, &ALICE, "funding", 0, 1000, vec![(&BOB, 1000)]);
+        let (block, _) = extend(&mut state, vec![transfer.clone()], 1);
+        extend(&mut state, Vec::new(), 1);
+        
+        // Found in memory, below the best block
+        let (proven, proof) = state.prove_transaction(&transfer.hash).unwrap();
+        assert_eq!(proven.hash, block.hash);
+        assert!(Block::verify_transaction_proof(&block.header.merkle_root, &transfer.hash, &proof));
+        assert!(!Block::verify_transaction_proof(&block.header.merkle_root, &block.transactions[0].hash, &proof));
+        
+        let (coinbase, proof) = state.prove_transaction(&block.transactions[0].hash).unwrap();
+        assert!(Block::verify_transaction_proof(&coinbase.header.merkle_root, &block.transactions[0].hash, &proof));
+        
+        assert!(state.prove_transaction(&"00".repeat(32)).is_none());
+    }
+}
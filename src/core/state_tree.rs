@@ -0,0 +1,599 @@
+use std::collections::{BTreeMap, HashMap};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use crate::core::encoding::{Canonical, Decoder, Encoder};
+use crate::core::state::{Account, UTXO};
+
+// Sparse Merkle tree used to commit to shard state.
+//
+// Keys are 32-byte hashes whose bits (most significant first) select the path
+// from the root. To keep the tree small, a subtree holding a single leaf is
+// represented by that leaf directly and an empty subtree by 32 zero bytes:
+//
+//   leaf  = SHA-256(0x00 || key || value_hash)
+//   inner = SHA-256(0x01 || left || right)
+//   empty = 0x00 * 32
+//
+// The state root is the root of a tree holding every account under
+// SHA-256("account:" || address) and every UTXO under
+// SHA-256("utxo:" || tx_hash:output_index). An account's contract storage is
+// committed through its own tree, keyed by SHA-256 of the storage key.
+//
+// Trees are updated in place: every inner node holding two leaves or more is
+// cached, so changing a leaf only rehashes the nodes on its path.
+
+pub type Hash = [u8; 32];
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+const EMPTY: Hash = [0u8; 32];
+
+fn sha256(parts: &[&[u8]]) -> Hash {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn hash_leaf(key: &Hash, value_hash: &Hash) -> Hash {
+    sha256(&[&[LEAF_PREFIX], key, value_hash])
+}
+
+fn hash_node(left: &Hash, right: &Hash) -> Hash {
+    sha256(&[&[NODE_PREFIX], left, right])
+}
+
+fn bit(key: &Hash, depth: usize) -> bool {
+    (key[depth / 8] >> (7 - depth % 8)) & 1 == 1
+}
+
+// First `depth` bits of `key`, the rest zeroed: the smallest key of the
+// subtree at `depth` on the key's path
+fn prefix(key: &Hash, depth: usize) -> Hash {
+    let mut prefix = [0u8; 32];
+    let full = depth / 8;
+    prefix[..full].copy_from_slice(&key[..full]);
+    if !depth.is_multiple_of(8) {
+        prefix[full] = key[full] & (0xff << (8 - depth % 8));
+    }
+    prefix
+}
+
+// Largest key of the subtree at `depth` starting at `prefix`
+fn last_key(prefix: &Hash, depth: usize) -> Hash {
+    let mut last = *prefix;
+    for (i, byte) in last.iter_mut().enumerate() {
+        if i * 8 >= depth {
+            *byte = 0xff;
+        } else if i * 8 + 8 > depth {
+            *byte |= 0xff >> (depth % 8);
+        }
+    }
+    last
+}
+
+// `prefix` with the bit at `depth` set: the right child's prefix
+fn right_child(prefix: &Hash, depth: usize) -> Hash {
+    let mut right = *prefix;
+    right[depth / 8] |= 0x80 >> (depth % 8);
+    right
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SparseMerkleTree {
+    leaves: BTreeMap<Hash, Hash>,        // key -> value hash
+    nodes: HashMap<(usize, Hash), Hash>, // (depth, prefix) -> hash of a subtree with two leaves or more
+}
+
+impl SparseMerkleTree {
+    pub fn new() -> Self {
+        SparseMerkleTree {
+            leaves: BTreeMap::new(),
+            nodes: HashMap::new(),
+        }
+    }
+
+    /// Builds a tree from its leaves at once, hashing every node only once.
+    pub fn from_leaves(leaves: impl IntoIterator<Item = (Hash, Hash)>) -> Self {
+        let mut tree = SparseMerkleTree {
+            leaves: leaves.into_iter().collect(),
+            nodes: HashMap::new(),
+        };
+        let sorted: Vec<(Hash, Hash)> = tree.leaves.iter().map(|(k, v)| (*k, *v)).collect();
+        tree.build(&sorted, 0);
+        tree
+    }
+
+    // Caches the nodes of a subtree; leaves must be sorted by key and share
+    // the first `depth` bits
+    fn build(&mut self, leaves: &[(Hash, Hash)], depth: usize) -> Hash {
+        match leaves {
+            [] => EMPTY,
+            [(key, value_hash)] => hash_leaf(key, value_hash),
+            _ => {
+                let split = leaves.partition_point(|(key, _)| !bit(key, depth));
+                let left = self.build(&leaves[..split], depth + 1);
+                let right = self.build(&leaves[split..], depth + 1);
+                let hash = hash_node(&left, &right);
+                self.nodes.insert((depth, prefix(&leaves[0].0, depth)), hash);
+                hash
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: Hash, value_hash: Hash) {
+        if self.leaves.insert(key, value_hash) != Some(value_hash) {
+            self.update_path(&key);
+        }
+    }
+
+    pub fn remove(&mut self, key: &Hash) {
+        if self.leaves.remove(key).is_some() {
+            self.update_path(key);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn root(&self) -> Hash {
+        self.subtree(&EMPTY, 0)
+    }
+
+    // Whether the subtree at `depth` on `key`'s path holds two leaves or more
+    fn is_branch(&self, key: &Hash, depth: usize) -> bool {
+        let prefix = prefix(key, depth);
+        self.leaves.range(prefix..=last_key(&prefix, depth)).nth(1).is_some()
+    }
+
+    // Hash of the subtree at `depth` on `key`'s path
+    fn subtree(&self, key: &Hash, depth: usize) -> Hash {
+        let prefix = prefix(key, depth);
+        let mut leaves = self.leaves.range(prefix..=last_key(&prefix, depth));
+        match (leaves.next(), leaves.next()) {
+            (None, _) => EMPTY,
+            (Some((key, value_hash)), None) => hash_leaf(key, value_hash),
+            _ => self.nodes[&(depth, prefix)],
+        }
+    }
+
+    // Rehashes the nodes on `key`'s path after its leaf changed, and drops
+    // the ones left with fewer than two leaves
+    fn update_path(&mut self, key: &Hash) {
+        let mut depth = 0;
+        while self.is_branch(key, depth) {
+            depth += 1;
+        }
+
+        let mut stale = depth;
+        while self.nodes.remove(&(stale, prefix(key, stale))).is_some() {
+            stale += 1;
+        }
+
+        for depth in (0..depth).rev() {
+            let prefix = prefix(key, depth);
+            let left = self.subtree(&prefix, depth + 1);
+            let right = self.subtree(&right_child(&prefix, depth), depth + 1);
+            self.nodes.insert((depth, prefix), hash_node(&left, &right));
+        }
+    }
+
+    /// Builds a proof of inclusion (or exclusion) of `key`.
+    pub fn prove(&self, key: &Hash) -> SparseMerkleProof {
+        let mut siblings = Vec::new();
+        let mut depth = 0;
+
+        // Walk down towards the key until reaching an empty or single-leaf subtree
+        while self.is_branch(key, depth) {
+            let prefix = prefix(key, depth);
+            let sibling = if bit(key, depth) {
+                self.subtree(&prefix, depth + 1)
+            } else {
+                self.subtree(&right_child(&prefix, depth), depth + 1)
+            };
+            siblings.push(hex::encode(sibling));
+            depth += 1;
+        }
+
+        let prefix = prefix(key, depth);
+        SparseMerkleProof {
+            siblings,
+            leaf: self.leaves.range(prefix..=last_key(&prefix, depth)).next().map(|(k, v)| ProofLeaf {
+                key: hex::encode(k),
+                value_hash: hex::encode(v),
+            }),
+        }
+    }
+}
+
+// Leaf found at the end of a proof path
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProofLeaf {
+    pub key: String,
+    pub value_hash: String,
+}
+
+// Siblings are listed from the root downwards. The path ends at `leaf`, or at
+// an empty subtree when `leaf` is None.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SparseMerkleProof {
+    pub siblings: Vec<String>,
+    pub leaf: Option<ProofLeaf>,
+}
+
+impl SparseMerkleProof {
+    /// Verifies that `key` maps to `value_hash` under `root`, or that `key`
+    /// is absent when `value_hash` is None.
+    pub fn verify(&self, root: &Hash, key: &Hash, value_hash: Option<&Hash>) -> bool {
+        if self.siblings.len() > 256 {
+            return false;
+        }
+
+        let terminal = match (&self.leaf, value_hash) {
+            // Inclusion: the path must end at exactly this key and value
+            (Some(leaf), Some(value_hash)) => {
+                match (decode_hash(&leaf.key), decode_hash(&leaf.value_hash)) {
+                    (Some(leaf_key), Some(leaf_value)) if &leaf_key == key && &leaf_value == value_hash => {
+                        hash_leaf(&leaf_key, &leaf_value)
+                    }
+                    _ => return false,
+                }
+            }
+            // Exclusion by another leaf occupying the key's subtree
+            (Some(leaf), None) => {
+                match (decode_hash(&leaf.key), decode_hash(&leaf.value_hash)) {
+                    (Some(leaf_key), Some(leaf_value)) if &leaf_key != key => {
+                        if (0..self.siblings.len()).any(|depth| bit(&leaf_key, depth) != bit(key, depth)) {
+                            return false;
+                        }
+                        hash_leaf(&leaf_key, &leaf_value)
+                    }
+                    _ => return false,
+                }
+            }
+            // Exclusion by an empty subtree
+            (None, None) => EMPTY,
+            (None, Some(_)) => return false,
+        };
+
+        let mut hash = terminal;
+        for (depth, sibling) in self.siblings.iter().enumerate().rev() {
+            let sibling = match decode_hash(sibling) {
+                Some(sibling) => sibling,
+                None => return false,
+            };
+
+            hash = if bit(key, depth) {
+                hash_node(&sibling, &hash)
+            } else {
+                hash_node(&hash, &sibling)
+            };
+        }
+
+        &hash == root
+    }
+}
+
+impl Canonical for ProofLeaf {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_str(&self.key);
+        encoder.put_str(&self.value_hash);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(ProofLeaf {
+            key: decoder.get_string()?,
+            value_hash: decoder.get_string()?,
+        })
+    }
+}
+
+impl Canonical for SparseMerkleProof {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_seq(&self.siblings);
+        encoder.put_option(&self.leaf, |e, leaf| leaf.encode(e));
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(SparseMerkleProof {
+            siblings: decoder.get_seq()?,
+            leaf: decoder.get_option(ProofLeaf::decode)?,
+        })
+    }
+}
+
+pub fn decode_hash(value: &str) -> Option<Hash> {
+    hex::decode(value).ok()?.try_into().ok()
+}
+
+pub fn account_key(address: &str) -> Hash {
+    sha256(&[b"account:", address.as_bytes()])
+}
+
+pub fn utxo_key(utxo_key: &str) -> Hash {
+    sha256(&[b"utxo:", utxo_key.as_bytes()])
+}
+
+pub fn storage_key(key: &str) -> Hash {
+    sha256(&[key.as_bytes()])
+}
+
+pub fn storage_tree(account: &Account) -> SparseMerkleTree {
+    SparseMerkleTree::from_leaves(
+        account.storage
+            .iter()
+            .map(|(key, value)| (storage_key(key), sha256(&[value]))),
+    )
+}
+
+// Tree over the accounts and UTXOs of a shard, with the contract storage tree
+// of every account that has storage. The state manager tells it which
+// entries changed.
+#[derive(Debug, Clone, Default)]
+pub struct StateTree {
+    tree: SparseMerkleTree,
+    storage: HashMap<String, SparseMerkleTree>, // By address
+}
+
+impl StateTree {
+    pub fn new(accounts: &HashMap<String, Account>, utxos: &HashMap<String, UTXO>) -> Self {
+        let storage: HashMap<String, SparseMerkleTree> = accounts
+            .iter()
+            .filter(|(_, account)| !account.storage.is_empty())
+            .map(|(address, account)| (address.clone(), storage_tree(account)))
+            .collect();
+
+        let account_leaves = accounts.iter().map(|(address, account)| {
+            let storage_root = storage.get(address).map(|tree| tree.root()).unwrap_or(EMPTY);
+            (account_key(address), account_value_hash(account, &storage_root))
+        });
+        let utxo_leaves = utxos.iter().map(|(key, utxo)| (utxo_key(key), utxo_value_hash(utxo)));
+
+        StateTree {
+            tree: SparseMerkleTree::from_leaves(account_leaves.chain(utxo_leaves)),
+            storage,
+        }
+    }
+
+    pub fn root(&self) -> Hash {
+        self.tree.root()
+    }
+
+    pub fn prove(&self, key: &Hash) -> SparseMerkleProof {
+        self.tree.prove(key)
+    }
+
+    /// Root of the contract storage of `address`.
+    pub fn storage_root(&self, address: &str) -> Hash {
+        self.storage.get(address).map(|tree| tree.root()).unwrap_or(EMPTY)
+    }
+
+    pub fn prove_storage(&self, address: &str, key: &str) -> SparseMerkleProof {
+        match self.storage.get(address) {
+            Some(tree) => tree.prove(&storage_key(key)),
+            None => SparseMerkleTree::new().prove(&storage_key(key)),
+        }
+    }
+
+    /// Updates a storage slot of `address`. The account itself must be
+    /// updated afterwards, since its hash covers the storage root.
+    pub fn set_storage(&mut self, address: &str, key: &str, value: Option<&[u8]>) {
+        let tree = self.storage.entry(address.to_string()).or_default();
+        match value {
+            Some(value) => tree.insert(storage_key(key), sha256(&[value])),
+            None => tree.remove(&storage_key(key)),
+        }
+
+        if tree.is_empty() {
+            self.storage.remove(address);
+        }
+    }
+
+    /// Rebuilds the storage tree of an account whose slots may all have changed.
+    pub fn reset_storage(&mut self, address: &str, account: Option<&Account>) {
+        match account.filter(|account| !account.storage.is_empty()) {
+            Some(account) => self.storage.insert(address.to_string(), storage_tree(account)),
+            None => self.storage.remove(address),
+        };
+    }
+
+    pub fn set_account(&mut self, address: &str, account: Option<&Account>) {
+        match account {
+            Some(account) => {
+                let value_hash = account_value_hash(account, &self.storage_root(address));
+                self.tree.insert(account_key(address), value_hash);
+            }
+            None => {
+                self.storage.remove(address);
+                self.tree.remove(&account_key(address));
+            }
+        }
+    }
+
+    pub fn set_utxo(&mut self, key: &str, utxo: Option<&UTXO>) {
+        match utxo {
+            Some(utxo) => self.tree.insert(utxo_key(key), utxo_value_hash(utxo)),
+            None => self.tree.remove(&utxo_key(key)),
+        }
+    }
+}
+
+/// Hash committed for an account: its canonical encoding with the contract
+/// storage replaced by the storage tree root.
+pub fn account_value_hash(account: &Account, storage_root: &Hash) -> Hash {
+    let mut encoder = Encoder::new();
+    encoder.put_str(&account.address);
+    encoder.put_u64(account.balance);
+    encoder.put_u64(account.nonce);
+    encoder.put_bytes(&account.code);
+    encoder.put_bytes(storage_root);
+    encoder.put_u64(account.stake_amount);
+    encoder.put_u32(account.contribution_score);
+    encoder.put_u64(account.last_updated);
+    encoder.put_u64(account.jailed_until_epoch);
+    encoder.put_u32(account.missed_proposals);
+    sha256(&[&encoder.finish()])
+}
+
+pub fn utxo_value_hash(utxo: &UTXO) -> Hash {
+    let mut encoder = Encoder::new();
+    encoder.put_str(&utxo.tx_hash);
+    encoder.put_u32(utxo.output_index);
+    encoder.put_u64(utxo.amount);
+    encoder.put_str(&utxo.owner);
+    encoder.put_str(&utxo.script_pubkey);
+    encoder.put_bool(utxo.is_spent);
+    encoder.put_u64(utxo.created_at);
+    encoder.put_option(&utxo.spent_at, |e, spent_at| e.put_u64(*spent_at));
+    sha256(&[&encoder.finish()])
+}
+
+// Proof of an account (or its absence) against a state root. The account is
+// returned without its contract storage, which is committed by `storage_root`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountProof {
+    pub address: String,
+    pub account: Option<Account>,
+    pub storage_root: String,
+    pub proof: SparseMerkleProof,
+}
+
+impl AccountProof {
+    pub fn verify(&self, state_root: &str) -> bool {
+        let (root, storage_root) = match (decode_hash(state_root), decode_hash(&self.storage_root)) {
+            (Some(root), Some(storage_root)) => (root, storage_root),
+            _ => return false,
+        };
+
+        let key = account_key(&self.address);
+        match &self.account {
+            Some(account) => {
+                if account.address != self.address {
+                    return false;
+                }
+                let value_hash = account_value_hash(account, &storage_root);
+                self.proof.verify(&root, &key, Some(&value_hash))
+            }
+            None => self.proof.verify(&root, &key, None),
+        }
+    }
+}
+
+// Proof of a single contract storage slot against an account's storage root
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageSlotProof {
+    pub key: String,
+    pub value: Option<String>, // Hex-encoded value, None if the slot is empty
+    pub proof: SparseMerkleProof,
+}
+
+impl StorageSlotProof {
+    pub fn verify(&self, storage_root: &str) -> bool {
+        let root = match decode_hash(storage_root) {
+            Some(root) => root,
+            None => return false,
+        };
+
+        let key = storage_key(&self.key);
+        match &self.value {
+            Some(value) => match hex::decode(value) {
+                Ok(value) => self.proof.verify(&root, &key, Some(&sha256(&[&value]))),
+                Err(_) => false,
+            },
+            None => self.proof.verify(&root, &key, None),
+        }
+    }
+}
+
+// Storage slots of one account, anchored to the state root through the account proof
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageProof {
+    pub account_proof: AccountProof,
+    pub slots: Vec<StorageSlotProof>,
+}
+
+impl StorageProof {
+    pub fn verify(&self, state_root: &str) -> bool {
+        self.account_proof.verify(state_root)
+            && self.slots.iter().all(|slot| slot.verify(&self.account_proof.storage_root))
+    }
+}
+
+// Proofs travel in transactions, such as refunds of cross-shard transfers.
+// The account is encoded like its value hash, without the storage root.
+impl Canonical for AccountProof {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_str(&self.address);
+        encoder.put_option(&self.account, |e, account| {
+            e.put_str(&account.address);
+            e.put_u64(account.balance);
+            e.put_u64(account.nonce);
+            e.put_bytes(&account.code);
+            e.put_u64(account.stake_amount);
+            e.put_u32(account.contribution_score);
+            e.put_u64(account.last_updated);
+            e.put_u64(account.jailed_until_epoch);
+            e.put_u32(account.missed_proposals);
+        });
+        encoder.put_str(&self.storage_root);
+        self.proof.encode(encoder);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(AccountProof {
+            address: decoder.get_string()?,
+            account: decoder.get_option(|d| {
+                Ok(Account {
+                    address: d.get_string()?,
+                    balance: d.get_u64()?,
+                    nonce: d.get_u64()?,
+                    code: d.get_bytes()?,
+                    storage: HashMap::new(),
+                    stake_amount: d.get_u64()?,
+                    contribution_score: d.get_u32()?,
+                    last_updated: d.get_u64()?,
+                    jailed_until_epoch: d.get_u64()?,
+                    missed_proposals: d.get_u32()?,
+                })
+            })?,
+            storage_root: decoder.get_string()?,
+            proof: SparseMerkleProof::decode(decoder)?,
+        })
+    }
+}
+
+impl Canonical for StorageSlotProof {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_str(&self.key);
+        encoder.put_option(&self.value, |e, value| e.put_str(value));
+        self.proof.encode(encoder);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(StorageSlotProof {
+            key: decoder.get_string()?,
+            value: decoder.get_option(|d| d.get_string())?,
+            proof: SparseMerkleProof::decode(decoder)?,
+        })
+    }
+}
+
+impl Canonical for StorageProof {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.account_proof.encode(encoder);
+        encoder.put_seq(&self.slots);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(StorageProof {
+            account_proof: AccountProof::decode(decoder)?,
+            slots: decoder.get_seq()?,
+        })
+    }
+}
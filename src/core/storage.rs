@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+use std::path::Path;
+use log::{info, debug};
+use serde::{Serialize, Deserialize};
+use rocksdb::{ColumnFamily, ColumnFamilyDescriptor, IteratorMode, Options, WriteBatch, DB};
+use crate::core::block::{Block, BlockHeader};
+use crate::core::encoding;
+use crate::core::state::{Account, BlockMetadata, UTXO};
+
+// Column families of the chain database
+pub const CF_BLOCKS: &str = "blocks";                     // block hash -> canonical block
+pub const CF_HEADERS: &str = "headers";                   // block hash -> canonical header
+pub const CF_TX_INDEX: &str = "tx_index";                 // tx hash -> block hash and position
+pub const CF_UTXOS: &str = "utxos";                       // tx_hash:output_index -> UTXO
+pub const CF_ACCOUNTS: &str = "accounts";                 // address -> account (without storage)
+pub const CF_CONTRACT_STORAGE: &str = "contract_storage"; // address 0x00 key -> value
+pub const CF_CHAIN_METADATA: &str = "chain_metadata";     // chain tip and block metadata
+pub const CF_UNDO: &str = "undo";                         // block hash -> undo journal
+
+const COLUMN_FAMILIES: [&str; 8] = [
+    CF_BLOCKS,
+    CF_HEADERS,
+    CF_TX_INDEX,
+    CF_UTXOS,
+    CF_ACCOUNTS,
+    CF_CONTRACT_STORAGE,
+    CF_CHAIN_METADATA,
+    CF_UNDO,
+];
+
+const KEY_HEIGHT: &[u8] = b"height";
+const KEY_BEST_BLOCK_HASH: &[u8] = b"best_block_hash";
+const KEY_FINALIZED_HEIGHT: &[u8] = b"finalized_height";
+const KEY_FINALIZED_HASH: &[u8] = b"finalized_hash";
+const KEY_GENESIS_HASH: &[u8] = b"genesis_hash";
+const BLOCK_METADATA_PREFIX: &str = "block:";
+
+// Location of a transaction inside a stored block
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxLocation {
+    pub block_hash: String,
+    pub index: u32,
+}
+
+// All state changes produced by applying a block, written in a single batch.
+// `None` values delete the entry. The same shape holds a block's undo journal:
+// the entries it touched as they were before the block was applied.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StateChanges {
+    pub accounts: HashMap<String, Option<Account>>,
+    pub utxos: HashMap<String, Option<UTXO>>,
+}
+
+// RocksDB-backed persistent chain storage for one shard
+pub struct ChainStore {
+    db: DB,
+}
+
+impl std::fmt::Debug for ChainStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChainStore").finish_non_exhaustive()
+    }
+}
+
+impl ChainStore {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+
+        let descriptors = COLUMN_FAMILIES
+            .iter()
+            .map(|name| ColumnFamilyDescriptor::new(*name, Options::default()));
+
+        let db = DB::open_cf_descriptors(&options, path, descriptors)
+            .map_err(|e| format!("Failed to open chain database at {}: {}", path.display(), e))?;
+
+        info!("Opened chain database at {}", path.display());
+        Ok(ChainStore { db })
+    }
+
+    fn cf(&self, name: &str) -> &ColumnFamily {
+        // Every column family is created on open, so a missing handle is a bug
+        self.db.cf_handle(name).expect("Column family not opened")
+    }
+
+    /// Atomically writes a block, its metadata, the new chain tip, the state
+    /// changes it produced and the journal needed to undo them.
+    pub fn write_block(
+        &self,
+        block: &Block,
+        metadata: &BlockMetadata,
+        changes: &StateChanges,
+        undo: &StateChanges,
+    ) -> Result<(), String> {
+        let mut batch = WriteBatch::default();
+
+        batch.put_cf(self.cf(CF_BLOCKS), &block.hash, encoding::to_bytes(block));
+        batch.put_cf(self.cf(CF_HEADERS), &block.hash, encoding::to_bytes(&block.header));
+
+        for (index, tx) in block.transactions.iter().enumerate() {
+            let mut location = block.hash.as_bytes().to_vec();
+            location.extend_from_slice(&(index as u32).to_le_bytes());
+            batch.put_cf(self.cf(CF_TX_INDEX), &tx.hash, location);
+        }
+
+        self.put_block_metadata(&mut batch, metadata)?;
+        self.put_chain_tip(&mut batch, metadata.height, &metadata.hash);
+        self.put_state_changes(&mut batch, changes)?;
+
+        let undo = bincode::serialize(undo).map_err(|e| format!("Failed to encode undo journal: {}", e))?;
+        batch.put_cf(self.cf(CF_UNDO), &block.hash, undo);
+
+        self.db.write(batch).map_err(|e| format!("Failed to write block {}: {}", block.hash, e))?;
+        debug!("Persisted block {} at height {}", block.hash, metadata.height);
+        Ok(())
+    }
+
+    /// Atomically reverts the tip block: restores the entries in its undo
+    /// journal, drops its transactions from the index and moves the chain tip
+    /// to its parent. The block itself stays stored so it can be reapplied.
+    pub fn revert_block(
+        &self,
+        block: &Block,
+        undo: &StateChanges,
+        parent_height: u64,
+        parent_hash: &str,
+    ) -> Result<(), String> {
+        let mut batch = WriteBatch::default();
+
+        for tx in &block.transactions {
+            batch.delete_cf(self.cf(CF_TX_INDEX), &tx.hash);
+        }
+
+        batch.delete_cf(self.cf(CF_UNDO), &block.hash);
+        self.put_chain_tip(&mut batch, parent_height, parent_hash);
+        self.put_state_changes(&mut batch, undo)?;
+
+        self.db.write(batch).map_err(|e| format!("Failed to revert block {}: {}", block.hash, e))?;
+        debug!("Reverted block {} to parent {}", block.hash, parent_hash);
+        Ok(())
+    }
+
+    /// Atomically writes the genesis state of a new database together with
+    /// the hash of the genesis it comes from.
+    pub fn write_genesis(&self, genesis_hash: &str, changes: &StateChanges) -> Result<(), String> {
+        let mut batch = WriteBatch::default();
+
+        batch.put_cf(self.cf(CF_CHAIN_METADATA), KEY_GENESIS_HASH, genesis_hash.as_bytes());
+        self.put_state_changes(&mut batch, changes)?;
+
+        self.db.write(batch).map_err(|e| format!("Failed to write genesis state: {}", e))?;
+        debug!("Persisted genesis state of genesis {}", genesis_hash);
+        Ok(())
+    }
+
+    /// Atomically writes state changes made outside of a block, such as the
+    /// shard map and pending imports of a shard a split starts.
+    pub fn write_state(&self, changes: &StateChanges) -> Result<(), String> {
+        let mut batch = WriteBatch::default();
+        self.put_state_changes(&mut batch, changes)?;
+
+        self.db.write(batch).map_err(|e| format!("Failed to write state: {}", e))?;
+        debug!("Persisted {} accounts and {} UTXOs", changes.accounts.len(), changes.utxos.len());
+        Ok(())
+    }
+
+    /// Records a newly finalized block and drops the undo journals of the
+    /// blocks it makes irreversible.
+    pub fn write_finalized(&self, height: u64, block_hash: &str, pruned: &[String]) -> Result<(), String> {
+        let mut batch = WriteBatch::default();
+
+        let cf = self.cf(CF_CHAIN_METADATA);
+        batch.put_cf(cf, KEY_FINALIZED_HEIGHT, height.to_le_bytes());
+        batch.put_cf(cf, KEY_FINALIZED_HASH, block_hash.as_bytes());
+
+        for hash in pruned {
+            batch.delete_cf(self.cf(CF_UNDO), hash);
+        }
+
+        self.db.write(batch).map_err(|e| format!("Failed to finalize block {}: {}", block_hash, e))?;
+        debug!("Finalized block {} at height {}", block_hash, height);
+        Ok(())
+    }
+
+    fn put_block_metadata(&self, batch: &mut WriteBatch, metadata: &BlockMetadata) -> Result<(), String> {
+        let key = format!("{}{}", BLOCK_METADATA_PREFIX, metadata.hash);
+        let value = bincode::serialize(metadata).map_err(|e| format!("Failed to encode block metadata: {}", e))?;
+        batch.put_cf(self.cf(CF_CHAIN_METADATA), key, value);
+        Ok(())
+    }
+
+    fn put_chain_tip(&self, batch: &mut WriteBatch, height: u64, best_block_hash: &str) {
+        let cf = self.cf(CF_CHAIN_METADATA);
+        batch.put_cf(cf, KEY_HEIGHT, height.to_le_bytes());
+        batch.put_cf(cf, KEY_BEST_BLOCK_HASH, best_block_hash.as_bytes());
+    }
+
+    fn put_state_changes(&self, batch: &mut WriteBatch, changes: &StateChanges) -> Result<(), String> {
+        for (key, utxo) in &changes.utxos {
+            match utxo {
+                Some(utxo) => {
+                    let value = bincode::serialize(utxo).map_err(|e| format!("Failed to encode UTXO {}: {}", key, e))?;
+                    batch.put_cf(self.cf(CF_UTXOS), key, value);
+                }
+                None => batch.delete_cf(self.cf(CF_UTXOS), key),
+            }
+        }
+
+        for (address, account) in &changes.accounts {
+            // Contract storage lives in its own column family; replace it wholesale
+            let (start, end) = storage_key_range(address);
+            batch.delete_range_cf(self.cf(CF_CONTRACT_STORAGE), start, end);
+
+            match account {
+                Some(account) => {
+                    let mut stored = account.clone();
+                    let storage = std::mem::take(&mut stored.storage);
+
+                    let value = bincode::serialize(&stored).map_err(|e| format!("Failed to encode account {}: {}", address, e))?;
+                    batch.put_cf(self.cf(CF_ACCOUNTS), address, value);
+
+                    for (key, value) in storage {
+                        batch.put_cf(self.cf(CF_CONTRACT_STORAGE), storage_key(address, &key), value);
+                    }
+                }
+                None => batch.delete_cf(self.cf(CF_ACCOUNTS), address),
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn get_block(&self, block_hash: &str) -> Result<Option<Block>, String> {
+        match self.db.get_cf(self.cf(CF_BLOCKS), block_hash).map_err(|e| e.to_string())? {
+            Some(bytes) => encoding::from_bytes(&bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_header(&self, block_hash: &str) -> Result<Option<BlockHeader>, String> {
+        match self.db.get_cf(self.cf(CF_HEADERS), block_hash).map_err(|e| e.to_string())? {
+            Some(bytes) => encoding::from_bytes(&bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_tx_location(&self, tx_hash: &str) -> Result<Option<TxLocation>, String> {
+        let bytes = match self.db.get_cf(self.cf(CF_TX_INDEX), tx_hash).map_err(|e| e.to_string())? {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+
+        if bytes.len() < 4 {
+            return Err(format!("Corrupt tx index entry for {}", tx_hash));
+        }
+
+        let (hash, index) = bytes.split_at(bytes.len() - 4);
+        Ok(Some(TxLocation {
+            block_hash: String::from_utf8(hash.to_vec()).map_err(|e| e.to_string())?,
+            index: u32::from_le_bytes(index.try_into().unwrap()),
+        }))
+    }
+
+    /// Returns the persisted chain tip as (height, best block hash), if any.
+    pub fn load_chain_tip(&self) -> Result<Option<(u64, String)>, String> {
+        let cf = self.cf(CF_CHAIN_METADATA);
+
+        let height = match self.db.get_cf(cf, KEY_HEIGHT).map_err(|e| e.to_string())? {
+            Some(bytes) => u64::from_le_bytes(bytes.as_slice().try_into().map_err(|_| "Corrupt chain height".to_string())?),
+            None => return Ok(None),
+        };
+
+        let best_block_hash = match self.db.get_cf(cf, KEY_BEST_BLOCK_HASH).map_err(|e| e.to_string())? {
+            Some(bytes) => String::from_utf8(bytes).map_err(|e| e.to_string())?,
+            None => return Ok(None),
+        };
+
+        Ok(Some((height, best_block_hash)))
+    }
+
+    /// Returns the last finalized block as (height, block hash), if any.
+    pub fn load_finalized(&self) -> Result<Option<(u64, String)>, String> {
+        let cf = self.cf(CF_CHAIN_METADATA);
+
+        let height = match self.db.get_cf(cf, KEY_FINALIZED_HEIGHT).map_err(|e| e.to_string())? {
+            Some(bytes) => u64::from_le_bytes(bytes.as_slice().try_into().map_err(|_| "Corrupt finalized height".to_string())?),
+            None => return Ok(None),
+        };
+
+        let block_hash = match self.db.get_cf(cf, KEY_FINALIZED_HASH).map_err(|e| e.to_string())? {
+            Some(bytes) => String::from_utf8(bytes).map_err(|e| e.to_string())?,
+            None => return Ok(None),
+        };
+
+        Ok(Some((height, block_hash)))
+    }
+
+    /// Returns the hash of the genesis the database was created from, if any.
+    pub fn load_genesis_hash(&self) -> Result<Option<String>, String> {
+        match self.db.get_cf(self.cf(CF_CHAIN_METADATA), KEY_GENESIS_HASH).map_err(|e| e.to_string())? {
+            Some(bytes) => Ok(Some(String::from_utf8(bytes).map_err(|e| e.to_string())?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn load_block_metadata(&self) -> Result<HashMap<String, BlockMetadata>, String> {
+        let mut blocks = HashMap::new();
+
+        for (key, value) in self.scan(CF_CHAIN_METADATA)? {
+            if !key.starts_with(BLOCK_METADATA_PREFIX.as_bytes()) {
+                continue;
+            }
+
+            let metadata: BlockMetadata = bincode::deserialize(&value).map_err(|e| format!("Corrupt block metadata: {}", e))?;
+            blocks.insert(metadata.hash.clone(), metadata);
+        }
+
+        Ok(blocks)
+    }
+
+    pub fn load_undo_journals(&self) -> Result<HashMap<String, StateChanges>, String> {
+        let mut journals = HashMap::new();
+
+        for (key, value) in self.scan(CF_UNDO)? {
+            let block_hash = String::from_utf8(key.to_vec()).map_err(|e| e.to_string())?;
+            let undo: StateChanges = bincode::deserialize(&value).map_err(|e| format!("Corrupt undo journal {}: {}", block_hash, e))?;
+            journals.insert(block_hash, undo);
+        }
+
+        Ok(journals)
+    }
+
+    pub fn load_utxos(&self) -> Result<HashMap<String, UTXO>, String> {
+        let mut utxos = HashMap::new();
+
+        for (key, value) in self.scan(CF_UTXOS)? {
+            let key = String::from_utf8(key.to_vec()).map_err(|e| e.to_string())?;
+            let utxo: UTXO = bincode::deserialize(&value).map_err(|e| format!("Corrupt UTXO {}: {}", key, e))?;
+            utxos.insert(key, utxo);
+        }
+
+        Ok(utxos)
+    }
+
+    pub fn load_accounts(&self) -> Result<HashMap<String, Account>, String> {
+        let mut accounts = HashMap::new();
+
+        for (key, value) in self.scan(CF_ACCOUNTS)? {
+            let address = String::from_utf8(key.to_vec()).map_err(|e| e.to_string())?;
+            let account: Account = bincode::deserialize(&value).map_err(|e| format!("Corrupt account {}: {}", address, e))?;
+            accounts.insert(address, account);
+        }
+
+        // Reattach contract storage to its accounts
+        for (key, value) in self.scan(CF_CONTRACT_STORAGE)? {
+            let separator = key.iter().position(|&b| b == 0).ok_or("Corrupt contract storage key")?;
+            let address = String::from_utf8(key[..separator].to_vec()).map_err(|e| e.to_string())?;
+            let storage_key = String::from_utf8(key[separator + 1..].to_vec()).map_err(|e| e.to_string())?;
+
+            if let Some(account) = accounts.get_mut(&address) {
+                account.storage.insert(storage_key, value.to_vec());
+            }
+        }
+
+        Ok(accounts)
+    }
+
+    fn scan(&self, cf: &str) -> Result<Vec<(Box<[u8]>, Box<[u8]>)>, String> {
+        self.db
+            .iterator_cf(self.cf(cf), IteratorMode::Start)
+            .map(|item| item.map_err(|e| format!("Failed to read {}: {}", cf, e)))
+            .collect()
+    }
+}
+
+fn storage_key(address: &str, key: &str) -> Vec<u8> {
+    let mut storage_key = address.as_bytes().to_vec();
+    storage_key.push(0);
+    storage_key.extend_from_slice(key.as_bytes());
+    storage_key
+}
+
+// Key range covering all storage entries of one account
+fn storage_key_range(address: &str) -> (Vec<u8>, Vec<u8>) {
+    let mut start = address.as_bytes().to_vec();
+    start.push(0);
+    let mut end = address.as_bytes().to_vec();
+    end.push(1);
+    (start, end)
+}
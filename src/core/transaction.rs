@@ -1,6 +1,12 @@
+use std::collections::HashSet;
 use serde::{Serialize, Deserialize};
 use sha2::{Sha256, Digest};
 use std::time::{SystemTime, UNIX_EPOCH};
+use crate::core::crypto;
+use crate::core::encoding::{self, Canonical, Decoder, Encoder};
+
+// Domain separator for input signing digests
+const SIGHASH_DOMAIN: &[u8] = b"NEXA-SIGHASH-V1";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TransactionType {
@@ -10,13 +16,17 @@ pub enum TransactionType {
     StakeDeposit,
     StakeWithdraw,
     ContributionReport,
+    SlashingEvidence,   // Proof of validator equivocation, carried in `data`
+    Coinbase,           // Block reward and fees, paid out by the first transaction of a block
+    Crosslink,          // Finalized header of another shard, checkpointed by a beacon block
+    ShardMigration,     // Step of a shard split or merge, carried in `data`
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransactionInput {
     pub previous_tx: String,     // Hash of the previous transaction
     pub index: u32,              // Index in the previous transaction's outputs
-    pub script_sig: String,      // Signature script (proves ownership)
+    pub script_sig: String,      // Hex-encoded public key of the spender (proves ownership with the input's signature)
     pub amount: u64,             // Amount of tokens
 }
 
@@ -24,7 +34,7 @@ pub struct TransactionInput {
 pub struct TransactionOutput {
     pub address: String,         // Recipient address
     pub amount: u64,             // Amount of tokens
-    pub script_pubkey: String,   // Public key script (defines spending conditions)
+    pub script_pubkey: String,   // Hex-encoded public key of the recipient (defines spending conditions)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,7 +48,7 @@ pub struct Transaction {
     pub shard_id: u16,           // Shard where this transaction belongs
     pub data: Vec<u8>,           // Additional data (e.g., for smart contracts)
     pub hash: String,            // Transaction hash
-    pub signatures: Vec<String>, // Signatures from all required parties
+    pub signatures: Vec<String>, // One ed25519 signature per input, over that input's signing digest
     pub privacy_proof: Option<String>, // zk-SNARK proof for private transactions
 }
 
@@ -75,50 +85,69 @@ impl Transaction {
     }
     
     pub fn calculate_hash(&self) -> String {
-        // Create a copy without the hash and signatures for hashing
-        let hash_tx = Transaction {
-            version: self.version,
-            tx_type: self.tx_type.clone(),
-            inputs: self.inputs.clone(),
-            outputs: self.outputs.clone(),
-            timestamp: self.timestamp,
-            lock_time: self.lock_time,
-            shard_id: self.shard_id,
-            data: self.data.clone(),
-            hash: String::new(),
-            signatures: Vec::new(),
-            privacy_proof: self.privacy_proof.clone(),
-        };
-        
-        let serialized = serde_json::to_string(&hash_tx).unwrap();
+        // Hash everything except the hash itself and the signatures
+        let mut encoder = Encoder::new();
+        self.encode_unsigned(&mut encoder);
+        encoding::hash_bytes(&encoder.finish())
+    }
+    
+    fn encode_unsigned(&self, encoder: &mut Encoder) {
+        encoder.put_u32(self.version);
+        self.tx_type.encode(encoder);
+        encoder.put_seq(&self.inputs);
+        encoder.put_seq(&self.outputs);
+        encoder.put_u64(self.timestamp);
+        encoder.put_u64(self.lock_time);
+        encoder.put_u16(self.shard_id);
+        encoder.put_bytes(&self.data);
+        encoder.put_option(&self.privacy_proof, |e, proof| e.put_str(proof));
+    }
+    
+    /// Returns the digest signed for the input at `input_index`.
+    ///
+    /// The digest commits to the transaction hash (which covers every input,
+    /// including its `script_sig`, and every output) and to the input index,
+    /// so a signature can't be replayed for another input or transaction.
+    pub fn signing_digest(&self, input_index: usize) -> Vec<u8> {
         let mut hasher = Sha256::new();
-        hasher.update(serialized.as_bytes());
-        let result = hasher.finalize();
-        hex::encode(result)
+        hasher.update(SIGHASH_DOMAIN);
+        hasher.update(self.hash.as_bytes());
+        hasher.update((input_index as u32).to_le_bytes());
+        hasher.finalize().to_vec()
     }
     
     pub fn sign(&mut self, private_keys: &[&[u8]]) -> Result<(), Box<dyn std::error::Error>> {
+        if private_keys.len() != self.inputs.len() {
+            return Err(format!("Expected {} private keys, got {}", self.inputs.len(), private_keys.len()).into());
+        }
+        
+        // Record each spender's public key in its input, then rehash so the
+        // signatures commit to the keys as well
+        for (input, key) in self.inputs.iter_mut().zip(private_keys) {
+            let signing_key = crypto::signing_key_from_bytes(key)?;
+            input.script_sig = hex::encode(signing_key.verifying_key().as_bytes());
+        }
+        self.hash = self.calculate_hash();
+        
         // Clear existing signatures
         self.signatures.clear();
         
-        // In a real implementation, this would use ed25519 or similar to sign the transaction hash
-        // For simplicity, we're just using placeholders
-        for (i, _key) in private_keys.iter().enumerate() {
-            self.signatures.push(format!("sig-{}-{}", i, self.hash));
+        for (i, key) in private_keys.iter().enumerate() {
+            let digest = self.signing_digest(i);
+            self.signatures.push(crypto::sign(key, &digest)?);
         }
         
         Ok(())
     }
     
     pub fn verify_signatures(&self, public_keys: &[&[u8]]) -> bool {
-        // In a real implementation, this would verify each signature using the corresponding public key
-        // For simplicity, we're just using placeholder checks
-        if self.signatures.len() != public_keys.len() {
+        if self.signatures.len() != self.inputs.len() || public_keys.len() != self.inputs.len() {
             return false;
         }
         
-        for (i, _) in self.signatures.iter().enumerate() {
-            if self.signatures[i] != format!("sig-{}-{}", i, self.hash) {
+        for (i, public_key) in public_keys.iter().enumerate() {
+            let digest = self.signing_digest(i);
+            if !crypto::verify(public_key, &digest, &self.signatures[i]) {
                 return false;
             }
         }
@@ -126,6 +155,22 @@ impl Transaction {
         true
     }
     
+    /// Verifies each input's signature against the public key carried in its
+    /// `script_sig`. Whether that key actually owns the spent output can only
+    /// be checked against state; see `StateManager::apply_transaction`.
+    pub fn verify_input_signatures(&self) -> bool {
+        let mut public_keys = Vec::with_capacity(self.inputs.len());
+        for input in &self.inputs {
+            match hex::decode(&input.script_sig) {
+                Ok(key) => public_keys.push(key),
+                Err(_) => return false,
+            }
+        }
+        
+        let key_refs: Vec<&[u8]> = public_keys.iter().map(|key| key.as_slice()).collect();
+        self.verify_signatures(&key_refs)
+    }
+    
     pub fn add_privacy_proof(&mut self, proof: String) {
         self.privacy_proof = Some(proof);
     }
@@ -136,25 +181,73 @@ impl Transaction {
         self.privacy_proof.is_some()
     }
     
+    /// Sum of the input amounts, or None if it overflows.
+    pub fn total_input(&self) -> Option<u64> {
+        self.inputs.iter().try_fold(0u64, |total, input| total.checked_add(input.amount))
+    }
+    
+    /// Sum of the output amounts, or None if it overflows.
+    pub fn total_output(&self) -> Option<u64> {
+        self.outputs.iter().try_fold(0u64, |total, output| total.checked_add(output.amount))
+    }
+    
+    /// Amount left over for the block producer: inputs minus outputs. None if
+    /// either total overflows.
+    pub fn fee(&self) -> Option<u64> {
+        Some(self.total_input()?.saturating_sub(self.total_output()?))
+    }
+    
     pub fn is_valid(&self) -> bool {
         // Check that the hash is correct
         if self.hash != self.calculate_hash() {
             return false;
         }
         
-        // Check that there are inputs and outputs
-        if self.inputs.is_empty() || self.outputs.is_empty() {
-            return false;
+        // Check that there are inputs and outputs. Slashing evidence may
+        // carry no value, so validators can submit it without funds, a
+        // coinbase mints what it pays out, a cross-shard receipt claim or
+        // refund creates the output its receipt promises, and crosslinks and
+        // shard migrations move no value through inputs or outputs.
+        match self.tx_type {
+            TransactionType::SlashingEvidence => {}
+            TransactionType::Coinbase => return self.inputs.is_empty(),
+            TransactionType::Crosslink | TransactionType::ShardMigration => {
+                return self.inputs.is_empty() && self.outputs.is_empty() && self.signatures.is_empty();
+            }
+            TransactionType::ShardCrossing if self.inputs.is_empty() => {
+                return self.outputs.is_empty() && self.signatures.is_empty();
+            }
+            _ => {
+                if self.inputs.is_empty() || self.outputs.is_empty() {
+                    return false;
+                }
+            }
         }
         
-        // Check that the total output amount doesn't exceed the total input amount
-        let total_input: u64 = self.inputs.iter().map(|input| input.amount).sum();
-        let total_output: u64 = self.outputs.iter().map(|output| output.amount).sum();
+        // Check that no output is spent twice within the transaction
+        let mut spent = HashSet::new();
+        for input in &self.inputs {
+            if !spent.insert((input.previous_tx.as_str(), input.index)) {
+                return false;
+            }
+        }
+        
+        // Check that the total output amount doesn't exceed the total input
+        // amount, and that neither total overflows
+        let (total_input, total_output) = match (self.total_input(), self.total_output()) {
+            (Some(total_input), Some(total_output)) => (total_input, total_output),
+            _ => return false,
+        };
         
         if total_output > total_input {
             return false;
         }
         
+        // Every input must carry a valid signature from the key in its script_sig
+        if !self.verify_input_signatures() {
+            return false;
+        }
+        
         // For private transactions, verify the privacy proof
         if matches!(self.tx_type, TransactionType::Transfer) && self.privacy_proof.is_some() {
             if !self.verify_privacy_proof() {
@@ -166,4 +259,117 @@ impl Transaction {
         
         true
     }
-}
\ No newline at end of file
+}
+
+impl Canonical for TransactionType {
+    fn encode(&self, encoder: &mut Encoder) {
+        let tag = match self {
+            TransactionType::Transfer => 0,
+            TransactionType::SmartContract => 1,
+            TransactionType::ShardCrossing => 2,
+            TransactionType::StakeDeposit => 3,
+            TransactionType::StakeWithdraw => 4,
+            TransactionType::ContributionReport => 5,
+            TransactionType::SlashingEvidence => 6,
+            TransactionType::Coinbase => 7,
+            TransactionType::Crosslink => 8,
+            TransactionType::ShardMigration => 9,
+        };
+        encoder.put_u8(tag);
+    }
+    
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        match decoder.get_u8()? {
+            0 => Ok(TransactionType::Transfer),
+            1 => Ok(TransactionType::SmartContract),
+            2 => Ok(TransactionType::ShardCrossing),
+            3 => Ok(TransactionType::StakeDeposit),
+            4 => Ok(TransactionType::StakeWithdraw),
+            5 => Ok(TransactionType::ContributionReport),
+            6 => Ok(TransactionType::SlashingEvidence),
+            7 => Ok(TransactionType::Coinbase),
+            8 => Ok(TransactionType::Crosslink),
+            9 => Ok(TransactionType::ShardMigration),
+            tag => Err(format!("Unknown transaction type {}", tag)),
+        }
+    }
+}
+
+impl Canonical for TransactionInput {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_str(&self.previous_tx);
+        encoder.put_u32(self.index);
+        encoder.put_str(&self.script_sig);
+        encoder.put_u64(self.amount);
+    }
+    
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(TransactionInput {
+            previous_tx: decoder.get_string()?,
+            index: decoder.get_u32()?,
+            script_sig: decoder.get_string()?,
+            amount: decoder.get_u64()?,
+        })
+    }
+}
+
+impl Canonical for TransactionOutput {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_str(&self.address);
+        encoder.put_u64(self.amount);
+        encoder.put_str(&self.script_pubkey);
+    }
+    
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(TransactionOutput {
+            address: decoder.get_string()?,
+            amount: decoder.get_u64()?,
+            script_pubkey: decoder.get_string()?,
+        })
+    }
+}
+
+// Wire format: the hashed fields followed by the hash and signatures
+impl Canonical for Transaction {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.encode_unsigned(encoder);
+        encoder.put_str(&self.hash);
+        encoder.put_len(self.signatures.len());
+        for signature in &self.signatures {
+            encoder.put_str(signature);
+        }
+    }
+    
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        let version = decoder.get_u32()?;
+        let tx_type = TransactionType::decode(decoder)?;
+        let inputs = decoder.get_seq()?;
+        let outputs = decoder.get_seq()?;
+        let timestamp = decoder.get_u64()?;
+        let lock_time = decoder.get_u64()?;
+        let shard_id = decoder.get_u16()?;
+        let data = decoder.get_bytes()?;
+        let privacy_proof = decoder.get_option(|d| d.get_string())?;
+        let hash = decoder.get_string()?;
+        
+        let signature_count = decoder.get_u32()?;
+        let mut signatures = Vec::new();
+        for _ in 0..signature_count {
+            signatures.push(decoder.get_string()?);
+        }
+        
+        Ok(Transaction {
+            version,
+            tx_type,
+            inputs,
+            outputs,
+            timestamp,
+            lock_time,
+            shard_id,
+            data,
+            hash,
+            signatures,
+            privacy_proof,
+        })
+    }
+}
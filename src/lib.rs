@@ -1,14 +1,20 @@
 pub mod core;
 pub mod network;
+pub mod node;
 pub mod smartcontracts;
 pub mod ai;
 pub mod wallets;
 
+use std::path::Path;
 use log::{info, error};
 
-pub fn initialize() -> Result<(), Box<dyn std::error::Error>> {
+/// Initializes every component from the genesis file at `config`.
+pub fn initialize(config: &Path) -> Result<(), Box<dyn std::error::Error>> {
     info!("Initializing NexaCore blockchain...");
     
+    // Chain parameters and genesis state come from the genesis file
+    core::genesis::load(config)?;
+    
     // Initialize core components
     core::initialize()?;
     
@@ -1,5 +1,6 @@
 use clap::{Parser, Subcommand};
 use log::{info, error};
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::process;
 
@@ -10,6 +11,7 @@ struct Cli {
     #[clap(subcommand)]
     command: Commands,
     
+    /// Genesis file (TOML, or JSON if it ends in .json)
     #[clap(short, long, value_parser, default_value = "config/config.toml")]
     config: PathBuf,
     
@@ -23,6 +25,27 @@ enum Commands {
     Node {
         #[clap(short, long, action)]
         validator: bool,
+        
+        /// Directory holding the chain database
+        #[clap(long, value_parser, default_value = "data")]
+        data_dir: PathBuf,
+        
+        /// File holding the hex-encoded validator signing key
+        #[clap(long, value_parser)]
+        validator_key: Option<PathBuf>,
+        
+        /// Address the P2P network listens on
+        #[clap(long, value_parser, default_value = "/ip4/0.0.0.0/tcp/30333")]
+        p2p_addr: String,
+        
+        /// Address the JSON-RPC server listens on
+        #[clap(long, value_parser, default_value = "127.0.0.1:8545")]
+        rpc_addr: SocketAddr,
+        
+        /// Shard to serve; validators move to the shard of their committee
+        /// once one is drawn
+        #[clap(long, value_parser, default_value_t = 0)]
+        shard: u16,
     },
     /// Start a light client
     Client {},
@@ -49,15 +72,66 @@ fn main() {
     
     info!("Starting NexaCore v0.1.0");
     
-    if let Err(e) = nexacore::initialize() {
+    if let Err(e) = nexacore::initialize(&cli.config) {
         error!("Failed to initialize NexaCore: {}", e);
         process::exit(1);
     }
     
+    // Set up signal handlers for graceful shutdown
+    ctrlc::set_handler(move || {
+        info!("Received shutdown signal");
+        if let Err(e) = nexacore::shutdown() {
+            error!("Error during shutdown: {}", e);
+        }
+        process::exit(0);
+    }).expect("Error setting Ctrl-C handler");
+    
     match &cli.command {
-        Commands::Node { validator } => {
+        Commands::Node { validator, data_dir, validator_key, p2p_addr, rpc_addr, shard } => {
             info!("Starting node (validator: {})", validator);
-            // Start node logic here
+            
+            // Resume from the persisted chain state
+            if let Err(e) = nexacore::core::state::open_storage(data_dir) {
+                error!("Failed to open chain storage: {}", e);
+                process::exit(1);
+            }
+            if let Err(e) = nexacore::core::chain::resume_shards() {
+                error!("Failed to resume shards: {}", e);
+                process::exit(1);
+            }
+            
+            if nexacore::core::state::get_state_manager(*shard).is_none() {
+                error!("Shard {} is not in the shard map", shard);
+                process::exit(1);
+            }
+            
+            let validator_key = match (validator, validator_key) {
+                (true, Some(path)) => match nexacore::node::load_validator_key(path) {
+                    Ok(key) => Some(key),
+                    Err(e) => {
+                        error!("Failed to load validator key from {}: {}", path.display(), e);
+                        process::exit(1);
+                    }
+                },
+                (true, None) => {
+                    error!("--validator requires --validator-key");
+                    process::exit(1);
+                }
+                (false, _) => None,
+            };
+            
+            let config = nexacore::node::NodeConfig {
+                shard_id: *shard,
+                p2p_address: p2p_addr.clone(),
+                rpc_address: *rpc_addr,
+                validator_key,
+            };
+            
+            let runtime = tokio::runtime::Runtime::new().expect("Failed to start async runtime");
+            if let Err(e) = runtime.block_on(nexacore::node::run(config)) {
+                error!("Node stopped: {}", e);
+                process::exit(1);
+            }
         },
         Commands::Client {} => {
             info!("Starting light client");
@@ -69,15 +143,6 @@ fn main() {
         },
     }
     
-    // Set up signal handlers for graceful shutdown
-    ctrlc::set_handler(move || {
-        info!("Received shutdown signal");
-        if let Err(e) = nexacore::shutdown() {
-            error!("Error during shutdown: {}", e);
-        }
-        process::exit(0);
-    }).expect("Error setting Ctrl-C handler");
-    
     // Keep the main thread alive
     loop {
         std::thread::sleep(std::time::Duration::from_secs(1));
@@ -13,8 +13,23 @@ use libp2p::{
     NetworkBehaviour, PeerId, Transport,
 };
 use crate::core::block::Block;
+use crate::core::chain;
+use crate::core::encoding::{self, Canonical, Decoder, Encoder};
+use crate::core::finality::Vote;
+use crate::core::genesis;
+use crate::core::mempool;
+use crate::core::state;
 use crate::core::transaction::Transaction;
 
+// How often transactions submitted to the local mempool are gossiped
+const MEMPOOL_ANNOUNCE_INTERVAL: Duration = Duration::from_millis(500);
+
+// Maximum number of blocks kept while waiting for their parent
+const MAX_ORPHAN_BLOCKS: usize = 256;
+
+// Maximum number of blocks sent in answer to one shard sync request
+const MAX_SYNC_BLOCKS: u64 = 128;
+
 // Message types for P2P communication
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
@@ -41,12 +56,105 @@ pub enum Message {
         shard_id: u16,
         address: String,
         port: u16,
+        genesis_hash: String, // Peers of another chain are ignored
     },
     ShardSync {
         shard_id: u16,
         from_block: u64,
         to_block: u64,
     },
+    Vote(Vote),
+}
+
+// Messages travel in the canonical binary encoding, one tag byte per variant
+impl Canonical for Message {
+    fn encode(&self, encoder: &mut Encoder) {
+        match self {
+            Message::NewBlock(block) => {
+                encoder.put_u8(0);
+                block.encode(encoder);
+            }
+            Message::NewTransaction(tx) => {
+                encoder.put_u8(1);
+                tx.encode(encoder);
+            }
+            Message::BlockRequest { block_hash, requester } => {
+                encoder.put_u8(2);
+                encoder.put_str(block_hash);
+                encoder.put_str(requester);
+            }
+            Message::BlockResponse { block, responder } => {
+                encoder.put_u8(3);
+                block.encode(encoder);
+                encoder.put_str(responder);
+            }
+            Message::TransactionRequest { tx_hash, requester } => {
+                encoder.put_u8(4);
+                encoder.put_str(tx_hash);
+                encoder.put_str(requester);
+            }
+            Message::TransactionResponse { transaction, responder } => {
+                encoder.put_u8(5);
+                transaction.encode(encoder);
+                encoder.put_str(responder);
+            }
+            Message::PeerAnnounce { peer_id, shard_id, address, port, genesis_hash } => {
+                encoder.put_u8(6);
+                encoder.put_str(peer_id);
+                encoder.put_u16(*shard_id);
+                encoder.put_str(address);
+                encoder.put_u16(*port);
+                encoder.put_str(genesis_hash);
+            }
+            Message::ShardSync { shard_id, from_block, to_block } => {
+                encoder.put_u8(7);
+                encoder.put_u16(*shard_id);
+                encoder.put_u64(*from_block);
+                encoder.put_u64(*to_block);
+            }
+            Message::Vote(vote) => {
+                encoder.put_u8(8);
+                vote.encode(encoder);
+            }
+        }
+    }
+    
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        match decoder.get_u8()? {
+            0 => Ok(Message::NewBlock(Block::decode(decoder)?)),
+            1 => Ok(Message::NewTransaction(Transaction::decode(decoder)?)),
+            2 => Ok(Message::BlockRequest {
+                block_hash: decoder.get_string()?,
+                requester: decoder.get_string()?,
+            }),
+            3 => Ok(Message::BlockResponse {
+                block: Block::decode(decoder)?,
+                responder: decoder.get_string()?,
+            }),
+            4 => Ok(Message::TransactionRequest {
+                tx_hash: decoder.get_string()?,
+                requester: decoder.get_string()?,
+            }),
+            5 => Ok(Message::TransactionResponse {
+                transaction: Transaction::decode(decoder)?,
+                responder: decoder.get_string()?,
+            }),
+            6 => Ok(Message::PeerAnnounce {
+                peer_id: decoder.get_string()?,
+                shard_id: decoder.get_u16()?,
+                address: decoder.get_string()?,
+                port: decoder.get_u16()?,
+                genesis_hash: decoder.get_string()?,
+            }),
+            7 => Ok(Message::ShardSync {
+                shard_id: decoder.get_u16()?,
+                from_block: decoder.get_u64()?,
+                to_block: decoder.get_u64()?,
+            }),
+            8 => Ok(Message::Vote(Vote::decode(decoder)?)),
+            tag => Err(format!("Unknown message type {}", tag)),
+        }
+    }
 }
 
 // Network behavior combining Floodsub and mDNS
@@ -63,7 +171,7 @@ struct NexaCoreBehaviour {
 impl NetworkBehaviourEventProcess<FloodsubEvent> for NexaCoreBehaviour {
     fn inject_event(&mut self, event: FloodsubEvent) {
         if let FloodsubEvent::Message(message) = event {
-            if let Ok(msg) = serde_json::from_slice::<Message>(&message.data) {
+            if let Ok(msg) = encoding::from_bytes::<Message>(&message.data) {
                 debug!("Received message: {:?} from {:?}", msg, message.source);
                 
                 // Forward the message to the handler
@@ -104,7 +212,11 @@ pub struct P2PManager {
     subscribed_topics: HashSet<String>,
     message_sender: mpsc::UnboundedSender<Message>,
     message_receiver: mpsc::UnboundedReceiver<Message>,
+    broadcast_sender: mpsc::UnboundedSender<Message>,
+    broadcast_receiver: mpsc::UnboundedReceiver<Message>,
+    orphan_blocks: HashMap<String, Vec<Block>>, // Key: hash of the missing parent
     shard_id: u16,
+    genesis_hash: String,
 }
 
 #[derive(Debug, Clone)]
@@ -123,9 +235,10 @@ impl P2PManager {
         let local_peer_id = PeerId::from(local_key.public());
         info!("Local peer ID: {}", local_peer_id);
         
-        // Create a channel for handling messages
+        // Create a channel for handling received messages, and one for
+        // messages other components want broadcast
         let (message_sender, message_receiver) = mpsc::unbounded_channel();
-        let (response_sender, _) = mpsc::unbounded_channel();
+        let (broadcast_sender, broadcast_receiver) = mpsc::unbounded_channel();
         
         // Create a transport
         let transport = libp2p::development_transport(local_key).await?;
@@ -138,7 +251,7 @@ impl P2PManager {
         let mut behaviour = NexaCoreBehaviour {
             floodsub: Floodsub::new(local_peer_id),
             mdns: Mdns::new(Default::default()).await?,
-            response_sender,
+            response_sender: message_sender.clone(),
         };
         
         // Subscribe to the topics
@@ -162,7 +275,11 @@ impl P2PManager {
             subscribed_topics,
             message_sender,
             message_receiver,
+            broadcast_sender,
+            broadcast_receiver,
+            orphan_blocks: HashMap::new(),
             shard_id,
+            genesis_hash: genesis::get().hash(),
         })
     }
     
@@ -182,11 +299,35 @@ impl P2PManager {
     }
     
     async fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut announce_interval = tokio::time::interval(MEMPOOL_ANNOUNCE_INTERVAL);
+        
         loop {
             tokio::select! {
                 event = self.swarm.select_next_some() => {
                     debug!("Swarm event: {:?}", event);
                 }
+                _ = announce_interval.tick() => {
+                    self.announce_transactions().await?;
+                }
+                msg = self.broadcast_receiver.recv() => {
+                    match msg {
+                        Some(Message::NewBlock(block)) => self.broadcast_block(&block).await?,
+                        Some(Message::Vote(vote)) => {
+                            let shard_topic = format!("nexacore-shard-{}", vote.shard_id);
+                            self.broadcast_message(&Message::Vote(vote), &shard_topic).await?;
+                        }
+                        Some(Message::ShardSync { shard_id, from_block, to_block }) => {
+                            // A validator joining a shard follows it from now on
+                            self.subscribe_shard(shard_id);
+                            self.request_shard_sync(shard_id, from_block, to_block).await?;
+                        }
+                        Some(msg) => {
+                            let shard_topic = format!("nexacore-shard-{}", self.shard_id);
+                            self.broadcast_message(&msg, &shard_topic).await?;
+                        }
+                        None => break,
+                    }
+                }
                 msg = self.message_receiver.recv() => {
                     if let Some(msg) = msg {
                         self.handle_message(msg).await?;
@@ -206,22 +347,28 @@ impl P2PManager {
             Message::NewBlock(block) => {
                 // Process new block
                 debug!("Received new block: {}", block.hash);
-                // Forward to appropriate handlers
+                self.import_block(block).await?;
             }
             Message::NewTransaction(tx) => {
                 // Process new transaction
                 debug!("Received new transaction: {}", tx.hash);
-                // Forward to appropriate handlers
+                
+                // Floodsub has already relayed it to our peers
+                if let Err(e) = mempool::submit_transaction(tx, false) {
+                    debug!("Ignoring gossiped transaction: {}", e);
+                }
             }
             Message::BlockRequest { block_hash, requester } => {
                 // Handle block request
                 debug!("Received block request for {}", block_hash);
-                // Fetch block and respond
+                if requester != self.local_peer_id.to_string() {
+                    self.respond_to_block_request(&block_hash).await?;
+                }
             }
             Message::BlockResponse { block, responder } => {
                 // Process block response
                 debug!("Received block response from {}", responder);
-                // Forward block to appropriate handlers
+                self.import_block(block).await?;
             }
             Message::TransactionRequest { tx_hash, requester } => {
                 // Handle transaction request
@@ -233,9 +380,16 @@ impl P2PManager {
                 debug!("Received transaction response from {}", responder);
                 // Forward transaction to appropriate handlers
             }
-            Message::PeerAnnounce { peer_id, shard_id, address, port } => {
+            Message::PeerAnnounce { peer_id, shard_id, address, port, genesis_hash } => {
                 // Process peer announcement
                 debug!("Received peer announcement: {} (shard {})", peer_id, shard_id);
+                
+                // Only peers started from the same genesis are on our chain
+                if genesis_hash != self.genesis_hash {
+                    warn!("Ignoring peer {} with genesis {} instead of {}", peer_id, genesis_hash, self.genesis_hash);
+                    return Ok(());
+                }
+                
                 // Add to known peers
                 self.add_peer(peer_id, shard_id, address, port).await?;
             }
@@ -243,7 +397,14 @@ impl P2PManager {
                 // Process shard sync request
                 debug!("Received shard sync request for shard {}: blocks {}-{}", 
                        shard_id, from_block, to_block);
-                // Handle shard synchronization
+                self.respond_to_shard_sync(shard_id, from_block, to_block).await?;
+            }
+            Message::Vote(vote) => {
+                // Process finality vote
+                debug!("Received {:?} from {} for block {}", vote.vote_type, vote.validator, vote.block_hash);
+                if let Err(e) = chain::import_vote(vote) {
+                    debug!("Ignoring vote: {}", e);
+                }
             }
         }
         
@@ -256,6 +417,7 @@ impl P2PManager {
             shard_id: self.shard_id,
             address: "127.0.0.1".to_string(), // In a real implementation, this would be the actual IP
             port: 8000, // In a real implementation, this would be the actual port
+            genesis_hash: self.genesis_hash.clone(),
         };
         
         self.broadcast_message(&announce_msg, "nexacore-global").await?;
@@ -298,11 +460,118 @@ impl P2PManager {
         Ok(())
     }
     
+    // Imports a received block, then any orphans that were waiting for it. A
+    // block whose parent is unknown is kept and the parent requested.
+    async fn import_block(&mut self, block: Block) -> Result<(), Box<dyn std::error::Error>> {
+        let mut pending = vec![block];
+        
+        while let Some(block) = pending.pop() {
+            let parent_known = state::get_state_manager(block.header.shard_id)
+                .map(|state_manager| {
+                    let state_manager = state_manager.lock().unwrap();
+                    state_manager.get_best_block_hash().is_empty()
+                        || state_manager.get_block_metadata(&block.header.previous_hash).is_some()
+                })
+                .unwrap_or(false);
+            
+            if !parent_known {
+                let parent_hash = block.header.previous_hash.clone();
+                let orphan_count: usize = self.orphan_blocks.values().map(|blocks| blocks.len()).sum();
+                if orphan_count < MAX_ORPHAN_BLOCKS {
+                    self.orphan_blocks.entry(parent_hash.clone()).or_default().push(block);
+                    self.request_block(&parent_hash).await?;
+                }
+                continue;
+            }
+            
+            match chain::import_block(&block) {
+                Ok(result) => {
+                    debug!("Imported block {}: {:?}", block.hash, result);
+                    if let Some(children) = self.orphan_blocks.remove(&block.hash) {
+                        pending.extend(children);
+                    }
+                }
+                Err(e) => warn!("Rejected block {}: {}", block.hash, e),
+            }
+        }
+        
+        Ok(())
+    }
+    
+    async fn respond_to_block_request(&mut self, block_hash: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let block = match state::get_state_manager(self.shard_id) {
+            Some(state_manager) => state_manager.lock().unwrap().get_block(block_hash),
+            None => None,
+        };
+        
+        if let Some(block) = block {
+            let msg = Message::BlockResponse {
+                block,
+                responder: self.local_peer_id.to_string(),
+            };
+            let shard_topic = format!("nexacore-shard-{}", self.shard_id);
+            self.broadcast_message(&msg, &shard_topic).await?;
+        }
+        
+        Ok(())
+    }
+    
+    // Sends the requested best-chain blocks of a shard, at most
+    // MAX_SYNC_BLOCKS of them, to the shard's topic
+    async fn respond_to_shard_sync(&mut self, shard_id: u16, from_block: u64, to_block: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let to_block = to_block.min(from_block.saturating_add(MAX_SYNC_BLOCKS - 1));
+        let blocks = match state::get_state_manager(shard_id) {
+            Some(state_manager) => state_manager.lock().unwrap().get_best_chain_blocks(from_block, to_block),
+            None => return Ok(()),
+        };
+        
+        let shard_topic = format!("nexacore-shard-{}", shard_id);
+        for block in blocks {
+            let msg = Message::BlockResponse {
+                block,
+                responder: self.local_peer_id.to_string(),
+            };
+            self.broadcast_message(&msg, &shard_topic).await?;
+        }
+        
+        Ok(())
+    }
+    
+    fn subscribe_shard(&mut self, shard_id: u16) {
+        let shard_topic = format!("nexacore-shard-{}", shard_id);
+        if self.subscribed_topics.insert(shard_topic.clone()) {
+            self.swarm.behaviour_mut().floodsub.subscribe(floodsub::Topic::new(shard_topic));
+            info!("Subscribed to shard {}", shard_id);
+        }
+    }
+    
+    /// Returns a sender for messages to broadcast from the network loop. New
+    /// blocks go to their shard and the global topic, votes to their shard,
+    /// and shard sync requests subscribe this node to the shard they ask
+    /// for. Everything else goes to this node's shard.
+    pub fn broadcaster(&self) -> mpsc::UnboundedSender<Message> {
+        self.broadcast_sender.clone()
+    }
+    
+    // Gossips transactions submitted to this node's mempool
+    async fn announce_transactions(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let transactions = match mempool::get_mempool(self.shard_id) {
+            Some(mempool) => mempool.lock().unwrap().take_unannounced(),
+            None => return Ok(()),
+        };
+        
+        for tx in &transactions {
+            self.broadcast_transaction(tx).await?;
+        }
+        
+        Ok(())
+    }
+    
     pub async fn broadcast_message(&mut self, msg: &Message, topic: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let json = serde_json::to_string(msg)?;
+        let payload = encoding::to_bytes(msg);
         let topic = floodsub::Topic::new(topic);
         
-        self.swarm.behaviour_mut().floodsub.publish(topic, json.as_bytes());
+        self.swarm.behaviour_mut().floodsub.publish(topic, payload);
         debug!("Broadcasted message to topic {}", topic);
         
         Ok(())
@@ -5,8 +5,16 @@ use serde::{Serialize, Deserialize};
 use jsonrpc::{Request, Response, Error as JsonRpcError};
 use tokio::net::TcpListener;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::core::beacon::{Crosslink, BEACON_ADDRESS, BEACON_SHARD};
 use crate::core::block::Block;
+use crate::core::cross_shard::{Receipt, ReceiptProof};
+use crate::core::encoding;
+use crate::core::mempool;
+use crate::core::merkle::MerkleProof;
+use crate::core::shard_map;
+use crate::core::state_tree::{AccountProof, SparseMerkleProof, StorageProof};
 use crate::core::transaction::Transaction;
+use crate::core::slashing;
 use crate::core::state;
 use crate::core::consensus;
 use crate::core::shard;
@@ -17,7 +25,7 @@ pub struct BlockchainInfo {
     pub chain_id: String,
     pub current_height: u64,
     pub best_block_hash: String,
-    pub difficulty: u32,
+    pub current_slot: u64,
     pub total_transactions: u64,
     pub shard_count: u16,
     pub node_count: u32,
@@ -43,6 +51,85 @@ pub struct AccountInfo {
     pub is_contract: bool,
 }
 
+// Inclusion proof of a transaction in a block, checkable against the block header
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionProof {
+    pub tx_hash: String,
+    pub block_hash: String,
+    pub merkle_root: String,
+    pub proof: MerkleProof,
+}
+
+// Account proven against the state root of `block_hash`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountWithProof {
+    pub block_hash: String,
+    pub state_root: String,
+    pub proof: AccountProof,
+}
+
+// Last finalized block of a shard
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FinalizedHead {
+    pub shard_id: u16,
+    pub height: u64,
+    pub block_hash: String,
+}
+
+// Equivocation seen by this node that hasn't been included in a block yet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingEvidence {
+    pub offender: String,
+    pub height: u64,
+    pub evidence: String, // Hex canonical encoding, the data of a SlashingEvidence transaction
+}
+
+// Contract storage slots proven against the state root of `block_hash`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageWithProof {
+    pub block_hash: String,
+    pub state_root: String,
+    pub proof: StorageProof,
+}
+
+// Shard that owns an address under the current shard map
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddressShard {
+    pub address: String,
+    pub prefix: u16,
+    pub shard_id: u16,
+    pub shard_map_version: u32,
+}
+
+// Receipt of a cross-shard send, with the proof a claim or refund carries
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceiptWithProof {
+    pub receipt_id: String,
+    pub receipt: Receipt,
+    pub finalized: bool,
+    pub anchored: bool,        // Whether the proof links the block to one the beacon state anchors
+    pub receipt_proof: String, // Hex canonical encoding of the receipt proof
+}
+
+// Latest crosslink of every shard, proven against the beacon state root of
+// `block_hash`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrosslinksWithProof {
+    pub block_hash: String,
+    pub state_root: String,
+    pub crosslinks: Vec<Crosslink>,
+    pub proof: StorageProof,
+}
+
+// UTXO, or its absence, proven against the state root of `block_hash`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UtxoWithProof {
+    pub block_hash: String,
+    pub state_root: String,
+    pub key: String,
+    pub proof: SparseMerkleProof,
+}
+
 pub struct RpcServer {
     bind_address: SocketAddr,
     shard_id: u16,
@@ -60,6 +147,7 @@ impl RpcServer {
         info!("Starting RPC server on {}", self.bind_address);
         
         let listener = TcpListener::bind(self.bind_address).await?;
+        let shard_id = self.shard_id;
         
         loop {
             match listener.accept().await {
@@ -84,7 +172,7 @@ impl RpcServer {
                                 match serde_json::from_str::<Request>(&request_str) {
                                     Ok(request) => {
                                         // Handle the request
-                                        let response = Self::handle_request(request).await;
+                                        let response = Self::handle_request(request, shard_id).await;
                                         
                                         // Send the response
                                         let response_str = serde_json::to_string(&response).unwrap();
@@ -118,14 +206,24 @@ impl RpcServer {
         }
     }
     
-    async fn handle_request(request: Request) -> Response {
+    async fn handle_request(request: Request, shard_id: u16) -> Response {
         match request.method.as_str() {
             "getBlockchainInfo" => Self::get_blockchain_info(request).await,
             "getBlock" => Self::get_block(request).await,
             "getTransaction" => Self::get_transaction(request).await,
-            "getAccount" => Self::get_account(request).await,
+            "getTransactionProof" => Self::get_transaction_proof(request, shard_id).await,
+            "getAccount" => Self::get_account(request, shard_id).await,
+            "getAccountProof" => Self::get_account_proof(request, shard_id).await,
+            "getStorageProof" => Self::get_storage_proof(request, shard_id).await,
+            "getFinalizedHead" => Self::get_finalized_head(request, shard_id).await,
+            "getReceiptProof" => Self::get_receipt_proof(request, shard_id).await,
+            "getUtxoProof" => Self::get_utxo_proof(request, shard_id).await,
+            "getSlashingEvidence" => Self::get_slashing_evidence(request, shard_id).await,
             "getShardInfo" => Self::get_shard_info(request).await,
             "getAllShards" => Self::get_all_shards(request).await,
+            "getShardMap" => Self::get_shard_map(request, shard_id).await,
+            "getAddressShard" => Self::get_address_shard(request, shard_id).await,
+            "getCrosslinks" => Self::get_crosslinks(request).await,
             "sendTransaction" => Self::send_transaction(request).await,
             "createAccount" => Self::create_account(request).await,
             "deployContract" => Self::deploy_contract(request).await,
@@ -147,7 +245,7 @@ impl RpcServer {
             chain_id: "nexacore-mainnet".to_string(),
             current_height: 1000,
             best_block_hash: "0x1234567890abcdef".to_string(),
-            difficulty: 12345,
+            current_slot: 1200,
             total_transactions: 5000,
             shard_count: 4,
             node_count: 100,
@@ -179,13 +277,18 @@ impl RpcServer {
             header: crate::core::block::BlockHeader {
                 version: 1,
                 previous_hash: "0x0000000000000000".to_string(),
+                height: 1,
                 merkle_root: "0xabcdef1234567890".to_string(),
+                state_root: "0x1234567890abcdef".to_string(),
                 timestamp: 1625097600,
                 shard_id: 0,
-                difficulty: 12345,
-                nonce: 42,
+                slot: 1,
+                missed_proposers: Vec::new(),
                 validator: "0xvalidator123".to_string(),
                 contribution_score: 100,
+                randao_reveal: String::new(),
+                randao_mix: String::new(),
+                beacon_height: 0,
             },
             transactions: Vec::new(),
             hash: block_hash.to_string(),
@@ -230,8 +333,43 @@ impl RpcServer {
         Response::result(request.id, serde_json::to_value(tx).unwrap())
     }
     
-    async fn get_account(request: Request) -> Response {
-        // Parse parameters
+    async fn get_transaction_proof(request: Request, shard_id: u16) -> Response {
+        // Parse parameters: transaction hash
+        let params = match request.params {
+            Some(params) => params,
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        let tx_hash = match params.get(0) {
+            Some(hash) => match hash.as_str() {
+                Some(hash_str) => hash_str,
+                None => return Response::error(request.id, JsonRpcError::invalid_params()),
+            },
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        let state_manager = match state::get_state_manager(shard_id) {
+            Some(manager) => manager,
+            None => return Response::error(request.id, JsonRpcError::internal_error()),
+        };
+        
+        let (block, proof) = match state_manager.lock().unwrap().prove_transaction(tx_hash) {
+            Some(found) => found,
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        let result = TransactionProof {
+            tx_hash: tx_hash.to_string(),
+            block_hash: block.hash.clone(),
+            merkle_root: block.header.merkle_root.clone(),
+            proof,
+        };
+        
+        Response::result(request.id, serde_json::to_value(result).unwrap())
+    }
+    
+    async fn get_account(request: Request, shard_id: u16) -> Response {
+        // Parse parameters: address
         let params = match request.params {
             Some(params) => params,
             None => return Response::error(request.id, JsonRpcError::invalid_params()),
@@ -245,19 +383,275 @@ impl RpcServer {
             None => return Response::error(request.id, JsonRpcError::invalid_params()),
         };
         
-        // In a real implementation, this would fetch the account from state
+        let state_manager = match state::get_state_manager(shard_id) {
+            Some(manager) => manager,
+            None => return Response::error(request.id, JsonRpcError::internal_error()),
+        };
         
-        // For this example, we'll just return a placeholder account
-        let account = AccountInfo {
-            address: address.to_string(),
-            balance: 1000000,
-            nonce: 5,
-            stake_amount: 50000,
-            contribution_score: 75,
-            is_contract: false,
+        // Addresses the state hasn't seen yet are empty accounts
+        let account = state_manager.lock().unwrap().get_account(address);
+        let result = match account {
+            Some(account) => AccountInfo {
+                address: account.address.clone(),
+                balance: account.balance,
+                nonce: account.nonce,
+                stake_amount: account.stake_amount,
+                contribution_score: account.contribution_score,
+                is_contract: !account.code.is_empty(),
+            },
+            None => AccountInfo {
+                address: address.to_string(),
+                balance: 0,
+                nonce: 0,
+                stake_amount: 0,
+                contribution_score: 0,
+                is_contract: false,
+            },
+        };
+        
+        Response::result(request.id, serde_json::to_value(result).unwrap())
+    }
+    
+    async fn get_account_proof(request: Request, shard_id: u16) -> Response {
+        // Parse parameters: address and optional block hash
+        let params = match request.params {
+            Some(params) => params,
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        let address = match params.get(0) {
+            Some(addr) => match addr.as_str() {
+                Some(addr_str) => addr_str,
+                None => return Response::error(request.id, JsonRpcError::invalid_params()),
+            },
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        let state_manager = match state::get_state_manager(shard_id) {
+            Some(manager) => manager,
+            None => return Response::error(request.id, JsonRpcError::internal_error()),
+        };
+        let state_manager = state_manager.lock().unwrap();
+        
+        let result = Self::prove_at(&state_manager, params.get(1), |state, block_hash| AccountWithProof {
+            block_hash,
+            state_root: state.state_root(),
+            proof: state.prove_account(address),
+        });
+        let result = match result {
+            Some(result) => result,
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        Response::result(request.id, serde_json::to_value(result).unwrap())
+    }
+    
+    async fn get_storage_proof(request: Request, shard_id: u16) -> Response {
+        // Parse parameters: address, storage keys and optional block hash
+        let params = match request.params {
+            Some(params) => params,
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        let address = match params.get(0) {
+            Some(addr) => match addr.as_str() {
+                Some(addr_str) => addr_str,
+                None => return Response::error(request.id, JsonRpcError::invalid_params()),
+            },
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        let keys: Vec<String> = match params.get(1).map(|keys| serde_json::from_value(keys.clone())) {
+            Some(Ok(keys)) => keys,
+            _ => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        let state_manager = match state::get_state_manager(shard_id) {
+            Some(manager) => manager,
+            None => return Response::error(request.id, JsonRpcError::internal_error()),
+        };
+        let state_manager = state_manager.lock().unwrap();
+        
+        let result = Self::prove_at(&state_manager, params.get(2), |state, block_hash| StorageWithProof {
+            block_hash,
+            state_root: state.state_root(),
+            proof: state.prove_storage(address, &keys),
+        });
+        let result = match result {
+            Some(result) => result,
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
         };
         
-        Response::result(request.id, serde_json::to_value(account).unwrap())
+        Response::result(request.id, serde_json::to_value(result).unwrap())
+    }
+    
+    // Builds a state proof against the block it is requested for: the best
+    // block by default, or a block of the best chain from the finalized block
+    // up. Older states aren't kept, so proofs against them, or against blocks
+    // off the best chain, can't be served.
+    fn prove_at<T>(
+        state_manager: &state::StateManager,
+        requested: Option<&serde_json::Value>,
+        prove: impl FnOnce(&state::StateManager, String) -> T,
+    ) -> Option<T> {
+        let best_block_hash = state_manager.get_best_block_hash();
+        
+        let block_hash = match requested {
+            None | Some(serde_json::Value::Null) => best_block_hash.clone(),
+            Some(hash) => hash.as_str()?.to_string(),
+        };
+        
+        if block_hash == best_block_hash {
+            return Some(prove(state_manager, block_hash));
+        }
+        
+        let state = state_manager.state_at(&block_hash)?;
+        Some(prove(&state, block_hash))
+    }
+    
+    async fn get_finalized_head(request: Request, shard_id: u16) -> Response {
+        let state_manager = match state::get_state_manager(shard_id) {
+            Some(manager) => manager,
+            None => return Response::error(request.id, JsonRpcError::internal_error()),
+        };
+        let state_manager = state_manager.lock().unwrap();
+        
+        // Null until the first block is finalized
+        let result = state_manager.get_finalized_head().map(|(height, block_hash)| FinalizedHead {
+            shard_id,
+            height,
+            block_hash,
+        });
+        
+        Response::result(request.id, serde_json::to_value(result).unwrap())
+    }
+    
+    async fn get_receipt_proof(request: Request, shard_id: u16) -> Response {
+        // Parse parameters: hash of the cross-shard send
+        let params = match request.params {
+            Some(params) => params,
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        let tx_hash = match params.get(0) {
+            Some(hash) => match hash.as_str() {
+                Some(hash_str) => hash_str,
+                None => return Response::error(request.id, JsonRpcError::invalid_params()),
+            },
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        // The shard's latest crosslink recorded by a finalized beacon block,
+        // which claims can anchor to, read before the shard is locked since
+        // the beacon shard's lock comes first
+        let crosslink = if shard_id == BEACON_SHARD {
+            None
+        } else {
+            state::get_state_manager(BEACON_SHARD).and_then(|beacon_state| {
+                let beacon_state = beacon_state.lock().unwrap();
+                beacon_state.anchor_of(shard_id, beacon_state.get_finalized_height())
+            })
+        };
+        
+        let state_manager = match state::get_state_manager(shard_id) {
+            Some(manager) => manager,
+            None => return Response::error(request.id, JsonRpcError::internal_error()),
+        };
+        let state_manager = state_manager.lock().unwrap();
+        
+        let (_, block) = match state_manager.get_transaction_with_block(tx_hash) {
+            Some(found) => found,
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        let finalized = state_manager
+            .get_block_metadata(&block.hash)
+            .is_some_and(|metadata| metadata.height <= state_manager.get_finalized_height());
+        
+        // Link the block to an anchored one: itself once finalized in the
+        // beacon shard, the latest crosslink of other shards
+        let descendants = match crosslink {
+            _ if shard_id == BEACON_SHARD => finalized.then(Vec::new),
+            Some(crosslink) => state_manager.descendant_headers(&block.hash, &crosslink.block_hash),
+            None => None,
+        };
+        let anchored = descendants.is_some();
+        
+        let receipt_proof = match ReceiptProof::new(&block, tx_hash, descendants.unwrap_or_default()) {
+            Some(proof) => proof,
+            None => return Response::error(request.id, JsonRpcError::internal_error()),
+        };
+        
+        // Fails for transactions that aren't cross-shard sends
+        let receipt = match receipt_proof.verify(crate::core::params::get().cross_shard.receipt_timeout) {
+            Ok(receipt) => receipt,
+            Err(_) => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        let result = ReceiptWithProof {
+            receipt_id: receipt.id(),
+            receipt,
+            finalized,
+            anchored,
+            receipt_proof: hex::encode(encoding::to_bytes(&receipt_proof)),
+        };
+        
+        Response::result(request.id, serde_json::to_value(result).unwrap())
+    }
+    
+    async fn get_utxo_proof(request: Request, shard_id: u16) -> Response {
+        // Parse parameters: UTXO key (tx_hash:output_index) and optional block hash
+        let params = match request.params {
+            Some(params) => params,
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        let key = match params.get(0) {
+            Some(key) => match key.as_str() {
+                Some(key_str) => key_str,
+                None => return Response::error(request.id, JsonRpcError::invalid_params()),
+            },
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        let state_manager = match state::get_state_manager(shard_id) {
+            Some(manager) => manager,
+            None => return Response::error(request.id, JsonRpcError::internal_error()),
+        };
+        let state_manager = state_manager.lock().unwrap();
+        
+        let result = Self::prove_at(&state_manager, params.get(1), |state, block_hash| UtxoWithProof {
+            block_hash,
+            state_root: state.state_root(),
+            key: key.to_string(),
+            proof: state.prove_utxo(key),
+        });
+        let result = match result {
+            Some(result) => result,
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        Response::result(request.id, serde_json::to_value(result).unwrap())
+    }
+    
+    async fn get_slashing_evidence(request: Request, shard_id: u16) -> Response {
+        let evidence_pool = match slashing::get_evidence_pool(shard_id) {
+            Some(pool) => pool,
+            None => return Response::error(request.id, JsonRpcError::internal_error()),
+        };
+        let evidence_pool = evidence_pool.lock().unwrap();
+        
+        let result: Vec<PendingEvidence> = evidence_pool
+            .pending()
+            .iter()
+            .map(|evidence| PendingEvidence {
+                offender: evidence.offender().to_string(),
+                height: evidence.height(),
+                evidence: hex::encode(encoding::to_bytes(evidence)),
+            })
+            .collect();
+        
+        Response::result(request.id, serde_json::to_value(result).unwrap())
     }
     
     async fn get_shard_info(request: Request) -> Response {
@@ -320,6 +714,78 @@ impl RpcServer {
         Response::result(request.id, serde_json::to_value(shards).unwrap())
     }
     
+    async fn get_shard_map(request: Request, shard_id: u16) -> Response {
+        let state_manager = match state::get_state_manager(shard_id) {
+            Some(manager) => manager,
+            None => return Response::error(request.id, JsonRpcError::internal_error()),
+        };
+        
+        // The latest version recorded on chain
+        let shard_map = state_manager.lock().unwrap().shard_map();
+        
+        Response::result(request.id, serde_json::to_value(shard_map).unwrap())
+    }
+    
+    async fn get_address_shard(request: Request, shard_id: u16) -> Response {
+        // Parse parameters: address
+        let params = match request.params {
+            Some(params) => params,
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        let address = match params.get(0) {
+            Some(addr) => match addr.as_str() {
+                Some(addr_str) => addr_str,
+                None => return Response::error(request.id, JsonRpcError::invalid_params()),
+            },
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        let state_manager = match state::get_state_manager(shard_id) {
+            Some(manager) => manager,
+            None => return Response::error(request.id, JsonRpcError::internal_error()),
+        };
+        let shard_map = state_manager.lock().unwrap().shard_map();
+        
+        let result = AddressShard {
+            address: address.to_string(),
+            prefix: shard_map::address_prefix(address),
+            shard_id: shard_map.shard_of(address),
+            shard_map_version: shard_map.version,
+        };
+        
+        Response::result(request.id, serde_json::to_value(result).unwrap())
+    }
+    
+    async fn get_crosslinks(request: Request) -> Response {
+        // Parse parameters: optional block hash of the beacon chain
+        let requested = request.params.as_ref().and_then(|params| params.get(0));
+        
+        // Served by nodes that follow the beacon chain
+        let state_manager = match state::get_state_manager(BEACON_SHARD) {
+            Some(manager) => manager,
+            None => return Response::error(request.id, JsonRpcError::internal_error()),
+        };
+        let state_manager = state_manager.lock().unwrap();
+        
+        let result = Self::prove_at(&state_manager, requested, |state, block_hash| {
+            let crosslinks = state.crosslinks();
+            let keys: Vec<String> = crosslinks.iter().map(Crosslink::storage_key).collect();
+            CrosslinksWithProof {
+                block_hash,
+                state_root: state.state_root(),
+                proof: state.prove_storage(BEACON_ADDRESS, &keys),
+                crosslinks,
+            }
+        });
+        let result = match result {
+            Some(result) => result,
+            None => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
+        
+        Response::result(request.id, serde_json::to_value(result).unwrap())
+    }
+    
     async fn send_transaction(request: Request) -> Response {
         // Parse parameters
         let params = match request.params {
@@ -332,14 +798,19 @@ impl RpcServer {
             None => return Response::error(request.id, JsonRpcError::invalid_params()),
         };
         
-        // In a real implementation, this would:
-        // 1. Parse the transaction from JSON
-        // 2. Validate the transaction
-        // 3. Add it to the mempool
-        // 4. Broadcast it to the network
+        let tx: Transaction = match serde_json::from_value(tx_json.clone()) {
+            Ok(tx) => tx,
+            Err(_) => return Response::error(request.id, JsonRpcError::invalid_params()),
+        };
         
-        // For this example, we'll just return a success response with a placeholder tx hash
-        let tx_hash = "0xtxhash123456789".to_string();
+        // Validate against the shard state and queue for gossip
+        let tx_hash = match mempool::submit_transaction(tx, true) {
+            Ok(tx_hash) => tx_hash,
+            Err(e) => {
+                warn!("Rejected transaction: {}", e);
+                return Response::error(request.id, JsonRpcError::invalid_params());
+            }
+        };
         
         Response::result(request.id, serde_json::to_value(tx_hash).unwrap())
     }
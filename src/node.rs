@@ -0,0 +1,189 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use log::{info, debug, error};
+use tokio::sync::mpsc;
+use crate::core::{chain, crypto, mempool, shard, state};
+use crate::network::p2p::{Message, P2PManager};
+use crate::network::rpc::RpcServer;
+
+// How often a validator checks whether it may propose
+const PROPOSAL_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often a validator checks for blocks to vote on
+const VOTE_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often a validator checks whether it joins another shard next epoch
+const HANDOFF_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    pub shard_id: u16, // Served until the validator is assigned to a committee
+    pub p2p_address: String,
+    pub rpc_address: SocketAddr,
+    pub validator_key: Option<Vec<u8>>, // Signing key of the local validator, if any
+}
+
+/// Reads a validator signing key stored as hex in `path`.
+pub fn load_validator_key(path: &Path) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let contents = std::fs::read_to_string(path)?;
+    let key = hex::decode(contents.trim())?;
+    
+    // Reject malformed keys up front rather than on the first block
+    crypto::signing_key_from_bytes(&key)?;
+    Ok(key)
+}
+
+/// Runs a full node: the P2P network, the RPC server and, for validators,
+/// block production and voting in the shard of their committee. Blocks from
+/// peers are imported by the P2P handler.
+pub async fn run(config: NodeConfig) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Starting node for shard {}", config.shard_id);
+    
+    let mut p2p = P2PManager::new(config.shard_id).await?;
+    let broadcaster = p2p.broadcaster();
+    let rpc = RpcServer::new(config.rpc_address, config.shard_id);
+    
+    tokio::select! {
+        result = p2p.start(config.p2p_address.clone()) => result,
+        result = rpc.start() => result,
+        result = produce_blocks(config.shard_id, config.validator_key.clone(), broadcaster.clone()) => result,
+        result = cast_votes(config.shard_id, config.validator_key.clone(), broadcaster.clone()) => result,
+        result = follow_committee(config.shard_id, config.validator_key.clone(), broadcaster) => result,
+    }
+}
+
+fn validator_address(key: &[u8]) -> Result<String, String> {
+    let key = crypto::signing_key_from_bytes(key)?;
+    Ok(crypto::public_key_to_address(key.verifying_key().as_bytes()))
+}
+
+// Shard the validator serves this epoch: that of its committee, or the
+// node's own shard while no committees are drawn
+fn serving_shard(default_shard: u16, address: &str) -> u16 {
+    let sharding = shard::get_engine();
+    let sharding = sharding.lock().unwrap();
+    sharding
+        .as_ref()
+        .and_then(|sharding| sharding.get_node_shard(address))
+        .unwrap_or(default_shard)
+}
+
+async fn produce_blocks(
+    shard_id: u16,
+    validator_key: Option<Vec<u8>>,
+    broadcaster: mpsc::UnboundedSender<Message>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = match validator_key {
+        Some(key) => key,
+        None => return std::future::pending().await, // Non-validators only import
+    };
+    
+    // Check often enough to propose soon after our turn in a slot comes
+    let address = validator_address(&key)?;
+    let mut interval = tokio::time::interval(PROPOSAL_INTERVAL);
+    loop {
+        interval.tick().await;
+        
+        // Producing locks the shard and applies its transactions, so it runs
+        // on a blocking thread rather than holding up the async workers
+        let serving = serving_shard(shard_id, &address);
+        let signing_key = key.clone();
+        let produced = tokio::task::spawn_blocking(move || {
+            let expired = mempool::expire_transactions(serving);
+            if expired > 0 {
+                debug!("Expired {} transactions from the shard {} mempool", expired, serving);
+            }
+            chain::produce_block(serving, &signing_key)
+        })
+        .await?;
+        
+        match produced {
+            Ok(Some(block)) => {
+                if broadcaster.send(Message::NewBlock(block)).is_err() {
+                    return Err("P2P network stopped".into());
+                }
+            }
+            Ok(None) => {} // Not our turn, or this slot already has a block
+            Err(e) => error!("Failed to produce block: {}", e),
+        }
+    }
+}
+
+async fn cast_votes(
+    shard_id: u16,
+    validator_key: Option<Vec<u8>>,
+    broadcaster: mpsc::UnboundedSender<Message>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = match validator_key {
+        Some(key) => key,
+        None => return std::future::pending().await, // Non-validators don't vote
+    };
+    
+    let address = validator_address(&key)?;
+    let mut interval = tokio::time::interval(VOTE_INTERVAL);
+    loop {
+        interval.tick().await;
+        
+        // Voting locks the shard and can finalize or reorganize its chain,
+        // so like producing it runs on a blocking thread
+        let serving = serving_shard(shard_id, &address);
+        let signing_key = key.clone();
+        let cast = tokio::task::spawn_blocking(move || chain::cast_votes(serving, &signing_key)).await?;
+        
+        match cast {
+            Ok(votes) => {
+                for vote in votes {
+                    if broadcaster.send(Message::Vote(vote)).is_err() {
+                        return Err("P2P network stopped".into());
+                    }
+                }
+            }
+            Err(e) => error!("Failed to cast finality votes: {}", e),
+        }
+    }
+}
+
+// Syncs the shard a validator joins next epoch, during the epoch before it
+// starts serving there
+async fn follow_committee(
+    shard_id: u16,
+    validator_key: Option<Vec<u8>>,
+    broadcaster: mpsc::UnboundedSender<Message>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = match validator_key {
+        Some(key) => key,
+        None => return std::future::pending().await, // Non-validators serve no committee
+    };
+    
+    let address = validator_address(&key)?;
+    let mut synced_epoch = None;
+    let mut interval = tokio::time::interval(HANDOFF_INTERVAL);
+    loop {
+        interval.tick().await;
+        
+        let (epoch, next_shard) = {
+            let sharding = shard::get_engine();
+            let sharding = sharding.lock().unwrap();
+            match sharding.as_ref() {
+                Some(sharding) => (sharding.get_committee_epoch(), sharding.get_next_node_shard(&address)),
+                None => continue,
+            }
+        };
+        
+        let next_shard = match next_shard {
+            Some(next_shard) if next_shard != serving_shard(shard_id, &address) && synced_epoch != Some(epoch) => next_shard,
+            _ => continue,
+        };
+        
+        let from_block = state::get_state_manager(next_shard)
+            .map_or(0, |state_manager| state_manager.lock().unwrap().get_current_height()) + 1;
+        info!("Joining shard {} after epoch {}, syncing from block {}", next_shard, epoch, from_block);
+        
+        let request = Message::ShardSync { shard_id: next_shard, from_block, to_block: u64::MAX };
+        if broadcaster.send(request).is_err() {
+            return Err("P2P network stopped".into());
+        }
+        synced_epoch = Some(epoch);
+    }
+}